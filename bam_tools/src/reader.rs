@@ -61,23 +61,30 @@ impl Reader {
 
     // Resizes the buffer so an additional record can fit in the end and fills this empty section.
     pub fn append_record(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
-        let block_size = self.read_block_size();
+        let block_size = self.read_block_size()?;
 
         let prev_len = buf.len();
         buf.resize(prev_len + block_size, 0);
 
-        self.read_exact(&mut buf[prev_len..prev_len + block_size])
-            .expect("Failed to read.");
+        self.read_exact(&mut buf[prev_len..prev_len + block_size])?;
 
         Ok(block_size)
     }
 
-    fn read_block_size(&mut self) -> usize {
-        match self.read_u32::<LittleEndian>() {
-            Ok(bs) => bs as usize,
-            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
-            Err(e) => panic!("{}", e),
+    // Returns 0 at EOF and error if stream ends in the middle of block size.
+    fn read_block_size(&mut self) -> io::Result<usize> {
+        let mut bytes = [0; 4];
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match self.read(&mut bytes[filled..]) {
+                Ok(0) if filled == 0 => return Ok(0),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
         }
+        Ok(u32::from_le_bytes(bytes) as usize)
     }
 
     pub fn records(&mut self) -> Records<'_> {
@@ -85,17 +92,34 @@ impl Reader {
     }
 
     pub fn read_header(&mut self) -> io::Result<(Vec<u8>, usize)> {
-        let magic = read_magic(self)?;
+        read_bam_header(self)
+    }
 
-        if magic != MAGIC_NUMBER {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid BAM header",
-            ));
+    /// Reports count of bytes read without percentage, for inputs of unknown
+    /// size (e.g. stdin). Does nothing if progress is already tracked.
+    pub fn track_progress_of_unknown_size(&mut self) {
+        if self.progress_bar.is_none() {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(ProgressStyle::default_spinner()
+                .template("{spinner:.green} [{elapsed_precise}] {bytes} ({bytes_per_sec})"));
+            self.progress_bar = Some(pb);
         }
+    }
+}
+
+/// Checks magic and parses header of decompressed BAM stream. Returns the
+/// same as `Reader::read_header`.
+pub fn read_bam_header<R: Read>(reader: &mut R) -> io::Result<(Vec<u8>, usize)> {
+    let magic = read_magic(reader)?;
 
-        read_header(self)
+    if magic != MAGIC_NUMBER {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid BAM header",
+        ));
     }
+
+    read_header(reader)
 }
 
 pub fn parse_reference_sequences(mut bytes: &[u8]) -> io::Result<Vec<(String, u32)>> {
@@ -5,7 +5,7 @@ use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::borrow::Cow;
 use std::ops::{Deref, DerefMut};
 
-use super::tags::get_hit_count;
+use super::tags::{get_hit_count, get_int_tag};
 
 /// Provides convenient access to BAM-style raw read (record bytes)
 /// Cow is used so BAMRawRecord can either own or borrow underlying data (if it won't be mutated).
@@ -117,7 +117,10 @@ impl<'a> BAMRawRecord<'a> {
     // Calculates range of bytes containing specified field.
     pub fn get_range(&self, field: &Fields) -> std::ops::Range<usize> {
         match field {
-            Fields::ReadName => 32..(32 + self.get_var_field_len(field)),
+            Fields::ReadName | Fields::RawSequence | Fields::RawQual | Fields::RawTags => {
+                let offset = self.get_offset(field);
+                offset..(offset + self.get_var_field_len(field))
+            }
             _ => panic!("This field is not supported: {} \n", *field as usize),
         }
     }
@@ -156,10 +159,17 @@ impl<'a> BAMRawRecord<'a> {
         }
     }
 
-    pub(crate) fn get_tag(&self, tag: &[u8; 2]) -> Option<&[u8]> {
+    /// Value of auxiliary field `tag`. Strings are returned without trailing
+    /// NUL.
+    pub fn get_tag(&self, tag: &[u8; 2]) -> Option<&[u8]> {
         get_tag(self.get_bytes(&Fields::RawTags), tag).map(|tag_val| tag_val.0)
     }
 
+    /// Value of integer auxiliary field `tag` of any width.
+    pub fn get_int_tag(&self, tag: &[u8; 2]) -> Option<i64> {
+        get_int_tag(self.get_bytes(&Fields::RawTags), tag)
+    }
+
     pub fn get_hit_count(&self) -> Option<i32> {
         get_hit_count(self.get_bytes(&Fields::RawTags))
     }
@@ -295,8 +305,8 @@ pub fn put_sequence(mut dst: &mut [u8], read_length: usize, sequence: &String) -
     while let Some(l) = bases.next() {
         // § 4.2.3 "SEQ and QUAL encoding" (2021-06-03): "When `l_seq` is odd the bottom 4 bits of
         // the last byte are undefined, but we recommend writing these as zero."
-        let r = bases.next().unwrap_or('\0');
-        let b = encode_base(l) << 4 | encode_base(r);
+        let r = bases.next().map_or(0, encode_base);
+        let b = encode_base(l) << 4 | r;
         dst.write_u8(b)?;
     }
 
@@ -109,6 +109,22 @@ pub(crate) fn get_tag<'a>(data: &'a [u8], tag: &[u8; 2]) -> Option<(&'a [u8], Ta
     None
 }
 
+/// Value of integer tag of any width. None if tag is missing or is not an
+/// integer.
+pub fn get_int_tag(data: &[u8], tag: &[u8; 2]) -> Option<i64> {
+    let (mut value, tag_type) = get_tag(data, tag)?;
+    let val = match tag_type {
+        TagType::c => value.read_i8().unwrap() as i64,
+        TagType::C => value.read_u8().unwrap() as i64,
+        TagType::s => value.read_i16::<LittleEndian>().unwrap() as i64,
+        TagType::S => value.read_u16::<LittleEndian>().unwrap() as i64,
+        TagType::i => value.read_i32::<LittleEndian>().unwrap() as i64,
+        TagType::I => value.read_u32::<LittleEndian>().unwrap() as i64,
+        _ => return None,
+    };
+    Some(val)
+}
+
 // Returns value of HI tag.
 // The field type is i so it's assumed it will fit in i32.
 pub fn get_hit_count(data: &[u8]) -> Option<i32> {
@@ -113,7 +113,8 @@ fn do_index_sort<W: Write, IndexW: std::io::Write>(
     let mut all_keys: Vec<(KeyTuple, Range<usize>)> = Vec::new();
 
     let mut i = 0;
-    while let Some(Ok(rec)) = records.next_rec() {
+    while let Some(rec) = records.next_rec() {
+        let rec = rec?;
         let wrapper = BAMRawRecord(Cow::Borrowed(rec));
         let key = create_key_tuple("", &wrapper, &SortBy::CoordinatesAndStrand);
         all_keys.push((key, i..i));
@@ -18,7 +18,7 @@ echo "Temp dir is set as: $TMPDIR"
 samtools_sorted_bam_file="$output_path.sorted.samtools.bam"
 sambamba_sorted_bam_file="$output_path.sorted.sambamba.bam"
 gbam_file="$output_path.gbam"
-gbam_index_file="$output_path.gbam.gbai"
+gbam_index_file="$output_path.gbam.order"
 
 samtools_depth_file="$output_path.samtools.depth"
 sambamba_depth_file="$output_path.sambamba.depth"
@@ -31,7 +31,7 @@ echo ""
 echo ""
 echo "---------------------------------------------------------------------------------------------------------------"
 
-/usr/bin/time -v $gbam_binary_path $input_path -c -s --sort-temp-mode ram -o $gbam_file --index-sort
+/usr/bin/time -v $gbam_binary_path write $input_path -s --sort-temp-mode ram -o $gbam_file --index-sort
 echo "---------------------------------------------------------------------------------------------------------------"
 /usr/bin/time -v samtools sort -@ 8 $input_path -o $samtools_sorted_bam_file
 echo "---------------------------------------------------------------------------------------------------------------"
@@ -44,7 +44,7 @@ echo ""
 echo ""
 echo "---------------------------------------------------------------------------------------------------------------"
 
-/usr/bin/time -v $gbam_binary_path --flagstat $gbam_file
+/usr/bin/time -v $gbam_binary_path stats $gbam_file
 echo "---------------------------------------------------------------------------------------------------------------"
 /usr/bin/time -v samtools flagstat -@ 8 $samtools_sorted_bam_file
 echo "---------------------------------------------------------------------------------------------------------------"
@@ -57,7 +57,7 @@ echo ""
 echo ""
 echo "---------------------------------------------------------------------------------------------------------------"
 
-/usr/bin/time -v $gbam_binary_path --depth $gbam_file --thread-num 12 --index-file $gbam_index_file > $gbam_depth_file
+/usr/bin/time -v $gbam_binary_path depth $gbam_file --threads 12 --index-file $gbam_index_file > $gbam_depth_file
 echo "---------------------------------------------------------------------------------------------------------------"
 /usr/bin/time -v samtools depth -@ 8 $samtools_sorted_bam_file > $samtools_depth_file
 echo "---------------------------------------------------------------------------------------------------------------"
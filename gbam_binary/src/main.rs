@@ -1,21 +1,34 @@
 // use gbam_tools::bam_to_gbam;
-use bam_tools::{record::fields::Fields, MEGA_BYTE_SIZE};
+use bam_tools::{record::fields::{Fields, FIELDS_NUM}, MEGA_BYTE_SIZE};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use gbam_tools::{
-    bam::bam_to_gbam::bam_sort_to_gbam,
+    bam::bam_to_gbam::{bam_sort_to_gbam, is_bam},
     bam::gbam_to_bam::gbam_to_bam,
+    bam::cram_writer::gbam_to_cram,
+    bam::fastq_writer::{gbam_to_fastq, FastqOutput},
+    bam::sam_writer::gbam_to_sam,
+    bam::stream::{stream_records, StreamFormat},
+    bam::merge::SortOrder,
+    bam::header_edit::HeaderEdits,
+    meta::{NameTokenization, QualBinning},
+    record_filter::RecordFilter,
+    reference::read_fai,
+    progress::{Progress, ProgressObserver},
+    split_writer::SplitBy,
+    query::coverage::{CoverageOptions, DEFAULT_EXCLUDE_FLAGS},
     query::depth::main_depth,
-    reader::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord},
-    {bam_to_gbam, Codecs},
+    reader::{builder::ReaderBuilder, expr::FilterExpr, filter::RecordPredicate, gbai::GbamIndex, parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord, region::Region, storage::Access},
+    {bam_to_gbam, estimate_bam_to_gbam, external_sort_to_gbam, fastq_to_gbam, merge_to_gbam, resumable_to_gbam, sam_to_gbam, split_to_gbam, Codecs, WriterBuilder},
     query::flagstat::collect_stats,
 };
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use itertools::zip_eq;
 use std::fs::OpenOptions;
 
 use std::io::{BufRead, BufReader, Seek, SeekFrom};
 
 
-use std::{path::PathBuf, convert::TryInto, io::{Read}, io::{BufWriter, Write}};
+use std::{path::{Path, PathBuf}, convert::TryInto, io::{Read}, io::{BufWriter, Write}};
 use std::time::Instant;
 use std::fs::File;
 use structopt::StructOpt;
@@ -25,167 +38,674 @@ use gbam_tools::query::cigar::base_coverage;
 
 use rayon::prelude::*;
 
+/// Checkpoint interval of --resume without --checkpoint-every.
+const DEFAULT_CHECKPOINT_EVERY: u64 = 10_000_000;
+/// Exit code of command which failed while running.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code of invalid arguments or conflicting options.
+const EXIT_USAGE: i32 = 2;
+
+/// Columnar storage of aligned reads: converts BAM, SAM and FASTQ to GBAM
+/// and queries GBAM files. Exits with 1 if command fails and with 2 if
+/// arguments are invalid.
 #[derive(StructOpt)]
+#[structopt(name = "gbam")]
 struct Cli {
+    /// Number of threads: compressing blocks of `write`, decompressing blocks ahead of `view` output and decoding records of other commands. All cores by default. WARNING: each `depth` thread will attempt to allocate up to 1GB.
+    #[structopt(long, global = true)]
+    threads: Option<usize>,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+// Parsed once, size of arguments doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt)]
+enum Command {
+    /// Convert BAM, SAM or FASTQ to GBAM.
+    Write(WriteArgs),
+    /// Write records to stdout as uncompressed BAM (can be piped to samtools view) or SAM.
+    View(ViewArgs),
+    /// Convert GBAM file to BAM.
+    ToBam(ToBamArgs),
+    /// Convert GBAM file to CRAM against reference.
+    ToCram(ToCramArgs),
+    /// Convert GBAM file to FASTQ of primary reads. Mates are paired by name, see --mate-fastq and --interleave.
+    ToFastq(ToFastqArgs),
+    /// Build index of coordinate sorted GBAM file for region queries (view --region), written to <file>.gbai (see --in-footer).
+    Index(IndexArgs),
+    /// Collect statistics of FLAG field of all records, as samtools flagstat does.
+    Stats(InputArgs),
+    /// Depth at positions of --query or BED file.
+    Depth(DepthArgs),
+    /// Coverage of regions by records passing --exclude-flags and --min-mapq. Per base as samtools depth does (1-based positions, zero depth omitted) or, with --window, mean depth of windows as BED. Only RNAME, POS, CIGAR, FLAG and MAPQ columns are decoded.
+    Coverage(CoverageArgs),
+    /// Print layout of one ReadName block and the first names stored in it.
+    Inspect(InspectArgs),
+    /// Patch the gbam file with dups detected by other software.
+    PatchDups(PatchDupsArgs),
+    /// Time reading of files.
+    Bench {
+        #[structopt(subcommand)]
+        bench: Bench,
+    },
+}
+
+#[derive(StructOpt)]
+struct InputArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+}
+
+#[derive(StructOpt)]
+struct WriteArgs {
+    /// BAM, SAM or FASTQ (see --fastq) file, "-" reads them from stdin.
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Output GBAM file.
+    #[structopt(short, parse(from_os_str))]
+    out_path: Option<PathBuf>,
     /// Sort BAM file before converting it to GBAM.
     #[structopt(short, long)]
     sort: bool,
-    /// Specify which kind of temporary medium to use while sorting: ram, lz4_ram, file, lz4_file
+    /// Sort by read name instead of coordinates, mates are kept adjacent with the first mate going first. Implies --sort.
+    #[structopt(long)]
+    name_sort: bool,
+    /// Codec used for GBAM columns: gzip, lz4, lz4hc[:level], brotli, zstd, zstd-long[:window_log], none or auto (best of several, chosen per block). Brotli by default.
+    #[structopt(long)]
+    codec: Option<Codecs>,
+    /// Lossy quality binning: illumina8 or comma separated bin edges, e.g. 10,20,30.
+    #[structopt(long)]
+    qual_binning: Option<QualBinning>,
+    /// Store RNEXT, PNEXT and TLEN relative to record's own RNAME and POS.
+    #[structopt(long)]
+    mate_relative: bool,
+    /// Input is FASTQ (optionally gzipped), reads are stored unaligned.
+    #[structopt(long)]
+    fastq: bool,
+    /// FASTQ file with second mates, for paired reads. Implies --fastq.
+    #[structopt(long, parse(from_os_str))]
+    mate_fastq: Option<PathBuf>,
+    /// Coordinate sorted BAM or SAM files merged with the input.
+    #[structopt(long, parse(from_os_str))]
+    merge: Vec<PathBuf>,
+    /// @PG, @RG or @CO line added to header, "\t" stands for tab. @PG and @RG lines replace ones with the same ID. Example: --header-line '@CO\tfiltered'
+    #[structopt(long)]
+    header_line: Vec<String>,
+    /// Rename sample in @RG lines: <old>=<new>.
+    #[structopt(long)]
+    rename_sample: Vec<String>,
+    /// Keep only records with all of these flags, e.g. 0x2 or 2.
+    #[structopt(long, parse(try_from_str = parse_flags))]
+    include_flags: Option<u16>,
+    /// Drop records with any of these flags, e.g. 0x904.
+    #[structopt(long, parse(try_from_str = parse_flags))]
+    exclude_flags: Option<u16>,
+    /// Drop records with lower MAPQ.
+    #[structopt(long)]
+    min_mapq: Option<u8>,
+    /// Keep only records overlapping the region, e.g. chr1:100-200. May be repeated.
+    #[structopt(long)]
+    region: Vec<String>,
+    /// Keep only records overlapping regions of BED file.
+    #[structopt(long, parse(from_os_str))]
+    region_bed: Option<PathBuf>,
+    /// Fields not stored, comma separated: ReadName, RawQual and/or RawTags. Reader returns `*` names, `*` qualities and no tags instead.
+    #[structopt(long)]
+    omit: Option<String>,
+    /// Split records into GBAM file per read group (rg) or per value of string tag (e.g. CB). Output path is used as prefix, e.g. out gives out.<group>.gbam and out.unassigned.gbam.
+    #[structopt(long)]
+    split_by: Option<SplitBy>,
+    /// Tokenize read names: auto (blocks of at least 1000 names), on (every block) or off. Files with tokenized names can't be read by gbam_tools older than nametok codec.
+    #[structopt(long, default_value = "off")]
+    tokenize_names: NameTokenization,
+    /// FASTA (with .fai next to it) or .fai the input has to be aligned to. Conversion fails before writing anything if a reference sequence of the header is missing or has different length.
+    #[structopt(long, parse(from_os_str))]
+    reference: Option<PathBuf>,
+    /// Mark duplicates of coordinate sorted (or --sort) input, as samtools markdup or Picard MarkDuplicates would.
+    #[structopt(long)]
+    mark_duplicates: bool,
+    /// Maximum distance in pixels between optical duplicates for --mark-duplicates.
+    #[structopt(long, default_value = "100")]
+    optical_distance: u32,
+    /// Save checkpoint every N records, so conversion interrupted by crash can be resumed with --resume. Output is written to <out>.partial and checkpoint to <out>.checkpoint until finished.
+    #[structopt(long)]
+    checkpoint_every: Option<u64>,
+    /// Resume conversion from its last checkpoint, see --checkpoint-every. Input and options have to be the same as of interrupted conversion.
+    #[structopt(long)]
+    resume: bool,
+    /// Print per-field compression statistics after converting.
+    #[structopt(long)]
+    report: bool,
+    /// Only estimate GBAM size, compressing every N-th block of each field. Nothing is written.
+    #[structopt(long)]
+    dry_run: Option<u64>,
+    /// Codecs to compare in --dry-run, comma separated. Each is used as --codec would be, --field-codecs apply to all of them.
+    #[structopt(long)]
+    dry_run_codecs: Option<String>,
+    /// Per-field codec overrides, comma separated. Besides generic codecs RawSequence accepts seq2bit, RawQual accepts qualctx, RawCigar accepts cigarsplit, RawTags accepts tagsplit, Mapq accepts mapqrle, Flags accepts flagplanes, Pos accepts posdelta (for coordinate sorted input) and ReadName accepts nametok (see --tokenize-names). Example: RawQual=qualctx,RawTags=zstd-long
+    #[structopt(long)]
+    field_codecs: Option<String>,
+    /// Specify which kind of temporary medium to use while sorting: ram, lz4_ram, file, lz4_file or gbam (sorted chunks spilled as temporary GBAM files, always used for SAM input)
     #[structopt(long)]
     sort_temp_mode: Option<String>,
-    /// Determines whether conversion is requested
-    #[structopt(short, long)]
-    convert_to_gbam: bool,
-    /// Convert to bam
+    /// Sort temp directory.
+    #[structopt(long, parse(from_os_str))]
+    temp_dir: Option<PathBuf>,
+    /// When sorting, only sort the indices of records but not the data itself. Record order is written to <out-path>.order, see --index-file of other commands.
     #[structopt(long)]
-    convert_to_bam: bool,
-    /// Perform the test
-    #[structopt(short, long)]
-    test: bool,
-    /// Fetch cigar in parallel for testing purposes.
-    #[structopt(short, long)]
-    parallel_cigar_fetch: bool,
-    /// Get depth at position.
-    #[structopt(short, long)]
-    depth: bool,
-    /// Collect statistic from flag field from all records in the file.
-    #[structopt(short, long)]
-    flagstat: bool,
-    /// The path to the BAM file to read
+    index_sort: bool,
+}
+
+#[derive(StructOpt)]
+struct ViewArgs {
+    /// GBAM file, or http(s):// or s3:// URL if built with `remote` feature.
     #[structopt(parse(from_os_str))]
     in_path: PathBuf,
-    /// The path to write output GBAM file
+    /// Write SAM text (with header) instead of BAM.
+    #[structopt(long)]
+    sam: bool,
+    /// Stream records with bounded memory, encoding them on another thread, for pipes into tools reading BAM only as BGZF: BAM is written in uncompressed BGZF blocks as samtools view -u does (SAM with --sam). File is read without memory mapping unless --access is given. `gbam view --stream file.gbam | variant_caller`
+    #[structopt(long)]
+    stream: bool,
+    /// Print only text of header.
+    #[structopt(short = "H", long)]
+    header_only: bool,
+    /// Skip RawQual and RawSequence fields, for piping to samtools markdup. `gbam view --markdup little.gbam > /tmp/testpipe.bam & samtools markdup -u /tmp/testpipe.bam /tmp/testoutpipe.bam`
+    #[structopt(long)]
+    markdup: bool,
+    /// Records of every region of sorted file are fetched in turn, as samtools view does, e.g. chr1:100-200. May be repeated.
+    #[structopt(long)]
+    region: Vec<String>,
+    /// Output only records matching filter expression, as samtools view -e does. Blocks which can't match MAPQ, POS, TLEN and RNAME conditions are skipped. Example: --expr 'mapq >= 30 && !(flag & 0x904) && [RG] == "grp1"'
+    #[structopt(long)]
+    expr: Option<String>,
+    /// Record order written by index sort (--index-sort of write).
+    #[structopt(long, parse(from_os_str))]
+    index_file: Option<PathBuf>,
+    /// How the file is read: mmap (default) or read, for filesystems where memory mapping is slow.
+    #[structopt(long)]
+    access: Option<Access>,
+}
+
+#[derive(StructOpt)]
+struct ToBamArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Output BAM file.
+    #[structopt(short, parse(from_os_str))]
+    out_path: PathBuf,
+}
+
+#[derive(StructOpt)]
+struct ToCramArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Output CRAM file.
+    #[structopt(short, parse(from_os_str))]
+    out_path: PathBuf,
+    /// FASTA (with .fai next to it) records are encoded against.
+    #[structopt(long, parse(from_os_str))]
+    reference: PathBuf,
+}
+
+#[derive(StructOpt)]
+struct ToFastqArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Output FASTQ file, of first mates with --mate-fastq.
+    #[structopt(short, parse(from_os_str))]
+    out_path: PathBuf,
+    /// FASTQ file second mates are written to.
+    #[structopt(long, parse(from_os_str))]
+    mate_fastq: Option<PathBuf>,
+    /// Write mates of pair one after another to -o.
+    #[structopt(long, conflicts_with = "mate-fastq")]
+    interleave: bool,
+    /// With --mate-fastq, FASTQ file for reads without mate, which are dropped otherwise.
+    #[structopt(long, parse(from_os_str))]
+    singletons: Option<PathBuf>,
+    /// Don't append /1 and /2 to read names of mates.
+    #[structopt(long)]
+    no_mate_suffix: bool,
+}
+
+#[derive(StructOpt)]
+struct IndexArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Store index in the GBAM file itself instead of .gbai.
+    #[structopt(long)]
+    in_footer: bool,
+}
+
+#[derive(StructOpt)]
+struct DepthArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Output .bed.gz file, depth of every position is written to stdout otherwise.
     #[structopt(short, parse(from_os_str))]
     out_path: Option<PathBuf>,
-    /// Depth query. Example: chr1:54-54, or chrX:1258-9999
+    /// Example: chr1:54-54, or chrX:1258-9999
     #[structopt(short, long)]
     query: Option<String>,
-    /// Depth query. Example: chr1:54, or chrX:1258
+    /// BED file of queried regions.
     #[structopt(short, parse(from_os_str))]
     bed_file: Option<PathBuf>,
-    /// Depth query. Filter reads with map quality lower than.
+    /// Filter reads with map quality lower than.
     #[structopt(long)]
     mapq: Option<u32>,
-    /// Depth query. Number of threads to use. WARNING: each thread will attempt to allocate up to 1GB.
-    #[structopt(long)]
-    thread_num: Option<usize>,
-    /// Sort temp directory.
+    /// Record order written by index sort (--index-sort of write).
     #[structopt(long, parse(from_os_str))]
-    temp_dir: Option<PathBuf>,
-    /// View header
-    #[structopt(short, long)]
-    header: bool,
-    /// View file in binary format. Can be piped to samtools view. `gbam_binary -v test_data/1gb.gbam | samtools view`
-    #[structopt(short, long)]
-    view: bool,
-    /// View file in binary format for piping to samtools markdup. `gbam_binary -v little.gbam > /tmp/testpipe.bam & samtools markdup -u /tmp/testpipe.bam /tmp/testoutpipe.bam`. It disables reading of two heavy fields to potentially speedup the process.
+    index_file: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+struct CoverageArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Output file, stdout by default.
+    #[structopt(short, parse(from_os_str))]
+    out_path: Option<PathBuf>,
+    /// Region coverage is computed for, e.g. chr1:100-200 (every reference by default). May be repeated.
     #[structopt(long)]
-    markdup_view: bool,
-    /// Patch the gbam file with dups detected by other software. A multiline file is expected with 0 and 1, where 1 means mark as duplicate. The GBAM flags column has to be not compressed for patching to work as expected.
-    /// For generating the markdup marks use: <time (../target/release/gbam_binary -v little.gbam > /tmp/testpipe.bam & samtools markdup -@5 -u /tmp/testpipe.bam /tmp/testoutpipe.bam & samtools view /tmp/testoutpipe.bam | awk '{print and($2, 0x400)!=0}' | ../target/release/gbam_binary --patch-gbam-with-dups little.gbam)>
-    /// For pipes use <mkfifo> command.
+    region: Vec<String>,
+    /// Window size in bases.
     #[structopt(long)]
-    patch_gbam_with_dups: bool,
-    /// When sorting and converting file, only sort the indices of records but not the data itself.
+    window: Option<u32>,
+    /// Count deleted bases as covered.
     #[structopt(long)]
-    index_sort: bool,
-    /// Index file for use in Depth.
+    count_deletions: bool,
+    /// Records not counted (0x704 by default).
+    #[structopt(long, parse(try_from_str = parse_flags))]
+    exclude_flags: Option<u16>,
+    /// Skip records with lower MAPQ.
+    #[structopt(long)]
+    min_mapq: Option<u8>,
+}
+
+#[derive(StructOpt)]
+struct InspectArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Block number.
+    #[structopt(long, default_value = "0")]
+    block: usize,
+    /// Number of names to print.
+    #[structopt(long, default_value = "10")]
+    names_num: usize,
+}
+
+/// A multiline file is expected on stdin with 0 and 1, where 1 means mark as duplicate. The GBAM flags column has to be not compressed for patching to work as expected.
+/// For generating the markdup marks use: <time (../target/release/gbam view little.gbam > /tmp/testpipe.bam & samtools markdup -@5 -u /tmp/testpipe.bam /tmp/testoutpipe.bam & samtools view /tmp/testoutpipe.bam | awk '{print and($2, 0x400)!=0}' | ../target/release/gbam patch-dups little.gbam)>
+/// For pipes use <mkfifo> command.
+#[derive(StructOpt)]
+struct PatchDupsArgs {
+    #[structopt(parse(from_os_str))]
+    in_path: PathBuf,
+    /// Record order written by index sort (--index-sort of write).
     #[structopt(long, parse(from_os_str))]
     index_file: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+enum Bench {
+    /// Query CIGAR field throughout whole file.
+    Cigar(InputArgs),
+    /// Fetch CIGAR in parallel.
+    ParallelCigar(InputArgs),
     /// Calculate uncompressed size of BAM file.
-    #[structopt(long)]
-    calc_uncompressed_size: bool,
+    UncompressedSize(InputArgs),
+}
+
+/// Why command failed, decides exit code.
+enum CliError {
+    /// Invalid or conflicting options.
+    Usage(String),
+    Failed(Box<dyn std::error::Error>),
+}
+
+impl<E: std::error::Error + 'static> From<E> for CliError {
+    fn from(err: E) -> Self {
+        CliError::Failed(Box::new(err))
+    }
 }
 
-/// Limited wrapper of `gbam_tools` converts BAM file to GBAM
-/// file. Also limited tests may be run.
+type CliResult = Result<(), CliError>;
+
+fn usage(message: impl Into<String>) -> CliError {
+    CliError::Usage(message.into())
+}
+
+fn failed(message: impl Into<String>) -> CliError {
+    CliError::Failed(message.into().into())
+}
+
+/// Limited wrapper of `gbam_tools`: converts BAM file to GBAM file and
+/// queries it. Also limited tests may be run.
 fn main() {
-    let args = Cli::from_args();
+    let cli = match Cli::from_iter_safe(env::args()) {
+        Ok(cli) => cli,
+        Err(err) if err.use_stderr() => {
+            eprintln!("{}", err.message);
+            std::process::exit(EXIT_USAGE);
+        }
+        // Help or version.
+        Err(err) => {
+            // Help piped into `head` may be cut off.
+            let _ = writeln!(std::io::stdout(), "{}", err.message);
+            return;
+        }
+    };
     let arguments_strings: Vec<String> = env::args().collect();
     let full_command = arguments_strings.join(" ");
-    if args.convert_to_gbam {
-        convert(args, full_command);
-    } else if args.test {
-        test(args);
-    } else if args.parallel_cigar_fetch {
-        test_parallel_cigar_fetch(args);
-    } else if args.depth {
-        depth(args);
-    } else if args.convert_to_bam {
-        convert_to_bam(args);
-    } else if args.flagstat {
-        flagstat(args);
-    } else if args.header {
-        view_header(args);
-    } else if args.view {
-        let mut template = ParsingTemplate::new();
-        template.set_all();
-        view_file(args, template);
-    } else if args.markdup_view {
-        let mut template = ParsingTemplate::new();
-        template.set_all_except(&[Fields::RawQual,Fields::RawSequence]);
-        view_file(args, template);
-    } else if args.patch_gbam_with_dups {
-        patch_dups(args);
-    }else if args.calc_uncompressed_size {
-        test_file_uncompressed_size_fetch(args);
-    }
-}
-
-fn convert(args: Cli, full_command: String) {
-    let in_path = args
-        .in_path
-        .as_path()
-        .to_str()
-        .expect("Couldn't parse input path");
-    let out_path = args
-        .out_path
-        .as_ref()
-        .expect("Output path is mandatory for this operation.")
-        .as_path()
-        .to_str()
-        .unwrap();
-    if args.sort {
-        bam_sort_to_gbam(in_path, out_path, Codecs::Brotli, args.sort_temp_mode, args.temp_dir, full_command, args.index_sort);
+    match run(cli, full_command) {
+        Ok(()) => {}
+        Err(CliError::Usage(message)) => {
+            eprintln!("error: {}", message);
+            std::process::exit(EXIT_USAGE);
+        }
+        Err(CliError::Failed(err)) => {
+            eprintln!("error: {}", err);
+            std::process::exit(EXIT_FAILURE);
+        }
+    }
+}
+
+fn run(cli: Cli, full_command: String) -> CliResult {
+    if cli.threads == Some(0) {
+        return Err(usage("--threads must be at least 1."));
+    }
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()?;
+    }
+    let threads = cli.threads;
+    match cli.command {
+        Command::Write(args) => write(args, threads, full_command),
+        Command::View(args) => ignore_broken_pipe(view_file(args, threads)),
+        Command::ToBam(args) => convert_to_bam(args),
+        Command::ToCram(args) => convert_to_cram(args),
+        Command::ToFastq(args) => convert_to_fastq(args),
+        Command::Index(args) => build_index(args),
+        Command::Stats(args) => flagstat(args),
+        Command::Depth(args) => depth(args, threads),
+        Command::Coverage(args) => coverage(args),
+        Command::Inspect(args) => inspect_names(args),
+        Command::PatchDups(args) => patch_dups(args),
+        Command::Bench { bench: Bench::Cigar(args) } => test(args),
+        Command::Bench { bench: Bench::ParallelCigar(args) } => test_parallel_cigar_fetch(args),
+        Command::Bench { bench: Bench::UncompressedSize(args) } => test_file_uncompressed_size_fetch(args),
+    }
+}
+
+/// Output closed by reader of pipe (e.g. `| head`) ends command normally.
+fn ignore_broken_pipe(result: CliResult) -> CliResult {
+    match result {
+        Err(CliError::Failed(err)) if err.downcast_ref::<std::io::Error>().is_some_and(|err| err.kind() == std::io::ErrorKind::BrokenPipe) => Ok(()),
+        result => result,
+    }
+}
+
+fn path_str(path: &Path) -> Result<&str, CliError> {
+    path.to_str().ok_or_else(|| usage(format!("Path is not valid UTF-8: {}", path.display())))
+}
+
+fn write(args: WriteArgs, threads: Option<usize>, full_command: String) -> CliResult {
+    let in_path = path_str(&args.in_path)?;
+    if let Some(sample_every) = args.dry_run {
+        return estimate(&args, in_path, sample_every, threads);
+    }
+    let out_path = path_str(args.out_path.as_ref().ok_or_else(|| usage("Output path (-o) is mandatory for this operation."))?)?;
+    let codecs = field_codecs(&args, args.codec.unwrap_or(Codecs::Brotli))?;
+    let mate_path = args.mate_fastq.as_deref().map(path_str).transpose()?;
+    let sort = args.sort || args.name_sort;
+    let order = if args.name_sort { SortOrder::QueryName } else { SortOrder::Coordinate };
+    if args.name_sort && args.index_sort {
+        return Err(usage("Index sort is supported only for coordinate order."));
+    }
+    let mut builder = WriterBuilder::new()
+        .codecs(codecs)
+        .qual_binning(args.qual_binning.clone())
+        .mate_relative(args.mate_relative)
+        .full_command(full_command)
+        .header_edits(header_edits(&args)?)
+        .filter(record_filter(&args)?)
+        .tokenize_names(args.tokenize_names);
+    if let Some(threads) = threads {
+        builder = builder.thread_num(threads);
+    }
+    if let Some(path) = args.reference.as_ref() {
+        builder = builder.reference(read_fai(path)?);
+    }
+    if let Some(names) = args.omit.as_deref() {
+        for field in names.split(',').map(parse_field) {
+            builder = builder.omit_field(field?);
+        }
+    }
+    builder.validate().map_err(|err| usage(err.to_string()))?;
+    if let Some(split_by) = args.split_by {
+        if sort || !args.merge.is_empty() || args.fastq {
+            return Err(usage("Split is supported only for single BAM or SAM input."));
+        }
+        let reports = split_to_gbam(in_path, out_path, split_by, builder)?;
+        for (path, report) in reports {
+            if args.report {
+                eprintln!("{}\n{}", path, report);
+            } else {
+                eprintln!("{}", path);
+            }
+        }
+        return Ok(());
+    }
+    let mark_duplicates = args.mark_duplicates.then_some(args.optical_distance);
+    if mark_duplicates.is_some() && (args.name_sort || args.fastq || mate_path.is_some()) {
+        return Err(usage("Duplicates can be marked only in coordinate sorted output."));
+    }
+    let builder = builder.mark_duplicates(mark_duplicates);
+    let observer: Option<Box<dyn ProgressObserver>> = Some(Box::new(ProgressBarObserver::new()));
+    let report = if args.checkpoint_every.is_some() || args.resume {
+        if mark_duplicates.is_some() {
+            return Err(usage("Checkpoints are not supported with duplicate marking."));
+        }
+        if sort || !args.merge.is_empty() || args.fastq || mate_path.is_some() {
+            return Err(usage("Checkpoints are supported only for single BAM or SAM input."));
+        }
+        let checkpoint_every = args.checkpoint_every.unwrap_or(DEFAULT_CHECKPOINT_EVERY);
+        resumable_to_gbam(in_path, out_path, builder, observer, checkpoint_every, args.resume)
+    } else if !args.merge.is_empty() {
+        if sort {
+            return Err(usage("Inputs of merge have to be sorted already."));
+        }
+        let mut in_paths = vec![in_path];
+        for path in &args.merge {
+            in_paths.push(path_str(path)?);
+        }
+        merge_to_gbam(&in_paths, out_path, builder, observer)
+    } else if args.fastq || mate_path.is_some() {
+        if sort {
+            return Err(usage("Sorting is not supported for FASTQ input."));
+        }
+        // Unaligned records have no mates to be relative to.
+        fastq_to_gbam(in_path, mate_path, out_path, builder.mate_relative(false), observer)
+    } else if sort && (!is_bam(in_path) || args.sort_temp_mode.as_deref() == Some("gbam")) {
+        external_sort_to_gbam(in_path, out_path, order, args.temp_dir, builder, observer)
+    } else if !is_bam(in_path) {
+        sam_to_gbam(in_path, out_path, builder, observer)
+    } else if sort {
+        bam_sort_to_gbam(in_path, out_path, order, args.sort_temp_mode, args.temp_dir, args.index_sort, builder, observer)
     } else {
-        bam_to_gbam(in_path, out_path, Codecs::Brotli, full_command);
+        bam_to_gbam(in_path, out_path, builder, observer)
+    }?;
+    if args.report {
+        eprintln!("{}", report);
     }
+    Ok(())
 }
 
-fn convert_to_bam(args: Cli) {
-    let in_path = args
-        .in_path
-        .as_path()
-        .to_str()
-        .expect("Couldn't parse input path.");
-    let out_path = args
-        .out_path
-        .as_ref()
-        .expect("Output path is mandatory for this operation.")
-        .as_path()
-        .to_str()
-        .unwrap();
-    gbam_to_bam(in_path, out_path);
+/// Renders progress of conversion on stderr. Hidden if stderr is not a
+/// terminal.
+struct ProgressBarObserver(ProgressBar);
+
+impl ProgressBarObserver {
+    fn new() -> Self {
+        let pb = ProgressBar::new_spinner();
+        pb.set_style(ProgressStyle::default_spinner()
+            .template("{spinner:.green} [{elapsed_precise}] {msg}"));
+        pb.enable_steady_tick(200);
+        Self(pb)
+    }
+
+    fn message(progress: &Progress) -> String {
+        format!(
+            "{} records, {} in, {} out, {} blocks",
+            progress.records,
+            HumanBytes(progress.bytes_in),
+            HumanBytes(progress.bytes_out),
+            progress.blocks_flushed
+        )
+    }
+}
+
+impl ProgressObserver for ProgressBarObserver {
+    fn on_progress(&mut self, progress: &Progress) {
+        self.0.set_message(Self::message(progress));
+    }
+
+    fn on_finish(&mut self, progress: &Progress) {
+        self.0.finish_with_message(Self::message(progress));
+    }
+}
+
+/// Header edits of --header-line and --rename-sample.
+fn header_edits(args: &WriteArgs) -> Result<HeaderEdits, CliError> {
+    let mut edits = HeaderEdits::new();
+    for line in &args.header_line {
+        edits.add_line(&line.replace("\\t", "\t")).map_err(|err| usage(err.to_string()))?;
+    }
+    for rename in &args.rename_sample {
+        let (from, to) = rename
+            .split_once('=')
+            .ok_or_else(|| usage(format!("Expected <old>=<new>, got: {}", rename)))?;
+        edits.rename_sample(from, to);
+    }
+    Ok(edits)
+}
+
+/// Filter of --include-flags, --exclude-flags, --min-mapq, --region and
+/// --region-bed, if any of them is given.
+fn record_filter(args: &WriteArgs) -> Result<Option<RecordFilter>, CliError> {
+    let mut filter = RecordFilter::new();
+    filter.required_flags = args.include_flags.unwrap_or(0);
+    filter.excluded_flags = args.exclude_flags.unwrap_or(0);
+    filter.min_mapq = args.min_mapq.unwrap_or(0);
+    for region in &args.region {
+        filter.add_region(region).map_err(|err| usage(err.to_string()))?;
+    }
+    if let Some(path) = args.region_bed.as_ref() {
+        filter.add_bed_regions(path)?;
+    }
+    let is_empty = filter.required_flags == 0 && filter.excluded_flags == 0 && filter.min_mapq == 0 && !filter.has_regions();
+    Ok((!is_empty).then_some(filter))
+}
+
+/// Flags as decimal or hexadecimal number.
+fn parse_flags(s: &str) -> Result<u16, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Codecs of all fields for default `codec` and --field-codecs overrides.
+fn field_codecs(args: &WriteArgs, codec: Codecs) -> Result<Vec<Codecs>, CliError> {
+    let mut codecs = vec![codec; FIELDS_NUM];
+    if let Some(field_codecs) = args.field_codecs.as_ref() {
+        for (field, codec) in parse_field_codecs(field_codecs)? {
+            codecs[field as usize] = codec;
+        }
+    }
+    Ok(codecs)
+}
+
+fn estimate(args: &WriteArgs, in_path: &str, sample_every: u64, threads: Option<usize>) -> CliResult {
+    let default_codecs = match args.dry_run_codecs.as_ref() {
+        Some(list) => list.split(',').map(|codec| codec.parse::<Codecs>().map_err(usage)).collect::<Result<_, _>>()?,
+        None => vec![args.codec.unwrap_or(Codecs::Brotli)],
+    };
+    let configs = default_codecs.iter().map(|codec| field_codecs(args, *codec)).collect::<Result<_, _>>()?;
+    // Global pool has --threads threads, all cores by default.
+    let thread_num = threads.unwrap_or_else(rayon::current_num_threads);
+    let reports = estimate_bam_to_gbam(in_path, configs, thread_num, args.qual_binning.clone(), args.mate_relative, sample_every)?;
+    for (codec, report) in default_codecs.iter().zip(reports) {
+        println!("{:?}: projected size {} bytes", codec, report.total_projected());
+        if args.report {
+            println!("{}\n", report);
+        }
+    }
+    Ok(())
+}
+
+fn parse_field_codecs(field_codecs: &str) -> Result<Vec<(Fields, Codecs)>, CliError> {
+    field_codecs
+        .split(',')
+        .map(|item| {
+            let (name, codec) = item
+                .split_once('=')
+                .ok_or_else(|| usage(format!("Expected <field>=<codec>, got: {}", item)))?;
+            Ok((parse_field(name)?, codec.parse::<Codecs>().map_err(usage)?))
+        })
+        .collect()
+}
+
+fn parse_field(name: &str) -> Result<Fields, CliError> {
+    Fields::iterator()
+        .find(|field| field.to_string() == name)
+        .copied()
+        .ok_or_else(|| usage(format!("Unknown field: {}", name)))
+}
+
+fn convert_to_bam(args: ToBamArgs) -> CliResult {
+    gbam_to_bam(path_str(&args.in_path)?, path_str(&args.out_path)?)?;
+    Ok(())
 }
 
-fn flagstat(args: Cli) {
-    let in_path = args
-        .in_path
-        .as_path()
-        .to_str()
-        .expect("Couldn't parse input path.");
+fn convert_to_cram(args: ToCramArgs) -> CliResult {
+    let mut reader = ReaderBuilder::new().open(File::open(&args.in_path)?)?;
+    let out = BufWriter::new(File::create(&args.out_path)?);
+    gbam_to_cram(&mut reader, &[], &args.reference, out)?;
+    Ok(())
+}
 
-    let file = File::open(in_path).unwrap();
+fn convert_to_fastq(args: ToFastqArgs) -> CliResult {
+    let create = |path: &PathBuf| File::create(path).map(BufWriter::new);
+    let output = match (&args.mate_fastq, args.interleave) {
+        (Some(mate_path), _) => FastqOutput::Paired {
+            read1: create(&args.out_path)?,
+            read2: create(mate_path)?,
+            single: args.singletons.as_ref().map(create).transpose()?,
+        },
+        (None, true) => FastqOutput::Interleaved(create(&args.out_path)?),
+        (None, false) => FastqOutput::Single(create(&args.out_path)?),
+    };
+    let fields = [Fields::ReadName, Fields::Flags, Fields::RawSequence, Fields::RawQual];
+    let mut reader = ReaderBuilder::new().fields(&fields).open(File::open(&args.in_path)?)?;
+    gbam_to_fastq(&mut reader, &[], output, !args.no_mate_suffix)?;
+    Ok(())
+}
+
+fn flagstat(args: InputArgs) -> CliResult {
+    let file = File::open(&args.in_path)?;
     collect_stats(file);
+    Ok(())
 }
 
-fn test(args: Cli) {
+fn test(args: InputArgs) -> CliResult {
     let mut tmplt = ParsingTemplate::new();
     tmplt.set(&Fields::RawCigar, true);
 
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+    let file = File::open(&args.in_path)?;
 
-    let mut reader = Reader::new(file, tmplt).unwrap();
+    let mut reader = Reader::new(file, tmplt)?;
     let mut records = reader.records();
     let now = Instant::now();
 
@@ -200,20 +720,21 @@ fn test(args: Cli) {
         now.elapsed().as_millis()
     );
     drop(records);
+    Ok(())
 }
 
-fn test_parallel_cigar_fetch(args: Cli) {
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
-    let temp_reader = Reader::new(file.try_clone().unwrap(), ParsingTemplate::new()).unwrap();
+fn test_parallel_cigar_fetch(args: InputArgs) -> CliResult {
+    let file = File::open(&args.in_path)?;
+    let temp_reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
     let file_meta = temp_reader.file_meta;
     let total_records = temp_reader.amount;
     let now = Instant::now();
-    
+
     (0..total_records).into_par_iter().chunks(500_000).for_each(|records_range| {
         let mut rec =  GbamRecord::default();
         let mut tmplt = ParsingTemplate::new();
         tmplt.set(&Fields::RawCigar, true);
-    
+
         let mut reader = Reader::new_with_meta(file.try_clone().unwrap(), tmplt, &file_meta, None).unwrap();
 
         let mut collector = Vec::with_capacity(records_range.len());
@@ -228,76 +749,164 @@ fn test_parallel_cigar_fetch(args: Cli) {
         "Fetching CIGAR in parallel took: {}",
         now.elapsed().as_millis()
     );
+    Ok(())
 }
 
-fn test_file_uncompressed_size_fetch(args: Cli) {
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+fn test_file_uncompressed_size_fetch(args: InputArgs) -> CliResult {
+    let file = File::open(&args.in_path)?;
 
-    let file_sz = file.metadata().unwrap().len();
+    let file_sz = file.metadata()?.len();
     if file_sz == 0 {
         println!("File is empty.");
-        return;
+        return Ok(());
     }
 
     let mut reader = file;
-    
+
 
     let mut buf: [u8; 1000] = [0; 1000];
     const OFFEST_IN_BGZF_FILE_TILL_BLOCK_SIZE_VALUE : usize = 128/8;
     let mut total_uncrompressed_size_of_file : usize = 0;
     const ERR : &str = "Couldn't parse the bgzf block.";
     loop {
-        let cur_reader_pos = reader.seek(std::io::SeekFrom::Current(0)).unwrap();
+        let cur_reader_pos = reader.stream_position()?;
         if file_sz == cur_reader_pos {
             break;
         }
         if file_sz-cur_reader_pos == 28 {
             break;
         }
-        reader.read_exact(&mut buf[..OFFEST_IN_BGZF_FILE_TILL_BLOCK_SIZE_VALUE]).expect(ERR); 
-        let block_size = reader.read_u16::<LittleEndian>().expect(ERR)+1;
+        reader.read_exact(&mut buf[..OFFEST_IN_BGZF_FILE_TILL_BLOCK_SIZE_VALUE]).map_err(|_| failed(ERR))?;
+        let block_size = reader.read_u16::<LittleEndian>().map_err(|_| failed(ERR))?+1;
         let uncompressed_info_start = cur_reader_pos+block_size as u64 - std::mem::size_of::<u32>() as u64;
-        assert!(uncompressed_info_start < file_sz);
-        reader.seek(std::io::SeekFrom::Start(uncompressed_info_start)).unwrap();
-        let uncompressed_block_size = reader.read_u32::<LittleEndian>().expect(ERR);
+        if uncompressed_info_start >= file_sz {
+            return Err(failed(ERR));
+        }
+        reader.seek(std::io::SeekFrom::Start(uncompressed_info_start))?;
+        let uncompressed_block_size = reader.read_u32::<LittleEndian>().map_err(|_| failed(ERR))?;
         total_uncrompressed_size_of_file += uncompressed_block_size as usize;
-        
+
     }
 
     println!("Total uncompressed size of file is: {}", total_uncrompressed_size_of_file);
+    Ok(())
 }
 
-fn read_index(index: PathBuf) -> Option<std::sync::Arc<Vec<u32>>> {
-    let file = File::open(index).unwrap();
-    let size = file.metadata().unwrap().len();
+fn read_index(index: &Path) -> std::io::Result<std::sync::Arc<Vec<u32>>> {
+    let file = File::open(index)?;
+    let size = file.metadata()?.len();
     let mut f = std::io::BufReader::new(file);
     let mut res = vec![0 as u32; (size/(std::mem::size_of::<u32>() as u64)).try_into().unwrap()];
 
     for slot in &mut res {
-        *slot = f.read_u32::<LittleEndian>().unwrap();
+        *slot = f.read_u32::<LittleEndian>()?;
     }
 
-    Some(std::sync::Arc::new(res))
+    Ok(std::sync::Arc::new(res))
 }
 
-fn depth(args: Cli) {
-    let in_path = args.in_path.as_path().to_str().unwrap();
-    let gbam_file = File::open(in_path).unwrap();
-    main_depth(gbam_file, args.bed_file.as_ref(), args.index_file.and_then(read_index), args.query, args.mapq, args.out_path, args.thread_num);
+fn depth(args: DepthArgs, threads: Option<usize>) -> CliResult {
+    let gbam_file = File::open(&args.in_path)?;
+    let index = args.index_file.as_deref().map(read_index).transpose()?;
+    main_depth(gbam_file, args.bed_file.as_ref(), index, args.query, args.mapq, args.out_path, threads);
+    Ok(())
+}
+
+/// Most bases of per-base coverage computed at once.
+const COVERAGE_CHUNK: u32 = 1 << 24;
+
+fn coverage(args: CoverageArgs) -> CliResult {
+    let options = CoverageOptions {
+        exclude_flags: args.exclude_flags.unwrap_or(DEFAULT_EXCLUDE_FLAGS),
+        min_mapq: args.min_mapq.unwrap_or(0),
+        window: args.window.unwrap_or(1),
+        count_deletions: args.count_deletions,
+    };
+    if options.window == 0 {
+        return Err(usage("--window must be at least 1."));
+    }
+    let reader = ReaderBuilder::new().open(File::open(&args.in_path)?)?;
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    let sorted = reader.is_sorted();
+    let mut regions = args.region.clone();
+    if regions.is_empty() {
+        regions = ref_seqs.iter().map(|(name, _)| name.clone()).collect();
+    }
+    // Bad regions fail before anything is written.
+    let parsed = regions
+        .iter()
+        .map(|region| Region::parse(region, &ref_seqs).map_err(|err| usage(err.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut out: Box<dyn Write> = match &args.out_path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+    for parsed in parsed {
+        let name = &ref_seqs[parsed.ref_id as usize].0;
+        // Per-base coverage of whole chromosome would take gigabytes,
+        // sorted file is cheap to query in chunks.
+        let step = if options.window == 1 && sorted { COVERAGE_CHUNK } else { u32::MAX };
+        let mut start = parsed.start;
+        while start < parsed.end {
+            let end = parsed.end.min(start.saturating_add(step));
+            let coverage = reader.coverage(&format!("{}:{}-{}", name, start + 1, end), &options)?;
+            if options.window == 1 {
+                for (offset, depth) in coverage.bases.iter().enumerate().filter(|(_, &depth)| depth > 0) {
+                    writeln!(out, "{}\t{}\t{}", name, start as usize + offset + 1, depth)?;
+                }
+            } else {
+                for (win_start, win_end, mean) in coverage.windows() {
+                    writeln!(out, "{}\t{}\t{}\t{:.2}", name, win_start, win_end, mean)?;
+                }
+            }
+            start = end;
+        }
+    }
+    out.flush()?;
+    Ok(())
 }
 
-fn view_header(args: Cli){
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
-    let reader = Reader::new(file, ParsingTemplate::new()).unwrap();
-    
-    let header_len = (&reader.file_meta.get_sam_header()[..std::mem::size_of::<u32>()]).read_u32::<LittleEndian>().unwrap() as usize;
+fn view_header(reader: &Reader) -> CliResult {
+    let header_len = (&reader.file_meta.get_sam_header()[..std::mem::size_of::<u32>()]).read_u32::<LittleEndian>()? as usize;
     let header_bytes = reader.file_meta.get_sam_header()[std::mem::size_of::<u32>()..std::mem::size_of::<u32>()+header_len].to_owned();
-    let header = 
-        String::from_utf8(header_bytes).unwrap();
-   
-    println!("{}", header);
+    let header = String::from_utf8_lossy(&header_bytes);
+
+    writeln!(std::io::stdout(), "{}", header)?;
+    Ok(())
+}
+
+fn inspect_names(args: InspectArgs) -> CliResult {
+    let file = File::open(&args.in_path)?;
+    let mut reader = Reader::new(file, ParsingTemplate::new_with(&[Fields::ReadName]))?;
+    let file_meta = reader.file_meta.clone();
+
+    let block_num = args.block;
+    let blocks = file_meta.view_blocks(&Fields::ReadName);
+    let block = blocks.get(block_num).ok_or_else(|| {
+        usage(format!("Block {} requested, but ReadName column has {} blocks.", block_num, blocks.len()))
+    })?;
+
+    println!("ReadName block {} of {}", block_num, blocks.len());
+    println!("codec: {:?}", file_meta.get_block_codec(&Fields::ReadName, block_num));
+    println!("seekpos: {}", block.seekpos);
+    println!("names: {}", block.numitems);
+    println!("compressed size: {}", block.block_size);
+    println!("uncompressed size: {}", block.uncompressed_size);
+    if block.block_size > 0 {
+        println!("ratio: {:.2}", block.uncompressed_size as f64 / block.block_size as f64);
+    }
+
+    let first_rec: usize = blocks[..block_num].iter().map(|b| b.numitems as usize).sum();
+    let names_num = std::cmp::min(args.names_num, block.numitems as usize);
+    let mut rec = GbamRecord::default();
+    for rec_num in first_rec..first_rec + names_num {
+        reader.try_fill_record(rec_num, &mut rec)?;
+        println!("{}", String::from_utf8_lossy(rec.name().unwrap_or(b"*")));
+    }
+    Ok(())
 }
 
+#[allow(dead_code)]
 fn parse_tag(tags: &[u8], target_tag: &str) -> Option<String> {
     // Convert the target tag to bytes
     let target_bytes = target_tag.as_bytes();
@@ -332,65 +941,158 @@ fn parse_tag(tags: &[u8], target_tag: &str) -> Option<String> {
 }
 
 
-fn view_file(args: Cli, template: ParsingTemplate){
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+/// Opens local file or, with `remote` feature, URL (see `HttpStorage`).
+fn open_reader(builder: ReaderBuilder, location: &str) -> std::io::Result<Reader> {
+    #[cfg(feature = "remote")]
+    if gbam_tools::reader::remote::is_remote(location) {
+        return builder.open_url(location);
+    }
+    builder.open(File::open(location)?)
+}
 
-    let mut reader = Reader::new_with_index(file, template, args.index_file.and_then(read_index)).unwrap();
+fn view_file(args: ViewArgs, threads: Option<usize>) -> CliResult {
+    let mut template = ParsingTemplate::new();
+    if args.markdup {
+        template.set_all_except(&[Fields::RawQual, Fields::RawSequence]);
+    } else {
+        template.set_all();
+    }
+    let mut builder = ReaderBuilder::new()
+        .template(template)
+        .access(args.access.unwrap_or(if args.stream { Access::Read } else { Access::Mmap }))
+        .index_mapping(args.index_file.as_deref().map(read_index).transpose()?);
+    if let Some(thread_num) = threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(thread_num).build()?;
+        builder = builder.prefetch(thread_num, std::sync::Arc::new(pool));
+    }
+    if !args.region.is_empty() {
+        if let Some(index) = GbamIndex::read_sidecar(&args.in_path)? {
+            builder = builder.index(index);
+        }
+    }
+    let mut reader = open_reader(builder, path_str(&args.in_path)?)?;
+    if args.header_only {
+        return view_header(&reader);
+    }
 
     let st = std::io::stdout();
     let lock = st.lock();
     let mut stdout = BufWriter::with_capacity(64 * 1024, lock);
 
+    // Bad regions, expression and unsorted input fail before anything is
+    // written.
+    for region in &args.region {
+        reader.fetch(region).map_err(|err| usage(err.to_string()))?;
+    }
+    let expr = args
+        .expr
+        .as_ref()
+        .map(|text| FilterExpr::parse(text, reader.file_meta.get_ref_seqs()).map_err(|err| usage(err.to_string())))
+        .transpose()?;
+
+    if args.stream {
+        let format = if args.sam { StreamFormat::Sam } else { StreamFormat::UncompressedBam };
+        stream_records(&mut reader, &args.region, expr.as_ref(), format, &mut stdout)?;
+        return Ok(());
+    }
+
+    if args.sam {
+        gbam_to_sam(&mut reader, &args.region, expr.as_ref(), true, &mut stdout)?.flush()?;
+        return Ok(());
+    }
+
     const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
-    stdout.write_all(BAM_MAGIC).unwrap();
-    stdout.write_all(reader.file_meta.get_sam_header()).unwrap();
-    
-    let mut records = reader.records();
+    stdout.write_all(BAM_MAGIC)?;
+    stdout.write_all(reader.file_meta.get_sam_header())?;
+
     let mut buf = Vec::new();
-    while let Some(rec) = records.next_rec() {
-        rec.convert_to_bytes(&mut buf);
-        if stdout.write_all(&buf).is_err() {
-            break;
+    for region in &args.region {
+        let mut records = reader.fetch(region)?;
+        while let Some(rec) = records.try_next_rec()? {
+            if expr.as_ref().is_some_and(|expr| !expr.accepts(rec)) {
+                continue;
+            }
+            rec.convert_to_bytes(&mut buf);
+            stdout.write_all(&buf)?;
         }
     }
+    if !args.region.is_empty() {
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    if let Some(expr) = &expr {
+        let mut records = reader.filtered(expr)?;
+        while let Some(rec) = records.try_next_rec()? {
+            rec.convert_to_bytes(&mut buf);
+            stdout.write_all(&buf)?;
+        }
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    let mut rec = GbamRecord::default();
+    let mut records = reader.records();
+    while records.read_into(&mut rec)? {
+        rec.convert_to_bytes(&mut buf);
+        stdout.write_all(&buf)?;
+    }
+    stdout.flush()?;
+    Ok(())
 }
 
 
 
-fn patch_dups(args: Cli){
+fn build_index(args: IndexArgs) -> CliResult {
+    let index = GbamIndex::build(&args.in_path)?;
+    if args.in_footer {
+        index.embed(&args.in_path)?;
+    } else {
+        index.write(&GbamIndex::sidecar_path(&args.in_path))?;
+    }
+    Ok(())
+}
+
+fn patch_dups(args: PatchDupsArgs) -> CliResult {
 
     let file = OpenOptions::new()
         .write(true)
         .read(true)
-        .open(args.in_path.as_path().to_str().unwrap())
-        .unwrap();
+        .open(&args.in_path)?;
 
-    let reader = Reader::new_with_index(file.try_clone().unwrap(), ParsingTemplate::new(), args.index_file.and_then(read_index)).unwrap();
+    let index = args.index_file.as_deref().map(read_index).transpose()?;
+    let reader = Reader::new_with_index(file.try_clone()?, ParsingTemplate::new(), index)?;
     let file_meta = reader.file_meta.clone();
 
     let mut buf = Vec::new();
 
-    let codec = file_meta.get_field_codec(&Fields::Flags);
-    assert!(codec == &Codecs::NoCompression);
+    // Flags are patched in place, so every block has to be stored as is.
+    for block_num in 0..file_meta.view_blocks(&Fields::Flags).len() {
+        if file_meta.get_block_codec(&Fields::Flags, block_num) != Codecs::NoCompression {
+            return Err(failed("Flags column has to be stored without compression to be patched."));
+        }
+    }
 
-    let mut read_manual = BufReader::with_capacity(MEGA_BYTE_SIZE, file.try_clone().unwrap());
-    let mut write_manual = BufWriter::with_capacity(MEGA_BYTE_SIZE, file.try_clone().unwrap());
+    let mut read_manual = BufReader::with_capacity(MEGA_BYTE_SIZE, file.try_clone()?);
+    let mut write_manual = BufWriter::with_capacity(MEGA_BYTE_SIZE, file.try_clone()?);
     for block in file_meta.view_blocks(&Fields::Flags){
         let available_in_block = block.numitems;
         buf.resize(block.block_size as usize, 0);
-        read_manual.seek(SeekFrom::Start(block.seekpos)).unwrap();
-        read_manual.read_exact(&mut buf).unwrap();
+        read_manual.seek(SeekFrom::Start(block.seekpos))?;
+        read_manual.read_exact(&mut buf)?;
         let slice = &mut buf[..];
         for (chunk, is_dup) in zip_eq(slice.chunks_mut(2), std::io::stdin().lock().lines().take(available_in_block as usize)){
-            let mut val = (&chunk[..]).read_u16::<byteorder::LittleEndian>().unwrap();
-            if is_dup.unwrap() == "1" {
+            let mut val = (&chunk[..]).read_u16::<byteorder::LittleEndian>()?;
+            if is_dup? == "1" {
                 val = val | 0x400;
             }
-            (&mut chunk[..]).write_u16::<byteorder::LittleEndian>(val).unwrap();
+            (&mut chunk[..]).write_u16::<byteorder::LittleEndian>(val)?;
         }
-        write_manual.seek(SeekFrom::Start(block.seekpos)).unwrap();
-        write_manual.write_all(&buf).unwrap();
+        write_manual.seek(SeekFrom::Start(block.seekpos))?;
+        write_manual.write_all(&buf)?;
     }
+    write_manual.flush()?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -459,4 +1161,32 @@ mod tests {
         let result = parse_tag(tags, "TAG");
         assert_eq!(result, Some("value1".to_string()));
     }
+
+    #[test]
+    fn test_cli_subcommands() {
+        let parse = |args: &[&str]| Cli::from_iter_safe(std::iter::once("gbam").chain(args.iter().copied()));
+        let cli = parse(&["write", "in.bam", "-o", "out.gbam", "--sort", "--threads", "4"]).ok().unwrap();
+        assert_eq!(cli.threads, Some(4));
+        assert!(matches!(cli.command, Command::Write(WriteArgs { sort: true, .. })));
+        let cli = parse(&["--threads", "2", "view", "in.gbam", "--region", "chr1", "--region", "chr2:1-10"]).ok().unwrap();
+        assert!(matches!(cli.command, Command::View(ViewArgs { ref region, .. }) if region.len() == 2));
+        assert!(matches!(parse(&["bench", "cigar", "in.gbam"]).ok().unwrap().command, Command::Bench { bench: Bench::Cigar(_) }));
+        assert!(parse(&["view"]).is_err());
+        assert!(parse(&["to-fastq", "in.gbam", "-o", "1.fq", "--mate-fastq", "2.fq", "--interleave"]).is_err());
+        assert!(parse(&["-v", "in.gbam"]).is_err());
+
+        let exit_code = |result: CliResult| match result {
+            Ok(()) => 0,
+            Err(CliError::Usage(_)) => EXIT_USAGE,
+            Err(CliError::Failed(_)) => EXIT_FAILURE,
+        };
+        let cli = parse(&["write", "in.bam", "--omit", "Pos", "-o", "out.gbam"]).ok().unwrap();
+        assert_eq!(exit_code(run(cli, String::new())), EXIT_USAGE);
+        let cli = parse(&["stats", "/nonexistent/in.gbam"]).ok().unwrap();
+        assert_eq!(exit_code(run(cli, String::new())), EXIT_FAILURE);
+        let cli = parse(&["write", "/nonexistent/in.sam", "-o", "/nonexistent/out.gbam"]).ok().unwrap();
+        assert_eq!(exit_code(run(cli, String::new())), EXIT_FAILURE);
+        let broken_pipe = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed");
+        assert_eq!(exit_code(ignore_broken_pipe(Err(broken_pipe.into()))), 0);
+    }
 }
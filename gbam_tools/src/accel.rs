@@ -0,0 +1,170 @@
+use crate::Codecs;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, RwLock};
+
+/// Stream formats an accelerated backend may produce. Output has to be
+/// readable by the software implementation and vice versa, so files don't
+/// depend on the machine they were written on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccelFormat {
+    /// Gzip member, used by `Codecs::Gzip` (e.g. ISA-L igzip).
+    Deflate,
+    /// Zstd frames, used by `Codecs::Zstd` (e.g. QAT zstd plugin).
+    Zstd,
+}
+
+impl AccelFormat {
+    fn of(codec: &Codecs) -> Option<Self> {
+        match codec {
+            Codecs::Gzip => Some(AccelFormat::Deflate),
+            Codecs::Zstd => Some(AccelFormat::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Hardware accelerated implementation of a stream format. Bindings to
+/// ISA-L, QAT and alike live outside of this crate and are plugged in with
+/// `register_backend`.
+pub trait AccelBackend: Send + Sync {
+    /// Human readable name, e.g. "isal".
+    fn name(&self) -> &str;
+    /// Whether required CPU features or devices are present. Checked on
+    /// registration.
+    fn is_available(&self) -> bool;
+    /// Appends compressed `source` to `dest`.
+    fn compress(&self, source: &[u8], dest: &mut Vec<u8>) -> Result<()>;
+    /// Appends decompressed `source` to `dest`.
+    fn decompress(&self, source: &[u8], dest: &mut Vec<u8>) -> Result<()>;
+}
+
+static BACKENDS: RwLock<[Option<Arc<dyn AccelBackend>>; 2]> = RwLock::new([None, None]);
+
+/// Uses `backend` for `format` in this process. Fails if the backend is not
+/// available on this machine, software implementation stays in use then.
+pub fn register_backend(format: AccelFormat, backend: Arc<dyn AccelBackend>) -> Result<()> {
+    if !backend.is_available() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Backend <{}> is not available.", backend.name()),
+        ));
+    }
+    BACKENDS.write().unwrap()[format as usize] = Some(backend);
+    Ok(())
+}
+
+/// Switches `format` back to software implementation.
+pub fn unregister_backend(format: AccelFormat) {
+    BACKENDS.write().unwrap()[format as usize] = None;
+}
+
+/// Name of backend in use for `format`, if any.
+pub fn backend_name(format: AccelFormat) -> Option<String> {
+    BACKENDS.read().unwrap()[format as usize]
+        .as_ref()
+        .map(|backend| backend.name().to_owned())
+}
+
+fn backend(codec: &Codecs) -> Option<Arc<dyn AccelBackend>> {
+    AccelFormat::of(codec).and_then(|format| BACKENDS.read().unwrap()[format as usize].clone())
+}
+
+/// Compresses with accelerated backend of `codec`, if any. Returns false if
+/// software implementation has to be used, either because there is no
+/// backend or it failed on this block.
+pub(crate) fn try_compress(codec: &Codecs, source: &[u8], dest: &mut Vec<u8>) -> bool {
+    match backend(codec) {
+        Some(backend) => {
+            dest.clear();
+            backend.compress(source, dest).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Decompression counterpart of `try_compress`.
+pub(crate) fn try_decompress(codec: &Codecs, source: &[u8], dest: &mut Vec<u8>) -> bool {
+    match backend(codec) {
+        Some(backend) => {
+            dest.clear();
+            backend.decompress(source, dest).is_ok()
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::compress;
+    use crate::reader::column::decompress_block;
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Software gzip counting calls, fails on blocks starting with 0xFF.
+    struct CountingGzip(AtomicUsize);
+
+    impl AccelBackend for CountingGzip {
+        fn name(&self) -> &str {
+            "counting-gzip"
+        }
+        fn is_available(&self) -> bool {
+            true
+        }
+        fn compress(&self, source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            if source.first() == Some(&0xFF) {
+                return Err(Error::other("device error"));
+            }
+            let mut encoder = GzEncoder::new(dest, Compression::fast());
+            encoder.write_all(source)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        fn decompress(&self, source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            GzDecoder::new(source).read_to_end(dest)?;
+            Ok(())
+        }
+    }
+
+    struct Unavailable;
+
+    impl AccelBackend for Unavailable {
+        fn name(&self) -> &str {
+            "unavailable"
+        }
+        fn is_available(&self) -> bool {
+            false
+        }
+        fn compress(&self, _source: &[u8], _dest: &mut Vec<u8>) -> Result<()> {
+            unreachable!()
+        }
+        fn decompress(&self, _source: &[u8], _dest: &mut Vec<u8>) -> Result<()> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_backend_with_fallback() {
+        assert!(register_backend(AccelFormat::Zstd, Arc::new(Unavailable)).is_err());
+        assert_eq!(backend_name(AccelFormat::Zstd), None);
+
+        let backend = Arc::new(CountingGzip(AtomicUsize::new(0)));
+        register_backend(AccelFormat::Deflate, backend.clone()).unwrap();
+        for first in [0u8, 0xFF] {
+            let source: Vec<u8> = (0..10_000).map(|i| (i % 13) as u8 | first).collect();
+            let compressed = compress(&source, Vec::new(), Codecs::Gzip).unwrap();
+            let mut decompressed = Vec::new();
+            decompress_block(&compressed, &mut decompressed, &Codecs::Gzip).unwrap();
+            assert_eq!(decompressed, source);
+        }
+        // Two compressions, one of them fell back to software, and two
+        // decompressions.
+        assert_eq!(backend.0.load(Ordering::SeqCst), 4);
+        unregister_backend(AccelFormat::Deflate);
+    }
+}
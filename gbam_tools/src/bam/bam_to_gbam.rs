@@ -1,61 +1,400 @@
 use crate::MEGA_BYTE_SIZE;
-use crate::{Codecs, Writer};
-use bam_tools::parse_reference_sequences;
+use crate::bam::external_sort::ExternalSorter;
+use crate::checkpoint::{checkpoint_path, partial_path, Checkpoint};
+use crate::bam::fastq_reader::FastqReader;
+use crate::bam::merge::{MergeInput, MergeReader, RecordSource, SortOrder};
+use crate::bam::sam_reader::{RefSeqs, SamReader};
+use crate::meta::QualBinning;
+use crate::progress::ProgressObserver;
+use crate::split_writer::{SplitBy, SplitWriter};
+use crate::writer_builder::WriterBuilder;
+use crate::{Codecs, CompressionReport, Writer};
+use bam_tools::{parse_reference_sequences, read_bam_header};
 use bam_tools::record::bamrawrecord::BAMRawRecord;
-use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use bam_tools::record::fields::Fields;
 use bam_tools::sorting::sort;
-use bam_tools::sorting::sort::TempFilesMode;
+use bam_tools::sorting::sort::{SortBy, TempFilesMode};
 use bam_tools::Reader;
+use byteorder::{ByteOrder, LittleEndian};
 use std::borrow::Cow;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::fs::{self, File, OpenOptions};
+use flate2::read::MultiGzDecoder;
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read};
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::str::FromStr;
 use tempdir::TempDir;
 
 
 const MEM_LIMIT: usize = 2000 * MEGA_BYTE_SIZE;
 
+/// Input path standing for stdin. Inputs are only read sequentially, so
+/// conversion works on pipes, e.g. `samtools view -u in.bam | gbam write - -o out.gbam`.
+pub const STDIN_PATH: &str = "-";
+
 /// Converts BAM file to GBAM file. This uses the `bam_parallel` reader.
-pub fn bam_to_gbam(in_path: &str, out_path: &str, codec: Codecs, full_command: String) {
-    let (mut bam_reader, mut writer) = get_bam_reader_gbam_writer(in_path, out_path, codec, full_command);
+/// Writer is configured with `builder` (see `WriterBuilder`), header,
+/// reference sequences and sort order are taken from input. Progress is
+/// reported to `observer` if given, reader's own progress bar is shown
+/// otherwise. Returns per-field compression totals. Fails if input can't be
+/// read, configuration is invalid or output can't be written, in which case
+/// nothing is left at `out_path`.
+pub fn bam_to_gbam(in_path: &str, out_path: &str, builder: WriterBuilder, observer: Option<Box<dyn ProgressObserver>>) -> io::Result<CompressionReport> {
+    let (mut bam_reader, mut writer) = get_bam_reader_gbam_writer(in_path, out_path, builder, observer.is_none())?;
+    if let Some(observer) = observer {
+        writer.set_progress_observer(observer);
+    }
 
     let mut records = bam_reader.records();
-    while let Some(Ok(rec)) = records.next_rec() {
-        let wrapper = BAMRawRecord(Cow::Borrowed(rec));
-        writer.push_record(&wrapper);
+    while let Some(rec) = records.next_rec() {
+        let wrapper = BAMRawRecord(Cow::Borrowed(rec?));
+        writer.push_record(&wrapper)?;
     }
 
-    writer.finish().unwrap();
+    writer.finish()?;
+    commit_output(out_path)?;
+    Ok(writer.compression_report().cloned().unwrap())
 }
 
-/// Converts BAM file to GBAM file. Sorts BAM file in process. This uses the `bam_parallel` reader.
-pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_temp_mode: Option<String>, temp_dir: Option<PathBuf>, full_command: String, index_sort: bool) {
-    let fin_for_ref_seqs = File::open(in_path).expect("failed");
-    
-    let mut reader_for_header_only = Reader::new(fin_for_ref_seqs, 1, None);
-    let (sam_header, ref_seqs, _) =
-        read_sam_header_and_ref_seqs(&mut reader_for_header_only);
+/// Converts SAM file to GBAM file. Arguments are the same as of
+/// `bam_to_gbam`.
+pub fn sam_to_gbam(in_path: &str, out_path: &str, builder: WriterBuilder, observer: Option<Box<dyn ProgressObserver>>) -> io::Result<CompressionReport> {
+    let (input, _) = open_input(in_path)?;
+    let mut sam_reader = SamReader::new(BufReader::with_capacity(MEGA_BYTE_SIZE, input));
+    let (sam_header, ref_seqs) = sam_reader.read_header()?;
+    let is_sorted = is_coordinate_sorted(&sam_header);
+
+    let builder = builder.header(ref_seqs, sam_header).sorted(is_sorted);
+    let mut writer = build_writer(builder, out_path)?;
+    if let Some(observer) = observer {
+        writer.set_progress_observer(observer);
+    }
+
+    while let Some(rec) = sam_reader.next_rec() {
+        let wrapper = BAMRawRecord(Cow::Borrowed(rec?));
+        writer.push_record(&wrapper)?;
+    }
 
+    writer.finish()?;
+    commit_output(out_path)?;
+    Ok(writer.compression_report().cloned().unwrap())
+}
 
-    let fin = File::open(in_path).expect("failed");
-    let fout = File::create(out_path).expect("failed");
+/// Merges coordinate sorted BAM or SAM inputs into single GBAM file. Headers
+/// are reconciled (see `MergeReader`). Other arguments are the same as of
+/// `bam_to_gbam`.
+pub fn merge_to_gbam(in_paths: &[&str], out_path: &str, builder: WriterBuilder, observer: Option<Box<dyn ProgressObserver>>) -> io::Result<CompressionReport> {
+    let inputs = in_paths.iter().map(|in_path| open_record_source(in_path, observer.is_none())).collect::<io::Result<_>>()?;
+    let mut merge_reader = MergeReader::new(inputs)?;
 
-    let file_size = fin.metadata().unwrap().len();
+    let builder = builder
+        .header(merge_reader.ref_seqs().clone(), merge_reader.header().to_vec())
+        .sorted(true);
+    let mut writer = build_writer(builder, out_path)?;
+    if let Some(observer) = observer {
+        writer.set_progress_observer(observer);
+    }
 
-    let buf_reader = BufReader::new(fin);
-    let buf_writer = BufWriter::new(fout);
+    while let Some(rec) = merge_reader.next_rec() {
+        let wrapper = BAMRawRecord(Cow::Borrowed(rec?));
+        writer.push_record(&wrapper)?;
+    }
 
-    let mut writer = Writer::new(
-        buf_writer,
-        vec![codec; FIELDS_NUM],
-        8,
-        vec![Fields::RefID],
-        ref_seqs,
-        sam_header,
-        full_command,
-        true
-    );
+    writer.finish()?;
+    commit_output(out_path)?;
+    Ok(writer.compression_report().cloned().unwrap())
+}
+
+/// Converts BAM or SAM file to GBAM file sorted in `order`. Unlike
+/// `bam_sort_to_gbam`, sorted chunks are spilled into temporary GBAM files
+/// inside `temp_dir` (see `ExternalSorter`). Other arguments are the same
+/// as of `bam_to_gbam`.
+pub fn external_sort_to_gbam(in_path: &str, out_path: &str, order: SortOrder, temp_dir: Option<PathBuf>, builder: WriterBuilder, observer: Option<Box<dyn ProgressObserver>>) -> io::Result<CompressionReport> {
+    let (mut source, sam_header, ref_seqs) = open_record_source(in_path, observer.is_none())?;
+    let tmp_dir_path = temp_dir.unwrap_or_else(std::env::temp_dir);
+    let mut sorter = ExternalSorter::new(order, sam_header, ref_seqs, MEM_LIMIT, &tmp_dir_path)?;
+    let mut rec = Vec::new();
+    while source.read_record(&mut rec)? {
+        sorter.push_record(&rec)?;
+    }
+    let mut merge_reader = sorter.finish()?;
+
+    let builder = builder
+        .header(merge_reader.ref_seqs().clone(), merge_reader.header().to_vec())
+        .sorted(order == SortOrder::Coordinate);
+    let mut writer = build_writer(builder, out_path)?;
+    if let Some(observer) = observer {
+        writer.set_progress_observer(observer);
+    }
+
+    while let Some(rec) = merge_reader.next_rec() {
+        let wrapper = BAMRawRecord(Cow::Borrowed(rec?));
+        writer.push_record(&wrapper)?;
+    }
+
+    writer.finish()?;
+    commit_output(out_path)?;
+    Ok(writer.compression_report().cloned().unwrap())
+}
+
+/// Splits BAM or SAM file into GBAM file per value of `split_by` (see
+/// `SplitWriter`), paths of outputs start with `out_prefix`. Other arguments
+/// are the same as of `bam_to_gbam`. Returns path and per-field compression
+/// totals of every output.
+pub fn split_to_gbam(in_path: &str, out_prefix: &str, split_by: SplitBy, builder: WriterBuilder) -> io::Result<Vec<(String, CompressionReport)>> {
+    let (mut source, sam_header, ref_seqs) = open_record_source(in_path, true)?;
+    let is_sorted = is_coordinate_sorted(&sam_header);
+    let builder = builder.header(ref_seqs, sam_header).sorted(is_sorted);
+    let mut writer = SplitWriter::new(out_prefix, split_by, builder)?;
+
+    let mut rec = Vec::new();
+    while source.read_record(&mut rec)? {
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&rec)))?;
+    }
+
+    writer.finish()
+}
+
+/// Converts BAM or SAM file to GBAM file, saving checkpoint into
+/// `checkpoint_path(out_path)` every `checkpoint_every` records. If `resume`
+/// is set, conversion interrupted earlier continues from its last
+/// checkpoint: input is read from the start again and records up to the
+/// checkpoint are skipped. Checkpoint is removed once finished. Other
+/// arguments are the same as of `bam_to_gbam` and should not change on
+/// resume.
+pub fn resumable_to_gbam(in_path: &str, out_path: &str, builder: WriterBuilder, observer: Option<Box<dyn ProgressObserver>>, checkpoint_every: u64, resume: bool) -> io::Result<CompressionReport> {
+    if checkpoint_every == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Checkpoint interval should be positive."));
+    }
+    let (mut source, sam_header, ref_seqs) = open_record_source(in_path, observer.is_none())?;
+    let is_sorted = is_coordinate_sorted(&sam_header);
+    let builder = builder.header(ref_seqs, sam_header).sorted(is_sorted).collect_stats(Fields::RefID);
+    builder.validate()?;
+    let checkpoint_path = PathBuf::from(checkpoint_path(out_path));
+    let checkpoint = if resume {
+        Some(Checkpoint::load(&checkpoint_path)?)
+    } else {
+        None
+    };
+    let fout = match &checkpoint {
+        Some(checkpoint) => {
+            let fout = OpenOptions::new().write(true).open(partial_path(out_path))?;
+            if fout.metadata()?.len() < checkpoint.data_end() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Output is shorter than its checkpoint."));
+            }
+            // Drops blocks written after the checkpoint.
+            fout.set_len(checkpoint.data_end())?;
+            fout
+        }
+        None => create_output(out_path)?,
+    };
+    let mut writer = builder.build(BufWriter::new(fout))?;
+
+    let mut rec = Vec::new();
+    if let Some(checkpoint) = checkpoint {
+        for _ in 0..checkpoint.records() {
+            if !source.read_record(&mut rec)? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Input is shorter than its checkpoint."));
+            }
+        }
+        writer.resume(checkpoint)?;
+    }
+    if let Some(observer) = observer {
+        writer.set_progress_observer(observer);
+    }
+
+    while source.read_record(&mut rec)? {
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&rec)))?;
+        if writer.progress().records.is_multiple_of(checkpoint_every) {
+            let checkpoint = writer.checkpoint()?;
+            // Checkpoint must not refer to data lost on crash.
+            writer.get_ref().get_ref().sync_data()?;
+            checkpoint.save(&checkpoint_path)?;
+        }
+    }
+
+    writer.finish()?;
+    commit_output(out_path)?;
+    if checkpoint_path.exists() {
+        fs::remove_file(&checkpoint_path)?;
+    }
+    Ok(writer.compression_report().cloned().unwrap())
+}
+
+fn open_record_source(in_path: &str, track_progress: bool) -> io::Result<MergeInput> {
+    if is_bam(in_path) {
+        let mut bam_reader = open_bam(in_path, track_progress)?;
+        let (sam_header, ref_seqs, _) = read_sam_header_and_ref_seqs(&mut bam_reader)?;
+        Ok((RecordSource::Bam(bam_reader), sam_header, ref_seqs))
+    } else {
+        let (input, _) = open_input(in_path)?;
+        let input: Box<dyn BufRead> = Box::new(BufReader::with_capacity(MEGA_BYTE_SIZE, input));
+        let mut sam_reader = SamReader::new(input);
+        let (sam_header, ref_seqs) = sam_reader.read_header()?;
+        Ok((RecordSource::Sam(sam_reader), sam_header, ref_seqs))
+    }
+}
+
+/// Converts FASTQ file, or pair of files if `mate_path` is given, to GBAM
+/// file of unaligned records. Gzipped input is accepted. Other arguments are
+/// the same as of `bam_to_gbam`.
+pub fn fastq_to_gbam(in_path: &str, mate_path: Option<&str>, out_path: &str, builder: WriterBuilder, observer: Option<Box<dyn ProgressObserver>>) -> io::Result<CompressionReport> {
+    let mut fastq_reader = match mate_path {
+        Some(mate_path) => FastqReader::paired(open_fastq(in_path)?, open_fastq(mate_path)?),
+        None => FastqReader::new(open_fastq(in_path)?),
+    };
+
+    let builder = builder.header(Vec::new(), fastq_reader.header()).sorted(false);
+    let mut writer = build_writer(builder, out_path)?;
+    if let Some(observer) = observer {
+        writer.set_progress_observer(observer);
+    }
+
+    while let Some(rec) = fastq_reader.next_rec() {
+        let wrapper = BAMRawRecord(Cow::Borrowed(rec?));
+        writer.push_record(&wrapper)?;
+    }
+
+    writer.finish()?;
+    commit_output(out_path)?;
+    Ok(writer.compression_report().cloned().unwrap())
+}
+
+fn open_fastq(path: &str) -> io::Result<Box<dyn BufRead>> {
+    let is_gzipped = is_bam(path);
+    let (input, _) = open_input(path)?;
+    if is_gzipped {
+        Ok(Box::new(BufReader::with_capacity(MEGA_BYTE_SIZE, MultiGzDecoder::new(input))))
+    } else {
+        Ok(Box::new(BufReader::with_capacity(MEGA_BYTE_SIZE, input)))
+    }
+}
+
+/// Checks if input starts with gzip magic, as BAM (BGZF) files do. Anything
+/// else is treated as SAM text. Stdin is peeked, checked bytes are still
+/// read afterwards.
+pub fn is_bam(in_path: &str) -> bool {
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+    if in_path == STDIN_PATH {
+        return io::stdin().lock().fill_buf().is_ok_and(|buf| {
+            !buf.is_empty() && GZIP_MAGIC.starts_with(&buf[..buf.len().min(GZIP_MAGIC.len())])
+        });
+    }
+    let mut magic = [0; 2];
+    File::open(in_path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && magic == GZIP_MAGIC
+}
+
+/// Validates configuration of `builder` and creates writer into
+/// `partial_path(out_path)`, so invalid configuration leaves no output.
+fn build_writer(builder: WriterBuilder, out_path: &str) -> io::Result<Writer<BufWriter<File>>> {
+    let builder = builder.collect_stats(Fields::RefID);
+    builder.validate()?;
+    Ok(builder.build(BufWriter::new(create_output(out_path)?))?)
+}
+
+/// Creates output of conversion at `partial_path(out_path)`, see
+/// `commit_output`.
+fn create_output(out_path: &str) -> io::Result<File> {
+    File::create(partial_path(out_path))
+}
+
+/// Moves finished output to `out_path`, so output at `out_path` is never
+/// left incomplete.
+fn commit_output(out_path: &str) -> io::Result<()> {
+    fs::rename(partial_path(out_path), out_path)
+}
+
+/// Opens input of conversion. Returns size of input if it's known, which is
+/// not the case for stdin.
+fn open_input(in_path: &str) -> io::Result<(Box<dyn Read + Send>, Option<u64>)> {
+    if in_path == STDIN_PATH {
+        return Ok((Box::new(io::stdin()), None));
+    }
+    let fin = File::open(in_path).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", in_path, err)))?;
+    let file_size = fin.metadata()?.len();
+    Ok((Box::new(fin), Some(file_size)))
+}
+
+/// Parallel BAM reader of input. If `track_progress` is set, progress of
+/// input of unknown size is reported as count of bytes read.
+fn open_bam(in_path: &str, track_progress: bool) -> io::Result<Reader> {
+    let (input, file_size) = open_input(in_path)?;
+    let mut reader = Reader::new(BufReader::new(input), 4, file_size.filter(|_| track_progress));
+    if file_size.is_none() && track_progress {
+        reader.track_progress_of_unknown_size();
+    }
+    Ok(reader)
+}
+
+/// Estimates GBAM size for every codec configuration in `configs` without
+/// writing anything. Input is read once, every configuration compresses
+/// only each `sample_every`-th block of every field (see
+/// `Writer::new_dry_run`). Configurations share `thread_num` compression
+/// threads. Returns projected report of every configuration.
+pub fn estimate_bam_to_gbam(in_path: &str, configs: Vec<Vec<Codecs>>, thread_num: usize, qual_binning: Option<QualBinning>, mate_relative: bool, sample_every: u64) -> io::Result<Vec<CompressionReport>> {
+    let mut bam_reader = open_bam(in_path, true)?;
+    let (sam_header, _, _) = read_sam_header_and_ref_seqs(&mut bam_reader)?;
+    let is_sorted = is_coordinate_sorted(&sam_header);
+
+    let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(thread_num).build().map_err(io::Error::other)?);
+    let mut writers: Vec<_> = configs
+        .into_iter()
+        .map(|codecs| {
+            let mut writer = Writer::new_dry_run(codecs, 1, is_sorted, sample_every);
+            writer.set_thread_pool(pool.clone())?;
+            if let Some(binning) = qual_binning.clone() {
+                writer.set_qual_binning(binning);
+            }
+            if mate_relative {
+                writer.set_mate_relative();
+            }
+            Ok(writer)
+        })
+        .collect::<io::Result<_>>()?;
+
+    let mut records = bam_reader.records();
+    while let Some(rec) = records.next_rec() {
+        let wrapper = BAMRawRecord(Cow::Borrowed(rec?));
+        for writer in writers.iter_mut() {
+            writer.push_record(&wrapper)?;
+        }
+    }
+
+    writers
+        .iter_mut()
+        .map(|writer| {
+            writer.finish()?;
+            Ok(writer.compression_report().cloned().unwrap())
+        })
+        .collect()
+}
+
+/// Path of record order written by index sort: `<file>.order`. Differs from
+/// sidecar index `<file>.gbai` (see `GbamIndex::sidecar_path`), so both can
+/// be kept for one file.
+pub fn index_sort_path(out_path: &str) -> String {
+    format!("{}.order", out_path)
+}
+
+/// Converts BAM file to GBAM file. Sorts BAM file in `order` in process. This uses the `bam_parallel` reader.
+/// Progress of reading input is shown by the sorter, `observer` follows
+/// writing of sorted records.
+#[allow(clippy::too_many_arguments)]
+pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, order: SortOrder, mut sort_temp_mode: Option<String>, temp_dir: Option<PathBuf>, index_sort: bool, builder: WriterBuilder, observer: Option<Box<dyn ProgressObserver>>) -> io::Result<CompressionReport> {
+    let (input, file_size) = open_input(in_path)?;
+    // Input is opened once, so stdin can be sorted too.
+    let (sam_header, ref_seqs, input) = read_header_and_replay(input)?;
+    let sam_header = set_sort_order(&sam_header, order);
+
+    let buf_reader = BufReader::new(input);
+    let builder = builder.header(ref_seqs, sam_header).sorted(order == SortOrder::Coordinate);
+    let mut writer = build_writer(builder, out_path)?;
+    if let Some(observer) = observer {
+        writer.set_progress_observer(observer);
+    }
 
     let tmp_dir_path = temp_dir.map_or(std::env::temp_dir(), |path| path);
     if sort_temp_mode.is_none() {
@@ -66,15 +405,15 @@ pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_t
         "lz4_file" => TempFilesMode::LZ4CompressedFiles,
         "ram" => TempFilesMode::InMemoryBlocks,
         "lz4_ram" => TempFilesMode::InMemoryBlocksLZ4,
-        _ => panic!("Unknown sort_temp_mode mode."),
+        mode => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown sort_temp_mode mode: {}", mode))),
     };
     
     let index_file = if index_sort {
-        Some(BufWriter::with_capacity(33_554_432, File::create(out_path.clone().to_owned()+".gbai").unwrap()))
+        Some(BufWriter::with_capacity(33_554_432, File::create(index_sort_path(out_path))?))
     }
     else{None};
 
-    let dir = TempDir::new_in(tmp_dir_path, "BAM sort temporary directory.").unwrap();
+    let dir = TempDir::new_in(tmp_dir_path, "BAM sort temporary directory.")?;
 
     sort::sort_bam(
         MEM_LIMIT,
@@ -85,12 +424,16 @@ pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_t
         8,
         tmp_medium_mode,
         index_file,
-        sort::SortBy::CoordinatesAndStrand,
-        Some(file_size)
-    )
-    .unwrap();
+        match order {
+            SortOrder::Coordinate => SortBy::CoordinatesAndStrand,
+            SortOrder::QueryName => SortBy::NameAndMatchMates,
+        },
+        file_size
+    )?;
 
-    writer.finish().unwrap();
+    writer.finish()?;
+    commit_output(out_path)?;
+    Ok(writer.compression_report().cloned().unwrap())
 }
 
 /// Consumes SAM header from input BAM reader.
@@ -104,40 +447,84 @@ pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_t
 /// **tuple.1** -> parsed reference sequences from BAM header.
 ///
 /// **tuple.2** -> offset to reference sequences in tuple.0. It's before n_ref uint32_t.
-fn read_sam_header_and_ref_seqs(reader: &mut Reader) -> (Vec<u8>, Vec<(String, u32)>, usize) {
-    let (bytes_of_header, ref_sequences_offset) = reader.read_header().unwrap();
-    let sequences = parse_reference_sequences(&bytes_of_header[ref_sequences_offset..]).unwrap();
-    (bytes_of_header, sequences, ref_sequences_offset)
+fn read_sam_header_and_ref_seqs(reader: &mut Reader) -> io::Result<(Vec<u8>, RefSeqs, usize)> {
+    let (bytes_of_header, ref_sequences_offset) = reader.read_header()?;
+    let sequences = parse_reference_sequences(&bytes_of_header[ref_sequences_offset..])?;
+    Ok((bytes_of_header, sequences, ref_sequences_offset))
+}
+
+/// Reads BAM header from the start of `input` without parallel reader.
+/// Returns header bytes, reference sequences and reader yielding all of
+/// `input` from the start again, as sorting parses header on its own.
+fn read_header_and_replay<R: Read + Send>(input: R) -> io::Result<(Vec<u8>, RefSeqs, impl Read + Send)> {
+    let mut decoder = MultiGzDecoder::new(Recorder { inner: input, consumed: Vec::new() });
+    let (sam_header, ref_seqs_offset) = read_bam_header(&mut decoder)?;
+    let ref_seqs = parse_reference_sequences(&sam_header[ref_seqs_offset..])?;
+    let Recorder { inner, consumed } = decoder.into_inner();
+    Ok((sam_header, ref_seqs, Cursor::new(consumed).chain(inner)))
+}
+
+/// Keeps copy of everything read from `inner`.
+struct Recorder<R> {
+    inner: R,
+    consumed: Vec<u8>,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Replaces SO tag of @HD line in header bytes laid out as in BAM file,
+/// adds @HD line if there is none.
+fn set_sort_order(sam_header: &[u8], order: SortOrder) -> Vec<u8> {
+    let l_text = LittleEndian::read_u32(sam_header) as usize;
+    let text = String::from_utf8_lossy(&sam_header[4..4 + l_text]);
+    let sort_tag = format!("SO:{}", order.header_value());
+    let mut new_text = String::new();
+    if !text.starts_with("@HD\t") {
+        new_text.push_str(&format!("@HD\tVN:1.6\t{}\n", sort_tag));
+    }
+    for line in text.split_inclusive('\n') {
+        if !line.starts_with("@HD\t") {
+            new_text.push_str(line);
+            continue;
+        }
+        let mut tags: Vec<&str> = line.trim_end_matches('\n').split('\t').filter(|tag| !tag.starts_with("SO:")).collect();
+        tags.push(&sort_tag);
+        new_text.push_str(&tags.join("\t"));
+        new_text.push('\n');
+    }
+    let mut bytes = (new_text.len() as u32).to_le_bytes().to_vec();
+    bytes.extend_from_slice(new_text.as_bytes());
+    // Reference sequences are kept as is.
+    bytes.extend_from_slice(&sam_header[4 + l_text..]);
+    bytes
+}
+
+/// Checks if @HD line of BAM header declares coordinate sort order.
+fn is_coordinate_sorted(sam_header: &[u8]) -> bool {
+    String::from_utf8_lossy(sam_header)
+        .lines()
+        .find(|line| line.contains("@HD\t"))
+        .is_some_and(|line| line.split('\t').any(|tag| tag == "SO:coordinate"))
 }
 
 fn get_bam_reader_gbam_writer(
     in_path: &str,
     out_path: &str,
-    codec: Codecs,
-    full_command: String,
-) -> (Reader, Writer<BufWriter<File>>) {
-    let fin = File::open(in_path).expect("failed");
-    let fout = File::create(out_path).expect("failed");
-
-    let file_size = fin.metadata().unwrap().len();
+    builder: WriterBuilder,
+    track_progress: bool,
+) -> io::Result<(Reader, Writer<BufWriter<File>>)> {
+    let mut bgzf_reader = open_bam(in_path, track_progress)?;
 
-    let buf_reader = BufReader::new(fin);
-    let buf_writer = BufWriter::new(fout);
+    let (sam_header, ref_seqs, _) = read_sam_header_and_ref_seqs(&mut bgzf_reader)?;
+    let is_sorted = is_coordinate_sorted(&sam_header);
 
-    let mut bgzf_reader = Reader::new(buf_reader, 4, Some(file_size));
+    let writer = build_writer(builder.header(ref_seqs, sam_header).sorted(is_sorted), out_path)?;
 
-    let (sam_header, ref_seqs, _) = read_sam_header_and_ref_seqs(&mut bgzf_reader);
-
-    let writer = Writer::new(
-        buf_writer,
-        vec![codec; FIELDS_NUM],
-        8,
-        vec![Fields::RefID],
-        ref_seqs,
-        sam_header,
-        full_command,
-        false,
-    );
-
-    (bgzf_reader, writer)
+    Ok((bgzf_reader, writer))
 }
@@ -0,0 +1,141 @@
+use std::io::{Result, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::{write::DeflateEncoder, Compression};
+use rayon::prelude::*;
+
+/// Uncompressed bytes of block, the same as htslib uses, so compressed block
+/// never exceeds 64 KiB.
+const BLOCK_DATA_SIZE: usize = 0xff00;
+/// Full blocks compressed in parallel at once.
+const BATCH_BLOCKS: usize = 64;
+/// Gzip header with BGZF extra field, up to BSIZE.
+const BLOCK_HEADER: [u8; 16] = [
+    0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0,
+];
+/// Empty block which ends BGZF file (SAM specification 4.1.2).
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0, 0x1b, 0, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Writes BGZF (blocked gzip of BAM files). Blocks are compressed in
+/// batches on rayon's global thread pool. `finish` must be called to write
+/// the last block and EOF marker.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    level: Compression,
+    /// Data of blocks to compress, all but the last one are full.
+    blocks: Vec<Vec<u8>>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W, level: Compression) -> Self {
+        Self {
+            inner,
+            level,
+            blocks: vec![Vec::with_capacity(BLOCK_DATA_SIZE)],
+        }
+    }
+
+    /// Writes remaining data and EOF marker, returns inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.flush()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Compresses and writes full blocks, and the last one if `all`.
+    fn write_blocks(&mut self, all: bool) -> Result<()> {
+        if all && !self.blocks.last().unwrap().is_empty() {
+            self.blocks.push(Vec::with_capacity(BLOCK_DATA_SIZE));
+        }
+        let full = self.blocks.len() - 1;
+        let level = self.level;
+        let compressed = self.blocks[..full]
+            .par_iter()
+            .map(|data| compress_block(data, level))
+            .collect::<Result<Vec<_>>>()?;
+        for block in compressed {
+            self.inner.write_all(&block)?;
+        }
+        self.blocks.drain(..full);
+        Ok(())
+    }
+}
+
+/// BGZF block of `data`, which is at most `BLOCK_DATA_SIZE` bytes.
+fn compress_block(data: &[u8], level: Compression) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(BLOCK_HEADER.len() + 2 + data.len()), level);
+    encoder.get_mut().extend_from_slice(&BLOCK_HEADER);
+    // BSIZE is patched below.
+    encoder.get_mut().extend_from_slice(&[0, 0]);
+    encoder.write_all(data)?;
+    let mut block = encoder.finish()?;
+    block.write_u32::<LittleEndian>(crc32fast::hash(data))?;
+    block.write_u32::<LittleEndian>(data.len() as u32)?;
+    let bsize = (block.len() - 1) as u16;
+    block[BLOCK_HEADER.len()..BLOCK_HEADER.len() + 2].copy_from_slice(&bsize.to_le_bytes());
+    Ok(block)
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, mut buf: &[u8]) -> Result<usize> {
+        let written = buf.len();
+        while !buf.is_empty() {
+            let block = self.blocks.last_mut().unwrap();
+            let len = buf.len().min(BLOCK_DATA_SIZE - block.len());
+            block.extend_from_slice(&buf[..len]);
+            buf = &buf[len..];
+            if block.len() == BLOCK_DATA_SIZE {
+                self.blocks.push(Vec::with_capacity(BLOCK_DATA_SIZE));
+                if self.blocks.len() > BATCH_BLOCKS {
+                    self.write_blocks(false)?;
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    /// Ends current block, so everything written so far can be decompressed.
+    fn flush(&mut self) -> Result<()> {
+        self.write_blocks(true)?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_bgzf_writer() {
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8 ^ (i / 1000) as u8).collect();
+        let mut writer = BgzfWriter::new(Vec::new(), Compression::default());
+        for chunk in data.chunks(7_777) {
+            writer.write_all(chunk).unwrap();
+        }
+        let bgzf = writer.finish().unwrap();
+        assert!(bgzf.ends_with(&BGZF_EOF));
+
+        // Blocks are valid gzip members with correct BSIZE.
+        let mut offset = 0;
+        let mut blocks = 0;
+        while offset < bgzf.len() {
+            assert_eq!(&bgzf[offset..offset + 16], &BLOCK_HEADER[..]);
+            offset += u16::from_le_bytes([bgzf[offset + 16], bgzf[offset + 17]]) as usize + 1;
+            blocks += 1;
+        }
+        assert_eq!(offset, bgzf.len());
+        assert_eq!(blocks, data.len().div_ceil(BLOCK_DATA_SIZE) + 1);
+
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&bgzf[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+
+        let empty = BgzfWriter::new(Vec::new(), Compression::default()).finish().unwrap();
+        assert_eq!(empty, BGZF_EOF);
+    }
+}
@@ -0,0 +1,1102 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Error, ErrorKind, Write};
+use std::mem;
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::{write::GzEncoder, Compression};
+
+use super::gbam_to_bam::for_each_record;
+use super::merge::{header_text, tag_value_size};
+use super::sam_reader::invalid_sam;
+use crate::query::cigar::base_coverage;
+use crate::reader::{reader::Reader, record::GbamRecord};
+use crate::reference::{check_references, read_fai, IndexedFasta};
+
+/// Records of slice. Every container holds one slice, as htslib writes by
+/// default.
+const SLICE_RECORDS: usize = 10_000;
+/// Slice ends at change of reference sequence once it has this many records,
+/// otherwise records of several references share multi-reference slice.
+const MIN_SINGLE_REF_RECORDS: usize = 1_000;
+
+/// Data series written, each to its own external block with content ID of
+/// its index + 1. Integers are ITF8, RN, IN and SC end with NUL, the rest
+/// are single bytes.
+const DATA_SERIES: [&[u8; 2]; 25] = [
+    b"BF", b"CF", b"RI", b"RL", b"AP", b"RG", b"RN", b"MF", b"NS", b"NP", b"TS", b"TL", b"FN", b"FC", b"FP", b"DL",
+    b"BS", b"IN", b"RS", b"PD", b"HC", b"SC", b"MQ", b"BA", b"QS",
+];
+const BF: usize = 0;
+const CF: usize = 1;
+const RI: usize = 2;
+const RL: usize = 3;
+const AP: usize = 4;
+const RG: usize = 5;
+const RN: usize = 6;
+const MF: usize = 7;
+const NS: usize = 8;
+const NP: usize = 9;
+const TS: usize = 10;
+const TL: usize = 11;
+const FN: usize = 12;
+const FC: usize = 13;
+const FP: usize = 14;
+const DL: usize = 15;
+const BS: usize = 16;
+const IN: usize = 17;
+const RS: usize = 18;
+const PD: usize = 19;
+const HC: usize = 20;
+const SC: usize = 21;
+const MQ: usize = 22;
+const BA: usize = 23;
+const QS: usize = 24;
+/// Series of byte arrays ended with NUL.
+const STOP_SERIES: [usize; 3] = [RN, IN, SC];
+
+/// CF: quality scores are stored as array.
+const CF_QUAL_ARRAY: i32 = 0x1;
+/// CF: mate fields are stored with the record.
+const CF_DETACHED: i32 = 0x2;
+/// CF: sequence is `*`.
+const CF_NO_SEQ: i32 = 0x8;
+
+/// Substitution matrix of compression header: for every reference base of
+/// ACGTN, the other four bases in ACGTN order have codes 0 to 3.
+const SUBSTITUTION_MATRIX: [u8; 5] = [0x1b; 5];
+const BASES: &[u8; 5] = b"ACGTN";
+
+/// Container which ends CRAM 3.0 file.
+pub const CRAM_EOF: [u8; 38] = [
+    0x0f, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0x0f, 0xe0, 0x45, 0x4f, 0x46, 0, 0, 0, 0, 0x01, 0, 0x05, 0xbd, 0xd9, 0x4f,
+    0, 0x01, 0, 0x06, 0x06, 0x01, 0, 0x01, 0, 0x01, 0, 0xee, 0x63, 0x01, 0x4b,
+];
+
+/// Block content types.
+const FILE_HEADER: u8 = 0;
+const COMPRESSION_HEADER: u8 = 1;
+const SLICE_HEADER: u8 = 2;
+const EXTERNAL_DATA: u8 = 4;
+const CORE_DATA: u8 = 5;
+
+/// Block compression methods.
+const RAW: u8 = 0;
+const GZIP: u8 = 1;
+
+/// Codec IDs of data series encodings.
+const EXTERNAL: i32 = 1;
+const BYTE_ARRAY_LEN: i32 = 4;
+const BYTE_ARRAY_STOP: i32 = 5;
+
+fn write_itf8(buf: &mut Vec<u8>, val: i32) {
+    let val = val as u32;
+    match val {
+        0..=0x7f => buf.push(val as u8),
+        0x80..=0x3fff => buf.extend_from_slice(&[0x80 | (val >> 8) as u8, val as u8]),
+        0x4000..=0x1f_ffff => buf.extend_from_slice(&[0xc0 | (val >> 16) as u8, (val >> 8) as u8, val as u8]),
+        0x20_0000..=0xfff_ffff => buf.extend_from_slice(&(0xe000_0000 | val).to_be_bytes()),
+        _ => buf.extend_from_slice(&[
+            0xf0 | (val >> 28) as u8,
+            (val >> 20) as u8,
+            (val >> 12) as u8,
+            (val >> 4) as u8,
+            (val & 0xf) as u8,
+        ]),
+    }
+}
+
+fn write_ltf8(buf: &mut Vec<u8>, val: u64) {
+    // Bytes after the first one, which starts with as many ones.
+    let extra = (0..8).find(|&n| val < 1 << (7 * (n + 1))).unwrap_or(8);
+    match extra {
+        0 => buf.push(val as u8),
+        8 => {
+            buf.push(0xff);
+            buf.extend_from_slice(&val.to_be_bytes());
+        }
+        _ => {
+            buf.push(!(0xffu8 >> extra) | (val >> (8 * extra)) as u8);
+            buf.extend_from_slice(&val.to_be_bytes()[8 - extra..]);
+        }
+    }
+}
+
+/// Serialized block with CRC32, compressed with gzip if it's `EXTERNAL_DATA`
+/// and not empty.
+fn block(content_type: u8, content_id: i32, data: &[u8]) -> io::Result<Vec<u8>> {
+    let (method, compressed) = if content_type == EXTERNAL_DATA && !data.is_empty() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        (GZIP, encoder.finish()?)
+    } else {
+        (RAW, data.to_vec())
+    };
+    let mut block = vec![method, content_type];
+    write_itf8(&mut block, content_id);
+    write_itf8(&mut block, compressed.len() as i32);
+    write_itf8(&mut block, data.len() as i32);
+    block.extend_from_slice(&compressed);
+    block.write_u32::<LittleEndian>(crc32fast::hash(&block))?;
+    Ok(block)
+}
+
+/// Writes container of `blocks` with landmarks at offsets of `landmarks`
+/// blocks.
+fn write_container<W: Write>(out: &mut W, header: &SliceHeader, bases: u64, blocks: &[Vec<u8>], landmarks: &[usize]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    buf.write_i32::<LittleEndian>(blocks.iter().map(Vec::len).sum::<usize>() as i32)?;
+    write_itf8(&mut buf, header.ref_id);
+    write_itf8(&mut buf, header.start);
+    write_itf8(&mut buf, header.span);
+    write_itf8(&mut buf, header.records as i32);
+    write_ltf8(&mut buf, header.record_counter);
+    write_ltf8(&mut buf, bases);
+    write_itf8(&mut buf, blocks.len() as i32);
+    write_itf8(&mut buf, landmarks.len() as i32);
+    for &landmark in landmarks {
+        write_itf8(&mut buf, blocks[..landmark].iter().map(Vec::len).sum::<usize>() as i32);
+    }
+    buf.write_u32::<LittleEndian>(crc32fast::hash(&buf))?;
+    out.write_all(&buf)?;
+    blocks.iter().try_for_each(|block| out.write_all(block))
+}
+
+/// Map of compression header: size in bytes, number of entries, entries.
+fn map(entries: usize, content: &[u8]) -> Vec<u8> {
+    let mut count = Vec::new();
+    write_itf8(&mut count, entries as i32);
+    let mut map = Vec::new();
+    write_itf8(&mut map, (count.len() + content.len()) as i32);
+    map.extend_from_slice(&count);
+    map.extend_from_slice(content);
+    map
+}
+
+fn encoding(codec: i32, params: &[u8]) -> Vec<u8> {
+    let mut encoding = Vec::new();
+    write_itf8(&mut encoding, codec);
+    write_itf8(&mut encoding, params.len() as i32);
+    encoding.extend_from_slice(params);
+    encoding
+}
+
+fn external(content_id: i32) -> Vec<u8> {
+    let mut params = Vec::new();
+    write_itf8(&mut params, content_id);
+    encoding(EXTERNAL, &params)
+}
+
+/// Position and span of slice (and of its container).
+struct SliceHeader {
+    ref_id: i32,
+    start: i32,
+    span: i32,
+    records: usize,
+    record_counter: u64,
+}
+
+/// Read feature of mapped record, with 1-based position in read.
+enum Feature<'a> {
+    Substitution(usize, u8),
+    Base(usize, u8, u8),
+    Insertion(usize, &'a [u8]),
+    SoftClip(usize, &'a [u8]),
+    Deletion(usize, u32),
+    RefSkip(usize, u32),
+    HardClip(usize, u32),
+    Padding(usize, u32),
+}
+
+/// Data series of records of slice being built.
+#[derive(Default)]
+struct Slice {
+    series: [Vec<u8>; DATA_SERIES.len()],
+    /// Values of tags by tag key (name and type), each in its own block.
+    tags: BTreeMap<i32, Vec<u8>>,
+    /// Tag lines (names and types of tags of record) of TD dictionary.
+    tag_lines: Vec<Vec<u8>>,
+    tag_line_ids: HashMap<Vec<u8>, i32>,
+    records: usize,
+    bases: u64,
+    /// Reference of all records, -2 if they have several.
+    ref_id: Option<i32>,
+    /// 1-based span of placed records.
+    start: i32,
+    end: i32,
+}
+
+impl Slice {
+    fn int(&mut self, series: usize, val: i32) {
+        write_itf8(&mut self.series[series], val);
+    }
+
+    fn byte(&mut self, series: usize, val: u8) {
+        self.series[series].push(val);
+    }
+
+    fn bytes(&mut self, series: usize, val: &[u8]) {
+        self.series[series].extend_from_slice(val);
+        self.series[series].push(0);
+    }
+
+    /// Encodes record against `ref_bases` of its reference, empty if it's
+    /// unmapped.
+    fn push(&mut self, rec: &GbamRecord, ref_bases: &[u8], read_groups: &HashMap<Vec<u8>, i32>) -> io::Result<()> {
+        let missing = || invalid_sam("Record lacks fields needed for CRAM.".to_owned());
+        let flag = rec.flag.ok_or_else(missing)?;
+        let ref_id = rec.refid.ok_or_else(missing)?;
+        let pos = rec.pos.ok_or_else(missing)?;
+        let cigar = rec.cigar.as_ref().ok_or_else(missing)?;
+        let seq = rec.seq.as_deref().ok_or_else(missing)?.as_bytes();
+        let qual = rec.qual.as_deref().ok_or_else(missing)?;
+        let name = rec.name().ok_or_else(missing)?;
+        let mapped = flag & 0x4 == 0;
+        if mapped && ref_id < 0 {
+            return Err(invalid_sam("Mapped record has no reference.".to_owned()));
+        }
+        let read_len = if seq.is_empty() && mapped { cigar.read_length() as usize } else { seq.len() };
+        let mut cf = CF_DETACHED;
+        if seq.is_empty() {
+            cf |= CF_NO_SEQ;
+        } else {
+            cf |= CF_QUAL_ARRAY;
+        }
+
+        self.int(BF, i32::from(flag));
+        self.int(CF, cf);
+        self.int(RI, ref_id);
+        self.int(RL, read_len as i32);
+        self.int(AP, pos + 1);
+        let (tags, rg) = self.push_tag_values(rec.tags.as_deref().ok_or_else(missing)?, read_groups)?;
+        self.int(RG, rg);
+        self.bytes(RN, name);
+        self.int(MF, i32::from(flag & 0x20 != 0) | i32::from(flag & 0x8 != 0) << 1);
+        self.int(NS, rec.next_ref_id.ok_or_else(missing)?);
+        self.int(NP, rec.next_pos.ok_or_else(missing)? + 1);
+        self.int(TS, rec.tlen.ok_or_else(missing)?);
+        let line_id = match self.tag_line_ids.get(&tags) {
+            Some(&id) => id,
+            None => {
+                let id = self.tag_lines.len() as i32;
+                self.tag_lines.push(tags.clone());
+                self.tag_line_ids.insert(tags, id);
+                id
+            }
+        };
+        self.int(TL, line_id);
+
+        if mapped {
+            let unknown;
+            let bases = if seq.is_empty() {
+                unknown = vec![b'N'; read_len];
+                &unknown[..]
+            } else {
+                seq
+            };
+            let features = features(cigar.ops().map(|op| (op.op_type(), op.length())), bases, qual, ref_bases, pos as usize)?;
+            self.int(FN, features.len() as i32);
+            let mut last_pos = 0;
+            for feature in &features {
+                let (code, pos) = match *feature {
+                    Feature::Substitution(pos, _) => (b'X', pos),
+                    Feature::Base(pos, _, _) => (b'B', pos),
+                    Feature::Insertion(pos, _) => (b'I', pos),
+                    Feature::SoftClip(pos, _) => (b'S', pos),
+                    Feature::Deletion(pos, _) => (b'D', pos),
+                    Feature::RefSkip(pos, _) => (b'N', pos),
+                    Feature::HardClip(pos, _) => (b'H', pos),
+                    Feature::Padding(pos, _) => (b'P', pos),
+                };
+                self.byte(FC, code);
+                self.int(FP, (pos - last_pos) as i32);
+                last_pos = pos;
+                match *feature {
+                    Feature::Substitution(_, code) => self.byte(BS, code),
+                    Feature::Base(_, base, qual) => {
+                        self.byte(BA, base);
+                        self.byte(QS, qual);
+                    }
+                    Feature::Insertion(_, bases) => self.bytes(IN, bases),
+                    Feature::SoftClip(_, bases) => self.bytes(SC, bases),
+                    Feature::Deletion(_, len) => self.int(DL, len as i32),
+                    Feature::RefSkip(_, len) => self.int(RS, len as i32),
+                    Feature::HardClip(_, len) => self.int(HC, len as i32),
+                    Feature::Padding(_, len) => self.int(PD, len as i32),
+                }
+            }
+            self.int(MQ, i32::from(rec.mapq.ok_or_else(missing)?));
+        } else {
+            self.series[BA].extend_from_slice(seq);
+        }
+        if cf & CF_QUAL_ARRAY != 0 {
+            if qual.len() != read_len {
+                return Err(invalid_sam("Quality and sequence lengths differ.".to_owned()));
+            }
+            self.series[QS].extend_from_slice(qual);
+        }
+
+        self.ref_id = match self.ref_id {
+            Some(id) if id != ref_id => Some(-2),
+            _ => Some(ref_id),
+        };
+        if ref_id >= 0 {
+            let span = if mapped { base_coverage(&cigar.0).max(1) as i32 } else { 1 };
+            if self.records == 0 || pos + 1 < self.start {
+                self.start = pos + 1;
+            }
+            self.end = self.end.max(pos + span);
+        }
+        self.records += 1;
+        self.bases += read_len as u64;
+        Ok(())
+    }
+
+    /// Appends values of BAM tags to their blocks, except RG of read group
+    /// of the header, which is stored as RG series. Returns tag line and
+    /// read group index.
+    fn push_tag_values(&mut self, mut tags: &[u8], read_groups: &HashMap<Vec<u8>, i32>) -> io::Result<(Vec<u8>, i32)> {
+        let mut line = Vec::new();
+        let mut rg = -1;
+        while !tags.is_empty() {
+            if tags.len() < 4 || (tags[2] == b'B' && tags.len() < 8) {
+                return Err(invalid_sam("Tags are cut short.".to_owned()));
+            }
+            let size = tag_value_size(tags[2], &tags[3..]);
+            let value = tags
+                .get(3..3 + size)
+                .ok_or_else(|| invalid_sam("Tags are cut short.".to_owned()))?;
+            let group = match &tags[..3] {
+                b"RGZ" => read_groups.get(value.strip_suffix(b"\0").unwrap_or(value)),
+                _ => None,
+            };
+            if let (Some(&group), -1) = (group, rg) {
+                rg = group;
+            } else {
+                let key = i32::from(tags[0]) << 16 | i32::from(tags[1]) << 8 | i32::from(tags[2]);
+                let block = self.tags.entry(key).or_default();
+                write_itf8(block, size as i32);
+                block.extend_from_slice(value);
+                line.extend_from_slice(&tags[..3]);
+            }
+            tags = &tags[3 + size..];
+        }
+        Ok((line, rg))
+    }
+}
+
+/// Read features of mapped record from its CIGAR operations, `pos` is
+/// 0-based. Bases out of reference are compared as N.
+fn features<'a, I>(ops: I, bases: &'a [u8], qual: &[u8], ref_bases: &[u8], pos: usize) -> io::Result<Vec<Feature<'a>>>
+where
+    I: Iterator<Item = (char, u32)>,
+{
+    let mut features = Vec::new();
+    let (mut read_pos, mut ref_pos) = (0, pos);
+    let short = || invalid_sam("Sequence is shorter than CIGAR.".to_owned());
+    for (op, len) in ops {
+        let size = len as usize;
+        match op {
+            'M' | '=' | 'X' => {
+                let read = bases.get(read_pos..read_pos + size).ok_or_else(short)?;
+                for (i, &base) in read.iter().enumerate() {
+                    let ref_base = ref_bases.get(ref_pos + i).copied().unwrap_or(b'N');
+                    if base == ref_base {
+                        continue;
+                    }
+                    let at = read_pos + i + 1;
+                    match (BASES.iter().position(|&b| b == ref_base), BASES.iter().position(|&b| b == base)) {
+                        (Some(ref_idx), Some(idx)) => features.push(Feature::Substitution(at, (idx - usize::from(idx > ref_idx)) as u8)),
+                        _ => features.push(Feature::Base(at, base, qual.get(at - 1).copied().unwrap_or(0xff))),
+                    }
+                }
+                read_pos += size;
+                ref_pos += size;
+            }
+            'I' | 'S' => {
+                let read = bases.get(read_pos..read_pos + size).ok_or_else(short)?;
+                features.push(if op == 'I' { Feature::Insertion(read_pos + 1, read) } else { Feature::SoftClip(read_pos + 1, read) });
+                read_pos += size;
+            }
+            'D' => {
+                features.push(Feature::Deletion(read_pos + 1, len));
+                ref_pos += size;
+            }
+            'N' => {
+                features.push(Feature::RefSkip(read_pos + 1, len));
+                ref_pos += size;
+            }
+            'H' => features.push(Feature::HardClip(read_pos + 1, len)),
+            _ => features.push(Feature::Padding(read_pos + 1, len)),
+        }
+    }
+    if read_pos != bases.len() {
+        return Err(invalid_sam("Sequence and CIGAR lengths differ.".to_owned()));
+    }
+    Ok(features)
+}
+
+/// Writes CRAM 3.0 against reference FASTA. Every data series is stored in
+/// its own gzip compressed external block, mates are stored with every
+/// record (detached), read names and quality scores are preserved. As with
+/// other CRAM writers, CIGAR `=` and `X` become `M`, mapping quality and
+/// CIGAR of unmapped records are dropped, and RG tag naming a read group of
+/// the header is stored as RG series, so readers put it after other tags.
+/// `finish` must be called to write the last container and EOF.
+pub struct CramWriter<W: Write> {
+    inner: W,
+    fasta: IndexedFasta,
+    ref_names: Vec<String>,
+    read_groups: HashMap<Vec<u8>, i32>,
+    /// Reference sequence last read from FASTA, by reference ID.
+    reference: Option<(i32, Vec<u8>)>,
+    slice: Slice,
+    record_counter: u64,
+}
+
+impl<W: Write> CramWriter<W> {
+    pub fn new(inner: W, fasta: IndexedFasta, ref_seqs: &[(String, u32)]) -> Self {
+        Self {
+            inner,
+            fasta,
+            ref_names: ref_seqs.iter().map(|(name, _)| name.clone()).collect(),
+            read_groups: HashMap::new(),
+            reference: None,
+            slice: Slice::default(),
+            record_counter: 0,
+        }
+    }
+
+    /// Writes file definition and header container with text of header laid
+    /// out as in BAM file (see `FileMeta::get_sam_header`). @SQ lines
+    /// without M5 get it from FASTA, and are made from `ref_seqs` if text
+    /// has none, as CRAM readers look references up by them.
+    pub fn write_header(&mut self, sam_header: &[u8], ref_seqs: &[(String, u32)]) -> io::Result<()> {
+        let text = if sam_header.len() < 4 { Default::default() } else { header_text(sam_header) };
+        let mut header = String::new();
+        let mut has_sq = false;
+        for line in text.lines() {
+            header.push_str(line);
+            if let Some(fields) = line.strip_prefix("@SQ\t") {
+                has_sq = true;
+                let name = fields.split('\t').find_map(|field| field.strip_prefix("SN:"));
+                if let (Some(name), false) = (name, fields.split('\t').any(|field| field.starts_with("M5:"))) {
+                    header.push_str(&format!("\tM5:{:x}", md5::compute(self.fasta.read_sequence(name)?)));
+                }
+            } else if let Some(fields) = line.strip_prefix("@RG\t") {
+                if let Some(id) = fields.split('\t').find_map(|field| field.strip_prefix("ID:")) {
+                    let index = self.read_groups.len() as i32;
+                    self.read_groups.entry(id.as_bytes().to_vec()).or_insert(index);
+                }
+            }
+            header.push('\n');
+        }
+        if !has_sq {
+            for (name, len) in ref_seqs {
+                let md5 = md5::compute(self.fasta.read_sequence(name)?);
+                header.push_str(&format!("@SQ\tSN:{}\tLN:{}\tM5:{:x}\n", name, len, md5));
+            }
+        }
+
+        self.inner.write_all(b"CRAM\x03\x00")?;
+        self.inner.write_all(&[0; 20])?;
+        let mut data = Vec::with_capacity(4 + header.len());
+        data.write_i32::<LittleEndian>(header.len() as i32)?;
+        data.extend_from_slice(header.as_bytes());
+        let header_block = block(FILE_HEADER, 0, &data)?;
+        let header = SliceHeader { ref_id: 0, start: 0, span: 0, records: 0, record_counter: 0 };
+        write_container(&mut self.inner, &header, 0, &[header_block], &[])
+    }
+
+    /// Loads reference sequence `ref_id` unless it's the last one loaded.
+    fn load_reference(&mut self, ref_id: i32) -> io::Result<()> {
+        if self.reference.as_ref().is_none_or(|(id, _)| *id != ref_id) {
+            let name = self
+                .ref_names
+                .get(ref_id as usize)
+                .ok_or_else(|| invalid_sam(format!("Reference ID {} is out of header.", ref_id)))?;
+            self.reference = Some((ref_id, self.fasta.read_sequence(name)?));
+        }
+        Ok(())
+    }
+
+    pub fn write_record(&mut self, rec: &GbamRecord) -> io::Result<()> {
+        let ref_id = rec.refid.unwrap_or(-1);
+        let ref_changed = self.slice.ref_id.is_some_and(|id| id != ref_id);
+        if self.slice.records == SLICE_RECORDS || (ref_changed && self.slice.records >= MIN_SINGLE_REF_RECORDS) {
+            self.write_slice()?;
+        }
+        let mapped = rec.flag.is_some_and(|flag| flag & 0x4 == 0);
+        if mapped && ref_id >= 0 {
+            self.load_reference(ref_id)?;
+        }
+        let ref_bases = match &self.reference {
+            Some((id, bases)) if mapped && *id == ref_id => &bases[..],
+            _ => &[],
+        };
+        self.slice.push(rec, ref_bases, &self.read_groups)
+    }
+
+    /// Writes container of records pushed so far.
+    fn write_slice(&mut self) -> io::Result<()> {
+        let mut slice = mem::take(&mut self.slice);
+        if slice.records == 0 {
+            return Ok(());
+        }
+        let ref_id = slice.ref_id.unwrap();
+        let mut header = SliceHeader { ref_id, start: 0, span: 0, records: slice.records, record_counter: self.record_counter };
+        let mut md5 = [0; 16];
+        if ref_id >= 0 {
+            self.load_reference(ref_id)?;
+            let bases = &self.reference.as_ref().unwrap().1;
+            let end = (slice.end as usize).min(bases.len());
+            header.start = slice.start;
+            header.span = (end + 1).saturating_sub(slice.start as usize) as i32;
+            md5 = md5::compute(bases.get(slice.start as usize - 1..end).unwrap_or_default()).0;
+        }
+        if ref_id != -2 {
+            slice.series[RI].clear();
+        }
+
+        let mut data_blocks = vec![block(CORE_DATA, 0, &[])?];
+        let mut content_ids = vec![0];
+        for (i, data) in slice.series.iter().enumerate().filter(|(_, data)| !data.is_empty()) {
+            data_blocks.push(block(EXTERNAL_DATA, i as i32 + 1, data)?);
+            content_ids.push(i as i32 + 1);
+        }
+        for (&key, data) in &slice.tags {
+            data_blocks.push(block(EXTERNAL_DATA, key, data)?);
+            content_ids.push(key);
+        }
+
+        let mut slice_header = Vec::new();
+        write_itf8(&mut slice_header, header.ref_id);
+        write_itf8(&mut slice_header, header.start);
+        write_itf8(&mut slice_header, header.span);
+        write_itf8(&mut slice_header, header.records as i32);
+        write_ltf8(&mut slice_header, header.record_counter);
+        write_itf8(&mut slice_header, data_blocks.len() as i32);
+        write_itf8(&mut slice_header, content_ids.len() as i32);
+        content_ids.iter().for_each(|&id| write_itf8(&mut slice_header, id));
+        // No embedded reference.
+        write_itf8(&mut slice_header, -1);
+        slice_header.extend_from_slice(&md5);
+
+        let mut blocks = vec![
+            block(COMPRESSION_HEADER, 0, &compression_header(&slice))?,
+            block(SLICE_HEADER, 0, &slice_header)?,
+        ];
+        blocks.append(&mut data_blocks);
+        write_container(&mut self.inner, &header, slice.bases, &blocks, &[1])?;
+        self.record_counter += slice.records as u64;
+        Ok(())
+    }
+
+    /// Writes remaining records and EOF container, returns inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_slice()?;
+        self.inner.write_all(&CRAM_EOF)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Compression header of container of `slice`: preservation map (read
+/// names kept, absolute positions, reference required), data series and
+/// tag encodings.
+fn compression_header(slice: &Slice) -> Vec<u8> {
+    let mut preservation = Vec::new();
+    preservation.extend_from_slice(b"RN\x01AP\x00RR\x01SM");
+    preservation.extend_from_slice(&SUBSTITUTION_MATRIX);
+    let dictionary: Vec<u8> = slice.tag_lines.iter().flat_map(|line| line.iter().copied().chain([0])).collect();
+    preservation.extend_from_slice(b"TD");
+    write_itf8(&mut preservation, dictionary.len() as i32);
+    preservation.extend_from_slice(&dictionary);
+
+    let mut series = Vec::new();
+    for (i, key) in DATA_SERIES.iter().enumerate() {
+        series.extend_from_slice(&key[..]);
+        let content_id = i as i32 + 1;
+        if STOP_SERIES.contains(&i) {
+            let mut params = vec![0];
+            write_itf8(&mut params, content_id);
+            series.extend_from_slice(&encoding(BYTE_ARRAY_STOP, &params));
+        } else {
+            series.extend_from_slice(&external(content_id));
+        }
+    }
+
+    let mut tags = Vec::new();
+    for &key in slice.tags.keys() {
+        write_itf8(&mut tags, key);
+        let params = [external(key), external(key)].concat();
+        tags.extend_from_slice(&encoding(BYTE_ARRAY_LEN, &params));
+    }
+
+    let mut header = map(5, &preservation);
+    header.extend_from_slice(&map(DATA_SERIES.len(), &series));
+    header.extend_from_slice(&map(slice.tags.len(), &tags));
+    header
+}
+
+/// Writes CRAM of records overlapping any of `regions` in turn, or of all
+/// records if there are no regions, against reference `fasta` (indexed with
+/// `.fai`). Invalid region or reference not matching the header fails
+/// before anything is written.
+pub fn gbam_to_cram<W: Write>(reader: &mut Reader, regions: &[String], fasta: &Path, out: W) -> io::Result<W> {
+    for region in regions {
+        reader.fetch(region)?;
+    }
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    check_references(&ref_seqs, &read_fai(fasta)?).map_err(|err| Error::new(ErrorKind::InvalidInput, err.to_string()))?;
+    let mut writer = CramWriter::new(out, IndexedFasta::open(fasta)?, &ref_seqs);
+    writer.write_header(reader.file_meta.get_sam_header(), &ref_seqs)?;
+    for_each_record(reader, regions, None, |rec| writer.write_record(rec))?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::bam::sam_writer::SamWriter;
+    use crate::meta::Codecs;
+    use crate::query::cigar::{Cigar, Op};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use byteorder::ReadBytesExt;
+    use flate2::read::GzDecoder;
+    use std::borrow::Cow;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use tempdir::TempDir;
+
+    fn itf8(bytes: &mut &[u8]) -> i32 {
+        let first = u32::from(bytes[0]);
+        let (len, mut val) = match first {
+            0..=0x7f => (1, first),
+            0x80..=0xbf => (2, first & 0x3f),
+            0xc0..=0xdf => (3, first & 0x1f),
+            _ => (if first < 0xf0 { 4 } else { 5 }, first & 0xf),
+        };
+        for i in 1..len {
+            val = if i == 4 { val << 4 | u32::from(bytes[i] & 0xf) } else { val << 8 | u32::from(bytes[i]) };
+        }
+        *bytes = &bytes[len..];
+        val as i32
+    }
+
+    fn ltf8(bytes: &mut &[u8]) -> u64 {
+        let extra = bytes[0].leading_ones() as usize;
+        let mut val = u64::from(bytes[0] & (0xffu16 >> (extra + 1).min(8)) as u8);
+        for i in 1..=extra {
+            val = val << 8 | u64::from(bytes[i]);
+        }
+        *bytes = &bytes[extra + 1..];
+        val
+    }
+
+    fn take<'a>(bytes: &mut &'a [u8], len: usize) -> &'a [u8] {
+        let (taken, rest) = bytes.split_at(len);
+        *bytes = rest;
+        taken
+    }
+
+    /// Reads block, checking its CRC32. Returns content type, content ID
+    /// and uncompressed data.
+    fn read_block(bytes: &mut &[u8]) -> Block {
+        let begin = *bytes;
+        let (method, content_type) = (bytes.read_u8().unwrap(), bytes.read_u8().unwrap());
+        let content_id = itf8(bytes);
+        let (size, raw_size) = (itf8(bytes) as usize, itf8(bytes) as usize);
+        let compressed = take(bytes, size);
+        let crc = crc32fast::hash(&begin[..begin.len() - bytes.len()]);
+        assert_eq!(bytes.read_u32::<LittleEndian>().unwrap(), crc);
+        let data = match method {
+            RAW => compressed.to_vec(),
+            GZIP => {
+                let mut data = Vec::new();
+                GzDecoder::new(compressed).read_to_end(&mut data).unwrap();
+                data
+            }
+            _ => panic!("Unexpected method {}", method),
+        };
+        assert_eq!(data.len(), raw_size);
+        (content_type, content_id, data)
+    }
+
+    /// Content type, content ID and data of block.
+    type Block = (u8, i32, Vec<u8>);
+
+    /// Reads container, checking its CRC32. Returns reference ID, start,
+    /// span, number of records and blocks.
+    fn read_container(bytes: &mut &[u8]) -> ((i32, i32, i32, i32), Vec<Block>) {
+        let begin = *bytes;
+        let len = bytes.read_i32::<LittleEndian>().unwrap() as usize;
+        let position = (itf8(bytes), itf8(bytes), itf8(bytes), itf8(bytes));
+        let (_counter, _bases) = (ltf8(bytes), ltf8(bytes));
+        let num_blocks = itf8(bytes);
+        let landmarks: Vec<_> = (0..itf8(bytes)).map(|_| itf8(bytes)).collect();
+        let crc = crc32fast::hash(&begin[..begin.len() - bytes.len()]);
+        assert_eq!(bytes.read_u32::<LittleEndian>().unwrap(), crc);
+        let mut data = take(bytes, len);
+        let mut blocks = Vec::new();
+        let mut offsets = Vec::new();
+        for _ in 0..num_blocks {
+            offsets.push((len - data.len()) as i32);
+            blocks.push(read_block(&mut data));
+        }
+        assert!(data.is_empty());
+        assert!(landmarks.iter().all(|landmark| blocks[offsets.iter().position(|o| o == landmark).unwrap()].0 == SLICE_HEADER));
+        (position, blocks)
+    }
+
+    /// Content ID of data series encoded as EXTERNAL or BYTE_ARRAY_STOP.
+    fn content_id(bytes: &mut &[u8]) -> i32 {
+        let codec = itf8(bytes);
+        let len = itf8(bytes) as usize;
+        let mut params = take(bytes, len);
+        if codec == BYTE_ARRAY_STOP {
+            assert_eq!(params.read_u8().unwrap(), 0);
+        } else {
+            assert_eq!(codec, EXTERNAL);
+        }
+        itf8(&mut params)
+    }
+
+    struct Blocks(HashMap<i32, Vec<u8>>);
+
+    impl Blocks {
+        fn data(&mut self, id: i32) -> &mut Vec<u8> {
+            self.0.get_mut(&id).unwrap_or_else(|| panic!("Block {} is missing", id))
+        }
+        fn int(&mut self, id: i32) -> i32 {
+            let data = self.data(id);
+            let mut rest = &data[..];
+            let val = itf8(&mut rest);
+            let read = data.len() - rest.len();
+            data.drain(..read);
+            val
+        }
+        fn bytes(&mut self, id: i32, len: usize) -> Vec<u8> {
+            self.data(id).drain(..len).collect()
+        }
+        fn stop(&mut self, id: i32) -> Vec<u8> {
+            let end = self.data(id).iter().position(|&c| c == 0).unwrap();
+            let bytes = self.bytes(id, end + 1);
+            bytes[..end].to_vec()
+        }
+    }
+
+    /// Decodes CRAM written by `CramWriter`: header text and records.
+    fn decode(cram: &[u8], references: &[Vec<u8>], read_groups: &[&str]) -> (String, Vec<GbamRecord>) {
+        assert_eq!(&cram[..6], b"CRAM\x03\x00");
+        let mut bytes = &cram[26..];
+        let (_, blocks) = read_container(&mut bytes);
+        assert_eq!(blocks[0].0, FILE_HEADER);
+        let mut data = &blocks[0].2[..];
+        let len = data.read_i32::<LittleEndian>().unwrap() as usize;
+        let text = String::from_utf8(data[..len].to_vec()).unwrap();
+
+        let mut records = Vec::new();
+        while bytes != CRAM_EOF {
+            let ((ref_id, start, span, num_records), blocks) = read_container(&mut bytes);
+            let mut header = &blocks[0].2[..];
+            assert_eq!((blocks[0].0, blocks[1].0, blocks[2].0), (COMPRESSION_HEADER, SLICE_HEADER, CORE_DATA));
+
+            let (_, entries) = (itf8(&mut header), itf8(&mut header));
+            let mut substitutions = [[0; 4]; 5];
+            let mut tag_lines = Vec::new();
+            for _ in 0..entries {
+                match take(&mut header, 2) {
+                    b"RN" | b"RR" => assert_eq!(header.read_u8().unwrap(), 1),
+                    b"AP" => assert_eq!(header.read_u8().unwrap(), 0),
+                    b"SM" => {
+                        for (ref_idx, &codes) in take(&mut header, 5).iter().enumerate() {
+                            let others = BASES.iter().enumerate().filter(|&(idx, _)| idx != ref_idx);
+                            for (j, (_, &base)) in others.enumerate() {
+                                substitutions[ref_idx][(codes >> (6 - 2 * j) & 3) as usize] = base;
+                            }
+                        }
+                    }
+                    b"TD" => {
+                        let len = itf8(&mut header) as usize;
+                        let dictionary = take(&mut header, len);
+                        tag_lines = dictionary[..len - 1].split(|&c| c == 0).map(<[u8]>::to_vec).collect();
+                    }
+                    key => panic!("Unexpected key {:?}", key),
+                }
+            }
+            let (_, entries) = (itf8(&mut header), itf8(&mut header));
+            let mut ids = HashMap::new();
+            for _ in 0..entries {
+                let key = take(&mut header, 2).to_vec();
+                ids.insert(key, content_id(&mut header));
+            }
+            let id = |key: &[u8]| ids[key];
+            let (_, entries) = (itf8(&mut header), itf8(&mut header));
+            let mut tag_ids = HashMap::new();
+            for _ in 0..entries {
+                let key = itf8(&mut header);
+                assert_eq!(itf8(&mut header), BYTE_ARRAY_LEN);
+                let len = itf8(&mut header) as usize;
+                let mut params = take(&mut header, len);
+                tag_ids.insert(key, (content_id(&mut params), content_id(&mut params)));
+            }
+            assert!(header.is_empty());
+
+            let mut slice_header = &blocks[1].2[..];
+            let position = (itf8(&mut slice_header), itf8(&mut slice_header), itf8(&mut slice_header), itf8(&mut slice_header));
+            assert_eq!(position, (ref_id, start, span, num_records));
+            ltf8(&mut slice_header);
+            assert_eq!(itf8(&mut slice_header) as usize, blocks.len() - 2);
+            let content_ids: Vec<_> = (0..itf8(&mut slice_header)).map(|_| itf8(&mut slice_header)).collect();
+            assert_eq!(content_ids, blocks[2..].iter().map(|block| block.1).collect::<Vec<_>>());
+            assert_eq!(itf8(&mut slice_header), -1);
+            if ref_id >= 0 {
+                let bases = &references[ref_id as usize][start as usize - 1..(start + span - 1) as usize];
+                assert_eq!(slice_header, &md5::compute(bases).0[..]);
+            }
+
+            let mut data = Blocks(blocks[3..].iter().map(|(_, id, data)| (*id, data.clone())).collect());
+            for _ in 0..num_records {
+                let flag = data.int(id(b"BF"));
+                let cf = data.int(id(b"CF"));
+                assert!(cf & CF_DETACHED != 0);
+                let ref_id = if ref_id == -2 { data.int(id(b"RI")) } else { ref_id };
+                let read_len = data.int(id(b"RL")) as usize;
+                let pos = data.int(id(b"AP")) - 1;
+                let rg = data.int(id(b"RG"));
+                let name = data.stop(id(b"RN"));
+                data.int(id(b"MF"));
+                let (next_ref_id, next_pos, tlen) = (data.int(id(b"NS")), data.int(id(b"NP")) - 1, data.int(id(b"TS")));
+                let mut tags = Vec::new();
+                for tag in tag_lines[data.int(id(b"TL")) as usize].chunks(3) {
+                    let key = i32::from(tag[0]) << 16 | i32::from(tag[1]) << 8 | i32::from(tag[2]);
+                    let (len_id, value_id) = tag_ids[&key];
+                    let len = data.int(len_id) as usize;
+                    tags.extend_from_slice(tag);
+                    tags.extend(data.bytes(value_id, len));
+                }
+                if rg >= 0 {
+                    tags.extend_from_slice(format!("RGZ{}\0", read_groups[rg as usize]).as_bytes());
+                }
+
+                let mut seq = Vec::new();
+                let mut ops: Vec<Op> = Vec::new();
+                let mut mapq = 0;
+                if flag & 0x4 == 0 {
+                    let reference = &references[ref_id as usize];
+                    let mut ref_pos = pos as usize;
+                    let mut push_op = |code: u32, len: u32| match ops.last_mut() {
+                        Some(op) if op.0 & 0xf == code => op.0 += len << 4,
+                        _ => ops.push(Op::new(len << 4 | code)),
+                    };
+                    let mut read_pos = 0;
+                    for _ in 0..data.int(id(b"FN")) {
+                        let code = data.bytes(id(b"FC"), 1)[0];
+                        read_pos += data.int(id(b"FP")) as usize;
+                        while seq.len() + 1 < read_pos {
+                            seq.push(*reference.get(ref_pos).unwrap_or(&b'N'));
+                            ref_pos += 1;
+                            push_op(0, 1);
+                        }
+                        match code {
+                            b'X' | b'B' => {
+                                let base = if code == b'X' {
+                                    let ref_base = *reference.get(ref_pos).unwrap_or(&b'N');
+                                    let ref_idx = BASES.iter().position(|&b| b == ref_base).unwrap();
+                                    substitutions[ref_idx][data.bytes(id(b"BS"), 1)[0] as usize]
+                                } else {
+                                    data.bytes(id(b"QS"), 1);
+                                    data.bytes(id(b"BA"), 1)[0]
+                                };
+                                seq.push(base);
+                                ref_pos += 1;
+                                push_op(0, 1);
+                            }
+                            b'I' | b'S' => {
+                                let bases = data.stop(id(if code == b'I' { b"IN" } else { b"SC" }));
+                                push_op(if code == b'I' { 1 } else { 4 }, bases.len() as u32);
+                                seq.extend(bases);
+                            }
+                            b'D' | b'N' => {
+                                let len = data.int(id(if code == b'D' { b"DL" } else { b"RS" }));
+                                ref_pos += len as usize;
+                                push_op(if code == b'D' { 2 } else { 3 }, len as u32);
+                            }
+                            b'H' => push_op(5, data.int(id(b"HC")) as u32),
+                            b'P' => push_op(6, data.int(id(b"PD")) as u32),
+                            _ => panic!("Unexpected feature {}", code as char),
+                        }
+                    }
+                    while seq.len() < read_len {
+                        seq.push(*reference.get(ref_pos).unwrap_or(&b'N'));
+                        ref_pos += 1;
+                        push_op(0, 1);
+                    }
+                    mapq = data.int(id(b"MQ")) as u8;
+                } else if cf & CF_NO_SEQ == 0 {
+                    seq = data.bytes(id(b"BA"), read_len);
+                }
+                let qual = if cf & CF_QUAL_ARRAY != 0 { data.bytes(id(b"QS"), read_len) } else { Vec::new() };
+                if cf & CF_NO_SEQ != 0 {
+                    seq.clear();
+                }
+                records.push(GbamRecord {
+                    refid: Some(ref_id),
+                    pos: Some(pos),
+                    mapq: Some(mapq),
+                    bin: None,
+                    flag: Some(flag as u16),
+                    next_ref_id: Some(next_ref_id),
+                    next_pos: Some(next_pos),
+                    tlen: Some(tlen),
+                    read_name: Some(name),
+                    cigar: Some(Cigar::new(ops)),
+                    seq: Some(String::from_utf8(seq).unwrap()),
+                    qual: Some(qual),
+                    tags: Some(tags),
+                });
+            }
+            assert!(data.0.values().all(Vec::is_empty));
+        }
+        (text, records)
+    }
+
+    /// Read of `reference` at `pos` with CIGAR of `ops`, with mismatches,
+    /// N and IUPAC bases.
+    fn read_seq(reference: &[u8], pos: usize, ops: &[(char, usize)], seed: usize) -> String {
+        let mut seq = String::new();
+        let mut ref_pos = pos;
+        for &(op, len) in ops {
+            for j in 0..len {
+                let k = seed + seq.len() + j;
+                match op {
+                    'M' => {
+                        let base = reference.get(ref_pos + j).map_or('N', |&base| base.to_ascii_uppercase() as char);
+                        seq.push(match k {
+                            _ if k.is_multiple_of(17) => if base == 'A' { 'C' } else { 'A' },
+                            _ if k.is_multiple_of(29) => 'N',
+                            _ if k.is_multiple_of(53) => 'R',
+                            _ => base,
+                        });
+                    }
+                    'I' | 'S' => seq.push(['A', 'C', 'G', 'T'][k % 4]),
+                    _ => {}
+                }
+            }
+            if matches!(op, 'M' | 'D' | 'N') {
+                ref_pos += len;
+            }
+        }
+        seq
+    }
+
+    #[test]
+    fn test_gbam_to_cram() {
+        let mut values = Vec::new();
+        for &val in &[0, 1, 127, 128, 0x3fff, 0x4000, 0x1f_ffff, 0x20_0000, 0xfff_ffff, 0x1000_0000, i32::MAX, -1, i32::MIN] {
+            write_itf8(&mut values, val);
+        }
+        for &val in &[0, 127, 128, 1 << 28, 1 << 35, 1 << 49, 1 << 56, u64::MAX] {
+            write_ltf8(&mut values, val);
+        }
+        let mut values = &values[..];
+        assert_eq!((0..13).map(|_| itf8(&mut values)).collect::<Vec<_>>()[10..], [i32::MAX, -1, i32::MIN]);
+        assert_eq!((0..8).map(|_| ltf8(&mut values)).collect::<Vec<_>>(), [0, 127, 128, 1 << 28, 1 << 35, 1 << 49, 1 << 56, u64::MAX]);
+        let mut eof = Vec::new();
+        let eof_header = SliceHeader { ref_id: -1, start: 0x454f46, span: 0, records: 0, record_counter: 0 };
+        write_container(&mut eof, &eof_header, 0, &[block(COMPRESSION_HEADER, 0, &[1, 0, 1, 0, 1, 0]).unwrap()], &[]).unwrap();
+        assert_eq!(eof, CRAM_EOF);
+
+        // Reference with soft masked bases, N and IUPAC codes, 60 bases a line.
+        let mut state = 7u32;
+        let mut references: Vec<Vec<u8>> = [3000, 1500]
+            .iter()
+            .map(|&len| {
+                (0..len)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                        b"ACGT"[(state >> 16) as usize % 4]
+                    })
+                    .collect()
+            })
+            .collect();
+        references[0][100..200].make_ascii_lowercase();
+        references[0][300] = b'N';
+        references[1][50] = b'y';
+        let dir = TempDir::new("gbam_to_cram").unwrap();
+        let fasta = dir.path().join("ref.fa");
+        let (mut fa, mut fai) = (Vec::new(), String::new());
+        for (name, bases) in ["chr1", "chr2"].iter().zip(&references) {
+            fa.extend_from_slice(format!(">{} description\n", name).as_bytes());
+            fai.push_str(&format!("{}\t{}\t{}\t60\t61\n", name, bases.len(), fa.len()));
+            for line in bases.chunks(60) {
+                fa.extend_from_slice(line);
+                fa.push(b'\n');
+            }
+        }
+        fs::write(&fasta, &fa).unwrap();
+        fs::write(dir.path().join("ref.fa.fai"), &fai).unwrap();
+        references.iter_mut().for_each(|bases| bases.make_ascii_uppercase());
+
+        let header = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:3000\n@SQ\tSN:chr2\tLN:1500\n@RG\tID:grp0\n@RG\tID:grp1\n";
+        let cigars: [&[(char, usize)]; 4] = [
+            &[('M', 20)],
+            &[('S', 2), ('M', 10), ('I', 1), ('M', 5), ('D', 2), ('M', 3), ('H', 3)],
+            &[('M', 5), ('N', 10), ('M', 10)],
+            &[('H', 1), ('M', 4), ('P', 1), ('I', 2), ('M', 8)],
+        ];
+        let mut lines = Vec::new();
+        // Sorted records of chr1 fill a slice, the rest share multi-reference slice.
+        for i in 0..10_600 {
+            let (ref_id, pos) = if i < 10_200 { (0, i / 5) } else { (i % 2, i % 200) };
+            let ops = cigars[i % 4];
+            let seq = read_seq(&references[ref_id], pos, ops, i);
+            let qual: String = if i % 50 == 0 { "*".to_owned() } else { (0..seq.len()).map(|j| (33 + (i + j) % 41) as u8 as char).collect() };
+            let cigar: String = ops.iter().map(|(op, len)| format!("{}{}", len, op)).collect();
+            let tags = ["NM:i:1\tRG:Z:grp1", "XA:Z:foo\tXB:B:c,1,-2", "RG:Z:unknown\tXf:f:1.5"][i % 3];
+            let (flag, mate) = if i % 2 == 0 { (99, "=\t500\t300") } else { (16, "*\t0\t0") };
+            lines.push(format!(
+                "r{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                i, flag, ["chr1", "chr2"][ref_id], pos + 1, i % 61, cigar, mate, seq, qual, tags
+            ));
+        }
+        lines.push("noseq\t0\tchr2\t10\t30\t5M\t*\t0\t0\t*\t*".to_owned());
+        lines.push("edge\t0\tchr2\t1495\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tRG:Z:grp0".to_owned());
+        lines.push("placed\t69\tchr2\t20\t0\t*\t=\t20\t0\tACGT\tIIII".to_owned());
+        lines.push("unmapped\t4\t*\t0\t0\t*\t*\t0\t0\tACGTN\t#####".to_owned());
+        let sam = format!("{}{}\n", header, lines.join("\n"));
+
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let path = dir.path().join("in.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Gzip], 2, Vec::new(), ref_seqs.clone(), sam_header, String::new(), false);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+        let cram = gbam_to_cram(&mut reader, &[], &fasta, Vec::new()).unwrap();
+        let (text, records) = decode(&cram, &references, &["grp0", "grp1"]);
+        let md5s: Vec<_> = references.iter().map(|bases| format!("{:x}", md5::compute(bases))).collect();
+        assert_eq!(text, header.replace("LN:3000", &format!("LN:3000\tM5:{}", md5s[0])).replace("LN:1500", &format!("LN:1500\tM5:{}", md5s[1])));
+        let mut sam_writer = SamWriter::new(Vec::new(), &ref_seqs);
+        for rec in &records {
+            sam_writer.write_record(rec).unwrap();
+        }
+        let decoded = String::from_utf8(sam_writer.into_inner()).unwrap();
+        for (decoded, line) in decoded.lines().zip(&lines) {
+            assert_eq!(decoded, line);
+        }
+        assert_eq!(decoded.lines().count(), lines.len());
+
+        // Reference not matching the header.
+        fs::write(dir.path().join("ref.fa.fai"), &fai[..fai.find("chr2").unwrap()]).unwrap();
+        let err = gbam_to_cram(&mut reader, &[], &fasta, Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,188 @@
+use super::merge::{GbamSource, MergeReader, SortKey, SortOrder};
+use super::sam_reader::RefSeqs;
+use crate::{Codecs, Writer};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use rayon::ThreadPool;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
+use tempdir::TempDir;
+
+/// Codec of temporary chunks, they are read only once.
+const CHUNK_CODEC: Codecs = Codecs::Lz4;
+const CHUNK_THREAD_NUM: usize = 4;
+
+/// Sort of any number of records. Records are collected until `mem_limit`
+/// bytes, sorted and spilled into temporary GBAM file. Sorted chunks are
+/// merged with `MergeReader`, so temporary storage takes about as much as
+/// the resulting GBAM file. Sort is stable.
+pub struct ExternalSorter {
+    order: SortOrder,
+    mem_limit: usize,
+    tmp_dir: TempDir,
+    sam_header: Vec<u8>,
+    ref_seqs: RefSeqs,
+    /// Records of current chunk, one after another.
+    data: Vec<u8>,
+    /// Key, start and end of every record in `data`.
+    index: Vec<(SortKey, usize, usize)>,
+    chunks_num: usize,
+    pool: Arc<ThreadPool>,
+}
+
+impl ExternalSorter {
+    /// Sorts records in `order`. Temporary files are created in a new
+    /// directory inside `tmp_dir`, which is removed when sorter is dropped.
+    pub fn new(order: SortOrder, sam_header: Vec<u8>, ref_seqs: RefSeqs, mem_limit: usize, tmp_dir: &Path) -> io::Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(CHUNK_THREAD_NUM)
+            .build()
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            order,
+            mem_limit,
+            tmp_dir: TempDir::new_in(tmp_dir, "gbam_sort")?,
+            sam_header,
+            ref_seqs,
+            data: Vec::new(),
+            index: Vec::new(),
+            chunks_num: 0,
+            pool: Arc::new(pool),
+        })
+    }
+
+    /// Takes raw BAM record (without `block_size`).
+    pub fn push_record(&mut self, rec: &[u8]) -> io::Result<()> {
+        let start = self.data.len();
+        self.data.extend_from_slice(rec);
+        self.index.push((self.order.key(rec), start, self.data.len()));
+        if self.data.len() + self.index.len() * std::mem::size_of::<(SortKey, usize, usize)>() >= self.mem_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Spills the last chunk and returns merged records of all chunks.
+    /// Temporary files live as long as the sorter, so it has to outlive
+    /// returned reader.
+    pub fn finish(&mut self) -> io::Result<MergeReader> {
+        if !self.index.is_empty() || self.chunks_num == 0 {
+            self.spill()?;
+        }
+        let inputs = (0..self.chunks_num)
+            .map(|chunk| GbamSource::open(File::open(self.chunk_path(chunk))?))
+            .collect::<io::Result<_>>()?;
+        MergeReader::with_order(inputs, self.order)
+    }
+
+    fn chunk_path(&self, chunk: usize) -> std::path::PathBuf {
+        self.tmp_dir.path().join(format!("chunk_{}.gbam", chunk))
+    }
+
+    /// Writes sorted records of current chunk into temporary GBAM file.
+    fn spill(&mut self) -> io::Result<()> {
+        self.index.sort_by(|(left, _, _), (right, _, _)| left.cmp(right));
+        let fout = File::create(self.chunk_path(self.chunks_num))?;
+        let mut writer = Writer::new(
+            BufWriter::new(fout),
+            vec![CHUNK_CODEC],
+            1,
+            Vec::new(),
+            self.ref_seqs.clone(),
+            self.sam_header.clone(),
+            String::new(),
+            false,
+        );
+        writer.set_thread_pool(self.pool.clone())?;
+        for (_, start, end) in &self.index {
+            let rec = BAMRawRecord(Cow::Borrowed(&self.data[*start..*end]));
+            writer.push_record(&rec)?;
+        }
+        writer.finish()?;
+        self.data.clear();
+        self.index.clear();
+        self.chunks_num += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use bam_tools::record::fields::Fields;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    #[test]
+    fn test_external_sort_over_chunks() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n@SQ\tSN:chr2\tLN:100000\n");
+        for i in 0..3000 {
+            let (rname, pos) = match i % 4 {
+                0 => ("*", 0),
+                1 => ("chr2", (i * 7919) % 1000 + 1),
+                _ => ("chr1", (i * 104729) % 5000 + 1),
+            };
+            let flag = if rname == "*" { 4 } else { 0 };
+            sam.push_str(&format!("r{}\t{}\t{}\t{}\t0\t*\t*\t0\t0\tACGT\tIIII\tXI:i:{}\n", i, flag, rname, pos, i));
+        }
+        let mut reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = reader.read_header().unwrap();
+        let tmp_dir = TempDir::new("external_sort_test").unwrap();
+        let mut sorter = ExternalSorter::new(SortOrder::Coordinate, sam_header, ref_seqs, 64 * 1024, tmp_dir.path()).unwrap();
+        let mut expected = Vec::new();
+        while let Some(rec) = reader.next_rec() {
+            let rec = rec.unwrap();
+            expected.push(rec.to_vec());
+            sorter.push_record(rec).unwrap();
+        }
+        assert!(sorter.chunks_num > 1);
+        expected.sort_by_key(|rec| SortOrder::Coordinate.key(rec));
+
+        let mut merge_reader = sorter.finish().unwrap();
+        let mut sorted = Vec::new();
+        while let Some(rec) = merge_reader.next_rec() {
+            sorted.push(rec.unwrap().to_vec());
+        }
+        assert_eq!(sorted.len(), expected.len());
+        for (rec, expected) in sorted.iter().zip(&expected) {
+            let rec = BAMRawRecord(Cow::Borrowed(&rec[..]));
+            let expected = BAMRawRecord(Cow::Borrowed(&expected[..]));
+            for field in [Fields::RefID, Fields::Pos, Fields::ReadName, Fields::RawTags] {
+                assert_eq!(rec.get_bytes(&field), expected.get_bytes(&field));
+            }
+        }
+        let positions: Vec<_> = sorted.iter().map(|rec| LittleEndian::read_i32(&rec[4..])).collect();
+        assert_eq!(positions.last(), Some(&-1));
+    }
+
+    #[test]
+    fn test_external_sort_by_name() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..2000 {
+            let name = (i * 7919) % 1000;
+            let flag = if i < 1000 { 0x81 } else { 0x41 };
+            sam.push_str(&format!("r{}\t{}\tchr1\t{}\t0\t*\t*\t0\t0\tACGT\tIIII\n", name, flag, i + 1));
+        }
+        let mut reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = reader.read_header().unwrap();
+        let tmp_dir = TempDir::new("external_sort_test").unwrap();
+        let mut sorter = ExternalSorter::new(SortOrder::QueryName, sam_header, ref_seqs, 32 * 1024, tmp_dir.path()).unwrap();
+        while let Some(rec) = reader.next_rec() {
+            sorter.push_record(rec.unwrap()).unwrap();
+        }
+
+        let mut merge_reader = sorter.finish().unwrap();
+        assert!(String::from_utf8_lossy(merge_reader.header()).contains("@HD\tVN:1.6\tSO:queryname\n"));
+        let mut keys = Vec::new();
+        while let Some(rec) = merge_reader.next_rec() {
+            let rec = BAMRawRecord(Cow::Owned(rec.unwrap().to_vec()));
+            keys.push((rec.get_bytes(&Fields::ReadName).to_vec(), LittleEndian::read_u16(rec.get_bytes(&Fields::Flags))));
+        }
+        assert_eq!(keys.len(), 2000);
+        assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(keys[0], (b"r0\0".to_vec(), 0x41));
+        assert_eq!(keys[1], (b"r0\0".to_vec(), 0x81));
+    }
+}
@@ -0,0 +1,187 @@
+use super::sam_reader::{header_bytes, invalid_sam, reg2bin, write_seq_qual};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{self, BufRead};
+
+const FLAG_PAIRED: u16 = 0x1;
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_MATE_UNMAPPED: u16 = 0x8;
+const FLAG_FIRST: u16 = 0x40;
+const FLAG_LAST: u16 = 0x80;
+
+/// Reads FASTQ files and converts reads into raw unaligned BAM records. Reads
+/// of paired files are interleaved, first mate goes first, as `samtools
+/// import` does.
+pub struct FastqReader<R: BufRead> {
+    inputs: Vec<R>,
+    next_input: usize,
+    lines: [String; 4],
+    mate_name: String,
+    record: Vec<u8>,
+}
+
+impl<R: BufRead> FastqReader<R> {
+    /// Reader of single end reads.
+    pub fn new(inner: R) -> Self {
+        Self::with_inputs(vec![inner])
+    }
+
+    /// Reader of paired end reads. Mates are expected to be in the same order
+    /// in both files.
+    pub fn paired(first: R, second: R) -> Self {
+        Self::with_inputs(vec![first, second])
+    }
+
+    fn with_inputs(inputs: Vec<R>) -> Self {
+        Self {
+            inputs,
+            next_input: 0,
+            lines: Default::default(),
+            mate_name: String::new(),
+            record: Vec::new(),
+        }
+    }
+
+    /// Header bytes laid out as in BAM file. Unaligned reads have no
+    /// reference sequences.
+    pub fn header(&self) -> Vec<u8> {
+        header_bytes("@HD\tVN:1.6\tSO:unsorted\n", &Vec::new()).unwrap()
+    }
+
+    /// Reads next read and returns it as raw BAM record (without
+    /// `block_size`).
+    pub fn next_rec(&mut self) -> Option<io::Result<&[u8]>> {
+        match self.read_lines() {
+            Ok(true) => {}
+            Ok(false) => return self.check_inputs_end().err().map(Err),
+            Err(e) => return Some(Err(e)),
+        }
+        match self.encode_record() {
+            Ok(()) => Some(Ok(&self.record)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Reads 4 lines of the next read. Returns false if current input has
+    /// ended.
+    fn read_lines(&mut self) -> io::Result<bool> {
+        let input = &mut self.inputs[self.next_input];
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            line.clear();
+            if input.read_line(line)? == 0 {
+                if i == 0 {
+                    return Ok(false);
+                }
+                return Err(invalid_sam("FASTQ file ends in the middle of a read.".to_owned()));
+            }
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+        }
+        Ok(true)
+    }
+
+    /// All inputs have to end at the same read.
+    fn check_inputs_end(&mut self) -> io::Result<()> {
+        if self.next_input != 0 {
+            return Err(invalid_sam("First FASTQ file has more reads than second.".to_owned()));
+        }
+        for input in &mut self.inputs[1..] {
+            if !input.fill_buf()?.is_empty() {
+                return Err(invalid_sam("Second FASTQ file has more reads than first.".to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_record(&mut self) -> io::Result<()> {
+        let [header, seq, plus, qual] = &self.lines;
+        if !header.starts_with('@') || !plus.starts_with('+') {
+            return Err(invalid_sam(format!("Invalid FASTQ read: {}", header)));
+        }
+        if seq.len() != qual.len() {
+            return Err(invalid_sam(format!("SEQ and QUAL lengths differ: {}", header)));
+        }
+        let name = header[1..].split_whitespace().next().unwrap_or("");
+        let name = name
+            .strip_suffix("/1")
+            .or_else(|| name.strip_suffix("/2"))
+            .unwrap_or(name);
+        if name.is_empty() || name.len() > 254 {
+            return Err(invalid_sam(format!("Invalid read name: {}", header)));
+        }
+
+        let mut flag = FLAG_UNMAPPED;
+        if self.inputs.len() == 2 {
+            flag |= FLAG_PAIRED | FLAG_MATE_UNMAPPED;
+            if self.next_input == 0 {
+                flag |= FLAG_FIRST;
+                self.mate_name.clear();
+                self.mate_name.push_str(name);
+            } else {
+                flag |= FLAG_LAST;
+                if name != self.mate_name {
+                    return Err(invalid_sam(format!(
+                        "Mate names differ: {} and {}",
+                        self.mate_name, name
+                    )));
+                }
+            }
+            self.next_input = 1 - self.next_input;
+        }
+
+        let dest = &mut self.record;
+        dest.clear();
+        dest.write_i32::<LittleEndian>(-1)?;
+        dest.write_i32::<LittleEndian>(-1)?;
+        dest.write_u8(name.len() as u8 + 1)?;
+        dest.write_u8(0)?;
+        dest.write_u16::<LittleEndian>(reg2bin(-1, 0))?;
+        dest.write_u16::<LittleEndian>(0)?;
+        dest.write_u16::<LittleEndian>(flag)?;
+        dest.write_u32::<LittleEndian>(seq.len() as u32)?;
+        dest.write_i32::<LittleEndian>(-1)?;
+        dest.write_i32::<LittleEndian>(-1)?;
+        dest.write_i32::<LittleEndian>(0)?;
+        dest.extend_from_slice(name.as_bytes());
+        dest.push(0);
+        write_seq_qual(seq.as_bytes(), Some(qual.as_bytes()), dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use bam_tools::record::fields::Fields;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_paired_fastq_to_raw_records() {
+        let first = "@r1/1 comment\nACGTN\n+\nIIII#\n@r2/1\nAC\n+r2\nII\n";
+        let second = "@r1/2\nTTGA\n+\n####\n@r2/2\nG\n+\nI\n";
+        let mut reader = FastqReader::paired(first.as_bytes(), second.as_bytes());
+        let mut recs = Vec::new();
+        while let Some(rec) = reader.next_rec() {
+            recs.push(BAMRawRecord(Cow::Owned(rec.unwrap().to_vec())));
+        }
+        assert_eq!(recs.len(), 4);
+        let names: Vec<_> = recs.iter().map(|rec| rec.get_bytes(&Fields::ReadName).to_vec()).collect();
+        assert_eq!(names, vec![b"r1\0".to_vec(), b"r1\0".to_vec(), b"r2\0".to_vec(), b"r2\0".to_vec()]);
+        let flags: Vec<_> = recs.iter().map(|rec| rec.get_bytes(&Fields::Flags).to_vec()).collect();
+        assert_eq!(flags[0], 77u16.to_le_bytes());
+        assert_eq!(flags[1], 141u16.to_le_bytes());
+        assert_eq!(recs[0].get_bytes(&Fields::RefID), &(-1i32).to_le_bytes());
+        assert_eq!(recs[0].get_bytes(&Fields::Bin), &4680u16.to_le_bytes());
+        assert_eq!(recs[0].get_bytes(&Fields::RawSequence), &[0x12, 0x48, 0xF0]);
+        assert_eq!(recs[0].get_bytes(&Fields::RawQual), b"((((\x02");
+
+        let mut reader = FastqReader::new("@r\nA\n+\nI\n".as_bytes());
+        let rec = BAMRawRecord(Cow::Owned(reader.next_rec().unwrap().unwrap().to_vec()));
+        assert_eq!(rec.get_bytes(&Fields::Flags), &4u16.to_le_bytes());
+        assert!(reader.next_rec().is_none());
+
+        let mut reader = FastqReader::paired("@r\nA\n+\nI\n".as_bytes(), "".as_bytes());
+        assert!(reader.next_rec().unwrap().is_ok());
+        assert!(reader.next_rec().unwrap().is_err());
+    }
+}
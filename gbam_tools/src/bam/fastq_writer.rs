@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use super::gbam_to_bam::for_each_record;
+use super::sam_reader::invalid_sam;
+use crate::reader::{reader::Reader, record::GbamRecord};
+
+const FLAG_PAIRED: u16 = 0x1;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_FIRST: u16 = 0x40;
+const FLAG_LAST: u16 = 0x80;
+/// Secondary and supplementary alignments are not reads of their own.
+const FLAG_NOT_PRIMARY: u16 = 0x100 | 0x800;
+/// Quality written for reads without qualities, as `samtools fastq` does.
+const DEFAULT_QUAL: u8 = 1;
+
+/// Where reads go.
+pub enum FastqOutput<W: Write> {
+    /// Every read in file order.
+    Single(W),
+    /// Every read, mates of pair one after another (first mate goes first).
+    /// Reads without mate are written in file order, those whose mate is
+    /// missing from file at the end.
+    Interleaved(W),
+    /// First and second mates to separate files in the same order. Reads
+    /// without mate go to `single`, or are dropped if there is none.
+    Paired { read1: W, read2: W, single: Option<W> },
+}
+
+/// Read waiting for its mate.
+struct Pending {
+    arrival: u64,
+    first: bool,
+    fastq: Vec<u8>,
+}
+
+/// Writes primary reads of GBAM records as FASTQ, reverse complemented back
+/// if they were aligned to reverse strand. Mates are paired by read name
+/// and flags, so they don't have to be next to each other (e.g. in
+/// coordinate sorted file), but reads wait for their mates in memory.
+/// Records must have ReadName, Flags, RawSequence and RawQual fields.
+/// `finish` must be called to write reads whose mates are missing.
+pub struct FastqWriter<W: Write> {
+    output: FastqOutput<W>,
+    mate_suffix: bool,
+    pending: HashMap<Vec<u8>, Pending>,
+    arrivals: u64,
+}
+
+impl<W: Write> FastqWriter<W> {
+    /// Read names of mates get `/1` and `/2` appended if `mate_suffix`.
+    pub fn new(output: FastqOutput<W>, mate_suffix: bool) -> Self {
+        Self {
+            output,
+            mate_suffix,
+            pending: HashMap::new(),
+            arrivals: 0,
+        }
+    }
+
+    pub fn write_record(&mut self, rec: &GbamRecord) -> io::Result<()> {
+        let missing = || invalid_sam("Record lacks fields needed for FASTQ.".to_owned());
+        let flag = rec.flag.ok_or_else(missing)?;
+        if flag & FLAG_NOT_PRIMARY != 0 {
+            return Ok(());
+        }
+        let name = rec.name().ok_or_else(missing)?;
+        let fastq = format_read(
+            name,
+            flag,
+            rec.seq.as_deref().ok_or_else(missing)?.as_bytes(),
+            rec.qual.as_deref().ok_or_else(missing)?,
+            self.mate_suffix,
+        );
+        let mate = match flag & (FLAG_PAIRED | FLAG_FIRST | FLAG_LAST) {
+            f if f == FLAG_PAIRED | FLAG_FIRST => Some(true),
+            f if f == FLAG_PAIRED | FLAG_LAST => Some(false),
+            _ => None,
+        };
+        let first = match (mate, &mut self.output) {
+            (None, _) | (_, FastqOutput::Single(_)) => return self.write_single(&fastq),
+            (Some(first), _) => first,
+        };
+        match self.pending.remove(name) {
+            Some(other) if other.first != first => {
+                let (read1, read2) = if first { (&fastq, &other.fastq) } else { (&other.fastq, &fastq) };
+                match &mut self.output {
+                    FastqOutput::Paired { read1: out1, read2: out2, .. } => {
+                        out1.write_all(read1)?;
+                        out2.write_all(read2)
+                    }
+                    FastqOutput::Single(out) | FastqOutput::Interleaved(out) => {
+                        out.write_all(read1)?;
+                        out.write_all(read2)
+                    }
+                }
+            }
+            other => {
+                // The same mate twice, the first one has no pair.
+                if let Some(other) = other {
+                    self.write_single(&other.fastq)?;
+                }
+                self.pending.insert(name.to_vec(), Pending { arrival: self.arrivals, first, fastq });
+                self.arrivals += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn write_single(&mut self, fastq: &[u8]) -> io::Result<()> {
+        match &mut self.output {
+            FastqOutput::Single(out) | FastqOutput::Interleaved(out) => out.write_all(fastq),
+            FastqOutput::Paired { single: Some(out), .. } => out.write_all(fastq),
+            FastqOutput::Paired { single: None, .. } => Ok(()),
+        }
+    }
+
+    /// Writes reads whose mates were not found in file arrival order,
+    /// flushes and returns output.
+    pub fn finish(mut self) -> io::Result<FastqOutput<W>> {
+        let mut orphans: Vec<_> = self.pending.drain().map(|(_, read)| read).collect();
+        orphans.sort_unstable_by_key(|read| read.arrival);
+        for read in orphans {
+            self.write_single(&read.fastq)?;
+        }
+        match &mut self.output {
+            FastqOutput::Single(out) | FastqOutput::Interleaved(out) => out.flush()?,
+            FastqOutput::Paired { read1, read2, single } => {
+                read1.flush()?;
+                read2.flush()?;
+                single.iter_mut().try_for_each(Write::flush)?;
+            }
+        }
+        Ok(self.output)
+    }
+}
+
+/// Complement of IUPAC base.
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        other => other,
+    }
+}
+
+/// FASTQ entry of read as it was sequenced.
+fn format_read(name: &[u8], flag: u16, seq: &[u8], qual: &[u8], mate_suffix: bool) -> Vec<u8> {
+    let mut fastq = Vec::with_capacity(name.len() + 2 * seq.len() + 8);
+    fastq.push(b'@');
+    fastq.extend_from_slice(name);
+    if mate_suffix && flag & FLAG_FIRST != 0 {
+        fastq.extend_from_slice(b"/1");
+    } else if mate_suffix && flag & FLAG_LAST != 0 {
+        fastq.extend_from_slice(b"/2");
+    }
+    fastq.push(b'\n');
+    let reverse = flag & FLAG_REVERSE != 0;
+    if reverse {
+        fastq.extend(seq.iter().rev().map(|&base| complement(base)));
+    } else {
+        fastq.extend_from_slice(seq);
+    }
+    fastq.extend_from_slice(b"\n+\n");
+    let qual = qual.iter().map(|&q| if q == 0xff { DEFAULT_QUAL } else { q } + 33);
+    if reverse {
+        fastq.extend(qual.rev());
+    } else {
+        fastq.extend(qual);
+    }
+    fastq.push(b'\n');
+    fastq
+}
+
+/// Writes primary reads of records overlapping any of `regions` in turn, or
+/// of all records if there are no regions, as FASTQ (see `FastqWriter`).
+pub fn gbam_to_fastq<W: Write>(
+    reader: &mut Reader,
+    regions: &[String],
+    output: FastqOutput<W>,
+    mate_suffix: bool,
+) -> io::Result<FastqOutput<W>> {
+    for region in regions {
+        reader.fetch(region)?;
+    }
+    let mut writer = FastqWriter::new(output, mate_suffix);
+    for_each_record(reader, regions, None, |rec| writer.write_record(rec))?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{Codecs, NameTokenization};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use bam_tools::record::fields::Fields;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_gbam_to_fastq() {
+        // Coordinate sorted: mates are apart, p3 lacks its second mate.
+        let sam = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n\
+            HWI:1:p1\t99\tchr1\t100\t60\t4M\t=\t300\t204\tACGT\tABCD\n\
+            HWI:1:p2\t163\tchr1\t150\t60\t3M\t=\t250\t103\tGGA\tIII\n\
+            se\t16\tchr1\t200\t60\t5M\t*\t0\t0\tAACGR\t!!#$%\n\
+            se\t272\tchr1\t210\t0\t5M\t*\t0\t0\tAACGR\t*\n\
+            HWI:1:p2\t83\tchr1\t250\t60\t3M\t=\t150\t-103\tTTC\tIJK\n\
+            HWI:1:p1\t147\tchr1\t300\t60\t4M\t=\t100\t-204\tCCAT\t*\n\
+            HWI:1:p3\t65\tchr1\t400\t60\t2M\t*\t0\t0\tAT\tII\n\
+            HWI:1:p1\t2145\tchr1\t500\t60\t4M\t=\t100\t-204\tACGT\tABCD\n";
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("gbam_to_fastq").unwrap();
+        let path = dir.path().join("in.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Gzip], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        writer.set_name_tokenization(NameTokenization::On);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+        let fields = [Fields::ReadName, Fields::Flags, Fields::RawSequence, Fields::RawQual];
+        let mut reader = ReaderBuilder::new().fields(&fields).open(File::open(&path).unwrap()).unwrap();
+        let text = |out: Vec<u8>| String::from_utf8(out).unwrap();
+
+        let p1 = ("@HWI:1:p1/1\nACGT\n+\nABCD\n", "@HWI:1:p1/2\nATGG\n+\n\"\"\"\"\n");
+        let p2 = ("@HWI:1:p2/1\nGAA\n+\nKJI\n", "@HWI:1:p2/2\nGGA\n+\nIII\n");
+        let se = "@se\nYCGTT\n+\n%$#!!\n";
+        let p3 = "@HWI:1:p3/1\nAT\n+\nII\n";
+        match gbam_to_fastq(&mut reader, &[], FastqOutput::Single(Vec::new()), true).unwrap() {
+            FastqOutput::Single(out) => assert_eq!(text(out), [p1.0, p2.1, se, p2.0, p1.1, p3].concat()),
+            _ => unreachable!(),
+        }
+        match gbam_to_fastq(&mut reader, &[], FastqOutput::Interleaved(Vec::new()), true).unwrap() {
+            FastqOutput::Interleaved(out) => assert_eq!(text(out), [se, p2.0, p2.1, p1.0, p1.1, p3].concat()),
+            _ => unreachable!(),
+        }
+        let output = FastqOutput::Paired { read1: Vec::new(), read2: Vec::new(), single: Some(Vec::new()) };
+        match gbam_to_fastq(&mut reader, &[], output, true).unwrap() {
+            FastqOutput::Paired { read1, read2, single } => {
+                assert_eq!(text(read1), [p2.0, p1.0].concat());
+                assert_eq!(text(read2), [p2.1, p1.1].concat());
+                assert_eq!(text(single.unwrap()), [se, p3].concat());
+            }
+            _ => unreachable!(),
+        }
+        match gbam_to_fastq(&mut reader, &[], FastqOutput::Single(Vec::new()), false).unwrap() {
+            FastqOutput::Single(out) => assert!(text(out).starts_with("@HWI:1:p1\nACGT\n")),
+            _ => unreachable!(),
+        }
+    }
+}
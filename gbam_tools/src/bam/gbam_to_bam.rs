@@ -1,69 +1,129 @@
-use crate::reader::parse_tmplt::ParsingTemplate;
-use crate::reader::records::Records;
-use rust_htslib::bam;
-use std::io::Write;
+use crate::reader::builder::ReaderBuilder;
+use crate::reader::expr::FilterExpr;
+use crate::reader::filter::RecordPredicate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use flate2::Compression;
+use std::io::{BufWriter, Result, Write};
 
-use std::convert::TryFrom;
 use std::fs::File;
 
-/// Converts GBAM file to BAM file. This uses the `noodles bam writer`.
-pub fn gbam_to_bam(in_path: &str, out_path: &str) {
-    let file = File::open(in_path).unwrap();
-    let mut template = ParsingTemplate::new();
-    template.set_all();
-    let mut reader = crate::reader::reader::Reader::new(file, template).unwrap();
+use super::bgzf::BgzfWriter;
 
-    let mut bam_header = bam::Header::new();
-    let ref_seqs = reader.file_meta.get_ref_seqs();
+/// Converts GBAM file to BAM file: header is written as stored at
+/// conversion, records as they were in input BAM (read names are
+/// detokenized while decoding blocks), compressed to BGZF blocks with EOF
+/// marker.
+pub fn gbam_to_bam(in_path: &str, out_path: &str) -> Result<()> {
+    let mut reader = ReaderBuilder::new().open(File::open(in_path)?)?;
+    let out = BufWriter::new(File::create(out_path)?);
+    let mut bgzf = BgzfWriter::new(out, Compression::default());
+    write_bam(&mut reader, &[], &mut bgzf)?;
+    bgzf.finish()?.flush()
+}
 
-    for ref_seq in ref_seqs {
-        bam_header.push_record(
-            bam::header::HeaderRecord::new(b"SQ")
-                .push_tag(b"SN", &ref_seq.0)
-                .push_tag(b"LN", &ref_seq.1),
-        );
+/// Writes uncompressed BAM (magic, header and records) of records
+/// overlapping any of `regions` in turn, as `samtools view` does, or of all
+/// records if there are no regions. Invalid region fails before anything is
+/// written.
+pub fn write_bam<W: Write>(reader: &mut Reader, regions: &[String], out: &mut W) -> Result<()> {
+    for region in regions {
+        reader.fetch(region)?;
     }
+    out.write_all(b"BAM\x01")?;
+    out.write_all(reader.file_meta.get_sam_header())?;
+    let mut buf = Vec::new();
+    for_each_record(reader, regions, None, |rec| {
+        rec.convert_to_bytes(&mut buf);
+        out.write_all(&buf)
+    })
+}
 
-    let mut records_it = Records::new(&mut reader);
-
-    let mut out = bam::Writer::from_path(out_path, &bam_header, bam::Format::Bam).unwrap();
-    out.set_threads(4).unwrap();
+/// Calls `f` for records overlapping any of `regions` in turn, or for all
+/// records if there are no regions, which match `filter`. Records of regions
+/// are checked after decoding, so reader must decode fields of filter.
+/// Stops at the first error.
+pub(crate) fn for_each_record<F>(reader: &mut Reader, regions: &[String], filter: Option<&FilterExpr>, mut f: F) -> Result<()>
+where
+    F: FnMut(&GbamRecord) -> Result<()>,
+{
+    match filter {
+        Some(filter) if regions.is_empty() => {
+            let mut records = reader.filtered(filter)?;
+            while let Some(rec) = records.try_next_rec()? {
+                f(rec)?;
+            }
+        }
+        None if regions.is_empty() => {
+            let mut rec = GbamRecord::default();
+            let mut records = reader.records();
+            while records.read_into(&mut rec)? {
+                f(&rec)?;
+            }
+        }
+        _ => {}
+    }
+    for region in regions {
+        let mut records = reader.fetch(region)?;
+        while let Some(rec) = records.try_next_rec()? {
+            if filter.is_none_or(|filter| filter.accepts(rec)) {
+                f(rec)?;
+            }
+        }
+    }
+    Ok(())
+}
 
-    let mut cigar_buf = Vec::new();
-    while let Some(rec) = records_it.next_rec() {
-        let mut record = bam::Record::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::bgzf::BGZF_EOF;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{Codecs, NameTokenization};
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use tempdir::TempDir;
 
-        record.set_bin(rec.bin.unwrap());
-        record.set_tid(rec.refid.unwrap());
-        record.set_mapq(rec.mapq.unwrap());
-        record.set_pos(rec.pos.unwrap() as i64);
-        record.set_flags(rec.flag.unwrap());
-        record.set_mtid(rec.next_ref_id.unwrap());
-        record.set_mpos(rec.next_pos.unwrap() as i64);
-        record.set_insert_size(rec.tlen.unwrap() as i64);
-        let rec_seq_len = rec.seq.as_ref().unwrap().len();
-        let mut qual = rec.qual.as_ref().unwrap().clone();
-        if qual.is_empty() {
-            qual = vec![255; rec_seq_len];
+    #[test]
+    fn test_gbam_to_bam() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:unsorted\n@SQ\tSN:chr1\tLN:100000\n@PG\tID:test\tPN:test\n");
+        for i in 0..5000 {
+            // Odd length sequences end with padding.
+            let (cigar, seq, qual) = if i % 2 == 0 { ("2S4M", "TTACGT", "IIIIII") } else { ("1S4M", "TACGN", "IIII#") };
+            sam.push_str(&format!(
+                "HWI-ST:8:1101:{}:{}\t{}\tchr1\t{}\t{}\t{}\t=\t{}\t{}\t{}\t{}\tNM:i:{}\tRG:Z:rg{}\n",
+                i % 97, i, if i % 5 == 0 { 16 } else { 0 }, i * 7 + 1, i % 60, cigar, i * 7 + 300, 300, seq, qual, i % 3, i % 2
+            ));
         }
+        sam.push_str("unmapped\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*\n");
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let mut expected = Vec::new();
+        let dir = TempDir::new("gbam_to_bam").unwrap();
+        let gbam_path = dir.path().join("in.gbam");
+        let mut writer = Writer::new(File::create(&gbam_path).unwrap(), vec![Codecs::Zstd], 2, Vec::new(), ref_seqs, sam_header.clone(), String::new(), false);
+        writer.set_name_tokenization(NameTokenization::On);
+        while let Some(rec) = sam_reader.next_rec() {
+            let rec = rec.unwrap();
+            expected.push(rec.to_vec());
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec))).unwrap();
+        }
+        writer.finish().unwrap();
 
-        cigar_buf.clear();
-        rec.cigar.as_ref().unwrap().ops().for_each(|op| {
-            cigar_buf
-                .write_all(op.length().to_string().as_bytes())
-                .unwrap();
-            cigar_buf.push(op.op_type() as u8);
-        });
-
-        let bam_cigar = bam::record::CigarString::try_from(&cigar_buf[..]).unwrap();
-        record.set_data(&rec.tags.as_ref().unwrap()[..]);
-        record.set(
-            &rec.read_name.as_ref().unwrap()[..rec.read_name.as_ref().unwrap().len() - 1],
-            Some(&bam_cigar),
-            rec.seq.as_ref().unwrap().as_bytes(),
-            &qual[..],
-        );
+        let bam_path = dir.path().join("out.bam");
+        gbam_to_bam(gbam_path.to_str().unwrap(), bam_path.to_str().unwrap()).unwrap();
+        assert!(std::fs::read(&bam_path).unwrap().ends_with(&BGZF_EOF));
 
-        out.write(&record).unwrap();
+        let mut bam_reader = bam_tools::Reader::new(File::open(&bam_path).unwrap(), 1, None);
+        let (header, _) = bam_reader.read_header().unwrap();
+        assert_eq!(header, sam_header);
+        let mut records = bam_reader.records();
+        let mut converted = Vec::new();
+        while let Some(rec) = records.next_rec() {
+            converted.push(rec.unwrap().clone());
+        }
+        assert_eq!(converted.len(), expected.len());
+        assert!(converted == expected);
     }
 }
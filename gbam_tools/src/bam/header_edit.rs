@@ -0,0 +1,134 @@
+use super::merge::header_text;
+use super::sam_reader::invalid_sam;
+use byteorder::{ByteOrder, LittleEndian};
+use std::io;
+
+/// Edits of SAM header applied while converting, so no separate reheader
+/// pass is needed. See `Writer::edit_header`.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderEdits {
+    lines: Vec<String>,
+    sample_renames: Vec<(String, String)>,
+}
+
+impl HeaderEdits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds @PG, @RG or @CO line. @PG and @RG lines replace lines of the
+    /// same type with the same ID, if there are any. Other lines are
+    /// appended to the header.
+    pub fn add_line(&mut self, line: &str) -> io::Result<()> {
+        let line = line.trim_end_matches('\n');
+        if line.contains('\n') {
+            return Err(invalid_sam(format!("Header line should not span lines: {}", line)));
+        }
+        match line.get(..4) {
+            Some("@PG\t") | Some("@RG\t") => {
+                if line_id(line).is_none() {
+                    return Err(invalid_sam(format!("Header line without ID: {}", line)));
+                }
+            }
+            Some("@CO\t") => {}
+            _ => return Err(invalid_sam(format!("Only @PG, @RG and @CO lines may be added: {}", line))),
+        }
+        self.lines.push(line.to_owned());
+        Ok(())
+    }
+
+    /// Renames sample `from` to `to` in SM tags of @RG lines, added ones
+    /// included.
+    pub fn rename_sample(&mut self, from: &str, to: &str) {
+        self.sample_renames.push((from.to_owned(), to.to_owned()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.sample_renames.is_empty()
+    }
+
+    /// Applies edits to header bytes laid out as in BAM file. Reference
+    /// sequences are kept as is.
+    pub fn apply(&self, sam_header: &[u8]) -> Vec<u8> {
+        if self.is_empty() {
+            return sam_header.to_vec();
+        }
+        let mut lines: Vec<String> = header_text(sam_header).lines().map(str::to_owned).collect();
+        for new_line in &self.lines {
+            let replaced = match line_id(new_line) {
+                Some(id) => lines
+                    .iter_mut()
+                    .find(|line| line.starts_with(&new_line[..4]) && line_id(line) == Some(id)),
+                None => None,
+            };
+            match replaced {
+                Some(line) => *line = new_line.clone(),
+                None => lines.push(new_line.clone()),
+            }
+        }
+        for line in lines.iter_mut().filter(|line| line.starts_with("@RG\t")) {
+            let fields: Vec<String> = line
+                .split('\t')
+                .map(|field| match field.strip_prefix("SM:") {
+                    Some(sample) => {
+                        let renamed = self.sample_renames.iter().fold(sample, |sample, (from, to)| {
+                            if sample == from { to } else { sample }
+                        });
+                        format!("SM:{}", renamed)
+                    }
+                    None => field.to_owned(),
+                })
+                .collect();
+            *line = fields.join("\t");
+        }
+
+        let mut text = lines.join("\n");
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        let l_text = LittleEndian::read_u32(sam_header) as usize;
+        let mut bytes = (text.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(text.as_bytes());
+        // Reference sequences are kept as is.
+        bytes.extend_from_slice(&sam_header[4 + l_text..]);
+        bytes
+    }
+}
+
+/// Value of ID tag of header line.
+fn line_id(line: &str) -> Option<&str> {
+    line.split('\t').find_map(|field| field.strip_prefix("ID:"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::header_bytes;
+
+    #[test]
+    fn test_header_edits() {
+        let ref_seqs = vec![("chr1".to_owned(), 1000)];
+        let text = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n@RG\tID:a\tSM:s1\n@RG\tID:b\tSM:s2\n@PG\tID:bwa\tPN:bwa\n";
+        let header = header_bytes(text, &ref_seqs).unwrap();
+
+        let mut edits = HeaderEdits::new();
+        edits.add_line("@PG\tID:bwa\tPN:bwa\tVN:0.7").unwrap();
+        edits.add_line("@PG\tID:gbam\tPN:gbam").unwrap();
+        edits.add_line("@RG\tID:c\tSM:s1").unwrap();
+        edits.add_line("@CO\tconverted").unwrap();
+        edits.rename_sample("s1", "sample1");
+        assert!(edits.add_line("@SQ\tSN:chr2\tLN:10").is_err());
+        assert!(edits.add_line("@RG\tSM:s3").is_err());
+
+        let edited = edits.apply(&header);
+        assert_eq!(
+            header_text(&edited),
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n@RG\tID:a\tSM:sample1\n@RG\tID:b\tSM:s2\n\
+             @PG\tID:bwa\tPN:bwa\tVN:0.7\n@PG\tID:gbam\tPN:gbam\n@RG\tID:c\tSM:sample1\n@CO\tconverted\n"
+        );
+        // Reference sequences are untouched.
+        let l_text = LittleEndian::read_u32(&header) as usize;
+        let edited_l_text = LittleEndian::read_u32(&edited) as usize;
+        assert_eq!(header[4 + l_text..], edited[4 + edited_l_text..]);
+    }
+}
@@ -0,0 +1,430 @@
+use super::sam_reader::{header_bytes, invalid_sam, RefSeqs, SamReader};
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader as GbamReader;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use bam_tools::Reader;
+use byteorder::{ByteOrder, LittleEndian};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead};
+
+const REFID_OFFSET: usize = 0;
+const POS_OFFSET: usize = 4;
+const FLAG_OFFSET: usize = 14;
+const NEXT_REFID_OFFSET: usize = 20;
+
+/// Source of raw BAM records (without `block_size`).
+pub enum RecordSource {
+    Bam(Reader),
+    Sam(SamReader<Box<dyn BufRead>>),
+    Gbam(Box<GbamSource>),
+}
+
+impl RecordSource {
+    /// Reads next record into `buf`. Returns false at the end of input.
+    pub fn read_record(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        match self {
+            RecordSource::Bam(reader) => Ok(reader.read_record(buf)? > 0),
+            RecordSource::Sam(reader) => match reader.next_rec() {
+                Some(rec) => {
+                    buf.clear();
+                    buf.extend_from_slice(rec?);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            RecordSource::Gbam(source) => source.read_record(buf),
+        }
+    }
+}
+
+/// Reads all records of GBAM file in stored order.
+pub struct GbamSource {
+    reader: GbamReader,
+    rec: GbamRecord,
+    rec_num: usize,
+}
+
+impl GbamSource {
+    /// Opens GBAM file as merge input.
+    pub fn open(file: File) -> io::Result<MergeInput> {
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let reader = GbamReader::new(file, template)?;
+        let sam_header = reader.file_meta.get_sam_header().to_vec();
+        let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+        let source = Self {
+            reader,
+            rec: GbamRecord::default(),
+            rec_num: 0,
+        };
+        Ok((RecordSource::Gbam(Box::new(source)), sam_header, ref_seqs))
+    }
+
+    fn read_record(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        if self.rec_num == self.reader.amount {
+            return Ok(false);
+        }
+        self.reader.fill_record(self.rec_num, &mut self.rec);
+        self.rec_num += 1;
+        self.rec.convert_to_bytes(buf);
+        // Drop `block_size`.
+        buf.drain(..4);
+        Ok(true)
+    }
+}
+
+/// Input of merge: records, header bytes laid out as in BAM file and
+/// reference sequences.
+pub type MergeInput = (RecordSource, Vec<u8>, RefSeqs);
+
+/// Order of records in sorted inputs and output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    /// By RNAME and POS, unmapped records without reference go last.
+    Coordinate,
+    /// By read name, then FLAG, so mates are adjacent with the first mate
+    /// going first. Same as `SortBy::NameAndMatchMates` of `bam_tools`
+    /// without hit count.
+    QueryName,
+}
+
+impl SortOrder {
+    /// Value of SO tag of @HD line.
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            SortOrder::Coordinate => "coordinate",
+            SortOrder::QueryName => "queryname",
+        }
+    }
+
+    pub(crate) fn key(&self, rec: &[u8]) -> SortKey {
+        match self {
+            SortOrder::Coordinate => {
+                let refid = LittleEndian::read_i32(&rec[REFID_OFFSET..]);
+                let pos = LittleEndian::read_i32(&rec[POS_OFFSET..]);
+                SortKey::Coordinate(refid as u32, pos)
+            }
+            SortOrder::QueryName => {
+                let rec = BAMRawRecord(Cow::Borrowed(rec));
+                let name = rec.get_bytes(&Fields::ReadName).to_vec();
+                let flag = LittleEndian::read_u16(&rec.0[FLAG_OFFSET..]);
+                SortKey::QueryName(name, flag)
+            }
+        }
+    }
+}
+
+/// Key of record in some `SortOrder`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum SortKey {
+    Coordinate(u32, i32),
+    QueryName(Vec<u8>, u16),
+}
+
+/// K-way merge of sorted inputs, coordinate sorted by default. Reference
+/// sequences and read groups of inputs are reconciled into a single header,
+/// records are rewritten to refer to it.
+pub struct MergeReader {
+    order: SortOrder,
+    sources: Vec<RecordSource>,
+    records: Vec<Vec<u8>>,
+    queue: BinaryHeap<Reverse<(SortKey, usize)>>,
+    header: MergedHeader,
+    record: Vec<u8>,
+}
+
+impl MergeReader {
+    /// Fails if headers of inputs can't be reconciled.
+    pub fn new(inputs: Vec<MergeInput>) -> io::Result<Self> {
+        Self::with_order(inputs, SortOrder::Coordinate)
+    }
+
+    /// Merges inputs sorted in `order`.
+    pub fn with_order(inputs: Vec<MergeInput>, order: SortOrder) -> io::Result<Self> {
+        let (sources, headers): (Vec<_>, Vec<_>) = inputs
+            .into_iter()
+            .map(|(source, header, ref_seqs)| (source, (header, ref_seqs)))
+            .unzip();
+        let header = MergedHeader::new(&headers, order)?;
+        let mut merge_reader = Self {
+            order,
+            records: vec![Vec::new(); sources.len()],
+            sources,
+            queue: BinaryHeap::new(),
+            header,
+            record: Vec::new(),
+        };
+        for input in 0..merge_reader.sources.len() {
+            merge_reader.advance(input)?;
+        }
+        Ok(merge_reader)
+    }
+
+    /// Header bytes laid out as in BAM file.
+    pub fn header(&self) -> &[u8] {
+        &self.header.bytes
+    }
+
+    pub fn ref_seqs(&self) -> &RefSeqs {
+        &self.header.ref_seqs
+    }
+
+    /// Returns next record of merged inputs. Fails if some input turns out
+    /// not to be sorted.
+    pub fn next_rec(&mut self) -> Option<io::Result<&[u8]>> {
+        let Reverse((_, input)) = self.queue.pop()?;
+        std::mem::swap(&mut self.record, &mut self.records[input]);
+        if let Err(e) = self.advance(input) {
+            return Some(Err(e));
+        }
+        Some(Ok(&self.record))
+    }
+
+    /// Reads next record of `input` and queues it.
+    fn advance(&mut self, input: usize) -> io::Result<()> {
+        let rec = &mut self.records[input];
+        if !self.sources[input].read_record(rec)? {
+            return Ok(());
+        }
+        self.header.rewrite_record(input, rec);
+        let key = self.order.key(rec);
+        if !self.record.is_empty() && key < self.order.key(&self.record) {
+            return Err(invalid_sam(format!(
+                "Input {} is not {} sorted.",
+                input + 1,
+                self.order.header_value()
+            )));
+        }
+        self.queue.push(Reverse((key, input)));
+        Ok(())
+    }
+}
+
+/// Header reconciled from headers of all inputs. Reference sequences of the
+/// first input go first, other inputs may add new ones, but have to list
+/// common ones in the same order and with the same lengths. Read groups
+/// with the same ID and different lines are renamed.
+pub(crate) struct MergedHeader {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) ref_seqs: RefSeqs,
+    /// New reference ID of every reference ID of every input.
+    ref_maps: Vec<Vec<i32>>,
+    /// Renamed read groups of every input.
+    rg_renames: Vec<HashMap<String, String>>,
+}
+
+impl MergedHeader {
+    pub(crate) fn new(headers: &[(Vec<u8>, RefSeqs)], order: SortOrder) -> io::Result<Self> {
+        let mut ref_seqs: RefSeqs = Vec::new();
+        let mut ref_ids = HashMap::new();
+        let mut ref_maps = Vec::new();
+        for (_, input_ref_seqs) in headers {
+            let mut ref_map = Vec::new();
+            for (name, len) in input_ref_seqs {
+                let id = *ref_ids.entry(name.clone()).or_insert_with(|| {
+                    ref_seqs.push((name.clone(), *len));
+                    ref_seqs.len() as i32 - 1
+                });
+                if ref_seqs[id as usize].1 != *len {
+                    return Err(invalid_sam(format!("Inputs differ in length of reference {}.", name)));
+                }
+                if ref_map.last().is_some_and(|&last| last > id) {
+                    return Err(invalid_sam("Inputs list references in different order.".to_owned()));
+                }
+                ref_map.push(id);
+            }
+            ref_maps.push(ref_map);
+        }
+
+        let mut text = format!("@HD\tVN:1.6\tSO:{}\n", order.header_value());
+        for (name, len) in &ref_seqs {
+            text.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", name, len));
+        }
+        let mut read_groups: HashMap<String, String> = HashMap::new();
+        let mut rg_renames = Vec::new();
+        let mut other_lines: Vec<String> = Vec::new();
+        for (input, (bytes, _)) in headers.iter().enumerate() {
+            let mut renames = HashMap::new();
+            for line in header_text(bytes).lines() {
+                if line.starts_with("@RG\t") {
+                    let id = line
+                        .split('\t')
+                        .find_map(|field| field.strip_prefix("ID:"))
+                        .ok_or_else(|| invalid_sam(format!("@RG line without ID: {}", line)))?;
+                    let mut new_id = id.to_owned();
+                    let mut new_line = line.to_owned();
+                    let mut suffix = input;
+                    while read_groups.get(&new_id).is_some_and(|known| *known != new_line) {
+                        new_id = format!("{}-{}", id, suffix);
+                        new_line = line.replacen(&format!("\tID:{}", id), &format!("\tID:{}", new_id), 1);
+                        suffix += 1;
+                    }
+                    if new_id != id {
+                        renames.insert(id.to_owned(), new_id.clone());
+                    }
+                    if read_groups.insert(new_id, new_line.clone()).is_none() {
+                        text.push_str(&new_line);
+                        text.push('\n');
+                    }
+                } else if (line.starts_with("@PG\t") || line.starts_with("@CO\t"))
+                    && !other_lines.iter().any(|known| known == line)
+                {
+                    other_lines.push(line.to_owned());
+                }
+            }
+            rg_renames.push(renames);
+        }
+        for line in other_lines {
+            text.push_str(&line);
+            text.push('\n');
+        }
+
+        Ok(Self {
+            bytes: header_bytes(&text, &ref_seqs)?,
+            ref_seqs,
+            ref_maps,
+            rg_renames,
+        })
+    }
+
+    /// Rewrites reference IDs and read group of record of `input`.
+    fn rewrite_record(&self, input: usize, rec: &mut Vec<u8>) {
+        let ref_map = &self.ref_maps[input];
+        for offset in [REFID_OFFSET, NEXT_REFID_OFFSET] {
+            let refid = LittleEndian::read_i32(&rec[offset..]);
+            if refid >= 0 {
+                LittleEndian::write_i32(&mut rec[offset..], ref_map[refid as usize]);
+            }
+        }
+        if !self.rg_renames[input].is_empty() {
+            rename_read_group(rec, &self.rg_renames[input]);
+        }
+    }
+
+    /// Same as `rewrite_record`, for decoded record.
+    pub(crate) fn rewrite_gbam_record(&self, input: usize, rec: &mut GbamRecord) {
+        let ref_map = &self.ref_maps[input];
+        for refid in rec.refid.iter_mut().chain(rec.next_ref_id.iter_mut()) {
+            if *refid >= 0 {
+                *refid = ref_map[*refid as usize];
+            }
+        }
+        if let (Some(tags), false) = (rec.tags.as_mut(), self.rg_renames[input].is_empty()) {
+            rename_tags_read_group(tags, 0, &self.rg_renames[input]);
+        }
+    }
+}
+
+/// Text of header bytes laid out as in BAM file.
+pub(crate) fn header_text(bytes: &[u8]) -> Cow<'_, str> {
+    let l_text = LittleEndian::read_u32(bytes) as usize;
+    String::from_utf8_lossy(bytes[4..4 + l_text].split(|&c| c == 0).next().unwrap())
+}
+
+/// Size of value of auxiliary field of type `val_type` at the start of
+/// `value`.
+pub(crate) fn tag_value_size(val_type: u8, value: &[u8]) -> usize {
+    match val_type {
+        b'A' | b'c' | b'C' => 1,
+        b's' | b'S' => 2,
+        b'i' | b'I' | b'f' => 4,
+        b'Z' | b'H' => value.iter().position(|&c| c == 0).map_or(value.len(), |len| len + 1),
+        b'B' => 5 + tag_value_size(value[0], &value[5..]) * LittleEndian::read_u32(&value[1..]) as usize,
+        _ => value.len(),
+    }
+}
+
+/// Replaces value of RG:Z field of raw record according to `renames`.
+fn rename_read_group(rec: &mut Vec<u8>, renames: &HashMap<String, String>) {
+    let tags_len = BAMRawRecord(Cow::Borrowed(&rec[..])).get_bytes(&Fields::RawTags).len();
+    let start = rec.len() - tags_len;
+    rename_tags_read_group(rec, start, renames);
+}
+
+/// Replaces value of RG:Z field of auxiliary fields starting at `start` of
+/// `rec` and running to its end.
+fn rename_tags_read_group(rec: &mut Vec<u8>, start: usize, renames: &HashMap<String, String>) {
+    let mut offset = start;
+    while offset + 3 <= rec.len() {
+        let val_type = rec[offset + 2];
+        let value = offset + 3;
+        let size = tag_value_size(val_type, &rec[value..]);
+        if &rec[offset..offset + 2] == b"RG" && val_type == b'Z' {
+            let id = String::from_utf8_lossy(&rec[value..value + size - 1]);
+            if let Some(new_id) = renames.get(id.as_ref()) {
+                let mut new_value = new_id.clone().into_bytes();
+                new_value.push(0);
+                rec.splice(value..value + size, new_value);
+            }
+            return;
+        }
+        offset = value + size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sam_input(sam: &str) -> MergeInput {
+        let mut reader = SamReader::new(Box::new(io::Cursor::new(sam.to_owned())) as Box<dyn BufRead>);
+        let (header, ref_seqs) = reader.read_header().unwrap();
+        (RecordSource::Sam(reader), header, ref_seqs)
+    }
+
+    #[test]
+    fn test_merge_sorted_inputs() {
+        let first = sam_input(
+            "@SQ\tSN:chr1\tLN:100\n@SQ\tSN:chr2\tLN:100\n@RG\tID:a\tSM:x\n\
+a1\t0\tchr1\t5\t0\t*\t*\t0\t0\tA\t*\tRG:Z:a\n\
+a2\t0\tchr2\t1\t0\t*\tchr1\t3\t0\tA\t*\tRG:Z:a\n\
+a3\t4\t*\t0\t0\t*\t*\t0\t0\tA\t*\tRG:Z:a\n",
+        );
+        let second = sam_input(
+            "@SQ\tSN:chr2\tLN:100\n@SQ\tSN:chr3\tLN:50\n@RG\tID:a\tSM:y\n\
+b2\t0\tchr2\t1\t0\t*\t=\t7\t0\tA\t*\tXX:B:c,1,2\tRG:Z:a\n\
+b1\t0\tchr3\t9\t0\t*\t*\t0\t0\tA\t*\tRG:Z:a\tNM:i:0\n",
+        );
+        let mut merge_reader = MergeReader::new(vec![first, second]).unwrap();
+        let ref_seqs: Vec<_> = merge_reader.ref_seqs().iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(ref_seqs, vec!["chr1", "chr2", "chr3"]);
+        let text = header_text(merge_reader.header()).into_owned();
+        assert!(text.contains("@RG\tID:a\tSM:x\n@RG\tID:a-1\tSM:y\n"));
+
+        let mut merged = Vec::new();
+        while let Some(rec) = merge_reader.next_rec() {
+            let rec = BAMRawRecord(Cow::Owned(rec.unwrap().to_vec()));
+            merged.push((
+                String::from_utf8_lossy(rec.get_bytes(&Fields::ReadName)).into_owned(),
+                LittleEndian::read_i32(rec.get_bytes(&Fields::RefID)),
+                LittleEndian::read_i32(rec.get_bytes(&Fields::NextRefID)),
+                rec.get_bytes(&Fields::RawTags).to_vec(),
+            ));
+        }
+        let names: Vec<_> = merged.iter().map(|rec| rec.0.as_str()).collect();
+        assert_eq!(names, vec!["a1\0", "a2\0", "b2\0", "b1\0", "a3\0"]);
+        assert_eq!((merged[1].1, merged[1].2), (1, 0));
+        assert_eq!((merged[2].1, merged[2].2), (1, 1));
+        assert_eq!(merged[3].1, 2);
+        assert_eq!(merged[3].3, b"RGZa-1\0NMC\0");
+        assert_eq!(merged[2].3, b"XXBc\x02\0\0\0\x01\x02RGZa-1\0");
+        assert_eq!(merged[4].3, b"RGZa\0");
+
+        let unsorted = sam_input(
+            "@SQ\tSN:chr1\tLN:100\n\
+r1\t0\tchr1\t5\t0\t*\t*\t0\t0\tA\t*\n\
+r2\t0\tchr1\t4\t0\t*\t*\t0\t0\tA\t*\n",
+        );
+        let mut merge_reader = MergeReader::new(vec![unsorted]).unwrap();
+        assert!(merge_reader.next_rec().unwrap().is_err());
+
+        let reordered = sam_input("@SQ\tSN:chr2\tLN:100\n@SQ\tSN:chr1\tLN:100\n");
+        let first = sam_input("@SQ\tSN:chr1\tLN:100\n@SQ\tSN:chr2\tLN:100\n");
+        assert!(MergeReader::new(vec![first, reordered]).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Error, ErrorKind};
+
+const CIGAR_OPS: &[u8] = b"MIDNSHP=X";
+const SEQ_CODES: &[u8] = b"=ACMGRSVTWYHKDBN";
+
+/// Names and lengths of reference sequences.
+pub type RefSeqs = Vec<(String, u32)>;
+
+pub(crate) fn invalid_sam(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn parse_num<T: std::str::FromStr>(field: &str, name: &str) -> io::Result<T> {
+    field
+        .parse()
+        .map_err(|_| invalid_sam(format!("Invalid {}: {}", name, field)))
+}
+
+/// Reads SAM text and converts records into raw BAM records, so they go
+/// through the same pipeline as records of BAM files.
+pub struct SamReader<R: BufRead> {
+    inner: R,
+    line: String,
+    record: Vec<u8>,
+    ref_ids: HashMap<String, i32>,
+}
+
+impl<R: BufRead> SamReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line: String::new(),
+            record: Vec::new(),
+            ref_ids: HashMap::new(),
+        }
+    }
+
+    /// Consumes header lines. Returns header bytes laid out as in BAM file
+    /// (see `bam_tools::Reader::read_header`) and reference sequences taken
+    /// from @SQ lines.
+    pub fn read_header(&mut self) -> io::Result<(Vec<u8>, RefSeqs)> {
+        let mut text = String::new();
+        let mut ref_seqs = Vec::new();
+        while self.inner.fill_buf()?.first() == Some(&b'@') {
+            self.line.clear();
+            self.inner.read_line(&mut self.line)?;
+            let line = self.line.trim_end_matches(['\n', '\r']);
+            if line.starts_with("@SQ\t") {
+                let tag = |key: &str| {
+                    line.split('\t')
+                        .find_map(|field| field.strip_prefix(key))
+                        .ok_or_else(|| invalid_sam(format!("@SQ line without {}: {}", key, line)))
+                };
+                let name = tag("SN:")?.to_owned();
+                let len = parse_num(tag("LN:")?, "reference length")?;
+                self.ref_ids.insert(name.clone(), ref_seqs.len() as i32);
+                ref_seqs.push((name, len));
+            }
+            text.push_str(line);
+            text.push('\n');
+        }
+
+        Ok((header_bytes(&text, &ref_seqs)?, ref_seqs))
+    }
+
+    /// Reads next alignment line and returns it as raw BAM record (without
+    /// `block_size`). Header has to be read first.
+    pub fn next_rec(&mut self) -> Option<io::Result<&[u8]>> {
+        loop {
+            self.line.clear();
+            match self.inner.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            if !self.line.trim_end().is_empty() {
+                break;
+            }
+        }
+        let line = self.line.trim_end_matches(['\n', '\r']);
+        match encode_record(line, &self.ref_ids, &mut self.record) {
+            Ok(()) => Some(Ok(&self.record)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Lays out header text and reference sequences as in BAM file.
+pub(crate) fn header_bytes(text: &str, ref_seqs: &RefSeqs) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    bytes.write_u32::<LittleEndian>(text.len() as u32)?;
+    bytes.extend_from_slice(text.as_bytes());
+    bytes.write_u32::<LittleEndian>(ref_seqs.len() as u32)?;
+    for (name, len) in ref_seqs {
+        bytes.write_u32::<LittleEndian>(name.len() as u32 + 1)?;
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.write_u32::<LittleEndian>(*len)?;
+    }
+    Ok(bytes)
+}
+
+fn ref_id(name: &str, ref_ids: &HashMap<String, i32>) -> io::Result<i32> {
+    match name {
+        "*" => Ok(-1),
+        _ => ref_ids
+            .get(name)
+            .copied()
+            .ok_or_else(|| invalid_sam(format!("Reference {} is not in header.", name))),
+    }
+}
+
+/// Encodes SAM line into `dest` as raw BAM record.
+fn encode_record(line: &str, ref_ids: &HashMap<String, i32>, dest: &mut Vec<u8>) -> io::Result<()> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 11 {
+        return Err(invalid_sam(format!("Expected at least 11 fields: {}", line)));
+    }
+    let read_name = fields[0];
+    let flag: u16 = parse_num(fields[1], "FLAG")?;
+    let refid = ref_id(fields[2], ref_ids)?;
+    let pos = parse_num::<i32>(fields[3], "POS")? - 1;
+    let mapq: u8 = parse_num(fields[4], "MAPQ")?;
+    let cigar = parse_cigar(fields[5])?;
+    let next_refid = match fields[6] {
+        "=" => refid,
+        name => ref_id(name, ref_ids)?,
+    };
+    let next_pos = parse_num::<i32>(fields[7], "PNEXT")? - 1;
+    let tlen: i32 = parse_num(fields[8], "TLEN")?;
+    let seq = match fields[9] {
+        "*" => "",
+        seq => seq,
+    };
+    let qual = fields[10];
+    if qual != "*" && qual.len() != seq.len() {
+        return Err(invalid_sam(format!("SEQ and QUAL lengths differ: {}", line)));
+    }
+    if read_name.len() > 254 {
+        return Err(invalid_sam(format!("Read name is too long: {}", read_name)));
+    }
+    if cigar.len() > u16::MAX as usize {
+        return Err(invalid_sam(format!("Too many CIGAR operations: {}", read_name)));
+    }
+
+    let ref_len: u32 = cigar
+        .iter()
+        .filter(|&&op| matches!(op & 0xF, 0 | 2 | 3 | 7 | 8))
+        .map(|op| op >> 4)
+        .sum();
+    let end = pos + std::cmp::max(ref_len, 1) as i32;
+
+    dest.clear();
+    dest.write_i32::<LittleEndian>(refid)?;
+    dest.write_i32::<LittleEndian>(pos)?;
+    dest.write_u8(read_name.len() as u8 + 1)?;
+    dest.write_u8(mapq)?;
+    dest.write_u16::<LittleEndian>(reg2bin(pos, end))?;
+    dest.write_u16::<LittleEndian>(cigar.len() as u16)?;
+    dest.write_u16::<LittleEndian>(flag)?;
+    dest.write_u32::<LittleEndian>(seq.len() as u32)?;
+    dest.write_i32::<LittleEndian>(next_refid)?;
+    dest.write_i32::<LittleEndian>(next_pos)?;
+    dest.write_i32::<LittleEndian>(tlen)?;
+    dest.extend_from_slice(read_name.as_bytes());
+    dest.push(0);
+    for op in &cigar {
+        dest.write_u32::<LittleEndian>(*op)?;
+    }
+    let qual = match qual {
+        "*" => None,
+        qual => Some(qual.as_bytes()),
+    };
+    write_seq_qual(seq.as_bytes(), qual, dest);
+    for tag in &fields[11..] {
+        encode_tag(tag, dest)?;
+    }
+    Ok(())
+}
+
+/// Appends 4-bit packed `seq` and Phred+33 `qual` decoded to raw qualities.
+/// Missing qualities are stored as 0xFF.
+pub(crate) fn write_seq_qual(seq: &[u8], qual: Option<&[u8]>, dest: &mut Vec<u8>) {
+    for pair in seq.chunks(2) {
+        let code = |base: u8| {
+            SEQ_CODES
+                .iter()
+                .position(|&c| c == base.to_ascii_uppercase())
+                .unwrap_or(15) as u8
+        };
+        let low = pair.get(1).map_or(0, |&base| code(base));
+        dest.push(code(pair[0]) << 4 | low);
+    }
+    match qual {
+        Some(qual) => dest.extend(qual.iter().map(|q| q.wrapping_sub(33))),
+        None => dest.resize(dest.len() + seq.len(), 0xFF),
+    }
+}
+
+fn parse_cigar(cigar: &str) -> io::Result<Vec<u32>> {
+    let mut ops = Vec::new();
+    if cigar == "*" {
+        return Ok(ops);
+    }
+    let mut len = 0u32;
+    for c in cigar.bytes() {
+        match c {
+            b'0'..=b'9' => len = len * 10 + (c - b'0') as u32,
+            _ => {
+                let op = CIGAR_OPS
+                    .iter()
+                    .position(|&op| op == c)
+                    .ok_or_else(|| invalid_sam(format!("Invalid CIGAR: {}", cigar)))?;
+                ops.push(len << 4 | op as u32);
+                len = 0;
+            }
+        }
+    }
+    Ok(ops)
+}
+
+/// Smallest integer type holding `value`, same choice as samtools makes.
+fn write_int(value: i64, dest: &mut Vec<u8>) -> io::Result<()> {
+    if value < 0 {
+        if value >= i8::MIN as i64 {
+            dest.push(b'c');
+            dest.write_i8(value as i8)
+        } else if value >= i16::MIN as i64 {
+            dest.push(b's');
+            dest.write_i16::<LittleEndian>(value as i16)
+        } else {
+            dest.push(b'i');
+            dest.write_i32::<LittleEndian>(value as i32)
+        }
+    } else if value <= u8::MAX as i64 {
+        dest.push(b'C');
+        dest.write_u8(value as u8)
+    } else if value <= u16::MAX as i64 {
+        dest.push(b'S');
+        dest.write_u16::<LittleEndian>(value as u16)
+    } else {
+        dest.push(b'I');
+        dest.write_u32::<LittleEndian>(value as u32)
+    }
+}
+
+fn write_array_item(subtype: u8, item: &str, dest: &mut Vec<u8>) -> io::Result<()> {
+    match subtype {
+        b'c' => dest.write_i8(parse_num(item, "array item")?),
+        b'C' => dest.write_u8(parse_num(item, "array item")?),
+        b's' => dest.write_i16::<LittleEndian>(parse_num(item, "array item")?),
+        b'S' => dest.write_u16::<LittleEndian>(parse_num(item, "array item")?),
+        b'i' => dest.write_i32::<LittleEndian>(parse_num(item, "array item")?),
+        b'I' => dest.write_u32::<LittleEndian>(parse_num(item, "array item")?),
+        b'f' => dest.write_f32::<LittleEndian>(parse_num(item, "array item")?),
+        _ => Err(invalid_sam(format!("Invalid array type: {}", subtype as char))),
+    }
+}
+
+/// Encodes `TAG:TYPE:VALUE` auxiliary field.
+fn encode_tag(tag: &str, dest: &mut Vec<u8>) -> io::Result<()> {
+    let bytes = tag.as_bytes();
+    if bytes.len() < 5 || bytes[2] != b':' || bytes[4] != b':' {
+        return Err(invalid_sam(format!("Invalid tag: {}", tag)));
+    }
+    let value = &tag[5..];
+    dest.extend_from_slice(&bytes[..2]);
+    match bytes[3] {
+        b'A' => {
+            dest.push(b'A');
+            dest.push(*value.as_bytes().first().unwrap_or(&0));
+        }
+        b'i' => write_int(parse_num(value, "integer tag")?, dest)?,
+        b'f' => {
+            dest.push(b'f');
+            dest.write_f32::<LittleEndian>(parse_num(value, "float tag")?)?;
+        }
+        b'Z' | b'H' => {
+            dest.push(bytes[3]);
+            dest.extend_from_slice(value.as_bytes());
+            dest.push(0);
+        }
+        b'B' => {
+            let mut items = value.split(',');
+            let subtype = items.next().unwrap_or("").as_bytes();
+            if subtype.len() != 1 {
+                return Err(invalid_sam(format!("Invalid array tag: {}", tag)));
+            }
+            let items: Vec<&str> = items.collect();
+            dest.push(b'B');
+            dest.push(subtype[0]);
+            dest.write_u32::<LittleEndian>(items.len() as u32)?;
+            for item in items {
+                write_array_item(subtype[0], item, dest)?;
+            }
+        }
+        _ => return Err(invalid_sam(format!("Invalid tag type: {}", tag))),
+    }
+    Ok(())
+}
+
+/// Bin of 0-based [beg, end) interval as defined in SAM specification.
+pub(crate) fn reg2bin(beg: i32, end: i32) -> u16 {
+    let end = end - 1;
+    for (shift, offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+        if beg >> shift == end >> shift {
+            return (offset + (beg >> shift)) as u16;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bam_tools::parse_reference_sequences;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use bam_tools::record::fields::Fields;
+    use std::borrow::Cow;
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n\
+@SQ\tSN:chr1\tLN:1000\n\
+@SQ\tSN:chr2\tLN:2000\n\
+r1\t99\tchr1\t100\t60\t5M2I3M\t=\t300\t210\tACGTNACGTA\tIIIII#####\tNM:i:2\tXS:i:-3\tMD:Z:8\tZB:B:S,1,300\n\
+r2\t4\t*\t0\t0\t*\t*\t0\t0\tAC\t*\n";
+
+    #[test]
+    fn test_sam_to_raw_records() {
+        let mut reader = SamReader::new(SAM.as_bytes());
+        let (header, ref_seqs) = reader.read_header().unwrap();
+        assert_eq!(ref_seqs, vec![("chr1".to_owned(), 1000), ("chr2".to_owned(), 2000)]);
+        let text_len = 4 + SAM.lines().take(3).map(|l| l.len() + 1).sum::<usize>();
+        assert_eq!(parse_reference_sequences(&header[text_len..]).unwrap(), ref_seqs);
+
+        let rec = BAMRawRecord(Cow::Owned(reader.next_rec().unwrap().unwrap().to_vec()));
+        assert_eq!(rec.get_bytes(&Fields::RefID), &0i32.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::Pos), &99i32.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::NextPos), &299i32.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::Bin), &reg2bin(99, 107).to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::ReadName), b"r1\0");
+        assert_eq!(rec.get_bytes(&Fields::RawSequence), &[0x12, 0x48, 0xF1, 0x24, 0x81]);
+        assert_eq!(rec.get_bytes(&Fields::RawQual), b"(((((\x02\x02\x02\x02\x02");
+        let mut tags = b"NMC\x02XSc\xFDMDZ8\0ZBBS\x02\0\0\0\x01\0".to_vec();
+        tags.extend_from_slice(&300u16.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::RawTags), &tags[..]);
+
+        let rec = BAMRawRecord(Cow::Owned(reader.next_rec().unwrap().unwrap().to_vec()));
+        assert_eq!(rec.get_bytes(&Fields::RefID), &(-1i32).to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::Bin), &4680u16.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::RawQual), &[0xFF, 0xFF]);
+        assert!(reader.next_rec().is_none());
+
+        let mut reader = SamReader::new("@SQ\tSN:chr1\tLN:10\nr\t0\tchr3\t1\t0\t*\t*\t0\t0\t*\t*\n".as_bytes());
+        reader.read_header().unwrap();
+        assert!(reader.next_rec().unwrap().is_err());
+    }
+}
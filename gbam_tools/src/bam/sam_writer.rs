@@ -0,0 +1,232 @@
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use super::gbam_to_bam::for_each_record;
+use super::sam_reader::invalid_sam;
+use crate::reader::{expr::FilterExpr, reader::Reader, record::GbamRecord};
+
+/// Formats GBAM records as SAM text lines. Fields are written straight to
+/// inner writer as they are decoded, without building the line first.
+/// Records must have all fields fetched.
+pub struct SamWriter<W: Write> {
+    inner: W,
+    ref_names: Vec<String>,
+}
+
+impl<W: Write> SamWriter<W> {
+    pub fn new(inner: W, ref_seqs: &[(String, u32)]) -> Self {
+        Self {
+            inner,
+            ref_names: ref_seqs.iter().map(|(name, _)| name.clone()).collect(),
+        }
+    }
+
+    /// Writes text of header laid out as in BAM file (see
+    /// `FileMeta::get_sam_header`). @SQ lines are made from `ref_seqs` if
+    /// text has none, as `samtools view -h` does.
+    pub fn write_header(&mut self, sam_header: &[u8], ref_seqs: &[(String, u32)]) -> io::Result<()> {
+        let mut bytes = sam_header;
+        let l_text = bytes.read_i32::<LittleEndian>()? as usize;
+        let text = bytes
+            .get(..l_text)
+            .ok_or_else(|| invalid_sam("Header text is cut short.".to_owned()))?;
+        let text = &text[..text.iter().position(|&c| c == 0).unwrap_or(text.len())];
+        self.inner.write_all(text)?;
+        if !text.is_empty() && !text.ends_with(b"\n") {
+            self.inner.write_all(b"\n")?;
+        }
+        let has_sq = text.starts_with(b"@SQ\t") || text.windows(5).any(|line| line == b"\n@SQ\t");
+        if !has_sq {
+            for (name, len) in ref_seqs {
+                writeln!(self.inner, "@SQ\tSN:{}\tLN:{}", name, len)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_record(&mut self, rec: &GbamRecord) -> io::Result<()> {
+        let missing = || invalid_sam("Record lacks fields needed for SAM.".to_owned());
+        let out = &mut self.inner;
+        out.write_all(rec.name().ok_or_else(missing)?)?;
+        let ref_id = rec.refid.ok_or_else(missing)?;
+        let next_ref_id = rec.next_ref_id.ok_or_else(missing)?;
+        write!(out, "\t{}\t", rec.flag.ok_or_else(missing)?)?;
+        write_ref_name(out, &self.ref_names, ref_id)?;
+        write!(out, "\t{}\t{}\t", i64::from(rec.pos.ok_or_else(missing)?) + 1, rec.mapq.ok_or_else(missing)?)?;
+        match rec.cigar.as_ref().ok_or_else(missing)? {
+            cigar if cigar.0.is_empty() => out.write_all(b"*")?,
+            cigar => write!(out, "{}", cigar)?,
+        }
+        out.write_all(b"\t")?;
+        if next_ref_id >= 0 && next_ref_id == ref_id {
+            out.write_all(b"=")?;
+        } else {
+            write_ref_name(out, &self.ref_names, next_ref_id)?;
+        }
+        write!(out, "\t{}\t{}\t", i64::from(rec.next_pos.ok_or_else(missing)?) + 1, rec.tlen.ok_or_else(missing)?)?;
+        match rec.seq.as_deref().ok_or_else(missing)? {
+            "" => out.write_all(b"*")?,
+            seq => out.write_all(seq.as_bytes())?,
+        }
+        out.write_all(b"\t")?;
+        match rec.qual.as_deref().ok_or_else(missing)? {
+            qual if qual.first().is_none_or(|&q| q == 0xff) => out.write_all(b"*")?,
+            qual => {
+                for &q in qual {
+                    out.write_all(&[q + 33])?;
+                }
+            }
+        }
+        write_tags(out, rec.tags.as_deref().ok_or_else(missing)?)?;
+        out.write_all(b"\n")
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+fn write_ref_name<W: Write>(out: &mut W, ref_names: &[String], ref_id: i32) -> io::Result<()> {
+    if ref_id < 0 {
+        return out.write_all(b"*");
+    }
+    let name = ref_names
+        .get(ref_id as usize)
+        .ok_or_else(|| invalid_sam(format!("Reference ID {} is out of header.", ref_id)))?;
+    out.write_all(name.as_bytes())
+}
+
+/// Writes auxiliary fields of BAM record as SAM `\tTG:type:value` fields.
+/// Integers of any width are written with type `i`.
+fn write_tags<W: Write>(out: &mut W, mut tags: &[u8]) -> io::Result<()> {
+    while !tags.is_empty() {
+        if tags.len() < 3 {
+            return Err(invalid_sam("Tags are cut short.".to_owned()));
+        }
+        let (tag, val_type) = (&tags[..2], tags[2]);
+        tags = &tags[3..];
+        out.write_all(b"\t")?;
+        out.write_all(tag)?;
+        match val_type {
+            b'A' => {
+                out.write_all(b":A:")?;
+                out.write_all(&[tags.read_u8()?])?;
+            }
+            b'Z' | b'H' => {
+                let end = tags
+                    .iter()
+                    .position(|&c| c == 0)
+                    .ok_or_else(|| invalid_sam("String tag without NUL.".to_owned()))?;
+                write!(out, ":{}:", val_type as char)?;
+                out.write_all(&tags[..end])?;
+                tags = &tags[end + 1..];
+            }
+            b'f' => write!(out, ":f:{}", tags.read_f32::<LittleEndian>()?)?,
+            b'B' => {
+                let subtype = tags.read_u8()?;
+                let len = tags.read_u32::<LittleEndian>()?;
+                write!(out, ":B:{}", subtype as char)?;
+                for _ in 0..len {
+                    out.write_all(b",")?;
+                    if subtype == b'f' {
+                        write!(out, "{}", tags.read_f32::<LittleEndian>()?)?;
+                    } else {
+                        write!(out, "{}", read_int(&mut tags, subtype)?)?;
+                    }
+                }
+            }
+            int_type => write!(out, ":i:{}", read_int(&mut tags, int_type)?)?,
+        }
+    }
+    Ok(())
+}
+
+fn read_int(bytes: &mut &[u8], int_type: u8) -> io::Result<i64> {
+    Ok(match int_type {
+        b'c' => i64::from(bytes.read_i8()?),
+        b'C' => i64::from(bytes.read_u8()?),
+        b's' => i64::from(bytes.read_i16::<LittleEndian>()?),
+        b'S' => i64::from(bytes.read_u16::<LittleEndian>()?),
+        b'i' => i64::from(bytes.read_i32::<LittleEndian>()?),
+        b'I' => i64::from(bytes.read_u32::<LittleEndian>()?),
+        _ => return Err(invalid_sam(format!("Unknown tag type {}.", int_type as char))),
+    })
+}
+
+/// Writes SAM text of records overlapping any of `regions` in turn, or of
+/// all records if there are no regions, which match `filter`, preceded by
+/// header if `header`. Invalid region fails before anything is written.
+pub fn gbam_to_sam<W: Write>(
+    reader: &mut Reader,
+    regions: &[String],
+    filter: Option<&FilterExpr>,
+    header: bool,
+    out: W,
+) -> io::Result<W> {
+    for region in regions {
+        reader.fetch(region)?;
+    }
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    let mut writer = SamWriter::new(out, &ref_seqs);
+    if header {
+        writer.write_header(reader.file_meta.get_sam_header(), &ref_seqs)?;
+    }
+    for_each_record(reader, regions, filter, |rec| writer.write_record(rec))?;
+    Ok(writer.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::Codecs;
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_gbam_to_sam() {
+        let header = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:chr2\tLN:5000\n@PG\tID:test\tPN:test\n";
+        let lines = [
+            "r1\t99\tchr1\t100\t60\t3S5M1I2D4M\t=\t300\t210\tAACCGGTTACGTA\tIIIIIIIIIIII#\tNM:i:3\tRG:Z:grp1\tXf:f:1.5\tXa:A:q",
+            "r2\t163\tchr1\t120\t0\t5M\tchr2\t1\t-5\tACGTN\t*\tXb:B:s,-1,200,3\tXh:H:1AE301\tXc:i:-100000\tXd:i:4000000000",
+            "r3\t4\tchr2\t50\t0\t*\t*\t0\t0\tACG\t!!!",
+            "r4\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*",
+        ];
+        let sam = format!("{}{}\n", header, lines.join("\n"));
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("gbam_to_sam").unwrap();
+        let path = dir.path().join("in.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Gzip], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+        let out = gbam_to_sam(&mut reader, &[], None, true, Vec::new()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), sam);
+
+        let out = gbam_to_sam(&mut reader, &["chr1:121-130".to_owned()], None, false, Vec::new()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", lines[1]));
+        assert!(gbam_to_sam(&mut reader, &["chr3".to_owned()], None, true, Vec::new()).is_err());
+        let filter = FilterExpr::parse("mapq == 0 && !(flag & 0x4) || [NM] > 2", reader.file_meta.get_ref_seqs()).unwrap();
+        let out = gbam_to_sam(&mut reader, &[], Some(&filter), false, Vec::new()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n{}\n", lines[0], lines[1]));
+        let out = gbam_to_sam(&mut reader, &["chr1".to_owned(), "chr2".to_owned()], Some(&filter), false, Vec::new()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n{}\n", lines[0], lines[1]));
+
+        // Header without @SQ lines gets them from reference sequences.
+        let mut writer = SamWriter::new(Vec::new(), &[]);
+        let text = b"@HD\tVN:1.6";
+        let mut bam_header = (text.len() as i32).to_le_bytes().to_vec();
+        bam_header.extend_from_slice(text);
+        writer.write_header(&bam_header, &[("chrM".to_owned(), 16569)]).unwrap();
+        assert_eq!(writer.into_inner(), b"@HD\tVN:1.6\n@SQ\tSN:chrM\tLN:16569\n");
+    }
+}
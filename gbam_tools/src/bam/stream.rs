@@ -0,0 +1,174 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::thread;
+
+use flate2::Compression;
+use flume::Sender;
+
+use super::bgzf::BgzfWriter;
+use super::gbam_to_bam::for_each_record;
+use super::sam_writer::gbam_to_sam;
+use crate::reader::{expr::FilterExpr, reader::Reader};
+
+/// Size of encoded chunks handed over to output, and their number waiting
+/// for it.
+const CHUNK_SIZE: usize = 256 * 1024;
+const CHUNKS_QUEUE: usize = 8;
+
+/// What `stream_records` writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// SAM text with header.
+    Sam,
+    /// BAM in BGZF blocks stored without compression, as `samtools view -u`
+    /// writes it, so tools which read BAM only as BGZF take it.
+    UncompressedBam,
+}
+
+/// Writes records overlapping any of `regions` in turn, or all records if
+/// there are no regions, which match `filter`, to `out` while they are
+/// decoded, e.g. to pipe them into variant caller without intermediate file.
+/// Records are encoded on another thread in chunks, at most `CHUNKS_QUEUE`
+/// of which wait for slow output, so memory in use doesn't grow with file.
+/// `out` is only written to, never seeked. Decoding stops once writing
+/// fails (e.g. with BrokenPipe if reader of pipe exits), and the error is
+/// returned. Invalid region fails before anything is written.
+pub fn stream_records<W: Write>(
+    reader: &mut Reader,
+    regions: &[String],
+    filter: Option<&FilterExpr>,
+    format: StreamFormat,
+    mut out: W,
+) -> Result<W> {
+    for region in regions {
+        reader.fetch(region)?;
+    }
+    let (tx, rx) = flume::bounded(CHUNKS_QUEUE);
+    thread::scope(|scope| {
+        let encoder = scope.spawn(move || {
+            let mut chunks = ChunkSender { tx, chunk: Vec::with_capacity(CHUNK_SIZE) };
+            encode(reader, regions, filter, format, &mut chunks).and_then(|_| chunks.flush())
+        });
+        let written = rx.iter().try_for_each(|chunk| out.write_all(&chunk));
+        // Encoder waiting for output stops once channel is closed.
+        drop(rx);
+        let encoded = encoder.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        written?;
+        encoded?;
+        out.flush()
+    })?;
+    Ok(out)
+}
+
+fn encode<W: Write>(reader: &mut Reader, regions: &[String], filter: Option<&FilterExpr>, format: StreamFormat, out: W) -> Result<()> {
+    match format {
+        StreamFormat::Sam => gbam_to_sam(reader, regions, filter, true, out).map(drop),
+        StreamFormat::UncompressedBam => {
+            let mut bgzf = BgzfWriter::new(out, Compression::none());
+            bgzf.write_all(b"BAM\x01")?;
+            bgzf.write_all(reader.file_meta.get_sam_header())?;
+            let mut buf = Vec::new();
+            for_each_record(reader, regions, filter, |rec| {
+                rec.convert_to_bytes(&mut buf);
+                bgzf.write_all(&buf)
+            })?;
+            bgzf.finish().map(drop)
+        }
+    }
+}
+
+/// Sends full chunks to output thread of `stream_records`.
+struct ChunkSender {
+    tx: Sender<Vec<u8>>,
+    chunk: Vec<u8>,
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .send(chunk)
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Output of stream was closed."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::bgzf::BGZF_EOF;
+    use crate::bam::gbam_to_bam::write_bam;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::Codecs;
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use flate2::read::MultiGzDecoder;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::io::Read;
+    use tempdir::TempDir;
+
+    /// Fails every write, as pipe whose reader exited.
+    #[derive(Debug)]
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> Result<usize> {
+            Err(Error::new(ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stream_records() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..20000 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\tNM:i:{}\n", i, i + 1, i % 3));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("stream_records").unwrap();
+        let path = dir.path().join("in.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Gzip], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+        let mut reader = ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+
+        // Output takes several chunks.
+        let out = stream_records(&mut reader, &[], None, StreamFormat::Sam, Vec::new()).unwrap();
+        assert!(out.len() > 2 * CHUNK_SIZE);
+        assert_eq!(String::from_utf8(out).unwrap(), sam);
+
+        let regions = ["chr1:101-200".to_owned()];
+        let filter = FilterExpr::parse("[NM] == 1", reader.file_meta.get_ref_seqs()).unwrap();
+        let out = stream_records(&mut reader, &regions, Some(&filter), StreamFormat::Sam, Vec::new()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().filter(|line| !line.starts_with('@')).count(), 35);
+
+        let bgzf = stream_records(&mut reader, &regions, None, StreamFormat::UncompressedBam, Vec::new()).unwrap();
+        assert!(bgzf.ends_with(&BGZF_EOF));
+        let mut bam = Vec::new();
+        MultiGzDecoder::new(&bgzf[..]).read_to_end(&mut bam).unwrap();
+        let mut expected = Vec::new();
+        write_bam(&mut reader, &regions, &mut expected).unwrap();
+        assert_eq!(bam, expected);
+
+        let err = stream_records(&mut reader, &[], None, StreamFormat::UncompressedBam, ClosedPipe).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert!(stream_records(&mut reader, &["chr2".to_owned()], None, StreamFormat::Sam, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::meta::{FileMeta, Stat};
+use crate::progress::Progress;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// Output of unfinished conversion. Renamed to the final path once finished,
+/// so a GBAM file at the final path is always complete.
+pub fn partial_path(out_path: &str) -> String {
+    format!("{}.partial", out_path)
+}
+
+/// Checkpoint of conversion writing into `partial_path(out_path)`.
+pub fn checkpoint_path(out_path: &str) -> String {
+    format!("{}.checkpoint", out_path)
+}
+
+/// State of writer allowing to resume writing after crash, see
+/// `Writer::checkpoint`. Holds meta of blocks written so far and data of
+/// blocks not filled yet.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub(crate) progress: Progress,
+    /// End of blocks written so far, output is truncated to it on resume.
+    pub(crate) data_end: u64,
+    pub(crate) file_meta: FileMeta,
+    /// Every column buffer, in order of writer columns.
+    pub(crate) buffers: Vec<BufferState>,
+    /// Data of all buffers one after another. Stored after JSON part.
+    #[serde(skip)]
+    pub(crate) data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BufferState {
+    pub field: Fields,
+    pub len: usize,
+    pub rec_count: u32,
+    pub block_num: u64,
+    pub stats: Option<Stat>,
+}
+
+impl Checkpoint {
+    /// Records pushed into the writer up to the checkpoint, filtered out
+    /// ones included. Input is resumed after them.
+    pub fn records(&self) -> u64 {
+        self.progress.records
+    }
+
+    /// Length of output the checkpoint refers to.
+    pub fn data_end(&self) -> u64 {
+        self.data_end
+    }
+
+    /// Saves checkpoint to `path`, replacing previous one atomically.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        let json = serde_json::to_vec(self)?;
+        out.write_u64::<LittleEndian>(json.len() as u64)?;
+        out.write_all(&json)?;
+        out.write_all(&self.data)?;
+        out.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let json_len = input.read_u64::<LittleEndian>()?;
+        let mut json = vec![0; json_len as usize];
+        input.read_exact(&mut json)?;
+        let mut checkpoint: Self = serde_json::from_slice(&json)?;
+        input.read_to_end(&mut checkpoint.data)?;
+        let data_len: usize = checkpoint.buffers.iter().map(|buffer| buffer.len).sum();
+        if data_len != checkpoint.data.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Checkpoint is truncated."));
+        }
+        Ok(checkpoint)
+    }
+}
@@ -0,0 +1,94 @@
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Error, ErrorKind, Read, Result};
+
+const ZSTD_LEVEL: i32 = 15;
+
+pub(crate) fn write_varint(dest: &mut Vec<u8>, mut val: u64) {
+    while val >= 0x80 {
+        dest.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    dest.push(val as u8);
+}
+
+pub(crate) fn read_varint(source: &mut &[u8]) -> Result<u64> {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = source.read_u8()?;
+        val |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(val);
+        }
+        shift += 7;
+        if shift > 63 {
+            return Err(Error::new(ErrorKind::InvalidData, "Varint is too long."));
+        }
+    }
+}
+
+/// Splits block of BAM CIGAR operations into two streams: op codes packed two
+/// per byte and varint lengths. Both streams are then compressed with ZSTD.
+/// Op codes can be scanned on their own, e.g. to find ops consuming
+/// reference, without touching lengths.
+///
+/// Layout before zstd: number of ops (u64), packed ops, lengths.
+pub(crate) fn compress(source: &[u8]) -> Result<Vec<u8>> {
+    if !source.len().is_multiple_of(4) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "CIGAR block size should be multiple of 4.",
+        ));
+    }
+    let ops_num = source.len() / 4;
+    let mut buf = Vec::with_capacity(8 + ops_num / 2 + ops_num * 2);
+    buf.write_u64::<LittleEndian>(ops_num as u64)?;
+    let ops_start = buf.len();
+    buf.resize(ops_start + ops_num.div_ceil(2), 0);
+    for (i, op) in source.chunks_exact(4).map(LittleEndian::read_u32).enumerate() {
+        buf[ops_start + i / 2] |= ((op & 0xf) as u8) << ((i % 2) * 4);
+        write_varint(&mut buf, (op >> 4) as u64);
+    }
+    zstd::stream::encode_all(&buf[..], ZSTD_LEVEL)
+}
+
+pub(crate) fn decompress(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let buf = zstd::stream::decode_all(source)?;
+    let mut reader = &buf[..];
+    let ops_num = reader.read_u64::<LittleEndian>()? as usize;
+    let mut ops = vec![0; ops_num.div_ceil(2)];
+    reader.read_exact(&mut ops)?;
+
+    dest.clear();
+    dest.reserve(ops_num * 4);
+    for i in 0..ops_num {
+        let code = ((ops[i / 2] >> ((i % 2) * 4)) & 0xf) as u32;
+        let len = read_varint(&mut reader)? as u32;
+        dest.write_u32::<LittleEndian>((len << 4) | code)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cigar_codec_roundtrip() {
+        // 100M, 50M2I48M, 10S90M and max length op.
+        let ops: Vec<u32> = [(100, 0), (50, 0), (2, 1), (48, 0), (10, 4), (90, 0), (0x0fff_ffff, 2)]
+            .iter()
+            .cycle()
+            .take(7000)
+            .map(|&(len, op)| (len << 4) | op)
+            .collect();
+        let mut source = Vec::new();
+        for op in ops {
+            source.write_u32::<LittleEndian>(op).unwrap();
+        }
+        let compressed = compress(&source).unwrap();
+        let mut decompressed = Vec::new();
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, source);
+    }
+}
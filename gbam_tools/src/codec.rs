@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, RwLock};
+
+/// User defined block codec. Blocks compressed with it are recorded in file
+/// meta as `Codecs::Custom(id)`, so the same codec has to be registered
+/// before reading such file.
+pub trait BlockCodec: Send + Sync {
+    /// Identifier persisted in metadata. Must be stable between sessions.
+    fn id(&self) -> u32;
+    /// Human readable name used in error messages.
+    fn name(&self) -> &str;
+    /// Appends compressed `source` to `dest`.
+    fn compress(&self, source: &[u8], dest: &mut Vec<u8>) -> Result<()>;
+    /// Appends decompressed `source` to `dest`.
+    fn decompress(&self, source: &[u8], dest: &mut Vec<u8>) -> Result<()>;
+}
+
+static CODEC_REGISTRY: RwLock<BTreeMap<u32, Arc<dyn BlockCodec>>> = RwLock::new(BTreeMap::new());
+
+/// Makes codec available to writers and readers of this process. Fails if a
+/// different codec with the same ID is already registered.
+pub fn register_codec(codec: Arc<dyn BlockCodec>) -> Result<()> {
+    let mut registry = CODEC_REGISTRY.write().unwrap();
+    if let Some(registered) = registry.get(&codec.id()) {
+        if registered.name() != codec.name() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "Codec ID {} is already taken by <{}>.",
+                    codec.id(),
+                    registered.name()
+                ),
+            ));
+        }
+    }
+    registry.insert(codec.id(), codec);
+    Ok(())
+}
+
+pub fn get_codec(id: u32) -> Result<Arc<dyn BlockCodec>> {
+    CODEC_REGISTRY
+        .read()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Codec with ID {} is not registered.", id),
+            )
+        })
+}
+
+/// IDs of all registered codecs.
+pub fn registered_codecs() -> Vec<u32> {
+    CODEC_REGISTRY.read().unwrap().keys().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::compress;
+    use crate::reader::column::decompress_block;
+    use crate::Codecs;
+
+    struct XorCodec;
+
+    impl BlockCodec for XorCodec {
+        fn id(&self) -> u32 {
+            1000
+        }
+        fn name(&self) -> &str {
+            "xor"
+        }
+        fn compress(&self, source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+            dest.extend(source.iter().map(|b| b ^ 0x5a));
+            Ok(())
+        }
+        fn decompress(&self, source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+            self.compress(source, dest)
+        }
+    }
+
+    #[test]
+    fn test_custom_codec_roundtrip() {
+        register_codec(Arc::new(XorCodec)).unwrap();
+        assert!(registered_codecs().contains(&1000));
+
+        let source = b"GBAM custom codec".to_vec();
+        let compressed = compress(&source, Vec::new(), Codecs::Custom(1000)).unwrap();
+        assert_ne!(compressed, source);
+        let mut decompressed = vec![0; source.len()];
+        decompress_block(&compressed, &mut decompressed, &Codecs::Custom(1000)).unwrap();
+        assert_eq!(decompressed, source);
+
+        assert!(decompress_block(&compressed, &mut decompressed, &Codecs::Custom(1001)).is_err());
+    }
+}
@@ -1,38 +1,295 @@
 use crate::SIZE_LIMIT;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
 use flume::{Receiver, Sender};
+use rayon::prelude::*;
 use rayon::ThreadPool;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
 
 use super::Codecs;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use brotli::CompressorWriter;
+use xxhash_rust::xxh64::xxh64;
 use zstd::stream::encode_all;
 // use lz4::EncoderBuilder;
 use std::io::Write;
 
 // use lz4_flex::block::{compress_into, get_maximum_output_size};
-use lzzzz::lz4;
+use lzzzz::{lz4, lz4_hc};
 
+use crate::codec::get_codec;
 use crate::writer::BlockInfo;
 
+/// Codecs tried when `Codecs::Auto` is requested.
+const AUTO_CANDIDATES: [Codecs; 3] = [Codecs::Lz4, Codecs::Zstd, Codecs::Brotli];
+/// Size of block prefix used for trial compression. Small compared to
+/// blocks, so choosing codec costs a fraction of compressing the block.
+const AUTO_SAMPLE_SIZE: usize = 64 * 1024;
+const ZSTD_LEVEL: i32 = 15;
+/// Blocks at least twice this size are split into chunks compressed in
+/// parallel, each chunk is a separate zstd frame.
+const ZSTD_CHUNK_SIZE: usize = SIZE_LIMIT;
+
 pub(crate) enum OrderingKey {
     Key(u64),
     UnusedBlock,
 }
 
+/// Failure to compress a block of `field` with `codec`. Panics of codecs are
+/// reported as errors of kind `Other`.
+#[derive(Debug)]
+pub struct CompressError {
+    pub field: Fields,
+    pub codec: Codecs,
+    pub source: std::io::Error,
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to compress {} block with {:?}: {}",
+            self.field, self.codec, self.source
+        )
+    }
+}
+
+impl std::error::Error for CompressError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<CompressError> for std::io::Error {
+    fn from(err: CompressError) -> Self {
+        std::io::Error::new(err.source.kind(), err)
+    }
+}
+
 /// Accompanies compressed buffer to generate meta when written out
 pub(crate) struct CompressTask {
     pub ordering_key: OrderingKey,
     pub block_info: BlockInfo,
-    pub buf: Vec<u8>,
+    pub buf: Result<Vec<u8>, CompressError>,
+    // Submission number, tasks are drained in this order.
+    seq: usize,
+}
+
+/// Compression totals of one field.
+#[derive(Clone, Debug, Default)]
+pub struct FieldReport {
+    pub blocks: usize,
+    pub uncompressed: u64,
+    pub compressed: u64,
+    /// Blocks compressed with each codec. More than one with `Codecs::Auto`.
+    pub codecs: Vec<(Codecs, usize)>,
+    /// Blocks left out by sampling of dry runs.
+    pub skipped_blocks: usize,
+    pub skipped_uncompressed: u64,
+}
+
+impl FieldReport {
+    pub fn total_uncompressed(&self) -> u64 {
+        self.uncompressed + self.skipped_uncompressed
+    }
+
+    /// Compressed size extrapolated to skipped blocks by compression ratio
+    /// of compressed ones. Same as `compressed` if nothing was skipped.
+    pub fn projected_compressed(&self) -> u64 {
+        if self.uncompressed == 0 {
+            return self.compressed;
+        }
+        (self.compressed as u128 * self.total_uncompressed() as u128 / self.uncompressed as u128)
+            as u64
+    }
+}
+
+/// Compression totals per field, collected while blocks are drained from
+/// `Compressor`.
+#[derive(Clone, Debug)]
+pub struct CompressionReport {
+    fields: Vec<FieldReport>,
+}
+
+impl Default for CompressionReport {
+    fn default() -> Self {
+        Self {
+            fields: vec![FieldReport::default(); FIELDS_NUM],
+        }
+    }
 }
+
+impl CompressionReport {
+    fn add(&mut self, block_info: &BlockInfo, compressed: usize) {
+        let report = &mut self.fields[block_info.field as usize];
+        report.blocks += 1;
+        report.uncompressed += block_info.uncompr_size as u64;
+        report.compressed += compressed as u64;
+        if let Some(codec) = block_info.codec {
+            match report.codecs.iter_mut().find(|(c, _)| *c == codec) {
+                Some((_, count)) => *count += 1,
+                None => report.codecs.push((codec, 1)),
+            }
+        }
+    }
+
+    fn add_skipped(&mut self, block_info: &BlockInfo) {
+        let report = &mut self.fields[block_info.field as usize];
+        report.skipped_blocks += 1;
+        report.skipped_uncompressed += block_info.uncompr_size as u64;
+    }
+
+    pub fn field(&self, field: &Fields) -> &FieldReport {
+        &self.fields[*field as usize]
+    }
+
+    /// Fields which had at least one block.
+    pub fn iter(&self) -> impl Iterator<Item = (Fields, &FieldReport)> {
+        Fields::iterator()
+            .map(move |field| (*field, &self.fields[*field as usize]))
+            .filter(|(_, report)| report.blocks + report.skipped_blocks > 0)
+    }
+
+    pub fn total_uncompressed(&self) -> u64 {
+        self.fields.iter().map(|report| report.total_uncompressed()).sum()
+    }
+
+    pub fn total_compressed(&self) -> u64 {
+        self.fields.iter().map(|report| report.compressed).sum()
+    }
+
+    /// Projected size of all compressed blocks, see
+    /// `FieldReport::projected_compressed`.
+    pub fn total_projected(&self) -> u64 {
+        self.fields.iter().map(|report| report.projected_compressed()).sum()
+    }
+
+    /// Whether some blocks were skipped and sizes are extrapolated.
+    pub fn is_estimate(&self) -> bool {
+        self.fields.iter().any(|report| report.skipped_blocks > 0)
+    }
+}
+
+/// Sizes of dry runs are projected ones, see `total_projected`.
+impl std::fmt::Display for CompressionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ratio = |uncompressed: u64, compressed: u64| {
+            uncompressed as f64 / std::cmp::max(compressed, 1) as f64
+        };
+        let compressed_title = if self.is_estimate() { "projected" } else { "compressed" };
+        writeln!(
+            f,
+            "{:<16}{:>8}{:>16}{:>16}{:>12}  codecs",
+            "field", "blocks", "uncompressed", compressed_title, "ratio"
+        )?;
+        for (field, report) in self.iter() {
+            let codecs: Vec<String> = report
+                .codecs
+                .iter()
+                .map(|(codec, count)| format!("{:?}x{}", codec, count))
+                .collect();
+            writeln!(
+                f,
+                "{:<16}{:>8}{:>16}{:>16}{:>12.2}  {}",
+                field.to_string(),
+                report.blocks + report.skipped_blocks,
+                report.total_uncompressed(),
+                report.projected_compressed(),
+                ratio(report.total_uncompressed(), report.projected_compressed()),
+                codecs.join(",")
+            )?;
+        }
+        let blocks: usize = self.fields.iter().map(|report| report.blocks).sum();
+        let skipped: usize = self.fields.iter().map(|report| report.skipped_blocks).sum();
+        write!(
+            f,
+            "{:<16}{:>8}{:>16}{:>16}{:>12.2}",
+            "total",
+            blocks + skipped,
+            self.total_uncompressed(),
+            self.total_projected(),
+            ratio(self.total_uncompressed(), self.total_projected())
+        )?;
+        if self.is_estimate() {
+            write!(f, "\nprojected from {} of {} blocks", blocks, blocks + skipped)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes of uncompressed data handed to compression threads and not yet
+/// compressed.
+struct InFlight {
+    bytes: Mutex<usize>,
+    released: Condvar,
+}
+
+impl InFlight {
+    /// Blocks until `size` fits into `budget`. A single block bigger than the
+    /// whole budget is let through once nothing else is in flight.
+    fn acquire(&self, size: usize, budget: usize) {
+        let mut bytes = self.bytes.lock().unwrap();
+        while *bytes > 0 && *bytes + size > budget {
+            bytes = self.released.wait(bytes).unwrap();
+        }
+        *bytes += size;
+    }
+
+    fn release(&self, size: usize) {
+        *self.bytes.lock().unwrap() -= size;
+        self.released.notify_one();
+    }
+}
+
+/// Channels of tasks and buffers. Result channel is seeded with one dummy
+/// task and buffer pool with one buffer per thread.
+type TaskChannels = (
+    Sender<CompressTask>,
+    Receiver<CompressTask>,
+    Sender<Vec<u8>>,
+    Receiver<Vec<u8>>,
+);
+
+fn task_channels(thread_num: usize) -> TaskChannels {
+    // Leaves room for dummy tasks plus one result per thread.
+    let (compr_data_tx, compr_data_rx) = flume::bounded(2 * thread_num);
+    // Every running task takes a buffer before returning one, so the pool
+    // never holds more than it was seeded with.
+    let (buf_tx, buf_rx) = flume::bounded(thread_num);
+    // Buffers grow on demand, blocks bigger than SIZE_LIMIT (long reads)
+    // just get a bigger buffer.
+    for _ in 0..thread_num {
+        buf_tx.send(Vec::new()).unwrap();
+        compr_data_tx
+            .send(CompressTask {
+                ordering_key: OrderingKey::UnusedBlock,
+                block_info: BlockInfo::default(),
+                buf: Ok(Vec::new()),
+                seq: 0,
+            })
+            .unwrap();
+    }
+    (compr_data_tx, compr_data_rx, buf_tx, buf_rx)
+}
+
 pub(crate) struct Compressor {
-    compr_pool: ThreadPool,
+    // May be shared with the rest of application.
+    compr_pool: Arc<ThreadPool>,
     compr_data_tx: Sender<CompressTask>,
     compr_data_rx: Receiver<CompressTask>,
     /// Buffers shared among threads
     buf_tx: Sender<Vec<u8>>,
     buf_rx: Receiver<Vec<u8>>,
+    in_flight: Arc<InFlight>,
+    // compress_block blocks once this many bytes are being compressed.
+    // Derived from thread number unless set explicitly.
+    in_flight_budget: Option<usize>,
+    report: CompressionReport,
+    // Blocks finished ahead of an earlier submitted one, keyed by `seq`.
+    reorder: BTreeMap<usize, CompressTask>,
     // Total number of decompression queryies
     sent: usize,
     // Processed blocks number
@@ -40,115 +297,242 @@ pub(crate) struct Compressor {
 }
 
 impl Compressor {
+    /// Compresses on a pool of `thread_num` threads owned by the compressor.
     pub fn new(thread_num: usize) -> Self {
-        let (compr_data_tx, compr_data_rx) = flume::unbounded();
-        let (buf_tx, buf_rx) = flume::unbounded();
-        for _ in 0..thread_num {
-            buf_tx.send(vec![0; SIZE_LIMIT]).unwrap();
-            compr_data_tx
-                .send(CompressTask {
-                    ordering_key: OrderingKey::UnusedBlock,
-                    block_info: BlockInfo::default(),
-                    buf: vec![0; SIZE_LIMIT],
-                })
-                .unwrap();
-        }
+        Self::with_pool(Arc::new(build_pool(thread_num)))
+    }
+
+    /// Compresses on `pool`, which may be shared with the rest of
+    /// application. As many blocks as there are threads in the pool are
+    /// compressed at once.
+    pub fn with_pool(pool: Arc<ThreadPool>) -> Self {
+        let (compr_data_tx, compr_data_rx, buf_tx, buf_rx) =
+            task_channels(pool.current_num_threads());
         Compressor {
-            compr_pool: rayon::ThreadPoolBuilder::new()
-                .num_threads(thread_num)
-                .build()
-                .unwrap(),
+            compr_pool: pool,
             compr_data_tx,
             compr_data_rx,
             buf_tx,
             buf_rx,
+            in_flight: Arc::new(InFlight {
+                bytes: Mutex::new(0),
+                released: Condvar::new(),
+            }),
+            in_flight_budget: None,
+            report: CompressionReport::default(),
+            reorder: BTreeMap::new(),
             sent: 0,
             received: 0,
         }
     }
 
+    /// `in_flight_budget` limits bytes of uncompressed data queued for
+    /// compression. Compressed blocks waiting to be drained are bounded by
+    /// the result channel.
+    pub fn set_in_flight_budget(&mut self, in_flight_budget: usize) {
+        self.in_flight_budget = Some(in_flight_budget);
+    }
+
+    /// Default budget allows two full blocks per thread.
+    fn in_flight_budget(&self) -> usize {
+        self.in_flight_budget
+            .unwrap_or(2 * self.compr_pool.current_num_threads() * SIZE_LIMIT)
+    }
+
+    /// Compress following blocks on `pool`. All submitted blocks have to be
+    /// drained first.
+    pub fn set_pool(&mut self, pool: Arc<ThreadPool>) {
+        assert!(
+            self.sent == self.received,
+            "Compressor has to be drained before changing thread pool."
+        );
+        let (compr_data_tx, compr_data_rx, buf_tx, buf_rx) =
+            task_channels(pool.current_num_threads());
+        self.compr_pool = pool;
+        self.compr_data_tx = compr_data_tx;
+        self.compr_data_rx = compr_data_rx;
+        self.buf_tx = buf_tx;
+        self.buf_rx = buf_rx;
+    }
+
+    /// Compress following blocks on a new pool of `thread_num` threads. All
+    /// submitted blocks have to be drained first.
+    pub fn set_thread_num(&mut self, thread_num: usize) {
+        self.set_pool(Arc::new(build_pool(thread_num)));
+    }
+
     pub fn compress_block(
         &mut self,
         ordering_key: OrderingKey,
-        block_info: BlockInfo,
+        mut block_info: BlockInfo,
         data: Vec<u8>,
         codec: Codecs,
     ) {
+        let size = data.capacity();
+        self.in_flight.acquire(size, self.in_flight_budget());
+        let in_flight = self.in_flight.clone();
         let buf_queue_tx = self.buf_tx.clone();
         let buf_queue_rx = self.buf_rx.clone();
         let compressed_tx = self.compr_data_tx.clone();
+        let seq = self.sent;
         self.sent += 1;
         self.compr_pool.install(|| {
             rayon::spawn(move || {
-                let mut buf = buf_queue_rx.recv().unwrap();
+                // Channels only disconnect once Compressor is dropped, then
+                // the result is not awaited by anyone.
+                let mut buf = buf_queue_rx.recv().unwrap_or_default();
                 buf.clear();
-                let compr_data = compress(&data[..block_info.uncompr_size], buf, codec);
-                buf_queue_tx.send(data).unwrap();
-
-                compressed_tx
-                    .send(CompressTask {
-                        ordering_key,
-                        block_info,
-                        buf: compr_data,
-                    })
-                    .unwrap();
+                let source = &data[..block_info.uncompr_size];
+                // A panicking codec must not take the thread down silently,
+                // the writer would wait for this block forever.
+                let compressed = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let codec = match codec {
+                        Codecs::Auto => choose_codec(source),
+                        _ => codec,
+                    };
+                    (codec, compress(source, buf, codec))
+                }));
+                let (codec, compr_data) = compressed
+                    .unwrap_or_else(|_| (codec, Err(std::io::Error::other("codec panicked"))));
+                block_info.checksum = Some(xxh64(source, 0));
+                block_info.codec = Some(codec);
+                let compr_data = compr_data.map_err(|source| CompressError {
+                    field: block_info.field,
+                    codec,
+                    source,
+                });
+                let _ = buf_queue_tx.send(shrink_oversized(data));
+                // Released before sending the result, consumer may be waiting
+                // for budget while result channel is full.
+                in_flight.release(size);
+
+                let _ = compressed_tx.send(CompressTask {
+                    ordering_key,
+                    block_info,
+                    buf: compr_data,
+                    seq,
+                });
             });
         });
     }
 
-    /// Drain completed tasks
+    /// Account block left out by sampling of dry run in the report.
+    pub fn skip_block(&mut self, block_info: &BlockInfo) {
+        self.report.add_skipped(block_info);
+    }
+
+    /// Drain completed tasks in the order they were submitted, so output
+    /// doesn't depend on thread count or scheduling. Blocks finished early
+    /// are held back until all preceding ones are drained.
     pub fn get_compr_block(&mut self) -> CompressTask {
-        let task = self.compr_data_rx.recv().unwrap();
-        // Correct for first dummy blocks
-        if let OrderingKey::Key(_) = task.ordering_key {
-            self.received += 1;
+        loop {
+            if let Some(task) = self.reorder.remove(&self.received) {
+                self.received += 1;
+                if let Ok(buf) = task.buf.as_ref() {
+                    self.report.add(&task.block_info, buf.len());
+                }
+                return task;
+            }
+            let task = self.compr_data_rx.recv().unwrap();
+            match task.ordering_key {
+                OrderingKey::Key(_) => {
+                    self.reorder.insert(task.seq, task);
+                }
+                // Dummy blocks carry no data and may be drained any time.
+                OrderingKey::UnusedBlock => return task,
+            }
         }
-        task
     }
 
-    /// Wait for all threads to finish and return leftovers
-    pub fn finish(&mut self) -> Vec<CompressTask> {
+    /// Wait for all submitted blocks and return them.
+    pub fn drain(&mut self) -> Vec<CompressTask> {
         let mut leftovers = Vec::new();
         while self.received != self.sent {
             leftovers.push(self.get_compr_block());
         }
         leftovers
     }
+
+    /// Wait for all threads to finish and return leftovers along with totals
+    /// of all compressed blocks.
+    pub fn finish(&mut self) -> (Vec<CompressTask>, CompressionReport) {
+        (self.drain(), std::mem::take(&mut self.report))
+    }
 }
 
-pub fn compress(source: &[u8], mut dest: Vec<u8>, codec: Codecs) -> Vec<u8> {
-    let compressed_bytes = match codec {
+fn build_pool(thread_num: usize) -> ThreadPool {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(thread_num)
+        .build()
+        .unwrap()
+}
+
+/// Trial compresses block prefix with every candidate and returns the codec
+/// which produced the smallest output.
+pub fn choose_codec(source: &[u8]) -> Codecs {
+    let sample = &source[..std::cmp::min(source.len(), AUTO_SAMPLE_SIZE)];
+    *AUTO_CANDIDATES
+        .iter()
+        .min_by_key(|&&codec| compress(sample, Vec::new(), codec).map_or(usize::MAX, |c| c.len()))
+        .unwrap()
+}
+
+/// Buffers grown by an unusually big block are shrunk back before returning to
+/// the pool, so a single long record doesn't pin memory till the end.
+fn shrink_oversized(mut buf: Vec<u8>) -> Vec<u8> {
+    if buf.capacity() > 2 * SIZE_LIMIT {
+        buf.clear();
+        buf.shrink_to(SIZE_LIMIT);
+    }
+    buf
+}
+
+/// Maps result of LZ4 and LZ4 HC compression into `dest`.
+fn lz4_result<E>(res: Result<usize, E>, mut dest: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    match res {
+        Ok(size) => {
+            dest.resize(size, 0);
+            Ok(dest)
+        }
+        Err(_) => Err(std::io::Error::other("Compression error")),
+    }
+}
+
+pub fn compress(source: &[u8], mut dest: Vec<u8>, codec: Codecs) -> std::io::Result<Vec<u8>> {
+    #[cfg(feature = "hw-accel")]
+    if crate::accel::try_compress(&codec, source, &mut dest) {
+        return Ok(dest);
+    }
+    match codec {
         Codecs::Gzip => {
             let mut encoder = GzEncoder::new(dest, Compression::default());
-            encoder.write_all(source).unwrap();
+            encoder.write_all(source)?;
             encoder.finish()
         }
         Codecs::Lz4 => {
             dest.clear();
             let res = lz4::compress_to_vec(source, &mut dest, lz4::ACC_LEVEL_DEFAULT);
-            match res {
-                Ok(size) => {
-                    dest.resize(size, 0);
-                    Ok(dest)
-                }
-                Err(_) => Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Compression error",
-                )),
-            }
+            lz4_result(res, dest)
+        },
+        Codecs::Lz4Hc(level) => {
+            dest.clear();
+            let res = lz4_hc::compress_to_vec(source, &mut dest, level);
+            lz4_result(res, dest)
         },
         Codecs::Brotli => {
             dest.clear();
             {
                 let mut writer = CompressorWriter::new(&mut dest, 4096, 6, 22);
-                writer.write_all(source).unwrap();
-                writer.flush().unwrap();
+                writer.write_all(source)?;
+                writer.flush()?;
             }
             Ok(dest)
         },
+        Codecs::Zstd if source.len() >= 2 * ZSTD_CHUNK_SIZE => compress_zstd_chunked(source),
+        Codecs::ZstdLong(window_log) => compress_zstd_long(source, dest, window_log),
         Codecs::Zstd => {
             // encode_all returns a Vec<u8>
-            match encode_all(source, 15) {
+            match encode_all(source, ZSTD_LEVEL) {
                 Ok(c) => Ok(c),
                 Err(_) => Err(std::io::Error::new(
                     std::io::ErrorKind::Other,
@@ -161,6 +545,171 @@ pub fn compress(source: &[u8], mut dest: Vec<u8>, codec: Codecs) -> Vec<u8> {
             dest.extend_from_slice(source);
             Ok(dest)
         }
-    };
-    compressed_bytes.unwrap()
+        Codecs::Custom(id) => get_codec(id).and_then(|codec| {
+            dest.clear();
+            codec.compress(source, &mut dest)?;
+            Ok(dest)
+        }),
+        Codecs::Seq2Bit => crate::seq_packing::pack(source),
+        Codecs::QualCtx => crate::qual_codec::compress(source, dest),
+        Codecs::CigarSplit => crate::cigar_codec::compress(source),
+        Codecs::TagSplit => crate::tag_codec::compress(source),
+        Codecs::MapqRle => crate::mapq_codec::compress(source),
+        Codecs::FlagPlanes => crate::flag_codec::compress(source),
+        Codecs::PosDelta => crate::pos_codec::compress(source),
+        Codecs::NameTok => crate::name_codec::compress(source),
+        Codecs::Constant(item_size) => crate::const_codec::compress(source, item_size as usize),
+        Codecs::Auto => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Codec has to be chosen before compressing the block.",
+        )),
+        Codecs::Unknown => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Unknown codec can't compress.",
+        )),
+    }
+}
+
+fn compress_zstd_long(source: &[u8], mut dest: Vec<u8>, window_log: u32) -> std::io::Result<Vec<u8>> {
+    dest.clear();
+    let mut encoder = zstd::stream::Encoder::new(dest, ZSTD_LEVEL)?;
+    encoder.long_distance_matching(true)?;
+    encoder.window_log(window_log)?;
+    encoder.write_all(source)?;
+    encoder.finish()
+}
+
+/// Compresses chunks of a big block on rayon threads and concatenates
+/// resulting frames. Zstd decoder reads concatenated frames as one stream.
+fn compress_zstd_chunked(source: &[u8]) -> std::io::Result<Vec<u8>> {
+    let frames = source
+        .par_chunks(ZSTD_CHUNK_SIZE)
+        .map(|chunk| encode_all(chunk, ZSTD_LEVEL))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    Ok(frames.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::column::decompress_block;
+
+    #[test]
+    fn test_chunked_zstd_roundtrip() {
+        let source: Vec<u8> = (0..2 * ZSTD_CHUNK_SIZE + 12_345)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let compressed = compress(&source, Vec::new(), Codecs::Zstd).unwrap();
+        let mut decompressed = Vec::new();
+        decompress_block(&compressed, &mut decompressed, &Codecs::Zstd).unwrap();
+        assert_eq!(decompressed, source);
+    }
+
+    #[test]
+    fn test_tiny_in_flight_budget() {
+        // Same submit and drain pattern as writer uses, budget lets only one
+        // block through at a time.
+        let mut compressor = Compressor::new(4);
+        compressor.set_in_flight_budget(1);
+        let mut compressed = 0;
+        for i in 0..64u64 {
+            let data = vec![i as u8; 1000];
+            let block_info = BlockInfo {
+                uncompr_size: data.len(),
+                ..Default::default()
+            };
+            compressor.compress_block(OrderingKey::Key(i), block_info, data, Codecs::Lz4);
+            if let OrderingKey::Key(_) = compressor.get_compr_block().ordering_key {
+                compressed += 1;
+            }
+        }
+        let (leftovers, report) = compressor.finish();
+        compressed += leftovers.len();
+        assert_eq!(compressed, 64);
+        let field_report = report.field(&Fields::RefID);
+        assert_eq!(field_report.blocks, 64);
+        assert_eq!(field_report.uncompressed, 64 * 1000);
+        assert_eq!(field_report.codecs, vec![(Codecs::Lz4, 64)]);
+    }
+    struct FailingCodec(u32);
+
+    impl crate::codec::BlockCodec for FailingCodec {
+        fn id(&self) -> u32 {
+            self.0
+        }
+        fn name(&self) -> &str {
+            "failing"
+        }
+        fn compress(&self, _source: &[u8], _dest: &mut Vec<u8>) -> std::io::Result<()> {
+            if self.0 == 1101 {
+                panic!("failing codec");
+            }
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "failing codec"))
+        }
+        fn decompress(&self, _source: &[u8], _dest: &mut Vec<u8>) -> std::io::Result<()> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_codec_errors_reach_consumer() {
+        for id in [1100, 1101] {
+            crate::codec::register_codec(Arc::new(FailingCodec(id))).unwrap();
+        }
+        let mut compressor = Compressor::new(2);
+        let codecs = [Codecs::Lz4, Codecs::Custom(1100), Codecs::Custom(1101)];
+        for (i, codec) in codecs.iter().enumerate() {
+            let block_info = BlockInfo {
+                uncompr_size: 10,
+                field: Fields::Mapq,
+                ..Default::default()
+            };
+            compressor.compress_block(OrderingKey::Key(i as u64), block_info, vec![0; 10], *codec);
+        }
+        let (tasks, report) = compressor.finish();
+        let results: Vec<_> = tasks
+            .into_iter()
+            .filter(|task| matches!(task.ordering_key, OrderingKey::Key(_)))
+            .map(|task| task.buf)
+            .collect();
+        assert!(results[0].is_ok());
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!((err.field, err.codec), (Fields::Mapq, Codecs::Custom(1100)));
+        assert_eq!(err.source.kind(), std::io::ErrorKind::InvalidData);
+        let err = results[2].as_ref().unwrap_err();
+        assert_eq!(err.codec, Codecs::Custom(1101));
+        assert_eq!(report.field(&Fields::Mapq).blocks, 1);
+    }
+
+    #[test]
+    fn test_auto_codec_recorded() {
+        let mut seed = 3u32;
+        let noise: Vec<u8> = (0..AUTO_SAMPLE_SIZE + 1000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"ACGT"[(seed >> 16) as usize % 4]
+            })
+            .collect();
+        let sources = [vec![7u8; 10_000], noise];
+        let mut compressor = Compressor::new(2);
+        for (i, source) in sources.iter().enumerate() {
+            let block_info = BlockInfo {
+                uncompr_size: source.len(),
+                ..Default::default()
+            };
+            compressor.compress_block(OrderingKey::Key(i as u64), block_info, source.clone(), Codecs::Auto);
+        }
+        let (tasks, report) = compressor.finish();
+        assert_eq!(report.field(&Fields::RefID).blocks, 2);
+        for task in tasks {
+            let OrderingKey::Key(i) = task.ordering_key else { continue };
+            let source = &sources[i as usize];
+            let codec = task.block_info.codec.unwrap();
+            assert!(AUTO_CANDIDATES.contains(&codec));
+            assert_eq!(codec, choose_codec(source));
+            let mut decompressed = Vec::new();
+            decompress_block(&task.buf.unwrap(), &mut decompressed, &codec).unwrap();
+            assert_eq!(&decompressed, source);
+        }
+    }
 }
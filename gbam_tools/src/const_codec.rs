@@ -0,0 +1,59 @@
+use crate::cigar_codec::{read_varint, write_varint};
+use std::io::{Error, ErrorKind, Result};
+
+/// Whether block consists of one `item_size` bytes item repeated.
+pub(crate) fn is_constant(source: &[u8], item_size: usize) -> bool {
+    let item = &source[..item_size.min(source.len())];
+    item_size > 0 && source.len().is_multiple_of(item_size) && source.chunks_exact(item_size).all(|chunk| chunk == item)
+}
+
+/// Stores block of one repeated item as number of repeats (varint) followed
+/// by the item. Nothing is entropy coded, so constant blocks, e.g. POS or
+/// CIGAR index of unaligned records, cost a single scan.
+pub(crate) fn compress(source: &[u8], item_size: usize) -> Result<Vec<u8>> {
+    if !is_constant(source, item_size) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Block should consist of one repeated item.",
+        ));
+    }
+    let mut buf = Vec::with_capacity(10 + item_size);
+    write_varint(&mut buf, (source.len() / item_size) as u64);
+    buf.extend_from_slice(&source[..item_size.min(source.len())]);
+    Ok(buf)
+}
+
+pub(crate) fn decompress(source: &[u8], dest: &mut Vec<u8>, item_size: usize) -> Result<()> {
+    let mut reader = source;
+    let count = read_varint(&mut reader)? as usize;
+    let item = reader;
+    if count > 0 && item.len() != item_size {
+        return Err(Error::new(ErrorKind::InvalidData, "Constant block is corrupted."));
+    }
+    dest.clear();
+    dest.reserve(count * item_size);
+    for _ in 0..count {
+        dest.extend_from_slice(item);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_const_codec_roundtrip() {
+        let source: Vec<u8> = (-1i32).to_le_bytes().repeat(1000);
+        let compressed = compress(&source, 4).unwrap();
+        assert!(compressed.len() < 8);
+        let mut decompressed = Vec::new();
+        decompress(&compressed, &mut decompressed, 4).unwrap();
+        assert_eq!(decompressed, source);
+
+        decompress(&compress(&[], 2).unwrap(), &mut decompressed, 2).unwrap();
+        assert!(decompressed.is_empty());
+        assert!(compress(&[1, 0, 2, 0], 2).is_err());
+        assert!(compress(&[1, 0, 1], 2).is_err());
+    }
+}
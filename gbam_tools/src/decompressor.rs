@@ -0,0 +1,192 @@
+use crate::meta::FileMeta;
+use crate::reader::column::decode_block;
+use crate::reader::storage::Storage;
+use bam_tools::record::fields::Fields;
+use flume::{Receiver, Sender};
+use rayon::ThreadPool;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+/// Accompanies decompressed buffer so the consumer knows which block it holds.
+pub struct DecompressTask {
+    pub ordering_key: u64,
+    pub field: Fields,
+    pub block_num: usize,
+    /// Fails if block can't be read, decompressed or doesn't match its
+    /// checksum. Panics of codecs are reported as errors of kind `Other`.
+    pub buf: std::io::Result<Vec<u8>>,
+}
+
+/// Counterpart of `Compressor`. Blocks are decompressed on a thread pool and
+/// returned in order of completion, the ordering key tells the consumer where
+/// the block belongs. Buffers are shared among threads: return them with
+/// `recycle` once the data is consumed to avoid reallocating.
+pub struct Decompressor {
+    decompr_pool: Arc<ThreadPool>,
+    decompr_data_tx: Sender<DecompressTask>,
+    decompr_data_rx: Receiver<DecompressTask>,
+    /// Buffers shared among threads
+    buf_tx: Sender<Vec<u8>>,
+    buf_rx: Receiver<Vec<u8>>,
+    // Total number of decompression queries
+    sent: usize,
+    // Processed blocks number
+    received: usize,
+}
+
+impl Decompressor {
+    pub fn new(thread_num: usize) -> Self {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_num)
+            .build()
+            .unwrap();
+        let decompressor = Self::with_pool(Arc::new(pool));
+        for _ in 0..thread_num {
+            decompressor.recycle(Vec::new());
+        }
+        decompressor
+    }
+
+    /// Decompressor running on `pool`, which may be shared with others (e.g.
+    /// decompressors of other columns).
+    pub fn with_pool(pool: Arc<ThreadPool>) -> Self {
+        let (decompr_data_tx, decompr_data_rx) = flume::unbounded();
+        let (buf_tx, buf_rx) = flume::unbounded();
+        Decompressor {
+            decompr_pool: pool,
+            decompr_data_tx,
+            decompr_data_rx,
+            buf_tx,
+            buf_rx,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Schedule decompression of block `block_num` of `field` from
+    /// `storage`.
+    pub fn decompress_block(
+        &mut self,
+        ordering_key: u64,
+        field: Fields,
+        block_num: usize,
+        storage: Arc<dyn Storage>,
+        meta: Arc<FileMeta>,
+    ) {
+        let buf_queue_rx = self.buf_rx.clone();
+        let decompressed_tx = self.decompr_data_tx.clone();
+        self.sent += 1;
+        self.decompr_pool.spawn(move || {
+            // The consumer waits for every scheduled block, so errors and
+            // panics are sent to it instead of taking the thread down.
+            let decompressed = panic::catch_unwind(AssertUnwindSafe(|| {
+                // Pool may be drained if consumer holds on to buffers.
+                let mut buf = buf_queue_rx.try_recv().unwrap_or_default();
+                decode_block(&*storage, &meta, field, block_num, &mut buf, &mut Vec::new()).map(|_| buf)
+            }));
+            let buf = decompressed.unwrap_or_else(|_| Err(std::io::Error::other("codec panicked")));
+            // Receiver is gone only once Decompressor is dropped, then the
+            // result is not awaited by anyone.
+            let _ = decompressed_tx.send(DecompressTask {
+                ordering_key,
+                field,
+                block_num,
+                buf,
+            });
+        });
+    }
+
+    /// Wait for next completed task
+    pub fn get_decompr_block(&mut self) -> DecompressTask {
+        let task = self.decompr_data_rx.recv().unwrap();
+        self.received += 1;
+        task
+    }
+
+    /// Number of scheduled blocks which were not drained yet
+    pub fn pending(&self) -> usize {
+        self.sent - self.received
+    }
+
+    /// Return buffer into the pool so it can be reused by the next task
+    pub fn recycle(&self, buf: Vec<u8>) {
+        self.buf_tx.send(buf).unwrap();
+    }
+
+    /// Wait for all threads to finish and return leftovers sorted by ordering
+    /// key
+    pub fn finish(&mut self) -> Vec<DecompressTask> {
+        let mut leftovers = Vec::new();
+        while self.received != self.sent {
+            leftovers.push(self.get_decompr_block());
+        }
+        leftovers.sort_by_key(|task| task.ordering_key);
+        leftovers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::Reader;
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_decompress_out_of_order_blocks() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:10000\n");
+        for i in 0..100 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\n", i, i + 1));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("decompressor").unwrap();
+        let path = dir.path().join("blocks.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Zstd], 2, Vec::new(), ref_seqs, sam_header, String::new(), false);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(10) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).unwrap();
+        let blocks: Vec<Vec<u8>> = (0..10)
+            .map(|i| {
+                let mut buf = Vec::new();
+                decode_block(&*reader.storage, &reader.file_meta, Fields::ReadName, i, &mut buf, &mut Vec::new()).unwrap();
+                buf
+            })
+            .collect();
+        let mut decompressor = Decompressor::new(4);
+        for i in (0..blocks.len()).rev() {
+            decompressor.decompress_block(i as u64, Fields::ReadName, i, reader.storage.clone(), reader.file_meta.clone());
+        }
+        let tasks = decompressor.finish();
+        assert_eq!(tasks.len(), blocks.len());
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.block_num, i);
+            assert_eq!(task.buf.unwrap(), blocks[i]);
+        }
+        assert_eq!(decompressor.pending(), 0);
+
+        // Corrupt block is reported instead of hanging the consumer.
+        let block_meta = &reader.file_meta.view_blocks(&Fields::ReadName)[3];
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[block_meta.seekpos as usize..][..block_meta.block_size as usize].fill(0xAB);
+        let corrupted = dir.path().join("corrupted.gbam");
+        std::fs::write(&corrupted, bytes).unwrap();
+        let reader = Reader::new(File::open(&corrupted).unwrap(), ParsingTemplate::new()).unwrap();
+        for i in 2..5 {
+            decompressor.decompress_block(i as u64, Fields::ReadName, i, reader.storage.clone(), reader.file_meta.clone());
+        }
+        let results: Vec<_> = decompressor.finish().into_iter().map(|task| task.buf).collect();
+        assert!(results[0].is_ok() && results[1].is_err() && results[2].is_ok());
+    }
+}
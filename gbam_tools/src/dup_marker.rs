@@ -0,0 +1,325 @@
+use crate::record_filter::reference_len;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+/// Maximum distance in pixels between clusters of optical duplicates, as
+/// Picard uses for unpatterned flowcells.
+pub const DEFAULT_OPTICAL_DISTANCE: u32 = 100;
+
+const PAIRED: u16 = 0x1;
+const MATE_UNMAPPED: u16 = 0x8;
+const REVERSE: u16 = 0x10;
+const MATE_REVERSE: u16 = 0x20;
+const DUPLICATE: u16 = 0x400;
+/// Unmapped, secondary, QC failed and supplementary records are neither
+/// marked nor compared.
+const NOT_EXAMINED: u16 = 0x4 | 0x100 | 0x200 | 0x800;
+/// Offset of FLAG in raw record.
+const FLAG_OFFSET: usize = 14;
+/// Qualities below are not counted into score of record, as in Picard.
+const MIN_SCORED_QUAL: u8 = 15;
+
+/// Totals of duplicate marking.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DuplicateStats {
+    /// Records compared, i.e. primary mapped ones.
+    pub examined: u64,
+    /// Records marked as duplicates, optical ones included.
+    pub duplicates: u64,
+    /// Duplicates close to another record of their group on the same tile.
+    pub optical_duplicates: u64,
+}
+
+/// Strand and unclipped 5' end of record and its mate. Records sharing
+/// the key are duplicates of each other.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct DupKey {
+    ref_id: i32,
+    five_prime: i64,
+    reverse: bool,
+    /// Reference, POS and strand of mapped mate.
+    mate: Option<(i32, i32, bool)>,
+}
+
+/// Cluster position parsed from Illumina read name.
+#[derive(Clone, Copy)]
+struct Location {
+    /// Hash of everything before X, i.e. flowcell, lane and tile.
+    tile: u64,
+    x: i64,
+    y: i64,
+}
+
+struct Candidate {
+    seq: u64,
+    score: i64,
+    location: Option<Location>,
+}
+
+struct Pending {
+    rec: Vec<u8>,
+    /// Set once group of the record is decided.
+    resolved: bool,
+}
+
+/// Marks PCR and optical duplicates of coordinate sorted records on the fly,
+/// so no separate Picard or samtools pass is needed. Records are keyed by
+/// strand and unclipped 5' end of the record and of its mate, the one of
+/// the highest sum of base qualities (plus `ms` tag of samtools fixmate,
+/// if present) is kept in every group, the rest get 0x400 FLAG. Records are
+/// held until no later record may join their group, i.e. about two read
+/// lengths, and released in input order. Without `ms` tags mates of the
+/// same pair are scored separately and may be marked inconsistently.
+pub struct DuplicateMarker {
+    optical_distance: i64,
+    pending: VecDeque<Pending>,
+    /// Sequence number of the front of `pending`.
+    first_seq: u64,
+    groups: BTreeMap<DupKey, Vec<Candidate>>,
+    /// Longest read so far, bounds distance between POS and 5' end.
+    max_len: i64,
+    stats: DuplicateStats,
+}
+
+impl DuplicateMarker {
+    /// Duplicates within `optical_distance` pixels of another record of
+    /// their group on the same tile are counted as optical.
+    pub fn new(optical_distance: u32) -> Self {
+        Self {
+            optical_distance: optical_distance as i64,
+            pending: VecDeque::new(),
+            first_seq: 0,
+            groups: BTreeMap::new(),
+            max_len: 0,
+            stats: DuplicateStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &DuplicateStats {
+        &self.stats
+    }
+
+    /// Takes next record of coordinate sorted input. Returns records which
+    /// are marked already, in input order.
+    pub fn push(&mut self, rec: &BAMRawRecord) -> Vec<Vec<u8>> {
+        let seq = self.first_seq + self.pending.len() as u64;
+        let mut bytes = rec.0.to_vec();
+        let key = dup_key(rec);
+        if let Some(key) = key {
+            let flags = LittleEndian::read_u16(&bytes[FLAG_OFFSET..]);
+            LittleEndian::write_u16(&mut bytes[FLAG_OFFSET..], flags & !DUPLICATE);
+            self.stats.examined += 1;
+            let l_seq = LittleEndian::read_u32(rec.get_bytes(&Fields::SequenceLength));
+            self.max_len = self.max_len.max(l_seq as i64).max(reference_len(rec) as i64);
+            self.groups.entry(key).or_default().push(Candidate {
+                seq,
+                score: score(rec),
+                location: location(rec.get_bytes(&Fields::ReadName)),
+            });
+        }
+        self.pending.push_back(Pending { rec: bytes, resolved: key.is_none() });
+
+        let ref_id = LittleEndian::read_i32(rec.get_bytes(&Fields::RefID));
+        let pos = LittleEndian::read_i32(rec.get_bytes(&Fields::Pos)) as i64;
+        // Unplaced records come last.
+        let max_len = self.max_len;
+        self.resolve_groups(|key| ref_id < 0 || key.ref_id < ref_id || key.five_prime + max_len < pos);
+        self.release()
+    }
+
+    /// Returns all records held.
+    pub fn finish(&mut self) -> Vec<Vec<u8>> {
+        self.resolve_groups(|_| true);
+        self.release()
+    }
+
+    /// Decides groups while `is_complete`, which holds for a prefix of
+    /// groups in key order.
+    fn resolve_groups<F: Fn(&DupKey) -> bool>(&mut self, is_complete: F) {
+        while let Some(entry) = self.groups.first_entry() {
+            if !is_complete(entry.key()) {
+                break;
+            }
+            let group = entry.remove();
+            self.resolve_group(&group);
+        }
+    }
+
+    fn resolve_group(&mut self, group: &[Candidate]) {
+        let first_seq = self.first_seq;
+        let name = |seq: u64| {
+            let rec = &self.pending[(seq - first_seq) as usize].rec;
+            BAMRawRecord(std::borrow::Cow::Borrowed(&rec[..])).get_bytes(&Fields::ReadName).to_vec()
+        };
+        // Ties are broken by name, so both mates of a pair win alike.
+        let best = group
+            .iter()
+            .max_by(|left, right| left.score.cmp(&right.score).then_with(|| name(right.seq).cmp(&name(left.seq))))
+            .unwrap()
+            .seq;
+        for candidate in group {
+            let pending = &mut self.pending[(candidate.seq - first_seq) as usize];
+            pending.resolved = true;
+            if candidate.seq == best {
+                continue;
+            }
+            let flags = LittleEndian::read_u16(&pending.rec[FLAG_OFFSET..]);
+            LittleEndian::write_u16(&mut pending.rec[FLAG_OFFSET..], flags | DUPLICATE);
+            self.stats.duplicates += 1;
+            if self.is_optical(candidate, group) {
+                self.stats.optical_duplicates += 1;
+            }
+        }
+    }
+
+    fn is_optical(&self, candidate: &Candidate, group: &[Candidate]) -> bool {
+        let location = match candidate.location {
+            Some(location) => location,
+            None => return false,
+        };
+        group
+            .iter()
+            .filter(|other| other.seq != candidate.seq)
+            .filter_map(|other| other.location)
+            .any(|other| {
+                other.tile == location.tile
+                    && (other.x - location.x).abs() <= self.optical_distance
+                    && (other.y - location.y).abs() <= self.optical_distance
+            })
+    }
+
+    fn release(&mut self) -> Vec<Vec<u8>> {
+        let mut released = Vec::new();
+        while self.pending.front().is_some_and(|pending| pending.resolved) {
+            released.push(self.pending.pop_front().unwrap().rec);
+            self.first_seq += 1;
+        }
+        released
+    }
+}
+
+fn dup_key(rec: &BAMRawRecord) -> Option<DupKey> {
+    let flags = LittleEndian::read_u16(rec.get_bytes(&Fields::Flags));
+    if flags & NOT_EXAMINED != 0 {
+        return None;
+    }
+    let pos = LittleEndian::read_i32(rec.get_bytes(&Fields::Pos)) as i64;
+    let cigar: Vec<u32> = rec.get_bytes(&Fields::RawCigar).chunks_exact(4).map(LittleEndian::read_u32).collect();
+    // Soft and hard clips at either end.
+    let clip_len = |ops: &mut dyn Iterator<Item = &u32>| -> i64 {
+        ops.take_while(|op| matches!(*op & 0xF, 4 | 5)).map(|op| (op >> 4) as i64).sum()
+    };
+    let reverse = flags & REVERSE != 0;
+    let five_prime = if reverse {
+        pos + reference_len(rec).max(1) as i64 - 1 + clip_len(&mut cigar.iter().rev())
+    } else {
+        pos - clip_len(&mut cigar.iter())
+    };
+    let mate = (flags & PAIRED != 0 && flags & MATE_UNMAPPED == 0).then(|| {
+        (
+            LittleEndian::read_i32(rec.get_bytes(&Fields::NextRefID)),
+            LittleEndian::read_i32(rec.get_bytes(&Fields::NextPos)),
+            flags & MATE_REVERSE != 0,
+        )
+    });
+    Some(DupKey {
+        ref_id: LittleEndian::read_i32(rec.get_bytes(&Fields::RefID)),
+        five_prime,
+        reverse,
+        mate,
+    })
+}
+
+/// Sum of base qualities of at least `MIN_SCORED_QUAL`, with mate score
+/// of `ms` tag added.
+fn score(rec: &BAMRawRecord) -> i64 {
+    let quals: i64 = rec
+        .get_bytes(&Fields::RawQual)
+        .iter()
+        .filter(|&&qual| qual >= MIN_SCORED_QUAL && qual != 0xFF)
+        .map(|&qual| qual as i64)
+        .sum();
+    quals + rec.get_int_tag(b"ms").unwrap_or(0)
+}
+
+/// Parses `...:<tile>:<x>:<y>` read names, e.g. Illumina
+/// `instrument:run:flowcell:lane:tile:x:y`. Anything after `#` or space
+/// (barcodes, comments) is ignored.
+fn location(read_name: &[u8]) -> Option<Location> {
+    let name = read_name.strip_suffix(b"\0").unwrap_or(read_name);
+    let end = name.iter().position(|&c| c == b'#' || c == b' ').unwrap_or(name.len());
+    let name = std::str::from_utf8(&name[..end]).ok()?;
+    let mut fields = name.rsplitn(3, ':');
+    let y = fields.next()?.parse().ok()?;
+    let x = fields.next()?.parse().ok()?;
+    let tile = fields.next()?;
+    // At least lane and tile precede X.
+    if !tile.contains(':') {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    tile.hash(&mut hasher);
+    Some(Location { tile: hasher.finish(), x, y })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_duplicate_marking() {
+        let sam = "@SQ\tSN:chr1\tLN:1000\n\
+                   i:1:1:100:100\t0\tchr1\t100\t60\t10M\t*\t0\t0\tAAAAAAAAAA\tIIIIIIIIII\n\
+                   i:1:1:150:130\t0\tchr1\t102\t60\t2S8M\t*\t0\t0\tAAAAAAAAAA\tJJJJJJJJJJ\n\
+                   i:1:2:100:100\t0\tchr1\t100\t60\t10M\t*\t0\t0\tAAAAAAAAAA\t##########\n\
+                   i:1:1:900:900\t16\tchr1\t100\t60\t10M\t*\t0\t0\tAAAAAAAAAA\tIIIIIIIIII\n\
+                   s\t256\tchr1\t100\t60\t10M\t*\t0\t0\tAAAAAAAAAA\tIIIIIIIIII\n\
+                   r1\t1040\tchr1\t105\t60\t5M5S\t*\t0\t0\tAAAAAAAAAA\tIIIIIIIIII\n\
+                   r2\t16\tchr1\t114\t60\t1M\t*\t0\t0\tA\tI\n\
+                   u\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+        let mut reader = SamReader::new(sam.as_bytes());
+        reader.read_header().unwrap();
+        let mut marker = DuplicateMarker::new(DEFAULT_OPTICAL_DISTANCE);
+        let mut marked = Vec::new();
+        while let Some(rec) = reader.next_rec() {
+            marked.extend(marker.push(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))));
+        }
+        marked.extend(marker.finish());
+
+        let flags: Vec<_> = marked
+            .iter()
+            .map(|rec| {
+                let rec = BAMRawRecord(Cow::Borrowed(&rec[..]));
+                let name = String::from_utf8_lossy(rec.get_bytes(&Fields::ReadName)).trim_end_matches('\0').to_owned();
+                (name, LittleEndian::read_u16(rec.get_bytes(&Fields::Flags)) & DUPLICATE != 0)
+            })
+            .collect();
+        let expected: Vec<_> = [
+            // The clipped one starts at 100 too and has better qualities.
+            ("i:1:1:100:100", true),
+            ("i:1:1:150:130", false),
+            ("i:1:2:100:100", true),
+            // Reverse strand.
+            ("i:1:1:900:900", false),
+            ("s", false),
+            // 5' ends at 114, the longer one wins, duplicate FLAG of input is reset.
+            ("r1", false),
+            ("r2", true),
+            ("u", false),
+        ]
+        .iter()
+        .map(|(name, dup)| (name.to_string(), *dup))
+        .collect();
+        assert_eq!(flags, expected);
+        assert_eq!(
+            *marker.stats(),
+            DuplicateStats { examined: 6, duplicates: 3, optical_duplicates: 1 }
+        );
+    }
+}
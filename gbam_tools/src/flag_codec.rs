@@ -0,0 +1,93 @@
+use crate::cigar_codec::{read_varint, write_varint};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Error, ErrorKind, Result};
+
+const ZSTD_LEVEL: i32 = 15;
+const PLANES_NUM: usize = 16;
+
+/// Splits 16-bit FLAG values into 16 bit-planes. Each plane is stored as
+/// lengths of alternating runs of zeroes and ones (starting with zeroes),
+/// runs of one plane sum up to number of flags. Correlated bits (paired,
+/// proper pair, reverse) give few long runs, constant bits give a single
+/// run.
+///
+/// Layout before zstd: number of flags (u64), runs of every plane.
+pub(crate) fn compress(source: &[u8]) -> Result<Vec<u8>> {
+    if !source.len().is_multiple_of(2) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "FLAG block size should be multiple of 2.",
+        ));
+    }
+    let flags: Vec<u16> = source.chunks_exact(2).map(LittleEndian::read_u16).collect();
+    let mut buf = Vec::new();
+    buf.write_u64::<LittleEndian>(flags.len() as u64)?;
+    for plane in 0..PLANES_NUM {
+        let mut bit = 0;
+        let mut run = 0u64;
+        for flag in &flags {
+            if (flag >> plane) & 1 != bit {
+                write_varint(&mut buf, run);
+                bit ^= 1;
+                run = 0;
+            }
+            run += 1;
+        }
+        write_varint(&mut buf, run);
+    }
+    zstd::stream::encode_all(&buf[..], ZSTD_LEVEL)
+}
+
+pub(crate) fn decompress(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let buf = zstd::stream::decode_all(source)?;
+    let mut buf = &buf[..];
+    let len = buf.read_u64::<LittleEndian>()? as usize;
+    let mut flags = vec![0u16; len];
+    for plane in 0..PLANES_NUM {
+        let mut bit = 0;
+        let mut pos = 0;
+        loop {
+            let run = read_varint(&mut buf)? as usize;
+            let end = pos + run;
+            if end > len {
+                return Err(Error::new(ErrorKind::InvalidData, "FLAG run is out of block."));
+            }
+            for flag in &mut flags[pos..end] {
+                *flag |= bit << plane;
+            }
+            pos = end;
+            bit ^= 1;
+            if pos == len {
+                break;
+            }
+        }
+    }
+    dest.clear();
+    for flag in flags {
+        dest.write_u16::<LittleEndian>(flag)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flag_codec_roundtrip() {
+        let mut source = Vec::new();
+        for i in 0..10_000u16 {
+            // Paired, proper pair, mate reverse or reverse, first or second.
+            let flag = 0x1 | 0x2 | [0x10 | 0x40, 0x20 | 0x80][(i % 2) as usize] | ((i / 9000) * 0x400);
+            source.write_u16::<LittleEndian>(flag).unwrap();
+        }
+        let compressed = compress(&source).unwrap();
+        let mut decompressed = Vec::new();
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, source);
+
+        let mut decompressed = Vec::new();
+        decompress(&compress(&[]).unwrap(), &mut decompressed).unwrap();
+        assert!(decompressed.is_empty());
+    }
+}
@@ -6,8 +6,28 @@ use std::mem;
 pub mod bam {
     /// BAM to GBAM converter
     pub mod bam_to_gbam;
+    /// BGZF writer
+    pub mod bgzf;
+    /// GBAM to CRAM converter
+    pub mod cram_writer;
+    /// Coordinate sort spilling to temporary GBAM files
+    pub mod external_sort;
+    /// FASTQ reader
+    pub mod fastq_reader;
+    /// FASTQ writer
+    pub mod fastq_writer;
     /// GBAM to BAM converter
     pub mod gbam_to_bam;
+    /// Header editing during conversion
+    pub mod header_edit;
+    /// Merge of coordinate sorted inputs
+    pub mod merge;
+    /// SAM text reader
+    pub mod sam_reader;
+    /// SAM text writer
+    pub mod sam_writer;
+    /// Streaming of records to pipes
+    pub mod stream;
 }
 ///
 pub mod utils {
@@ -16,19 +36,48 @@ pub mod utils {
 }
 
 pub mod reader {
+    /// Async reader for tokio services
+    #[cfg(feature = "tokio")]
+    pub mod async_reader;
+    /// Reader configuration
+    pub mod builder;
+    /// Cache of decompressed blocks
+    pub mod cache;
     pub mod column;
+    /// Record filter expressions
+    pub mod expr;
+    /// Record filters skipping blocks by their stats
+    pub mod filter;
+    /// GBAM index (.gbai)
+    pub mod gbai;
+    /// Mate lookup
+    pub mod mate;
+    /// Merged reading of several sorted files
+    pub mod multi;
     pub mod parse_tmplt;
+    /// Background decompression of upcoming blocks
+    mod prefetch;
     /// GBAM reader
     #[allow(clippy::module_inception)]
     pub mod reader;
     pub mod record;
     pub mod records;
+    /// Region queries over coordinate sorted file
+    pub mod region;
+    /// GBAM files over HTTP(S) and S3
+    #[cfg(feature = "remote")]
+    pub mod remote;
+    /// Chunk-parallel scans
+    mod scan;
+    /// Access to bytes of GBAM file
+    pub mod storage;
 
 }
 
 #[cfg(not(feature = "python-ffi"))]
 pub mod query {
     pub mod cigar;
+    pub mod coverage;
     pub mod depth;
     pub mod flagstat;
     pub mod int2str;
@@ -40,20 +89,63 @@ pub mod query {
 
 
 
+/// Hardware accelerated compression backends
+#[cfg(feature = "hw-accel")]
+pub mod accel;
+/// User defined codecs
+pub mod codec;
+/// Resumable writes
+pub mod checkpoint;
+/// Split stream codec for CIGAR column
+mod cigar_codec;
+/// Codec of constant blocks of unaligned input
+mod const_codec;
 /// Manages parallel compression
 mod compressor;
+/// Manages parallel decompression
+pub mod decompressor;
+/// Write time duplicate marking
+pub mod dup_marker;
+/// Bit-plane codec for FLAG column
+mod flag_codec;
+/// Run-length codec for MAPQ column
+mod mapq_codec;
+/// Relative encoding of mate fields
+mod mate_fields;
 /// Meta information for GBAM file
 pub mod meta;
+/// Token codec for read name column
+mod name_codec;
+/// Delta codec for POS column
+mod pos_codec;
+/// Progress reporting of the writer
+pub mod progress;
+/// Context model codec for quality column
+mod qual_codec;
+/// Write time record filtering
+pub mod record_filter;
+/// Reference sequence metadata and validation
+pub mod reference;
+/// 2-bit packing of sequence column
+mod seq_packing;
+/// Per-tag split of auxiliary data column
+mod tag_codec;
 /// Manages stats collection
 mod stats;
+/// Demultiplexing writer
+pub mod split_writer;
 /// GBAM writer
 pub mod writer;
+/// Validated writer configuration
+pub mod writer_builder;
 
 // use self::writer::Writer;
 // pub use {ParsingTemplate, Reader};
 use self::writer::Writer;
-pub use bam::bam_to_gbam::{bam_sort_to_gbam, bam_to_gbam};
+pub use bam::bam_to_gbam::{bam_sort_to_gbam, bam_to_gbam, estimate_bam_to_gbam, external_sort_to_gbam, fastq_to_gbam, merge_to_gbam, resumable_to_gbam, sam_to_gbam, split_to_gbam};
+pub use compressor::{CompressError, CompressionReport, FieldReport};
 pub use meta::Codecs;
+pub use writer_builder::{ConfigError, WriterBuilder};
 pub use bam_tools::record::fields::Fields;
 
 
@@ -0,0 +1,54 @@
+use crate::cigar_codec::{read_varint, write_varint};
+use byteorder::ReadBytesExt;
+use std::io::Result;
+
+const ZSTD_LEVEL: i32 = 15;
+
+/// Run-length encodes single byte values as (value, varint run length) pairs
+/// and compresses result with ZSTD. MAPQ is usually dominated by few values
+/// (0, 60), so runs are long.
+pub(crate) fn compress(source: &[u8]) -> Result<Vec<u8>> {
+    let mut runs = Vec::new();
+    let mut rest = source;
+    while let Some(&value) = rest.first() {
+        let run = rest.iter().take_while(|&&b| b == value).count();
+        runs.push(value);
+        write_varint(&mut runs, run as u64);
+        rest = &rest[run..];
+    }
+    zstd::stream::encode_all(&runs[..], ZSTD_LEVEL)
+}
+
+pub(crate) fn decompress(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let runs = zstd::stream::decode_all(source)?;
+    let mut runs = &runs[..];
+    dest.clear();
+    while !runs.is_empty() {
+        let value = runs.read_u8()?;
+        let run = read_varint(&mut runs)? as usize;
+        dest.resize(dest.len() + run, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapq_codec_roundtrip() {
+        // Runs of one value, and run too long for a single varint byte of
+        // run length (and for u16).
+        let mut source: Vec<u8> = (0..1000).map(|i| [0, 60, 60, 3][i % 4]).collect();
+        source.extend(std::iter::repeat_n(60, 100_000));
+        source.extend_from_slice(&[0, 255, 255]);
+        let compressed = compress(&source).unwrap();
+        let mut decompressed = Vec::new();
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, source);
+
+        let mut decompressed = Vec::new();
+        decompress(&compress(&[]).unwrap(), &mut decompressed).unwrap();
+        assert!(decompressed.is_empty());
+    }
+}
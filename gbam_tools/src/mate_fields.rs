@@ -0,0 +1,101 @@
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Stored in NextRefID column when mate is on the same reference.
+const SAME_REF: i32 = -2;
+
+const REFID_OFFSET: usize = 0;
+const POS_OFFSET: usize = 4;
+const NEXT_REFID_OFFSET: usize = 20;
+const NEXT_POS_OFFSET: usize = 24;
+const TLEN_OFFSET: usize = 28;
+
+/// Encodes mate fields of raw BAM record relative to its own RNAME and POS.
+/// If mate is on the same reference, RNEXT is replaced with `SAME_REF`, PNEXT
+/// with offset from POS and TLEN with residual from predicted `PNEXT - POS`,
+/// which leaves roughly read length for proper pairs. Records with mate on
+/// other reference or without mate are kept as is.
+pub(crate) fn encode(bytes: &mut [u8]) {
+    let refid = LittleEndian::read_i32(&bytes[REFID_OFFSET..]);
+    let next_refid = LittleEndian::read_i32(&bytes[NEXT_REFID_OFFSET..]);
+    if refid < 0 || next_refid != refid {
+        return;
+    }
+    let pos = LittleEndian::read_i32(&bytes[POS_OFFSET..]);
+    let next_pos = LittleEndian::read_i32(&bytes[NEXT_POS_OFFSET..]);
+    let tlen = LittleEndian::read_i32(&bytes[TLEN_OFFSET..]);
+    let offset = next_pos.wrapping_sub(pos);
+    LittleEndian::write_i32(&mut bytes[NEXT_REFID_OFFSET..], SAME_REF);
+    LittleEndian::write_i32(&mut bytes[NEXT_POS_OFFSET..], offset);
+    LittleEndian::write_i32(&mut bytes[TLEN_OFFSET..], tlen.wrapping_sub(offset));
+}
+
+/// Inverse of `encode`. Record has to hold RefID, Pos, NextRefID and NextPos
+/// (see `add_dependencies`).
+pub(crate) fn decode(rec: &mut GbamRecord) {
+    if rec.next_ref_id != Some(SAME_REF) {
+        return;
+    }
+    rec.next_ref_id = rec.refid;
+    if let (Some(pos), Some(offset)) = (rec.pos, rec.next_pos) {
+        rec.next_pos = Some(pos.wrapping_add(offset));
+        rec.tlen = rec.tlen.map(|tlen| tlen.wrapping_add(offset));
+    }
+}
+
+/// Mate fields can't be decoded on their own, adds fields they depend on.
+pub(crate) fn add_dependencies(tmplt: &mut ParsingTemplate) {
+    let mate_fields = [Fields::NextRefID, Fields::NextPos, Fields::TemplateLength];
+    if tmplt
+        .get_active_data_fields_iter()
+        .any(|field| mate_fields.contains(field))
+    {
+        for field in &[Fields::RefID, Fields::Pos, Fields::NextRefID, Fields::NextPos] {
+            tmplt.set(field, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_raw(bytes: &[u8]) -> GbamRecord {
+        let mut rec = GbamRecord::default();
+        rec.parse_from_bytes(&Fields::RefID, &bytes[REFID_OFFSET..]);
+        rec.parse_from_bytes(&Fields::Pos, &bytes[POS_OFFSET..]);
+        rec.parse_from_bytes(&Fields::NextRefID, &bytes[NEXT_REFID_OFFSET..]);
+        rec.parse_from_bytes(&Fields::NextPos, &bytes[NEXT_POS_OFFSET..]);
+        rec.parse_from_bytes(&Fields::TemplateLength, &bytes[TLEN_OFFSET..]);
+        decode(&mut rec);
+        rec
+    }
+
+    #[test]
+    fn test_mate_fields_roundtrip() {
+        // (refid, pos, next refid, next pos, tlen)
+        let cases = [
+            (1, 1000, 1, 1200, 300),
+            (1, 1200, 1, 1000, -300),
+            (1, 1000, 2, 500, 0),
+            (-1, -1, -1, -1, 0),
+            (0, i32::MAX, 0, 0, i32::MIN),
+        ];
+        for &(refid, pos, next_refid, next_pos, tlen) in &cases {
+            let mut bytes = vec![0u8; 32];
+            LittleEndian::write_i32(&mut bytes[REFID_OFFSET..], refid);
+            LittleEndian::write_i32(&mut bytes[POS_OFFSET..], pos);
+            LittleEndian::write_i32(&mut bytes[NEXT_REFID_OFFSET..], next_refid);
+            LittleEndian::write_i32(&mut bytes[NEXT_POS_OFFSET..], next_pos);
+            LittleEndian::write_i32(&mut bytes[TLEN_OFFSET..], tlen);
+            encode(&mut bytes);
+
+            let rec = decode_raw(&bytes);
+            assert_eq!(rec.next_ref_id, Some(next_refid));
+            assert_eq!(rec.next_pos, Some(next_pos));
+            assert_eq!(rec.tlen, Some(tlen));
+        }
+    }
+}
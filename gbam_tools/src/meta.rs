@@ -1,5 +1,8 @@
-use super::GBAM_MAGIC;
+use super::reader::gbai::GbamIndex;
+use super::reference::sq_md5s;
+use super::{GBAM_MAGIC, SIZE_LIMIT};
 use bam_tools::record::fields::{field_item_size, Fields, FIELDS_NUM};
+use lzzzz::lz4_hc;
 use serde::ser::{SerializeMap, Serializer};
 use serde::{Deserialize, Deserializer, Serialize};
 use std::marker::PhantomData;
@@ -8,6 +11,7 @@ use serde::de::{MapAccess, Visitor};
 // use serde::de::{Deserialize, Deserializer};
 // use serde_json::Result;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 /// Holds data related to GBAM file: gbam version, seekpos to meta.
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -44,16 +48,205 @@ pub enum Codecs {
     Gzip,
     /// LZ4 encoding
     Lz4,
+    /// LZ4 high compression encoding with compression level (1-12). Decoded
+    /// with regular LZ4 decoder.
+    Lz4Hc(i32),
     /// Brotli encoding
     Brotli,
     /// ZSTD encoding
     Zstd,
+    /// ZSTD encoding with long distance matching and given window log
+    /// (10-31). Useful for columns with long range redundancy (qualities,
+    /// tags).
+    ZstdLong(u32),
     /// No compression
     NoCompression,
+    /// Best of several codecs, chosen for each block by trial compression.
+    /// Actual codec is recorded in block meta.
+    Auto,
+    /// User defined codec registered with `codec::register_codec`.
+    Custom(u32),
+    /// Sequence specific: ACGT packed into 2 bits, other bases stored as
+    /// exceptions, then ZSTD. Only for `RawSequence` column.
+    Seq2Bit,
+    /// Quality specific: adaptive context model over previous qualities with
+    /// range coder (fqzcomp-style). Only for `RawQual` column.
+    QualCtx,
+    /// CIGAR specific: op codes and lengths compressed as separate streams.
+    /// Only for `RawCigar` column.
+    CigarSplit,
+    /// Auxiliary data specific: values of each tag compressed as a separate
+    /// column. Only for `RawTags` column.
+    TagSplit,
+    /// Run-length encoding followed by ZSTD. Intended for `Mapq` column.
+    MapqRle,
+    /// FLAG specific: every bit stored as separate run-length encoded plane,
+    /// then ZSTD. Only for `Flags` column.
+    FlagPlanes,
+    /// Varint deltas, reset at reference switches, followed by ZSTD. Suits
+    /// `Pos` column of coordinate sorted files, used only if set for it.
+    PosDelta,
+    /// Read name specific: names split into tokens encoded against the
+    /// previous name, then ZSTD. Only for `ReadName` column, see
+    /// `NameTokenization`.
+    NameTok,
+    /// Block of one item of given size repeated, item is stored once. Chosen
+    /// per block for fixed sized columns of unaligned input, which hold the
+    /// same value in every record (e.g. RefID, POS).
+    Constant(u32),
+    /// Codec this version doesn't know, e.g. of file written by a newer
+    /// one. Its blocks fail to decompress, other columns of file can still
+    /// be read. Meta holding it can't be written back.
+    #[serde(skip_serializing)]
+    Unknown,
 }
 
+/// Codec of meta, `Codecs::Unknown` if this version doesn't know it, so
+/// unknown codec fails only reading of its blocks rather than of file.
+fn deserialize_codec<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Codecs, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(Codecs::deserialize(value).unwrap_or(Codecs::Unknown))
+}
+
+fn deserialize_block_codec<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Codecs>, D::Error> {
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(value.map(|value| Codecs::deserialize(value).unwrap_or(Codecs::Unknown)))
+}
+
+/// Default window log for `Codecs::ZstdLong`, largest window zstd decoder
+/// accepts without extra configuration (128 MB).
+pub const ZSTD_LONG_DEFAULT_WINDOW_LOG: u32 = 27;
+
+impl std::str::FromStr for Codecs {
+    type Err = String;
+
+    /// Codecs with parameter accept it after a colon, e.g. `lz4hc:12`,
+    /// `zstd-long:30` or `custom:1000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param)),
+            None => (s, None),
+        };
+        let parse_param = |default: i32, min: i32, max: i32| match param {
+            None => Ok(default),
+            Some(val) => match val.parse::<i32>() {
+                Ok(val) if (min..=max).contains(&val) => Ok(val),
+                _ => Err(format!(
+                    "Parameter of {} should be in range {}-{}: {}",
+                    name, min, max, s
+                )),
+            },
+        };
+        match (name, param) {
+            ("gzip", None) => Ok(Codecs::Gzip),
+            ("lz4", None) => Ok(Codecs::Lz4),
+            ("brotli", None) => Ok(Codecs::Brotli),
+            ("zstd", None) => Ok(Codecs::Zstd),
+            ("none", None) => Ok(Codecs::NoCompression),
+            ("auto", None) => Ok(Codecs::Auto),
+            ("seq2bit", None) => Ok(Codecs::Seq2Bit),
+            ("qualctx", None) => Ok(Codecs::QualCtx),
+            ("cigarsplit", None) => Ok(Codecs::CigarSplit),
+            ("tagsplit", None) => Ok(Codecs::TagSplit),
+            ("mapqrle", None) => Ok(Codecs::MapqRle),
+            ("flagplanes", None) => Ok(Codecs::FlagPlanes),
+            ("posdelta", None) => Ok(Codecs::PosDelta),
+            ("nametok", None) => Ok(Codecs::NameTok),
+            ("lz4hc", _) => Ok(Codecs::Lz4Hc(parse_param(
+                lz4_hc::CLEVEL_DEFAULT,
+                1,
+                lz4_hc::CLEVEL_MAX,
+            )?)),
+            ("zstd-long", _) => Ok(Codecs::ZstdLong(
+                parse_param(ZSTD_LONG_DEFAULT_WINDOW_LOG as i32, 10, 31)? as u32,
+            )),
+            ("custom", Some(id)) => id
+                .parse::<u32>()
+                .map(Codecs::Custom)
+                .map_err(|_| format!("Custom codec ID should be a number: {}", s)),
+            _ => Err(format!("Unknown codec: {}", s)),
+        }
+    }
+}
+
+/// Lossy binning of base qualities applied at write time. Qualities from
+/// `lower[i]` up to the next bound are replaced with `value[i]`. Qualities
+/// below the first bound and missing qualities (0xFF) are kept as is.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct QualBinning {
+    pub lower: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl QualBinning {
+    /// Illumina 8-level binning. 0 and 1 (no call) are kept.
+    pub fn illumina8() -> Self {
+        QualBinning {
+            lower: vec![2, 10, 20, 25, 30, 35, 40],
+            value: vec![6, 15, 22, 27, 33, 37, 40],
+        }
+    }
+
+    /// Bins between consecutive edges are replaced with their middle, last
+    /// bin with its lower edge. Fails unless edges are ascending.
+    pub fn from_edges(edges: &[u8]) -> Result<Self, String> {
+        if edges.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(format!("Bin edges should be ascending: {:?}", edges));
+        }
+        let value = edges
+            .iter()
+            .enumerate()
+            .map(|(i, &lower)| match edges.get(i + 1) {
+                Some(&upper) => lower + (upper - 1 - lower) / 2,
+                None => lower,
+            })
+            .collect();
+        Ok(QualBinning {
+            lower: edges.to_vec(),
+            value,
+        })
+    }
+
+    /// Maps every possible quality byte to its binned value.
+    pub fn lookup_table(&self) -> [u8; 256] {
+        let mut table = [0u8; 256];
+        for (qual, binned) in table.iter_mut().enumerate() {
+            *binned = qual as u8;
+            if qual == 0xFF {
+                continue;
+            }
+            if let Some(bin) = self.lower.iter().rposition(|&lower| lower as usize <= qual) {
+                *binned = self.value[bin];
+            }
+        }
+        table
+    }
+}
+
+impl std::str::FromStr for QualBinning {
+    type Err = String;
+
+    /// Either `illumina8` or comma separated ascending bin edges, e.g.
+    /// `10,20,30`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "illumina8" {
+            return Ok(QualBinning::illumina8());
+        }
+        let edges = s
+            .split(',')
+            .map(|edge| edge.trim().parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Bin edges should be numbers: {}", s))?;
+        QualBinning::from_edges(&edges)
+    }
+}
+
+/// Fields block stats may be collected for.
+pub const STATS_FIELDS: [Fields; 4] = [Fields::RefID, Fields::Pos, Fields::Mapq, Fields::TemplateLength];
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
-/// Currently block stats only for RefID or POS are supported.
+/// Min and max value of block of one of `STATS_FIELDS`. TLEN stats are of
+/// values as decoded, even in mate relative files.
 pub struct Stat {
     pub min_value: i32,
     pub max_value: i32,
@@ -94,13 +287,93 @@ pub struct BlockMeta {
     pub block_size: u32,
     pub uncompressed_size: u64,
     pub stats: Option<Stat>,
+    /// Codec block was actually compressed with. Files written before it was
+    /// recorded use field codec.
+    #[serde(default, deserialize_with = "deserialize_block_codec")]
+    pub codec: Option<Codecs>,
+    /// xxhash64 of uncompressed block, verified on read. Absent in older
+    /// files.
+    pub checksum: Option<u64>,
+}
+
+/// Limits of uncompressed blocks of a field. Block is flushed once next
+/// record would exceed `max_bytes` or it holds `max_records` records. Block
+/// always holds at least one record, even if it is bigger than `max_bytes`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockLimits {
+    pub max_bytes: usize,
+    pub max_records: Option<u32>,
+}
+
+impl Default for BlockLimits {
+    fn default() -> Self {
+        BlockLimits {
+            max_bytes: SIZE_LIMIT,
+            max_records: None,
+        }
+    }
+}
+
+/// Smallest block of read names tokenized by `NameTokenization::Auto`.
+/// Tokens are encoded against the previous name, which doesn't pay off
+/// for a handful of names.
+pub const AUTO_TOKENIZE_MIN_NAMES: u32 = 1000;
+
+/// Whether blocks of `ReadName` column are compressed with
+/// `Codecs::NameTok` instead of the codec of the column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum NameTokenization {
+    /// Blocks of at least `AUTO_TOKENIZE_MIN_NAMES` names, unless the column
+    /// is not compressed.
+    Auto,
+    /// Every block.
+    On,
+    /// No block. Default, files stay readable by older readers, which don't
+    /// know `Codecs::NameTok`.
+    #[default]
+    Off,
+}
+
+impl NameTokenization {
+    /// Codec of read name block holding `names` names, `codec` being codec
+    /// of the column.
+    pub(crate) fn block_codec(&self, codec: Codecs, names: u32) -> Codecs {
+        match self {
+            NameTokenization::On => Codecs::NameTok,
+            NameTokenization::Auto if names >= AUTO_TOKENIZE_MIN_NAMES && codec != Codecs::NoCompression => {
+                Codecs::NameTok
+            }
+            _ => codec,
+        }
+    }
+}
+
+impl std::str::FromStr for NameTokenization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(NameTokenization::Auto),
+            "on" => Ok(NameTokenization::On),
+            "off" => Ok(NameTokenization::Off),
+            _ => Err(format!("Expected auto, on or off, got: {}", s)),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FieldMeta {
     item_size: Option<u32>, // NONE for variable sized fields
+    #[serde(deserialize_with = "deserialize_codec")]
     codec: Codecs,
     blocks: Vec<BlockMeta>,
+    /// Absent if default limits were used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    block_limits: Option<BlockLimits>,
+    /// Data of the field is not stored. Index of omitted variable sized
+    /// field holds length of every value instead of offsets.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    omitted: bool,
 }
 
 impl FieldMeta {
@@ -109,6 +382,8 @@ impl FieldMeta {
             item_size: field_item_size(field).map(|v| v as u32), // TODO
             codec,
             blocks: Vec::<BlockMeta>::new(),
+            block_limits: None,
+            omitted: false,
         }
     }
 }
@@ -119,6 +394,8 @@ impl Default for FieldMeta {
             item_size: None,
             codec: Codecs::Gzip,
             blocks: Vec::<BlockMeta>::new(),
+            block_limits: None,
+            omitted: false,
         }
     }
 }
@@ -133,6 +410,23 @@ pub struct FileMeta {
     field_to_meta: [FieldMeta; FIELDS_NUM],
     sam_header: Vec<u8>,
     name_to_ref_id: Vec<(String, u32)>,
+    /// Qualities were binned at write time if present.
+    #[serde(default)]
+    qual_binning: Option<QualBinning>,
+    /// Mate fields are stored relative to record's own RNAME and POS.
+    #[serde(default)]
+    mate_relative: bool,
+    /// M5 of @SQ lines, indexed by reference ID. Empty if the header has
+    /// none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ref_md5s: Vec<Option<String>>,
+    /// Longest reference span of records of every reference, indexed by
+    /// reference ID. Recorded for coordinate sorted files only.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ref_max_spans: Vec<u32>,
+    /// Index embedded with `GbamIndex::embed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<GbamIndex>,
 }
 
 impl FileMeta {
@@ -144,10 +438,48 @@ impl FileMeta {
     pub fn get_sam_header(&self) -> &[u8] {
         &self.sam_header[..]
     }
+
+    pub fn set_sam_header(&mut self, sam_header: Vec<u8>) {
+        self.sam_header = sam_header;
+    }
+
+    /// Longest reference span of records of `ref_id`, so records
+    /// overlapping a region start at most that far before it. None if not
+    /// recorded, see `Reader::fetch`.
+    pub fn get_max_span(&self, ref_id: usize) -> Option<u32> {
+        self.ref_max_spans.get(ref_id).copied()
+    }
+
+    pub(crate) fn update_max_span(&mut self, ref_id: i32, span: u32) {
+        let ref_id = match usize::try_from(ref_id) {
+            Ok(ref_id) if ref_id < self.name_to_ref_id.len() => ref_id,
+            _ => return,
+        };
+        if self.ref_max_spans.is_empty() {
+            self.ref_max_spans.resize(self.name_to_ref_id.len(), 0);
+        }
+        let max_span = &mut self.ref_max_spans[ref_id];
+        *max_span = (*max_span).max(span);
+    }
+
+    pub fn get_index(&self) -> Option<&GbamIndex> {
+        self.index.as_ref()
+    }
+
+    pub(crate) fn set_index(&mut self, index: Option<GbamIndex>) {
+        self.index = index;
+    }
+
+    /// M5 (lowercase hex MD5) of reference sequence `ref_id` as given by its
+    /// @SQ line at write time.
+    pub fn get_ref_md5(&self, ref_id: usize) -> Option<&str> {
+        self.ref_md5s.get(ref_id)?.as_deref()
+    }
 }
 
 // To make metadata easier to read, convert to json where fields are represented
-// as strings with their names, not numbers in enum.
+// as strings with their names, not numbers in enum. Fields are written in
+// enum order, so identical files get byte-identical metadata.
 
 fn serialize_field_to_meta<S>(
     meta: &[FieldMeta; FIELDS_NUM],
@@ -156,11 +488,11 @@ fn serialize_field_to_meta<S>(
 where
     S: Serializer,
 {
-    let mut map = FieldMetaMap(HashMap::new());
+    let mut map = serializer.serialize_map(Some(FIELDS_NUM))?;
     for field in Fields::iterator() {
-        map.0.insert(*field, meta[*field as usize].clone());
+        map.serialize_entry(&field.to_string(), &meta[*field as usize])?;
     }
-    map.serialize(serializer)
+    map.end()
 }
 
 fn from_str<'de, D>(deserializer: D) -> Result<[FieldMeta; FIELDS_NUM], D::Error>
@@ -240,6 +572,7 @@ impl<'de> Deserialize<'de> for FieldMetaMap {
 
 impl FileMeta {
     pub fn new(codec: Codecs, ref_seqs: Vec<(String, u32)>, sam_header: Vec<u8>) -> Self {
+        let ref_md5s = sq_md5s(&sam_header, &ref_seqs);
         let mut map: [FieldMeta; FIELDS_NUM] = Default::default();
         for field in Fields::iterator() {
             map[*field as usize] = FieldMeta::new(field, codec);
@@ -252,6 +585,11 @@ impl FileMeta {
             field_to_meta: map,
             sam_header,
             name_to_ref_id: ref_seqs,
+            qual_binning: None,
+            mate_relative: false,
+            ref_md5s,
+            ref_max_spans: Vec::new(),
+            index: None,
         }
     }
 
@@ -272,4 +610,80 @@ impl FileMeta {
     pub fn get_field_codec(&self, field: &Fields) -> &Codecs {
         &self.field_to_meta[*field as usize].codec
     }
+
+    /// Codec `block_num` block of `field` was actually compressed with. Same
+    /// as field codec for files written before it was recorded per block.
+    pub fn get_block_codec(&self, field: &Fields, block_num: usize) -> Codecs {
+        let field_meta = &self.field_to_meta[*field as usize];
+        field_meta.blocks[block_num].codec.unwrap_or(field_meta.codec)
+    }
+
+    pub fn set_field_codec(&mut self, field: &Fields, codec: Codecs) {
+        self.field_to_meta[*field as usize].codec = codec;
+    }
+
+    /// Limits blocks of `field` were written with.
+    pub fn get_block_limits(&self, field: &Fields) -> BlockLimits {
+        self.field_to_meta[*field as usize].block_limits.unwrap_or_default()
+    }
+
+    pub fn set_block_limits(&mut self, field: &Fields, limits: BlockLimits) {
+        self.field_to_meta[*field as usize].block_limits = Some(limits).filter(|limits| *limits != BlockLimits::default());
+    }
+
+    /// Whether data of `field` was omitted at write time.
+    pub fn is_omitted(&self, field: &Fields) -> bool {
+        self.field_to_meta[*field as usize].omitted
+    }
+
+    pub fn set_omitted(&mut self, field: &Fields) {
+        self.field_to_meta[*field as usize].omitted = true;
+    }
+
+    pub fn get_qual_binning(&self) -> Option<&QualBinning> {
+        self.qual_binning.as_ref()
+    }
+
+    pub fn set_qual_binning(&mut self, binning: QualBinning) {
+        self.qual_binning = Some(binning);
+    }
+
+    pub fn is_mate_relative(&self) -> bool {
+        self.mate_relative
+    }
+
+    pub fn set_mate_relative(&mut self, mate_relative: bool) {
+        self.mate_relative = mate_relative;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qual_binning_edges() {
+        // (quality, binned) on and around every edge.
+        let table = QualBinning::illumina8().lookup_table();
+        let expected = [
+            (0, 0), (1, 1), (2, 6), (9, 6), (10, 15), (19, 15), (20, 22), (24, 22), (25, 27),
+            (29, 27), (30, 33), (34, 33), (35, 37), (39, 37), (40, 40), (41, 40), (93, 40), (0xFF, 0xFF),
+        ];
+        for (qual, binned) in expected {
+            assert_eq!(table[qual], binned, "quality {}", qual);
+        }
+
+        let binning: QualBinning = "10,20,30".parse().unwrap();
+        assert_eq!(binning, QualBinning::from_edges(&[10, 20, 30]).unwrap());
+        assert_eq!(binning.value, [14, 24, 30]);
+        let table = binning.lookup_table();
+        let expected = [(9, 9), (10, 14), (19, 14), (20, 24), (29, 24), (30, 30), (60, 30), (0xFF, 0xFF)];
+        for (qual, binned) in expected {
+            assert_eq!(table[qual], binned, "quality {}", qual);
+        }
+        assert!("20,10".parse::<QualBinning>().is_err());
+        assert!(QualBinning::from_edges(&[10, 10, 30]).is_err());
+        assert!(QualBinning::from_edges(&[30, 20]).is_err());
+        assert_eq!(QualBinning::from_edges(&[0, 1, 255]).unwrap().value, [0, 127, 255]);
+    }
 }
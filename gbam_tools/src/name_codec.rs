@@ -0,0 +1,184 @@
+use crate::cigar_codec::{read_varint, write_varint};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Error, ErrorKind, Result, Write};
+
+const ZSTD_LEVEL: i32 = 15;
+/// Longer digit runs are kept as strings, they could overflow u64.
+const MAX_NUM_DIGITS: usize = 18;
+/// Numbers growing by less than this are stored as delta.
+const MAX_DELTA: u64 = 256;
+
+// Token types.
+const MATCH: u8 = 0;
+const DELTA: u8 = 1;
+const NUM: u8 = 2;
+const STR: u8 = 3;
+const END: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token<'a> {
+    Num(u64),
+    Str(&'a [u8]),
+}
+
+/// Splits name into runs of digits and runs of other characters. Digit runs
+/// with leading zeros are kept as strings, so they are restored as they were.
+fn tokenize(name: &[u8]) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while start < name.len() {
+        let is_digit = name[start].is_ascii_digit();
+        let end = name[start..]
+            .iter()
+            .position(|c| c.is_ascii_digit() != is_digit)
+            .map_or(name.len(), |len| start + len);
+        let run = &name[start..end];
+        let token = if is_digit && run.len() <= MAX_NUM_DIGITS && (run[0] != b'0' || run.len() == 1) {
+            Token::Num(run.iter().fold(0, |num, c| num * 10 + (c - b'0') as u64))
+        } else {
+            Token::Str(run)
+        };
+        tokens.push(token);
+        start = end;
+    }
+    tokens
+}
+
+/// Splits block of NUL terminated read names into tokens (see `tokenize`)
+/// and encodes every token against token at the same position of the
+/// previous name: repeated tokens take a single type byte, numbers growing
+/// by a little (e.g. coordinates of clusters on a tile) are stored as
+/// deltas. Types, strings, numbers and deltas are separate streams, which
+/// are then compressed with ZSTD.
+///
+/// Layout before zstd: lengths of types, strings and numbers (u64 each),
+/// types, NUL terminated strings, varint numbers, varint deltas.
+pub(crate) fn compress(source: &[u8]) -> Result<Vec<u8>> {
+    if source.last().is_some_and(|&c| c != 0) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Read names should be NUL terminated.",
+        ));
+    }
+    let (mut types, mut strs, mut nums, mut deltas) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut prev = Vec::new();
+    for name in source.split_inclusive(|&c| c == 0) {
+        let tokens = tokenize(&name[..name.len() - 1]);
+        for (i, token) in tokens.iter().enumerate() {
+            match (*token, prev.get(i)) {
+                (token, Some(prev)) if token == *prev => types.push(MATCH),
+                (Token::Num(num), Some(Token::Num(prev))) if num >= *prev && num - prev < MAX_DELTA => {
+                    types.push(DELTA);
+                    write_varint(&mut deltas, num - prev);
+                }
+                (Token::Num(num), _) => {
+                    types.push(NUM);
+                    write_varint(&mut nums, num);
+                }
+                (Token::Str(str), _) => {
+                    types.push(STR);
+                    strs.extend_from_slice(str);
+                    strs.push(0);
+                }
+            }
+        }
+        types.push(END);
+        prev = tokens;
+    }
+
+    let mut buf = Vec::with_capacity(24 + types.len() + strs.len() + nums.len() + deltas.len());
+    for stream in [&types, &strs, &nums] {
+        buf.write_u64::<LittleEndian>(stream.len() as u64)?;
+    }
+    for stream in [&types, &strs, &nums, &deltas] {
+        buf.extend_from_slice(stream);
+    }
+    zstd::stream::encode_all(&buf[..], ZSTD_LEVEL)
+}
+
+fn corrupted() -> Error {
+    Error::new(ErrorKind::InvalidData, "Read name block is corrupted.")
+}
+
+fn take<'a>(source: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if source.len() < len {
+        return Err(corrupted());
+    }
+    let (head, tail) = source.split_at(len);
+    *source = tail;
+    Ok(head)
+}
+
+pub(crate) fn decompress(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let buf = zstd::stream::decode_all(source)?;
+    let mut reader = &buf[..];
+    let types_len = reader.read_u64::<LittleEndian>()? as usize;
+    let strs_len = reader.read_u64::<LittleEndian>()? as usize;
+    let nums_len = reader.read_u64::<LittleEndian>()? as usize;
+    let types = take(&mut reader, types_len)?;
+    let mut strs = take(&mut reader, strs_len)?;
+    let mut nums = take(&mut reader, nums_len)?;
+    let mut deltas = reader;
+
+    dest.clear();
+    let mut prev = Vec::new();
+    let mut tokens = Vec::new();
+    for &kind in types {
+        let token = match (kind, prev.get(tokens.len())) {
+            (END, _) => {
+                dest.push(0);
+                std::mem::swap(&mut prev, &mut tokens);
+                tokens.clear();
+                continue;
+            }
+            (MATCH, Some(prev)) => *prev,
+            (DELTA, Some(Token::Num(prev))) => {
+                Token::Num(prev.checked_add(read_varint(&mut deltas)?).ok_or_else(corrupted)?)
+            }
+            (NUM, _) => Token::Num(read_varint(&mut nums)?),
+            (STR, _) => {
+                let len = strs.iter().position(|&c| c == 0).ok_or_else(corrupted)?;
+                let str = take(&mut strs, len + 1)?;
+                Token::Str(&str[..len])
+            }
+            _ => return Err(corrupted()),
+        };
+        match token {
+            Token::Num(num) => write!(dest, "{}", num)?,
+            Token::Str(str) => dest.extend_from_slice(str),
+        }
+        tokens.push(token);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_codec_roundtrip() {
+        let mut source = Vec::new();
+        for i in 0..1000u64 {
+            let name = format!("A00123:8:H7KJCDSXY:{}:1101:{}:{}", 1 + i / 500, 1000 + i * 7, 30_000 - i * 3);
+            source.extend_from_slice(name.as_bytes());
+            source.push(0);
+        }
+        // Leading zeros, digit runs too long for u64, empty and short names.
+        for name in ["read007", "r0", "x12345678901234567890y", "", "SRR1.5", "SRR1.6/1", "a:b"] {
+            source.extend_from_slice(name.as_bytes());
+            source.push(0);
+        }
+        let compressed = compress(&source).unwrap();
+        let mut decompressed = Vec::new();
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, source);
+
+        let plain = zstd::stream::encode_all(&source[..], ZSTD_LEVEL).unwrap();
+        assert!(compressed.len() < plain.len());
+
+        decompress(&compress(&[]).unwrap(), &mut decompressed).unwrap();
+        assert!(decompressed.is_empty());
+        assert!(compress(b"r1\0r2").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::cigar_codec::{read_varint, write_varint};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+
+const ZSTD_LEVEL: i32 = 15;
+/// Precedes position stored as is instead of as delta.
+const RESET: u64 = 0;
+
+/// Stores i32 positions as varint deltas from the previous position and
+/// compresses result with ZSTD. In coordinate sorted files deltas are small
+/// and non-negative. Delta is reset wherever position decreases, i.e. at
+/// switch to the next reference and at `-1` of unmapped reads: `RESET` is
+/// written followed by zigzag encoded position itself.
+pub(crate) fn compress(source: &[u8]) -> Result<Vec<u8>> {
+    if !source.len().is_multiple_of(4) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "POS block size should be multiple of 4.",
+        ));
+    }
+    let mut buf = Vec::with_capacity(source.len() / 2);
+    let mut prev = 0i64;
+    for pos in source.chunks_exact(4).map(LittleEndian::read_i32) {
+        let pos = i64::from(pos);
+        if pos >= prev {
+            write_varint(&mut buf, (pos - prev) as u64 + 1);
+        } else {
+            write_varint(&mut buf, RESET);
+            write_varint(&mut buf, ((pos << 1) ^ (pos >> 63)) as u64);
+        }
+        prev = pos;
+    }
+    zstd::stream::encode_all(&buf[..], ZSTD_LEVEL)
+}
+
+pub(crate) fn decompress(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let buf = zstd::stream::decode_all(source)?;
+    let mut buf = &buf[..];
+    dest.clear();
+    let mut prev = 0i64;
+    while !buf.is_empty() {
+        prev = match read_varint(&mut buf)? {
+            RESET => {
+                let zigzag = read_varint(&mut buf)?;
+                (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64)
+            }
+            delta => prev.saturating_add_unsigned(delta - 1),
+        };
+        let pos = i32::try_from(prev).map_err(|_| Error::new(ErrorKind::InvalidData, "POS is out of range."))?;
+        dest.write_i32::<LittleEndian>(pos)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pos_codec_roundtrip() {
+        // Sorted records of two references followed by unmapped ones.
+        let positions = [0, 5, 5, 1000, i32::MAX, 3, 4, 4, 70_000, -1, -1];
+        let mut source = Vec::new();
+        for pos in positions {
+            source.write_i32::<LittleEndian>(pos).unwrap();
+        }
+        let compressed = compress(&source).unwrap();
+        let mut decompressed = Vec::new();
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, source);
+
+        let mut decompressed = Vec::new();
+        decompress(&compress(&[]).unwrap(), &mut decompressed).unwrap();
+        assert!(decompressed.is_empty());
+        assert!(compress(&[0; 3]).is_err());
+        // Delta leading past i32.
+        let mut corrupted = Vec::new();
+        write_varint(&mut corrupted, u64::from(u32::MAX));
+        let corrupted = zstd::stream::encode_all(&corrupted[..], ZSTD_LEVEL).unwrap();
+        assert_eq!(decompress(&corrupted, &mut decompressed).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
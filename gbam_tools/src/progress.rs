@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+/// Totals of a writer so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Records pushed into the writer, filtered out ones included.
+    pub records: u64,
+    /// Bytes of raw BAM records pushed (without `block_size`).
+    pub bytes_in: u64,
+    /// Bytes written into output. Includes file meta once finished.
+    pub bytes_out: u64,
+    /// Compressed blocks written into output.
+    pub blocks_flushed: u64,
+}
+
+/// Receives progress of a writer, e.g. to render progress bar or export
+/// metrics. Called from the thread pushing records, so it should be cheap.
+pub trait ProgressObserver: Send {
+    /// Called every time blocks were written out.
+    fn on_progress(&mut self, progress: &Progress);
+
+    /// Called once the writer is finished, with final totals.
+    fn on_finish(&mut self, progress: &Progress) {
+        self.on_progress(progress);
+    }
+}
@@ -0,0 +1,265 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Error, ErrorKind, Result};
+
+/// Qualities above are clamped when used as context.
+const CTX_QUAL_MAX: usize = 63;
+const CTX_NUM: usize = (CTX_QUAL_MAX + 1) * (CTX_QUAL_MAX + 1);
+/// Frequency increment after each coded symbol.
+const FREQ_STEP: u32 = 16;
+/// Frequencies are halved once total reaches it. Keeps every frequency in
+/// u16 and total below `RC_TOP >> 8`, so range never drops to zero.
+const FREQ_MAX_TOTAL: u32 = 1 << 15;
+const RC_TOP: u32 = 1 << 24;
+
+/// Order-2 adaptive frequency models, one per context. Context is built from
+/// previous quality and maximum of two before it, as in fqzcomp. Position in
+/// read isn't known since blocks hold concatenated qualities without
+/// boundaries.
+struct Models {
+    alphabet: usize,
+    freqs: Vec<u16>,
+    totals: Vec<u32>,
+}
+
+impl Models {
+    fn new(alphabet: usize) -> Self {
+        Models {
+            alphabet,
+            freqs: vec![1; CTX_NUM * alphabet],
+            totals: vec![alphabet as u32; CTX_NUM],
+        }
+    }
+
+    fn context(history: &[u8; 3]) -> usize {
+        let q1 = (history[0] as usize).min(CTX_QUAL_MAX);
+        let q2 = (history[1].max(history[2]) as usize).min(CTX_QUAL_MAX);
+        q1 * (CTX_QUAL_MAX + 1) + q2
+    }
+
+    fn freqs(&self, ctx: usize) -> &[u16] {
+        &self.freqs[ctx * self.alphabet..(ctx + 1) * self.alphabet]
+    }
+
+    fn update(&mut self, ctx: usize, sym: usize) {
+        let freqs = &mut self.freqs[ctx * self.alphabet..(ctx + 1) * self.alphabet];
+        freqs[sym] += FREQ_STEP as u16;
+        self.totals[ctx] += FREQ_STEP;
+        if self.totals[ctx] >= FREQ_MAX_TOTAL {
+            let mut total = 0;
+            for freq in freqs.iter_mut() {
+                *freq = freq.div_ceil(2);
+                total += *freq as u32;
+            }
+            self.totals[ctx] = total;
+        }
+    }
+}
+
+fn push_history(history: &mut [u8; 3], qual: u8) {
+    history[2] = history[1];
+    history[1] = history[0];
+    history[0] = qual;
+}
+
+/// Range coder with carry propagation (as in LZMA).
+struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn new(out: Vec<u8>) -> Self {
+        RangeEncoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            out,
+        }
+    }
+
+    fn encode(&mut self, cum: u32, freq: u32, total: u32) {
+        let r = self.range / total;
+        self.low += r as u64 * cum as u64;
+        self.range = r * freq;
+        while self.range < RC_TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if self.low < 0xFF00_0000 || self.low > u32::MAX as u64 {
+            let carry = (self.low >> 32) as u8;
+            let mut temp = self.cache;
+            loop {
+                self.out.push(temp.wrapping_add(carry));
+                temp = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+}
+
+struct RangeDecoder<'a> {
+    code: u32,
+    range: u32,
+    r: u32,
+    input: &'a [u8],
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        let mut decoder = RangeDecoder {
+            code: 0,
+            range: u32::MAX,
+            r: 0,
+            input,
+        };
+        for _ in 0..5 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte() as u32;
+        }
+        decoder
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        match self.input.split_first() {
+            Some((&byte, rest)) => {
+                self.input = rest;
+                byte
+            }
+            None => 0,
+        }
+    }
+
+    fn get_freq(&mut self, total: u32) -> u32 {
+        self.r = self.range / total;
+        (self.code / self.r).min(total - 1)
+    }
+
+    fn decode(&mut self, cum: u32, freq: u32) -> Result<()> {
+        // Code stays within interval of decoded symbol unless input is
+        // corrupted.
+        self.code = self
+            .code
+            .checked_sub(self.r * cum)
+            .filter(|&code| code < self.r * freq)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Corrupted quality range code."))?;
+        self.range = self.r * freq;
+        while self.range < RC_TOP {
+            self.code = (self.code << 8) | self.next_byte() as u32;
+            self.range <<= 8;
+        }
+        Ok(())
+    }
+}
+
+/// Layout: source length (u64), alphabet size (u16), range coded qualities.
+pub(crate) fn compress(source: &[u8], mut dest: Vec<u8>) -> Result<Vec<u8>> {
+    let alphabet = source.iter().max().map_or(0, |&max| max as usize + 1);
+    dest.clear();
+    dest.write_u64::<LittleEndian>(source.len() as u64)?;
+    dest.write_u16::<LittleEndian>(alphabet as u16)?;
+    if source.is_empty() {
+        return Ok(dest);
+    }
+
+    let mut models = Models::new(alphabet);
+    let mut encoder = RangeEncoder::new(dest);
+    let mut history = [0u8; 3];
+    for &qual in source {
+        let ctx = Models::context(&history);
+        let freqs = models.freqs(ctx);
+        let cum: u32 = freqs[..qual as usize].iter().map(|&f| f as u32).sum();
+        encoder.encode(cum, freqs[qual as usize] as u32, models.totals[ctx]);
+        models.update(ctx, qual as usize);
+        push_history(&mut history, qual);
+    }
+    Ok(encoder.finish())
+}
+
+pub(crate) fn decompress(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let mut reader = source;
+    let len = reader.read_u64::<LittleEndian>()? as usize;
+    let alphabet = reader.read_u16::<LittleEndian>()? as usize;
+    dest.clear();
+    if len == 0 {
+        return Ok(());
+    }
+    if alphabet == 0 || alphabet > 256 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid quality alphabet size.",
+        ));
+    }
+
+    let mut models = Models::new(alphabet);
+    let mut decoder = RangeDecoder::new(reader);
+    let mut history = [0u8; 3];
+    dest.reserve(len);
+    for _ in 0..len {
+        let ctx = Models::context(&history);
+        let target = decoder.get_freq(models.totals[ctx]);
+        let freqs = models.freqs(ctx);
+        let mut cum = 0;
+        let mut qual = 0;
+        while cum + freqs[qual] as u32 <= target {
+            cum += freqs[qual] as u32;
+            qual += 1;
+        }
+        decoder.decode(cum, freqs[qual] as u32)?;
+        models.update(ctx, qual);
+        push_history(&mut history, qual as u8);
+        dest.push(qual as u8);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qual_codec_roundtrip() {
+        // Pseudo random qualities with a drop towards the read end, plus
+        // missing qualities.
+        let mut seed = 17u32;
+        let mut source: Vec<u8> = (0..200_000)
+            .map(|i| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let qual = 40 - ((i % 100) / 10) as u8 - ((seed >> 16) % 4) as u8;
+                qual + 33
+            })
+            .collect();
+        source.extend_from_slice(&[0xFF; 1000]);
+
+        let compressed = compress(&source, Vec::new()).unwrap();
+        assert!(compressed.len() < source.len() / 2);
+        let mut decompressed = Vec::new();
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, source);
+
+        let mut corrupted = compressed.clone();
+        corrupted[10..].iter_mut().for_each(|byte| *byte = 0xFF);
+        assert_eq!(
+            decompress(&corrupted, &mut decompressed).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
@@ -45,7 +45,7 @@ impl Op {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cigar(pub Vec<Op>);
 
 pub fn base_coverage(arr: &[Op]) -> u32 {
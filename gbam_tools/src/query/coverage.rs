@@ -0,0 +1,218 @@
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+
+use bam_tools::record::fields::Fields;
+
+use crate::reader::{reader::Reader, record::GbamRecord, region::Region};
+
+/// Records `samtools depth` skips by default: unmapped, secondary, QC failed
+/// and duplicates.
+pub const DEFAULT_EXCLUDE_FLAGS: u16 = 0x4 | 0x100 | 0x200 | 0x400;
+
+/// Which records and bases `Reader::coverage` counts.
+#[derive(Clone, Debug)]
+pub struct CoverageOptions {
+    /// Records with any of these flags are skipped.
+    pub exclude_flags: u16,
+    /// Records with lower MAPQ are skipped.
+    pub min_mapq: u8,
+    /// Bases per window, 1 gives depth of every base.
+    pub window: u32,
+    /// Whether deleted bases (D) are covered. Skipped ones (N) never are.
+    pub count_deletions: bool,
+}
+
+impl Default for CoverageOptions {
+    fn default() -> Self {
+        Self {
+            exclude_flags: DEFAULT_EXCLUDE_FLAGS,
+            min_mapq: 0,
+            window: 1,
+            count_deletions: false,
+        }
+    }
+}
+
+impl CoverageOptions {
+    fn accepts(&self, rec: &GbamRecord) -> bool {
+        rec.flag.is_some_and(|flag| flag & self.exclude_flags == 0)
+            && (self.min_mapq == 0 || rec.mapq.is_some_and(|mapq| mapq >= self.min_mapq))
+    }
+}
+
+/// Coverage of region, see `Reader::coverage`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    pub region: Region,
+    pub window: u32,
+    /// Covered bases of each window from region start, i.e. depth of every
+    /// base if window is 1.
+    pub bases: Vec<u64>,
+}
+
+impl Coverage {
+    /// 0-based half-open start and end of each window with its mean depth.
+    /// The last window is cut at region end.
+    pub fn windows(&self) -> impl Iterator<Item = (u32, u32, f64)> + '_ {
+        self.bases.iter().enumerate().map(move |(i, &bases)| {
+            let start = self.region.start + i as u32 * self.window;
+            let end = start.saturating_add(self.window).min(self.region.end);
+            (start, end, bases as f64 / f64::from(end - start))
+        })
+    }
+}
+
+/// Reference intervals of `rec` covering bases: M, = and X operations of
+/// CIGAR, and D ones if `count_deletions`.
+fn aligned_segments(rec: &GbamRecord, count_deletions: bool) -> Vec<(i64, i64)> {
+    let (mut pos, cigar) = match (rec.pos, rec.cigar.as_ref()) {
+        (Some(pos), Some(cigar)) if pos >= 0 => (i64::from(pos), cigar),
+        _ => return Vec::new(),
+    };
+    let mut segments = Vec::new();
+    for op in &cigar.0 {
+        if !op.is_consuming_reference() {
+            continue;
+        }
+        let end = pos + i64::from(op.length());
+        match op.op_type() {
+            'N' => {}
+            'D' if !count_deletions => {}
+            _ => match segments.last_mut() {
+                Some((_, last_end)) if *last_end == pos => *last_end = end,
+                _ => segments.push((pos, end)),
+            },
+        }
+        pos = end;
+    }
+    segments
+}
+
+impl Reader {
+    /// Coverage of `region` (e.g. `chr1:100-200`, see `Region::parse`) by
+    /// records passing `options`, per base or per window. Only RNAME, POS,
+    /// CIGAR and FLAG columns (and MAPQ if filtered by) are decoded, on
+    /// rayon's thread pool (see `par_scan`). Records of coordinate sorted
+    /// file are looked up as `fetch` does, other files are scanned whole.
+    /// Per-base coverage takes 8 bytes per base of region.
+    pub fn coverage(&self, region: &str, options: &CoverageOptions) -> Result<Coverage> {
+        let parsed = Region::parse(region, self.file_meta.get_ref_seqs())?;
+        if options.window == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Coverage window must not be empty."));
+        }
+        let (start, end) = (i64::from(parsed.start), i64::from(parsed.end));
+        let window = i64::from(options.window);
+        let per_base = options.window == 1;
+        // Per-base depth is collected as its differences: +1 where segment
+        // starts and -1 where it ends, wrapping until summed up.
+        let len = if per_base { end - start + 1 } else { (end - start + window - 1) / window };
+        let bases = Mutex::new(vec![0u64; len as usize]);
+
+        let mut fields = vec![Fields::RefID, Fields::Pos, Fields::RawCigar, Fields::Flags];
+        if options.min_mapq > 0 {
+            fields.push(Fields::Mapq);
+        }
+        let sorted = self.index_mapping.is_none() && self.is_sorted();
+        let regions = if sorted { vec![region.to_owned()] } else { Vec::new() };
+        self.par_scan(&regions, &fields, |batch| {
+            let mut bases = bases.lock().unwrap();
+            for rec in batch.iter().filter(|rec| options.accepts(rec) && parsed.overlaps(rec)) {
+                for (seg_start, seg_end) in aligned_segments(rec, options.count_deletions) {
+                    let (seg_start, seg_end) = (seg_start.max(start) - start, seg_end.min(end) - start);
+                    if seg_start >= seg_end {
+                        continue;
+                    }
+                    if per_base {
+                        bases[seg_start as usize] = bases[seg_start as usize].wrapping_add(1);
+                        bases[seg_end as usize] = bases[seg_end as usize].wrapping_sub(1);
+                        continue;
+                    }
+                    for bin in seg_start / window..=(seg_end - 1) / window {
+                        let covered = seg_end.min((bin + 1) * window) - seg_start.max(bin * window);
+                        bases[bin as usize] += covered as u64;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        let mut bases = bases.into_inner().unwrap();
+        if per_base {
+            let mut depth = 0u64;
+            for slot in bases.iter_mut() {
+                depth = depth.wrapping_add(*slot);
+                *slot = depth;
+            }
+            bases.pop();
+        }
+        Ok(Coverage { region: parsed, window: options.window, bases })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn write_gbam(path: &Path, sam: &str, sorted: bool) {
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let mut writer = Writer::new(File::create(path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), sorted);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(2) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_coverage() {
+        let lines = [
+            "r1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\t*",
+            "r2\t16\tchr1\t3\t10\t2M2D2M\t*\t0\t0\tACGT\t*",
+            "r3\t1024\tchr1\t3\t60\t4M\t*\t0\t0\tACGT\t*",
+            "r4\t0\tchr1\t5\t60\t2S1M3N2M\t*\t0\t0\tACGTA\t*",
+            "r5\t0\tchr2\t2\t60\t3M\t*\t0\t0\tACG\t*",
+            "r6\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*",
+        ];
+        let header = "@SQ\tSN:chr1\tLN:12\n@SQ\tSN:chr2\tLN:5\n";
+        let dir = TempDir::new("coverage").unwrap();
+        let sorted = dir.path().join("sorted.gbam");
+        write_gbam(&sorted, &format!("@HD\tVN:1.6\tSO:coordinate\n{}{}\n", header, lines.join("\n")), true);
+        let unsorted = dir.path().join("unsorted.gbam");
+        let mut shuffled = lines.to_vec();
+        shuffled.reverse();
+        write_gbam(&unsorted, &format!("{}{}\n", header, shuffled.join("\n")), false);
+
+        for path in [&sorted, &unsorted] {
+            let reader = ReaderBuilder::new().open(File::open(path).unwrap()).unwrap();
+            let bases = |region: &str, options: &CoverageOptions| reader.coverage(region, options).unwrap().bases;
+            let mut options = CoverageOptions::default();
+            assert_eq!(bases("chr1", &options), [1, 1, 2, 2, 1, 0, 1, 1, 1, 1, 0, 0]);
+            assert_eq!(bases("chr2:2-3", &options), [1, 1]);
+            options.count_deletions = true;
+            assert_eq!(bases("chr1:4-8", &options), [2, 2, 1, 1, 1]);
+            options.min_mapq = 20;
+            options.exclude_flags = 0;
+            assert_eq!(bases("chr1:3-6", &options), [2, 2, 2, 1]);
+
+            let options = CoverageOptions { window: 5, ..Default::default() };
+            let coverage = reader.coverage("chr1", &options).unwrap();
+            assert_eq!(coverage.bases, [7, 4, 0]);
+            let windows: Vec<_> = coverage.windows().collect();
+            assert_eq!(windows, [(0, 5, 1.4), (5, 10, 0.8), (10, 12, 0.0)]);
+        }
+        let reader = ReaderBuilder::new().open(File::open(&sorted).unwrap()).unwrap();
+        assert!(reader.coverage("chr3", &CoverageOptions::default()).is_err());
+        assert!(reader.coverage("chr1", &CoverageOptions { window: 0, ..Default::default() }).is_err());
+    }
+}
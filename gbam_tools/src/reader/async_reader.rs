@@ -0,0 +1,221 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use super::{reader::Reader, record::GbamRecord};
+use crate::bam::gbam_to_bam::write_bam;
+
+/// Records sent ahead of consumer.
+const RECORDS_QUEUE: usize = 1024;
+/// Size of BAM chunks sent by `BamBytes`, and their number sent ahead.
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHUNKS_QUEUE: usize = 4;
+
+/// Runs `produce` on blocking thread pool of tokio runtime, feeding channel
+/// with bounded capacity, so reading waits for slow consumer. Producer stops
+/// once receiver is dropped.
+fn spawn_producer<T, F>(capacity: usize, produce: F) -> Receiver<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Sender<T>) + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(capacity);
+    tokio::task::spawn_blocking(move || produce(&tx));
+    rx
+}
+
+/// Async stream of records. Blocks are fetched and decompressed by `Reader`
+/// on blocking thread pool, so executor threads never wait for disk or
+/// decompression. Must be created within tokio runtime.
+pub struct RecordStream {
+    rx: Receiver<Result<GbamRecord>>,
+}
+
+impl RecordStream {
+    /// All records of file in order.
+    pub fn new(mut reader: Reader) -> Self {
+        let rx = spawn_producer(RECORDS_QUEUE, move |tx| {
+            for rec in reader.records() {
+                if tx.blocking_send(rec).is_err() {
+                    return;
+                }
+            }
+        });
+        Self { rx }
+    }
+
+    /// Records overlapping `region`, see `Reader::fetch`. Invalid region is
+    /// the only item of stream.
+    pub fn fetch(mut reader: Reader, region: String) -> Self {
+        let rx = spawn_producer(RECORDS_QUEUE, move |tx| {
+            let mut records = match reader.fetch(&region) {
+                Ok(records) => records,
+                Err(err) => {
+                    let _ = tx.blocking_send(Err(err));
+                    return;
+                }
+            };
+            loop {
+                let rec = match records.try_next_rec() {
+                    Ok(Some(rec)) => Ok(rec.clone()),
+                    Ok(None) => return,
+                    Err(err) => Err(err),
+                };
+                if tx.blocking_send(rec).is_err() {
+                    return;
+                }
+            }
+        });
+        Self { rx }
+    }
+}
+
+impl Stream for RecordStream {
+    type Item = Result<GbamRecord>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Uncompressed BAM (as `gbam view` writes it) of whole file or of
+/// records overlapping regions, as `AsyncRead`. Suits services streaming
+/// slices of file, e.g. htsget backend. Must be created within tokio
+/// runtime.
+pub struct BamBytes {
+    rx: Receiver<Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    consumed: usize,
+}
+
+impl BamBytes {
+    /// BAM of records overlapping any of `regions` in turn, as `samtools
+    /// view` does, of all records if there are no regions. Invalid region
+    /// fails the first read, before anything is returned.
+    pub fn new(mut reader: Reader, regions: Vec<String>) -> Self {
+        let rx = spawn_producer(CHUNKS_QUEUE, move |tx| {
+            let mut out = ChunkSender { tx, chunk: Vec::with_capacity(CHUNK_SIZE) };
+            if let Err(err) = write_bam(&mut reader, &regions, &mut out).and_then(|_| out.flush()) {
+                let _ = tx.blocking_send(Err(err));
+            }
+        });
+        Self { rx, chunk: Vec::new(), consumed: 0 }
+    }
+}
+
+/// Sends full chunks to `BamBytes`.
+struct ChunkSender<'a> {
+    tx: &'a Sender<Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+}
+
+impl Write for ChunkSender<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "BAM stream was dropped."))
+    }
+}
+
+impl AsyncRead for BamBytes {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<()>> {
+        let this = &mut *self;
+        while this.consumed == this.chunk.len() {
+            match this.rx.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    this.chunk = chunk;
+                    this.consumed = 0;
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err)),
+                // End of file.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.remaining().min(this.chunk.len() - this.consumed);
+        buf.put_slice(&this.chunk[this.consumed..this.consumed + len]);
+        this.consumed += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::builder::ReaderBuilder;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use bam_tools::record::fields::Fields;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use tempdir::TempDir;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_async_reader() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n");
+        for i in 0..3000 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\n", i, i + 1));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("async").unwrap();
+        let path = dir.path().join("async.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(100) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let open = || ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+        let mut expected_bam = Vec::new();
+        write_bam(&mut open(), &[], &mut expected_bam).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut stream = RecordStream::new(open());
+            let mut count = 0;
+            while let Some(rec) = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+                assert_eq!(rec.unwrap().pos, Some(count));
+                count += 1;
+            }
+            assert_eq!(count, 3000);
+
+            let reader = Reader::with_fields(File::open(&path).unwrap(), &[Fields::Pos]).unwrap();
+            let mut stream = RecordStream::fetch(reader, "chr1:101-110".to_owned());
+            let mut positions = Vec::new();
+            while let Some(rec) = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+                positions.push(rec.unwrap().pos.unwrap());
+            }
+            assert_eq!(positions, (97..110).collect::<Vec<_>>());
+
+            let mut bam = Vec::new();
+            BamBytes::new(open(), Vec::new()).read_to_end(&mut bam).await.unwrap();
+            assert_eq!(bam, expected_bam);
+
+            let mut bam = Vec::new();
+            assert!(BamBytes::new(open(), vec!["chr2".to_owned()]).read_to_end(&mut bam).await.is_err());
+            assert!(bam.is_empty());
+        });
+    }
+}
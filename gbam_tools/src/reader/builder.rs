@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::Result;
+use std::sync::Arc;
+
+use bam_tools::record::fields::Fields;
+use memmap2::MmapOptions;
+use rayon::ThreadPool;
+
+use super::{
+    cache::BlockCache,
+    gbai::GbamIndex,
+    parse_tmplt::ParsingTemplate,
+    reader::{verify_and_parse_meta, Reader},
+    storage::{Access, FileStorage, Storage},
+};
+#[cfg(feature = "remote")]
+use super::remote::HttpStorage;
+
+/// Configures `Reader`: which fields are decoded, how file is accessed,
+/// prefetching, block cache and index. Decodes all fields of memory mapped file by
+/// default.
+#[derive(Default)]
+pub struct ReaderBuilder {
+    template: Option<ParsingTemplate>,
+    access: Access,
+    index_mapping: Option<Arc<Vec<u32>>>,
+    prefetch: Option<(usize, Arc<ThreadPool>)>,
+    block_cache: Option<Arc<BlockCache>>,
+    index: Option<GbamIndex>,
+}
+
+impl ReaderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode only `fields`, see `Reader::with_fields`.
+    pub fn fields(mut self, fields: &[Fields]) -> Self {
+        self.template = Some(ParsingTemplate::new_with(fields));
+        self
+    }
+
+    pub fn template(mut self, template: ParsingTemplate) -> Self {
+        self.template = Some(template);
+        self
+    }
+
+    /// How local file is accessed, see `Access`.
+    pub fn access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Order of records produced by index sort, see `Reader::new_with_index`.
+    pub fn index_mapping(mut self, index_mapping: Option<Arc<Vec<u32>>>) -> Self {
+        self.index_mapping = index_mapping;
+        self
+    }
+
+    /// See `Reader::set_prefetch`.
+    pub fn prefetch(mut self, blocks_ahead: usize, pool: Arc<ThreadPool>) -> Self {
+        self.prefetch = Some((blocks_ahead, pool));
+        self
+    }
+
+    /// See `Reader::set_block_cache`.
+    pub fn block_cache(mut self, cache: Arc<BlockCache>) -> Self {
+        self.block_cache = Some(cache);
+        self
+    }
+
+    /// See `Reader::set_index`.
+    pub fn index(mut self, index: GbamIndex) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn open(self, file: File) -> Result<Reader> {
+        let storage: Arc<dyn Storage> = match self.access {
+            Access::Mmap => Arc::new(unsafe { MmapOptions::new().map(&file)? }),
+            Access::Read => Arc::new(FileStorage::new(file)?),
+        };
+        self.build(storage)
+    }
+
+    /// Reader of file at `http://`, `https://` or `s3://` URL, see
+    /// `HttpStorage`. Access mode is ignored.
+    #[cfg(feature = "remote")]
+    pub fn open_url(self, url: &str) -> Result<Reader> {
+        self.build(Arc::new(HttpStorage::new(url)?))
+    }
+
+    /// Reader of file behind `storage`. Access mode is ignored.
+    pub fn build(self, storage: Arc<dyn Storage>) -> Result<Reader> {
+        let file_meta = Arc::new(verify_and_parse_meta(&*storage)?);
+        let template = self.template.unwrap_or_else(|| {
+            let mut template = ParsingTemplate::new();
+            template.set_all();
+            template
+        });
+        let mut reader = Reader::from_storage(storage, template, &file_meta, self.index_mapping)?;
+        if let Some((blocks_ahead, pool)) = self.prefetch {
+            reader.set_prefetch(blocks_ahead, pool);
+        }
+        if let Some(cache) = self.block_cache {
+            reader.set_block_cache(cache);
+        }
+        if let Some(index) = self.index {
+            reader.set_index(index)?;
+        }
+        Ok(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::Codecs;
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_reader_builder_access() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:1000\n");
+        for i in 0..100 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\n", i, i + 1));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("builder").unwrap();
+        let path = dir.path().join("access.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), false);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let read_all = |access| {
+            let mut reader = ReaderBuilder::new().access(access).open(File::open(&path).unwrap()).unwrap();
+            reader.records().map(|rec| format!("{:?}", rec.unwrap())).collect::<Vec<_>>()
+        };
+        let reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).unwrap();
+        assert_eq!(reader.mmap().unwrap().len() as u64, path.metadata().unwrap().len());
+        let mapped = read_all(Access::Mmap);
+        assert_eq!(mapped.len(), 100);
+        assert_eq!(read_all(Access::Read), mapped);
+
+        let mut reader = ReaderBuilder::new()
+            .fields(&[Fields::Pos])
+            .access(Access::Read)
+            .open(File::open(&path).unwrap())
+            .unwrap();
+        assert!(reader.columns[Fields::ReadName as usize].is_none());
+        assert!(reader.mmap().is_none() && !reader.is_sorted());
+        assert_eq!(reader.records().last().unwrap().unwrap().pos, Some(99));
+        assert!("mmap".parse::<Access>().is_ok() && "direct".parse::<Access>().is_err());
+
+        // File info without terminating NUL, and not UTF-8.
+        for (garbage, len) in [(b'{', 2000), (0xFF, 10)] {
+            let mut bytes = vec![garbage; len];
+            bytes.resize(2000, 0);
+            let damaged = dir.path().join("damaged.gbam");
+            std::fs::write(&damaged, bytes).unwrap();
+            let err = ReaderBuilder::new().open(File::open(&damaged).unwrap()).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+}
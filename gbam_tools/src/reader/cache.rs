@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use bam_tools::record::fields::Fields;
+
+/// Decompressed blocks of one file kept for reuse, keyed by field and block
+/// number, so random access (e.g. region hopping or mate lookups) doesn't
+/// decompress the same blocks over and over. Least recently used blocks are
+/// evicted once blocks take more than the byte budget. May be shared by
+/// readers of the same file across threads, see `Reader::set_block_cache`.
+pub struct BlockCache {
+    budget: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    blocks: HashMap<(Fields, usize), (Vec<u8>, u64)>,
+    /// Keys of blocks by the last time they were used.
+    by_use: BTreeMap<u64, (Fields, usize)>,
+    bytes: usize,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    /// Cache holding up to `budget` bytes of decompressed blocks. Blocks
+    /// larger than budget are not cached.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Copies cached block `block_num` of `field` into `buffer`. Returns
+    /// false if it isn't cached.
+    pub(crate) fn get(&self, field: Fields, block_num: usize, buffer: &mut Vec<u8>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.clock += 1;
+        match state.blocks.get_mut(&(field, block_num)) {
+            Some((block, last_use)) => {
+                state.by_use.remove(last_use);
+                *last_use = state.clock;
+                state.by_use.insert(state.clock, (field, block_num));
+                buffer.clear();
+                buffer.extend_from_slice(block);
+                state.hits += 1;
+                true
+            }
+            None => {
+                state.misses += 1;
+                false
+            }
+        }
+    }
+
+    /// Caches copy of decompressed block, evicting least recently used ones
+    /// to stay within budget.
+    pub(crate) fn insert(&self, field: Fields, block_num: usize, block: &[u8]) {
+        if block.len() > self.budget {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if state.blocks.contains_key(&(field, block_num)) {
+            return;
+        }
+        while state.bytes + block.len() > self.budget {
+            let (_, key) = state.by_use.pop_first().unwrap();
+            let (evicted, _) = state.blocks.remove(&key).unwrap();
+            state.bytes -= evicted.len();
+        }
+        state.clock += 1;
+        state.by_use.insert(state.clock, (field, block_num));
+        state.blocks.insert((field, block_num), (block.to_vec(), state.clock));
+        state.bytes += block.len();
+    }
+
+    /// Bytes of cached blocks.
+    pub fn bytes(&self) -> usize {
+        self.state.lock().unwrap().bytes
+    }
+
+    /// Number of blocks taken from cache and of ones which had to be
+    /// decompressed since cache was created.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.hits, state.misses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_block_cache() {
+        let cache = BlockCache::new(10);
+        let mut buffer = Vec::new();
+        cache.insert(Fields::Pos, 0, &[1; 4]);
+        cache.insert(Fields::Pos, 1, &[2; 4]);
+        assert!(cache.get(Fields::Pos, 0, &mut buffer));
+        assert_eq!(buffer, [1; 4]);
+        // Block 1 is the least recently used one.
+        cache.insert(Fields::Flags, 0, &[3; 4]);
+        cache.insert(Fields::Flags, 1, &[4; 11]);
+        assert_eq!(cache.bytes(), 8);
+        assert!(!cache.get(Fields::Pos, 1, &mut buffer));
+        assert!(!cache.get(Fields::Flags, 1, &mut buffer));
+        assert!(cache.get(Fields::Flags, 0, &mut buffer));
+        assert_eq!(buffer, [3; 4]);
+        assert_eq!(cache.hits_and_misses(), (2, 2));
+
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n");
+        for i in 0..40 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\t*\n", i, 10 * i + 1));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("block_cache").unwrap();
+        let path = dir.path().join("in.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(4) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let mut reader = ReaderBuilder::new().block_cache(cache.clone()).open(File::open(&path).unwrap()).unwrap();
+        let mut fetch = |region: &str| {
+            let mut records = reader.fetch(region).unwrap();
+            let mut positions = Vec::new();
+            while let Some(rec) = records.next_rec() {
+                positions.push(rec.pos.unwrap());
+            }
+            positions
+        };
+        assert_eq!(fetch("chr1:100-160"), [100, 110, 120, 130, 140, 150]);
+        assert_eq!(fetch("chr1:1-20"), [0, 10]);
+        let (hits, misses) = cache.hits_and_misses();
+        // Blocks of the first region are not decompressed again.
+        assert_eq!(fetch("chr1:100-160"), [100, 110, 120, 130, 140, 150]);
+        let (new_hits, new_misses) = cache.hits_and_misses();
+        assert!(new_hits > hits);
+        assert_eq!(new_misses, misses);
+
+        let cache = Arc::new(BlockCache::new(64));
+        let mut reader = ReaderBuilder::new().block_cache(cache.clone()).open(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.records().count(), 40);
+        assert!(cache.bytes() <= 64);
+    }
+}
@@ -1,5 +1,7 @@
-use std::{collections::BTreeMap, io::Result, sync::Arc};
+use std::{collections::BTreeMap, io::{Error, ErrorKind, Result}, sync::Arc};
 
+use super::cache::BlockCache;
+use super::prefetch::Prefetcher;
 use super::reader::generate_block_treemap;
 use super::record::GbamRecord;
 use crate::SIZE_LIMIT;
@@ -9,10 +11,10 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use flate2::write::GzDecoder;
 use std::io::{Read, Write};
 use brotli::Decompressor as BrotliDecompressorReader;
-use memmap2::Mmap;
-use std::convert::TryFrom;
+use rayon::ThreadPool;
 
-use crate::{meta::FileMeta, Codecs};
+use super::storage::Storage;
+use crate::{meta::{BlockMeta, FileMeta}, Codecs};
 
 // Contains fields needed both for fixed sized fields and variable sized fields.
 pub struct Inner {
@@ -22,11 +24,15 @@ pub struct Inner {
     range_end: usize,
     field: Fields,
     buffer: Vec<u8>,
-    reader: Arc<Mmap>,
+    reader: Arc<dyn Storage>,
+    /// Compressed block, if storage doesn't hold it in memory.
+    scratch: Vec<u8>,
+    prefetch: Option<Prefetcher>,
+    cache: Option<Arc<BlockCache>>,
 }
 
 impl Inner {
-    pub(crate) fn new(meta: Arc<FileMeta>, field: Fields, reader: Arc<Mmap>) -> Self {
+    pub(crate) fn new(meta: Arc<FileMeta>, field: Fields, reader: Arc<dyn Storage>) -> Self {
         Inner {
             meta,
             range_begin: 0,
@@ -34,15 +40,29 @@ impl Inner {
             field,
             buffer: Vec::<u8>::with_capacity(SIZE_LIMIT * 2),
             reader,
+            scratch: Vec::new(),
+            prefetch: None,
+            cache: None,
         }
     }
+
+    fn set_prefetch(&mut self, blocks_ahead: usize, pool: &Arc<ThreadPool>) {
+        self.prefetch = Some(Prefetcher::new(blocks_ahead, pool.clone()));
+    }
 }
 
 /// Defines how columns will operate. It is needed since variable sized fields
 /// columns also require parsing of additional fixed sized fields columns.
 pub trait Column {
-    // Fills GbamRecord field with data from corresponding BAM record.
-    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) ;
+    // Fills GbamRecord field with data from corresponding BAM record. Fails
+    // if block holding it can't be fetched.
+    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) -> Result<()>;
+    // Decompresses up to `blocks_ahead` blocks after the one being read on
+    // `pool`, see `Reader::set_prefetch`.
+    fn set_prefetch(&mut self, blocks_ahead: usize, pool: &Arc<ThreadPool>);
+    // Takes blocks from `cache` and puts decompressed ones there, see
+    // `Reader::set_block_cache`.
+    fn set_block_cache(&mut self, cache: &Arc<BlockCache>);
 }
 
 /// GBAM file column. Responsible for fetching data.
@@ -52,8 +72,17 @@ impl Column for FixedColumn {
     /// Fetches data into provider record buffer. If item is located outside of
     /// currently loaded data block, the new block will be loaded and
     /// decompressed.
-    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) {
-        rec.parse_from_bytes(&self.0.field.clone(), self.get_item(item_num));
+    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) -> Result<()> {
+        rec.parse_from_bytes(&self.0.field.clone(), self.get_item(item_num)?);
+        Ok(())
+    }
+
+    fn set_prefetch(&mut self, blocks_ahead: usize, pool: &Arc<ThreadPool>) {
+        self.0.set_prefetch(blocks_ahead, pool);
+    }
+
+    fn set_block_cache(&mut self, cache: &Arc<BlockCache>) {
+        self.0.cache = Some(cache.clone());
     }
 }
 
@@ -61,14 +90,14 @@ impl FixedColumn {
     pub fn new(inner: Inner, field_size: usize) -> Self {
         Self(inner, field_size)
     }
-    fn get_item(&mut self, item_num: usize) -> &[u8] {
+    fn get_item(&mut self, item_num: usize) -> Result<&[u8]> {
         if let Some(block_num) = self.find_block(item_num) {
-            Self::update_buffer(&mut self.0, block_num);
+            Self::update_buffer(&mut self.0, block_num)?;
         }
         let rec_num_in_block = item_num - self.0.range_begin;
         let item_size = self.1;
         let offset = rec_num_in_block * item_size;
-        &self.0.buffer[offset..offset + item_size]
+        Ok(&self.0.buffer[offset..offset + item_size])
     }
     // Finds blocks where record is located. None is returned if block is already loaded.
     fn find_block(&self, item_num: usize) -> Option<usize> {
@@ -80,12 +109,13 @@ impl FixedColumn {
         Some(item_num / block_len as usize)
     }
 
-    fn update_buffer(inner: &mut Inner, block_num: usize) {
-        fetch_block(inner, block_num).unwrap();
+    fn update_buffer(inner: &mut Inner, block_num: usize) -> Result<()> {
+        fetch_block(inner, block_num)?;
         let block_len = inner.meta.view_blocks(&inner.field)[0].numitems as usize;
         let cur_block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
         inner.range_begin = block_num * block_len;
         inner.range_end = inner.range_begin + cur_block_len;
+        Ok(())
     }
 }
 
@@ -98,8 +128,19 @@ pub struct VariableColumn {
 }
 
 impl Column for VariableColumn {
-    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) {
-        rec.parse_from_bytes(&self.inner.field.clone(), self.get_item(item_num));
+    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) -> Result<()> {
+        rec.parse_from_bytes(&self.inner.field.clone(), self.get_item(item_num)?);
+        Ok(())
+    }
+
+    fn set_prefetch(&mut self, blocks_ahead: usize, pool: &Arc<ThreadPool>) {
+        self.inner.set_prefetch(blocks_ahead, pool);
+        self.index.set_prefetch(blocks_ahead, pool);
+    }
+
+    fn set_block_cache(&mut self, cache: &Arc<BlockCache>) {
+        self.inner.cache = Some(cache.clone());
+        self.index.set_block_cache(cache);
     }
 }
 
@@ -112,19 +153,19 @@ impl VariableColumn {
         }
     }
 
-    fn get_item(&mut self, item_num: usize) -> &[u8] {
+    fn get_item(&mut self, item_num: usize) -> Result<&[u8]> {
         if let Some((range_begin, block_num)) = self.find_block(item_num) {
-            Self::update_buffer(&mut self.inner, block_num, range_begin);
+            Self::update_buffer(&mut self.inner, block_num, range_begin)?;
         }
         let rec_num_in_block = item_num - self.inner.range_begin;
         let mut read_offset =
-            |n| self.index.get_item(n).read_u32::<LittleEndian>().unwrap() as usize;
+            |n| self.index.get_item(n).map(|mut item| item.read_u32::<LittleEndian>().unwrap() as usize);
         let start = match rec_num_in_block {
             0 => 0,
-            _ => read_offset(item_num - 1),
+            _ => read_offset(item_num - 1)?,
         };
-        let end = read_offset(item_num);
-        &self.inner.buffer[start..end]
+        let end = read_offset(item_num)?;
+        Ok(&self.inner.buffer[start..end])
     }
 
     // Finds blocks where record is located. None is returned if block is already loaded.
@@ -144,48 +185,130 @@ impl VariableColumn {
         )
     }
 
-    fn update_buffer(inner: &mut Inner, block_num: usize, range_begin: usize) {
-        fetch_block(inner, block_num).unwrap();
+    fn update_buffer(inner: &mut Inner, block_num: usize, range_begin: usize) -> Result<()> {
+        fetch_block(inner, block_num)?;
         let block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
         inner.range_begin = range_begin;
         inner.range_end = inner.range_begin + block_len;
+        Ok(())
+    }
+}
+
+/// Column of field omitted at write time (see `Writer::omit_field`). Its
+/// index holds length of every value, values are synthesized.
+pub struct OmittedColumn {
+    field: Fields,
+    lengths: FixedColumn,
+    placeholder: Vec<u8>,
+}
+
+impl Column for OmittedColumn {
+    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) -> Result<()> {
+        self.placeholder.clear();
+        match self.field {
+            Fields::RawQual => {
+                // Missing qualities, `*` in SAM.
+                let len = self.lengths.get_item(item_num)?.read_u32::<LittleEndian>().unwrap();
+                self.placeholder.resize(len as usize, 0xFF);
+            }
+            // Missing name, `*` in SAM.
+            Fields::ReadName => self.placeholder.extend_from_slice(b"*\0"),
+            _ => {}
+        }
+        rec.parse_from_bytes(&self.field, &self.placeholder);
+        Ok(())
+    }
+
+    fn set_prefetch(&mut self, blocks_ahead: usize, pool: &Arc<ThreadPool>) {
+        self.lengths.set_prefetch(blocks_ahead, pool);
+    }
+
+    fn set_block_cache(&mut self, cache: &Arc<BlockCache>) {
+        self.lengths.set_block_cache(cache);
+    }
+}
+
+impl OmittedColumn {
+    pub fn new(field: Fields, lengths: FixedColumn) -> Self {
+        Self {
+            field,
+            lengths,
+            placeholder: Vec::new(),
+        }
     }
 }
 
-/// Fetch and decompress a data block.
+/// Fetch and decompress a data block, or take it from prefetcher or cache.
 fn fetch_block(inner_column: &mut Inner, block_num: usize) -> Result<()> {
-    // println!("Fetching for {}", inner_column.field);
-    let field = &inner_column.field;
-    let block_meta = inner_column.meta.view_blocks(field).get(block_num).unwrap();
-    let reader = &inner_column.reader;
+    if let Some(prefetch) = inner_column.prefetch.as_mut() {
+        let prefetched = prefetch.take(block_num);
+        prefetch.schedule(block_num, &inner_column.reader, &inner_column.meta, inner_column.field);
+        if let Some(block) = prefetched {
+            let consumed = std::mem::replace(&mut inner_column.buffer, block?);
+            prefetch.recycle(consumed);
+            return Ok(());
+        }
+    }
+    let Inner { reader, meta, field, buffer, scratch, cache, .. } = inner_column;
+    if cache.as_ref().is_some_and(|cache| cache.get(*field, block_num, buffer)) {
+        return Ok(());
+    }
+    decode_block(&**reader, meta, *field, block_num, buffer, scratch)?;
+    if let Some(cache) = cache {
+        cache.insert(*field, block_num, buffer);
+    }
+    Ok(())
+}
+
+/// Decompress block `block_num` of `field` into `buffer`. Compressed block is
+/// read into `scratch` unless storage holds it in memory.
+pub(crate) fn decode_block(reader: &dyn Storage, meta: &FileMeta, field: Fields, block_num: usize, buffer: &mut Vec<u8>, scratch: &mut Vec<u8>) -> Result<()> {
+    // println!("Fetching for {}", field);
+    let block_meta = meta.view_blocks(&field).get(block_num).unwrap();
     let block_size = block_meta.block_size;
     let uncompressed_size = block_meta.uncompressed_size;
 
-    let data =
-        &reader[usize::try_from(block_meta.seekpos).unwrap()..usize::try_from(block_meta.seekpos + block_size as u64).unwrap()];
-    // inner_column.buffer.clear();
+    let data = reader.read(block_meta.seekpos..block_meta.seekpos + block_size as u64, scratch)?;
+    // buffer.clear();
     // dbg!(uncompressed_size);
-    inner_column.buffer.resize(uncompressed_size as usize, 0);
-    let codec = inner_column.meta.get_field_codec(field);
+    buffer.resize(uncompressed_size as usize, 0);
+    let codec = &meta.get_block_codec(&field, block_num);
 
     if uncompressed_size > 0 {
-        decompress_block(data, &mut inner_column.buffer, codec).expect("Decompression failed.");
+        decompress_block(data, buffer, codec).map_err(|err| {
+            Error::new(err.kind(), format!("Decompression of {:?} block {} failed: {}", field, block_num, err))
+        })?;
+    }
+    verify_checksum(buffer, block_meta)
+}
+
+/// Checks decompressed block against checksum stored in its meta, if any.
+pub fn verify_checksum(data: &[u8], block_meta: &BlockMeta) -> Result<()> {
+    match block_meta.checksum {
+        Some(checksum) if xxhash_rust::xxh64::xxh64(data, 0) != checksum => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Block at {} is corrupted, checksum mismatch.", block_meta.seekpos),
+        )),
+        _ => Ok(()),
     }
-    
-    Ok(())
 }
 
 
 pub fn decompress_block(source: &[u8], dest: &mut Vec<u8>, codec: &Codecs) -> std::io::Result<()> {
     use std::io::Write;
+    #[cfg(feature = "hw-accel")]
+    if crate::accel::try_decompress(codec, source, dest) {
+        return Ok(());
+    }
     match codec {
         Codecs::Gzip => {
+            dest.clear();
             let mut decoder = GzDecoder::new(dest);
-            decoder.write_all(source).unwrap();
-            decoder.try_finish().unwrap();
+            decoder.write_all(source)?;
+            decoder.try_finish()?;
         }
-        Codecs::Lz4 => {
-            lz4::decompress(source, dest).unwrap();
+        Codecs::Lz4 | Codecs::Lz4Hc(_) => {
+            lz4::decompress(source, dest)?;
         }
         Codecs::Brotli => {
             dest.clear();
@@ -197,10 +320,154 @@ pub fn decompress_block(source: &[u8], dest: &mut Vec<u8>, codec: &Codecs) -> st
             let mut decoder = zstd::stream::Decoder::new(source)?;
             decoder.read_to_end(dest)?;
         }
+        Codecs::ZstdLong(window_log) => {
+            dest.clear();
+            let mut decoder = zstd::stream::Decoder::new(source)?;
+            decoder.window_log_max(*window_log)?;
+            decoder.read_to_end(dest)?;
+        }
         Codecs::NoCompression => {
             dest.clear();
             dest.extend_from_slice(source);
         }
+        Codecs::Custom(id) => {
+            dest.clear();
+            crate::codec::get_codec(*id)?.decompress(source, dest)?;
+        }
+        Codecs::Seq2Bit => {
+            crate::seq_packing::unpack(source, dest)?;
+        }
+        Codecs::QualCtx => {
+            crate::qual_codec::decompress(source, dest)?;
+        }
+        Codecs::CigarSplit => {
+            crate::cigar_codec::decompress(source, dest)?;
+        }
+        Codecs::TagSplit => {
+            crate::tag_codec::decompress(source, dest)?;
+        }
+        Codecs::MapqRle => {
+            crate::mapq_codec::decompress(source, dest)?;
+        }
+        Codecs::FlagPlanes => {
+            crate::flag_codec::decompress(source, dest)?;
+        }
+        Codecs::PosDelta => {
+            crate::pos_codec::decompress(source, dest)?;
+        }
+        Codecs::NameTok => {
+            crate::name_codec::decompress(source, dest)?;
+        }
+        Codecs::Constant(item_size) => {
+            crate::const_codec::decompress(source, dest, *item_size as usize)?;
+        }
+        Codecs::Auto => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Block codec was not recorded.",
+            ));
+        }
+        Codecs::Unknown => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Block was compressed with codec unknown to this version of gbam_tools.",
+            ));
+        }
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::bam::sam_writer::gbam_to_sam;
+    use crate::meta::{BlockLimits, FileInfo, NameTokenization, FILE_INFO_SIZE, ZSTD_LONG_DEFAULT_WINDOW_LOG};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::{calc_crc_for_meta_bytes, Writer};
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    /// Sets codec of every block of `field` in meta of file to `codec` JSON.
+    fn set_block_codecs(path: &Path, field: Fields, codec: &str) {
+        let mut bytes = std::fs::read(path).unwrap();
+        let info_end = bytes.iter().position(|&b| b == 0).unwrap();
+        let mut info: FileInfo = serde_json::from_slice(&bytes[..info_end]).unwrap();
+        let mut meta: serde_json::Value = serde_json::from_slice(&bytes[info.seekpos as usize..]).unwrap();
+        for block in meta["field_to_meta"][field.to_string()]["blocks"].as_array_mut().unwrap() {
+            block["codec"] = serde_json::from_str(codec).unwrap();
+        }
+        let meta = serde_json::to_vec(&meta).unwrap();
+        info.crc32 = calc_crc_for_meta_bytes(&meta);
+        bytes.truncate(info.seekpos as usize);
+        bytes.extend_from_slice(&meta);
+        let info = serde_json::to_vec(&info).unwrap();
+        bytes[..FILE_INFO_SIZE].fill(0);
+        bytes[..info.len()].copy_from_slice(&info);
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_block_codecs() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:unsorted\n@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..300 {
+            let qual = if i < 150 { "IIII" } else { "I#5+" };
+            sam.push_str(&format!("HWI:1:{}:{}\t0\tchr1\t{}\t{}\t4M\t*\t0\t0\tACGT\t{}\n", i % 7, i, 7 * i + 1, i % 61, qual));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("block_codecs").unwrap();
+        let path = dir.path().join("in.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Auto], 2, Vec::new(), ref_seqs, sam_header, String::new(), false);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(100) });
+        writer.set_name_tokenization(NameTokenization::On);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        // Every block is decoded with codec recorded for it.
+        let mut reader = ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+        let codecs: Vec<_> = (0..3).map(|block| reader.file_meta.get_block_codec(&Fields::ReadName, block)).collect();
+        assert_eq!(codecs, [Codecs::NameTok; 3]);
+        assert!(Fields::iterator().all(|field| reader.file_meta.view_blocks(field).iter().all(|block| block.codec.is_some() && block.codec != Some(Codecs::Auto))));
+        let out = gbam_to_sam(&mut reader, &[], None, true, Vec::new()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), sam);
+
+        // Codecs Auto doesn't choose from.
+        for codec in [Codecs::Lz4Hc(9), Codecs::ZstdLong(ZSTD_LONG_DEFAULT_WINDOW_LOG)] {
+            let mut sam_reader = SamReader::new(sam.as_bytes());
+            let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+            let path = dir.path().join("fixed.gbam");
+            let mut writer = Writer::new(File::create(&path).unwrap(), vec![codec], 2, Vec::new(), ref_seqs, sam_header, String::new(), false);
+            while let Some(rec) = sam_reader.next_rec() {
+                writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+            }
+            writer.finish().unwrap();
+            let mut reader = ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+            assert_eq!(reader.file_meta.get_block_codec(&Fields::ReadName, 0), codec);
+            let out = gbam_to_sam(&mut reader, &[], None, true, Vec::new()).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), sam);
+        }
+
+        // Unknown codecs fail only reading of their blocks.
+        set_block_codecs(&path, Fields::RawQual, r#"{"Fqz":3}"#);
+        set_block_codecs(&path, Fields::Mapq, r#""Fqz""#);
+        let mut reader = ReaderBuilder::new().fields(&[Fields::ReadName, Fields::Pos]).open(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.records().filter_map(|rec| rec.ok()).count(), 300);
+        assert_eq!(reader.file_meta.get_block_codec(&Fields::RawQual, 1), Codecs::Unknown);
+        assert!(serde_json::to_string(&*reader.file_meta).is_err());
+        let mut reader = ReaderBuilder::new().fields(&[Fields::RawQual]).open(File::open(&path).unwrap()).unwrap();
+        let err = reader.records().next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert!(err.to_string().contains("RawQual block 0") && err.to_string().contains("unknown"));
+
+        set_block_codecs(&path, Fields::RawQual, r#"{"Custom":4242}"#);
+        let mut reader = ReaderBuilder::new().fields(&[Fields::RawQual]).open(File::open(&path).unwrap()).unwrap();
+        let err = reader.records().next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("ID 4242 is not registered"));
+    }
+}
@@ -0,0 +1,546 @@
+use std::cmp::Ordering;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::RangeInclusive;
+
+use bam_tools::record::fields::Fields;
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::filter::{RangeFilter, RecordPredicate};
+use super::record::GbamRecord;
+use super::region::{Region, LOCATOR_FIELDS};
+use crate::bam::merge::tag_value_size;
+
+/// Record filter expression, a subset of `samtools view -e` syntax:
+///
+/// - `flag`, `mapq`, `pos` (1-based), `tlen`, `rname`, `rg` (value of RG
+///   tag) and tags as `[NM]` compared with `==`, `!=`, `<`, `<=`, `>`,
+///   `>=` to numbers (`30`, `0x10`, `0.5`) or strings (`"chr1"`).
+/// - `flag & 0x4` masks value, alone it is true if nonzero. Tag alone is
+///   true if record has it.
+/// - `region("chr1:100-200")` is true for records overlapping region (see
+///   `Region::parse`).
+/// - `!`, `&&`, `||` and parentheses.
+///
+/// Missing tags and values of other type compare false. Only fields used by
+/// expression are decoded to check it, and blocks whose stats show that no
+/// record can match conditions on MAPQ, POS, TLEN and RNAME joined with
+/// `&&` are skipped (see `RangeFilter`). Example:
+/// `mapq >= 30 && !(flag & 0x904) && [RG] == "grp1"`
+#[derive(Clone, Debug)]
+pub struct FilterExpr {
+    root: Node,
+    fields: Vec<Fields>,
+    ranges: RangeFilter,
+}
+
+impl FilterExpr {
+    /// Parses `text`. Reference names of `rname` comparisons and regions are
+    /// looked up in `ref_seqs`, unknown ones are an error.
+    pub fn parse(text: &str, ref_seqs: &[(String, u32)]) -> Result<Self> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0, ref_seqs };
+        let root = parser.expr()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(parser.error("Unexpected token"));
+        }
+        let mut fields = Vec::new();
+        root.fields(&mut fields);
+        fields.sort_unstable_by_key(|field| *field as usize);
+        fields.dedup();
+        let mut ranges = RangeFilter::new();
+        root.ranges(&mut ranges);
+        Ok(Self { root, fields, ranges })
+    }
+}
+
+impl RecordPredicate for FilterExpr {
+    fn fields(&self) -> Vec<Fields> {
+        self.fields.clone()
+    }
+
+    fn accepts(&self, rec: &GbamRecord) -> bool {
+        self.root.eval(rec)
+    }
+
+    fn ranges(&self) -> RangeFilter {
+        self.ranges.clone()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Tag([u8; 2]),
+    Int(i64),
+    Float(f64),
+    Str(Vec<u8>),
+    Op(&'static str),
+}
+
+/// Operators, longer first.
+const OPS: [&str; 12] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "&", "(", ")"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let bytes = text.as_bytes();
+    let invalid = |at: usize| Error::new(ErrorKind::InvalidInput, format!("Invalid filter expression at {}: {}", at, text));
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        let start = i;
+        if rest[0].is_ascii_whitespace() {
+            i += 1;
+        } else if let Some(op) = OPS.iter().find(|op| rest.starts_with(op.as_bytes())) {
+            tokens.push(Token::Op(op));
+            i += op.len();
+        } else if rest[0] == b'[' {
+            match rest {
+                [b'[', a, b, b']', ..] if a.is_ascii_alphanumeric() && b.is_ascii_alphanumeric() => tokens.push(Token::Tag([*a, *b])),
+                _ => return Err(invalid(start)),
+            }
+            i += 4;
+        } else if rest[0] == b'"' {
+            let len = rest[1..].iter().position(|&c| c == b'"').ok_or_else(|| invalid(start))?;
+            tokens.push(Token::Str(rest[1..1 + len].to_vec()));
+            i += len + 2;
+        } else if rest[0].is_ascii_digit() || (rest[0] == b'-' && rest.get(1).is_some_and(u8::is_ascii_digit)) {
+            let len = 1 + rest[1..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == b'.').count();
+            let number = &text[i..i + len];
+            let (negative, digits) = number.strip_prefix('-').map_or((false, number), |digits| (true, digits));
+            let token = if let Some(hex) = digits.strip_prefix("0x") {
+                i64::from_str_radix(hex, 16).map(|value| Token::Int(if negative { -value } else { value }))
+                    .map_err(|_| invalid(start))?
+            } else if let Ok(value) = number.parse() {
+                Token::Int(value)
+            } else {
+                Token::Float(number.parse().map_err(|_| invalid(start))?)
+            };
+            tokens.push(token);
+            i += len;
+        } else if rest[0].is_ascii_alphabetic() || rest[0] == b'_' {
+            let len = rest.iter().take_while(|c| c.is_ascii_alphanumeric() || **c == b'_').count();
+            tokens.push(Token::Ident(text[i..i + len].to_owned()));
+            i += len;
+        } else {
+            return Err(invalid(start));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+    Flag,
+    Mapq,
+    /// 1-based.
+    Pos,
+    Tlen,
+    /// Reference ID.
+    Rname,
+    Tag([u8; 2]),
+}
+
+/// Value of operand in record.
+#[derive(Clone, Copy, Debug)]
+enum Scalar<'a> {
+    Int(i64),
+    Float(f64),
+    Str(&'a [u8]),
+    /// B array, only its presence is checked.
+    Array,
+}
+
+impl Operand {
+    fn field(&self) -> Fields {
+        match self {
+            Operand::Flag => Fields::Flags,
+            Operand::Mapq => Fields::Mapq,
+            Operand::Pos => Fields::Pos,
+            Operand::Tlen => Fields::TemplateLength,
+            Operand::Rname => Fields::RefID,
+            Operand::Tag(_) => Fields::RawTags,
+        }
+    }
+
+    fn value<'a>(&self, rec: &'a GbamRecord) -> Option<Scalar<'a>> {
+        let int = |value: Option<i64>| value.map(Scalar::Int);
+        match self {
+            Operand::Flag => int(rec.flag.map(i64::from)),
+            Operand::Mapq => int(rec.mapq.map(i64::from)),
+            Operand::Pos => int(rec.pos.map(|pos| i64::from(pos) + 1)),
+            Operand::Tlen => int(rec.tlen.map(i64::from)),
+            Operand::Rname => int(rec.refid.map(i64::from)),
+            Operand::Tag(tag) => find_tag(rec.tags.as_deref()?, tag),
+        }
+    }
+}
+
+/// Value of auxiliary field `tag` of BAM record tags.
+fn find_tag<'a>(mut tags: &'a [u8], tag: &[u8; 2]) -> Option<Scalar<'a>> {
+    while tags.len() >= 3 {
+        let val_type = tags[2];
+        let value = &tags[3..];
+        if val_type == b'B' && value.len() < 5 {
+            return None;
+        }
+        let size = tag_value_size(val_type, value).min(value.len());
+        if &tags[..2] == tag {
+            let value = &value[..size];
+            return Some(match (val_type, value.len()) {
+                (b'c', 1) => Scalar::Int(i64::from(value[0] as i8)),
+                (b'C', 1) => Scalar::Int(i64::from(value[0])),
+                (b's', 2) => Scalar::Int(i64::from(LittleEndian::read_i16(value))),
+                (b'S', 2) => Scalar::Int(i64::from(LittleEndian::read_u16(value))),
+                (b'i', 4) => Scalar::Int(i64::from(LittleEndian::read_i32(value))),
+                (b'I', 4) => Scalar::Int(i64::from(LittleEndian::read_u32(value))),
+                (b'f', 4) => Scalar::Float(f64::from(LittleEndian::read_f32(value))),
+                (b'A', 1) => Scalar::Str(value),
+                (b'Z' | b'H', _) => Scalar::Str(value.strip_suffix(b"\0").unwrap_or(value)),
+                _ => Scalar::Array,
+            });
+        }
+        tags = &value[size..];
+    }
+    None
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Literal {
+    Int(i64),
+    Float(f64),
+    Str(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    fn parse(op: &str) -> Option<Self> {
+        Some(match op {
+            "==" => CmpOp::Eq,
+            "!=" => CmpOp::Ne,
+            "<" => CmpOp::Lt,
+            "<=" => CmpOp::Le,
+            ">" => CmpOp::Gt,
+            ">=" => CmpOp::Ge,
+            _ => return None,
+        })
+    }
+
+    fn holds(&self, ord: Ordering) -> bool {
+        match self {
+            CmpOp::Eq => ord == Ordering::Equal,
+            CmpOp::Ne => ord != Ordering::Equal,
+            CmpOp::Lt => ord == Ordering::Less,
+            CmpOp::Le => ord != Ordering::Greater,
+            CmpOp::Gt => ord == Ordering::Greater,
+            CmpOp::Ge => ord != Ordering::Less,
+        }
+    }
+
+    /// Values `v` for which `v op literal` holds.
+    fn range(&self, literal: i64) -> Option<(i64, i64)> {
+        Some(match self {
+            CmpOp::Eq => (literal, literal),
+            CmpOp::Ne => return None,
+            CmpOp::Lt => (i64::MIN, literal.saturating_sub(1)),
+            CmpOp::Le => (i64::MIN, literal),
+            CmpOp::Gt => (literal.saturating_add(1), i64::MAX),
+            CmpOp::Ge => (literal, i64::MAX),
+        })
+    }
+}
+
+fn compare(value: Scalar, literal: &Literal) -> Option<Ordering> {
+    match (value, literal) {
+        (Scalar::Int(a), Literal::Int(b)) => Some(a.cmp(b)),
+        (Scalar::Int(a), Literal::Float(b)) => (a as f64).partial_cmp(b),
+        (Scalar::Float(a), Literal::Int(b)) => a.partial_cmp(&(*b as f64)),
+        (Scalar::Float(a), Literal::Float(b)) => a.partial_cmp(b),
+        (Scalar::Str(a), Literal::Str(b)) => Some(a.cmp(b.as_slice())),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Region(Region),
+    Test {
+        operand: Operand,
+        mask: Option<i64>,
+        cmp: Option<(CmpOp, Literal)>,
+    },
+}
+
+impl Node {
+    fn eval(&self, rec: &GbamRecord) -> bool {
+        match self {
+            Node::And(a, b) => a.eval(rec) && b.eval(rec),
+            Node::Or(a, b) => a.eval(rec) || b.eval(rec),
+            Node::Not(a) => !a.eval(rec),
+            Node::Region(region) => region.overlaps(rec),
+            Node::Test { operand, mask, cmp } => {
+                let value = match (operand.value(rec), mask) {
+                    (Some(Scalar::Int(value)), Some(mask)) => Scalar::Int(value & mask),
+                    (Some(_), Some(_)) | (None, _) => return false,
+                    (Some(value), None) => value,
+                };
+                match (cmp, value) {
+                    (Some((op, literal)), value) => compare(value, literal).is_some_and(|ord| op.holds(ord)),
+                    (None, Scalar::Int(value)) => value != 0,
+                    (None, Scalar::Float(value)) => value != 0.0,
+                    (None, _) => true,
+                }
+            }
+        }
+    }
+
+    fn fields(&self, fields: &mut Vec<Fields>) {
+        match self {
+            Node::And(a, b) | Node::Or(a, b) => {
+                a.fields(fields);
+                b.fields(fields);
+            }
+            Node::Not(a) => a.fields(fields),
+            Node::Region(_) => fields.extend_from_slice(&LOCATOR_FIELDS),
+            Node::Test { operand, .. } => fields.push(operand.field()),
+        }
+    }
+
+    /// Adds ranges of conditions every matching record meets.
+    fn ranges(&self, ranges: &mut RangeFilter) {
+        let add = |ranges: &mut RangeFilter, field, (lo, hi): (i64, i64)| {
+            ranges.add_range(field, clamp(lo, hi)).expect("Stats are collected for field.");
+        };
+        match self {
+            Node::And(a, b) => {
+                a.ranges(ranges);
+                b.ranges(ranges);
+            }
+            Node::Region(region) => {
+                add(ranges, Fields::RefID, (i64::from(region.ref_id), i64::from(region.ref_id)));
+                add(ranges, Fields::Pos, (i64::MIN, i64::from(region.end) - 1));
+            }
+            Node::Test { operand, mask: None, cmp: Some((op, Literal::Int(literal))) } => {
+                let offset = match operand {
+                    Operand::Pos => 1,
+                    Operand::Mapq | Operand::Tlen | Operand::Rname => 0,
+                    Operand::Flag | Operand::Tag(_) => return,
+                };
+                if let Some((lo, hi)) = op.range(*literal) {
+                    add(ranges, operand.field(), (lo.saturating_sub(offset), hi.saturating_sub(offset)));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `lo..=hi` within `i32`, empty if it has no `i32` values.
+fn clamp(lo: i64, hi: i64) -> RangeInclusive<i32> {
+    let (min, max) = (i64::from(i32::MIN), i64::from(i32::MAX));
+    if lo > hi || lo > max || hi < min {
+        return RangeInclusive::new(1, 0);
+    }
+    lo.max(min) as i32..=hi.min(max) as i32
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    ref_seqs: &'a [(String, u32)],
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &str) -> Error {
+        let found = self.tokens.get(self.pos).map_or("end".to_owned(), |token| format!("{:?}", token));
+        Error::new(ErrorKind::InvalidInput, format!("Invalid filter expression: {} at {}.", msg, found))
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(*op),
+            _ => None,
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if self.peek_op() != Some(op) {
+            return Err(self.error(&format!("Expected {}", op)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Node> {
+        let mut node = self.and()?;
+        while self.peek_op() == Some("||") {
+            self.pos += 1;
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node> {
+        let mut node = self.unary()?;
+        while self.peek_op() == Some("&&") {
+            self.pos += 1;
+            node = Node::And(Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node> {
+        match self.peek_op() {
+            Some("!") => {
+                self.pos += 1;
+                Ok(Node::Not(Box::new(self.unary()?)))
+            }
+            Some("(") => {
+                self.pos += 1;
+                let node = self.expr()?;
+                self.expect_op(")")?;
+                Ok(node)
+            }
+            _ => self.test(),
+        }
+    }
+
+    fn test(&mut self) -> Result<Node> {
+        let operand = match self.tokens.get(self.pos) {
+            Some(Token::Ident(name)) => match name.as_str() {
+                "flag" => Operand::Flag,
+                "mapq" => Operand::Mapq,
+                "pos" => Operand::Pos,
+                "tlen" => Operand::Tlen,
+                "rname" => Operand::Rname,
+                "rg" => Operand::Tag(*b"RG"),
+                "region" => return self.region(),
+                _ => return Err(self.error("Unknown name")),
+            },
+            Some(Token::Tag(tag)) => Operand::Tag(*tag),
+            _ => return Err(self.error("Expected field or tag")),
+        };
+        self.pos += 1;
+        let mut mask = None;
+        if self.peek_op() == Some("&") {
+            self.pos += 1;
+            match self.tokens.get(self.pos) {
+                Some(Token::Int(value)) => mask = Some(*value),
+                _ => return Err(self.error("Expected integer mask")),
+            }
+            self.pos += 1;
+        }
+        let op = match self.peek_op().and_then(CmpOp::parse) {
+            Some(op) => op,
+            None => return Ok(Node::Test { operand, mask, cmp: None }),
+        };
+        self.pos += 1;
+        let literal = match self.tokens.get(self.pos) {
+            Some(Token::Int(value)) => Literal::Int(*value),
+            Some(Token::Float(value)) => Literal::Float(*value),
+            Some(Token::Str(name)) if operand == Operand::Rname => {
+                let ref_id = match name.as_slice() {
+                    b"*" => Some(-1),
+                    name => self.ref_seqs.iter().position(|(ref_name, _)| ref_name.as_bytes() == name).map(|id| id as i64),
+                };
+                Literal::Int(ref_id.ok_or_else(|| self.error("Unknown reference"))?)
+            }
+            Some(Token::Str(value)) => Literal::Str(value.clone()),
+            _ => return Err(self.error("Expected number or string")),
+        };
+        self.pos += 1;
+        Ok(Node::Test { operand, mask, cmp: Some((op, literal)) })
+    }
+
+    fn region(&mut self) -> Result<Node> {
+        self.pos += 1;
+        self.expect_op("(")?;
+        let region = match self.tokens.get(self.pos) {
+            Some(Token::Str(region)) => Region::parse(&String::from_utf8_lossy(region), self.ref_seqs)?,
+            _ => return Err(self.error("Expected region string")),
+        };
+        self.pos += 1;
+        self.expect_op(")")?;
+        Ok(Node::Region(region))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::reader::reader::Reader;
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_filter_expr() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n@SQ\tSN:chr2\tLN:100000\n");
+        for i in 0..600 {
+            let ref_name = if i < 400 { "chr1" } else { "chr2" };
+            sam.push_str(&format!(
+                "r{}\t{}\t{}\t{}\t{}\t10M\t*\t0\t0\t*\t*\tRG:Z:grp{}\tNM:i:{}\tXf:f:{}.5\n",
+                i, [0, 16, 4, 1024][i % 4], ref_name, i * 10 + 1, i / 100 * 10, i % 3, i % 5, i % 2
+            ));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("filter_expr").unwrap();
+        let path = dir.path().join("expr.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, vec![Fields::RefID, Fields::Mapq], ref_seqs.clone(), sam_header, String::new(), true);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(100) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = ReaderBuilder::new().fields(&[Fields::ReadName]).open(File::open(&path).unwrap()).unwrap();
+        let names = |reader: &mut Reader, text: &str| {
+            let expr = FilterExpr::parse(text, &ref_seqs).unwrap();
+            let mut records = reader.filtered(&expr).unwrap();
+            let mut count = 0;
+            while let Some(rec) = records.next_rec() {
+                // Only fields of reader are filled.
+                assert!(rec.read_name.is_some() && rec.flag.is_none());
+                count += 1;
+            }
+            count
+        };
+        let count = |pred: &dyn Fn(usize) -> bool| (0..600).filter(|&i| pred(i)).count();
+        assert_eq!(names(&mut reader, "mapq >= 30"), count(&|i| i >= 300));
+        assert_eq!(names(&mut reader, "!(flag & 0x404) && mapq < 20"), count(&|i| i % 4 < 2 && i < 200));
+        assert_eq!(names(&mut reader, "flag & 0x10 == 0x10 || [NM] > 3"), count(&|i| i % 4 == 1 || i % 5 == 4));
+        assert_eq!(names(&mut reader, "rg == \"grp1\" && rname == \"chr2\""), count(&|i| i % 3 == 1 && i >= 400));
+        assert_eq!(names(&mut reader, "[Xf] > 1 && [NM]"), count(&|i| i % 2 == 1 && i % 5 != 0));
+        assert_eq!(names(&mut reader, "[XX] || [RG] == 3"), 0);
+        assert_eq!(names(&mut reader, "region(\"chr1:96-105\") && pos > 91"), 1);
+        assert_eq!(names(&mut reader, "pos <= 100 || tlen != 0"), 10);
+
+        // Conditions joined with && skip blocks by their stats.
+        let expr = FilterExpr::parse("rname == \"chr2\" && mapq < 50 && (flag || mapq > 0)", &ref_seqs).unwrap();
+        assert_eq!(expr.ranges().candidate_records(&reader), [400..500]);
+        let expr = FilterExpr::parse("mapq < 10 || rname == \"chr2\"", &ref_seqs).unwrap();
+        assert_eq!(expr.ranges().candidate_records(&reader), [0..600]);
+
+        for invalid in ["mapq >=", "mapq > 1 1", "(flag", "foo == 1", "rname == \"chr3\"", "region(\"chr1:0\")", "[N] > 1", "flag & \"a\""] {
+            assert!(FilterExpr::parse(invalid, &ref_seqs).is_err(), "{}", invalid);
+        }
+    }
+}
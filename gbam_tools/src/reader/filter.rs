@@ -0,0 +1,263 @@
+use std::io::{Error, ErrorKind, Result};
+use std::ops::{Range, RangeInclusive};
+
+use bam_tools::record::fields::Fields;
+
+use super::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord};
+use crate::meta::{BlockMeta, STATS_FIELDS};
+
+/// Selection of records of `Reader::filtered`.
+pub trait RecordPredicate: Clone {
+    /// Fields `accepts` needs decoded.
+    fn fields(&self) -> Vec<Fields>;
+
+    fn accepts(&self, rec: &GbamRecord) -> bool;
+
+    /// Ranges all accepted records lie in, blocks outside of them are
+    /// skipped.
+    fn ranges(&self) -> RangeFilter;
+}
+
+/// Selects records by inclusive ranges of numeric fields, e.g. MAPQ of at
+/// least 30. Blocks whose min and max (see `WriterBuilder::collect_stats`)
+/// show that none of their records match are skipped without being fetched
+/// or decompressed, as Parquet predicate pushdown does. Records of other
+/// blocks are checked one by one. See `Reader::filtered`.
+#[derive(Clone, Debug, Default)]
+pub struct RangeFilter {
+    ranges: Vec<(Fields, RangeInclusive<i32>)>,
+}
+
+impl RangeFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps only records whose `field` lies in `range`. POS is 0-based and
+    /// RNAME is reference ID, as in `GbamRecord`. Only fields stats may be
+    /// collected for are accepted (see `STATS_FIELDS`).
+    pub fn add_range(&mut self, field: Fields, range: RangeInclusive<i32>) -> Result<()> {
+        if !STATS_FIELDS.contains(&field) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Records can't be filtered by {}.", field)));
+        }
+        self.ranges.push((field, range));
+        Ok(())
+    }
+
+    /// Ascending runs of records of `reader` which may match according to
+    /// block stats. Records of index sorted reader are all kept, blocks
+    /// don't follow their order.
+    #[allow(clippy::single_range_in_vec_init)]
+    pub(crate) fn candidate_records(&self, reader: &Reader) -> Vec<Range<usize>> {
+        let mut candidates = vec![0..reader.amount];
+        if reader.index_mapping.is_some() {
+            return candidates;
+        }
+        for (field, range) in &self.ranges {
+            let kept = kept_blocks(reader.file_meta.view_blocks(field), range);
+            candidates = intersect(&candidates, &kept);
+        }
+        candidates
+    }
+}
+
+impl RecordPredicate for RangeFilter {
+    fn fields(&self) -> Vec<Fields> {
+        self.ranges.iter().map(|(field, _)| *field).collect()
+    }
+
+    /// Record with fields of filter decoded matches all ranges.
+    fn accepts(&self, rec: &GbamRecord) -> bool {
+        self.ranges.iter().all(|(field, range)| {
+            let value = match field {
+                Fields::RefID => rec.refid,
+                Fields::Pos => rec.pos,
+                Fields::Mapq => rec.mapq.map(i32::from),
+                Fields::TemplateLength => rec.tlen,
+                _ => None,
+            };
+            value.is_some_and(|value| range.contains(&value))
+        })
+    }
+
+    fn ranges(&self) -> RangeFilter {
+        self.clone()
+    }
+}
+
+/// Runs of records of `blocks` without stats or with stats overlapping
+/// `range`.
+fn kept_blocks(blocks: &[BlockMeta], range: &RangeInclusive<i32>) -> Vec<Range<usize>> {
+    let mut kept: Vec<Range<usize>> = Vec::new();
+    let mut start = 0;
+    for block in blocks {
+        let end = start + block.numitems as usize;
+        let may_match = block
+            .stats
+            .as_ref()
+            .is_none_or(|stats| stats.min_value <= *range.end() && stats.max_value >= *range.start());
+        match kept.last_mut() {
+            Some(last) if may_match && last.end == start => last.end = end,
+            _ if may_match && end > start => kept.push(start..end),
+            _ => {}
+        }
+        start = end;
+    }
+    kept
+}
+
+/// Intersection of two ascending lists of disjoint ranges.
+fn intersect(a: &[Range<usize>], b: &[Range<usize>]) -> Vec<Range<usize>> {
+    let (mut i, mut j) = (0, 0);
+    let mut res = Vec::new();
+    while i < a.len() && j < b.len() {
+        let start = a[i].start.max(b[j].start);
+        let end = a[i].end.min(b[j].end);
+        if start < end {
+            res.push(start..end);
+        }
+        if a[i].end < b[j].end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    res
+}
+
+/// Iterates over records matching `RecordPredicate` in file order. Fields
+/// of predicate are decoded first, the rest only for matching records.
+pub struct FilteredRecords<'a, P> {
+    reader: &'a mut Reader,
+    /// Decodes fields of predicate.
+    checker: Reader,
+    filter: P,
+    candidates: std::vec::IntoIter<Range<usize>>,
+    cur: Range<usize>,
+    buf: GbamRecord,
+    check_buf: GbamRecord,
+}
+
+impl<P: RecordPredicate> FilteredRecords<'_, P> {
+    /// Panics if block can't be read, see `try_next_rec`.
+    pub fn next_rec(&mut self) -> Option<&GbamRecord> {
+        self.try_next_rec().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Next matching record, error if its block can't be read (e.g.
+    /// corrupted). Reading goes on after failed record.
+    pub fn try_next_rec(&mut self) -> Result<Option<&GbamRecord>> {
+        loop {
+            let rec_num = match self.cur.next() {
+                Some(rec_num) => rec_num,
+                None => match self.candidates.next() {
+                    Some(range) => {
+                        self.cur = range;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+            self.checker.try_fill_record(rec_num, &mut self.check_buf)?;
+            if self.filter.accepts(&self.check_buf) {
+                self.reader.try_fill_record(rec_num, &mut self.buf)?;
+                return Ok(Some(&self.buf));
+            }
+        }
+    }
+}
+
+impl Reader {
+    /// Get iterator over records matching `filter`, skipping blocks which
+    /// can't hold any (see `RangeFilter`).
+    pub fn filtered<P: RecordPredicate>(&mut self, filter: &P) -> Result<FilteredRecords<'_, P>> {
+        let checker = self.sibling(ParsingTemplate::new_with(&filter.fields()))?;
+        let candidates = filter.ranges().candidate_records(&checker).into_iter();
+        Ok(FilteredRecords {
+            reader: self,
+            checker,
+            filter: filter.clone(),
+            candidates,
+            cur: 0..0,
+            buf: GbamRecord::default(),
+            check_buf: GbamRecord::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_range_filter() {
+        assert_eq!(intersect(&[0..10, 20..30], &[5..25]), [5..10, 20..25]);
+        assert_eq!(intersect(&[0..10], &[10..20]), Vec::<Range<usize>>::new());
+
+        // MAPQ is the same within blocks of 100 records, TLEN of mates is
+        // stored relative to POS.
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..1000 {
+            sam.push_str(&format!(
+                "r{}\t99\tchr1\t{}\t{}\t4M\t=\t{}\t{}\tACGT\tIIII\n",
+                i, i + 1, i / 100 * 10, i + 201, 200 + i % 7 * 100
+            ));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("range_filter").unwrap();
+        let path = dir.path().join("stats.gbam");
+        let stats = vec![Fields::Mapq, Fields::TemplateLength];
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, stats, ref_seqs, sam_header, String::new(), true);
+        writer.set_mate_relative();
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(100) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+        let all: Vec<_> = reader.records().map(|rec| rec.unwrap()).collect();
+        let check = |reader: &mut Reader, filter: &RangeFilter| {
+            let mut seen = Vec::new();
+            let mut records = reader.filtered(filter).unwrap();
+            while let Some(rec) = records.next_rec() {
+                seen.push(format!("{:?}", rec));
+            }
+            let expected: Vec<_> = all.iter().filter(|rec| filter.accepts(rec)).map(|rec| format!("{:?}", rec)).collect();
+            assert_eq!(seen, expected);
+            seen.len()
+        };
+
+        let mut filter = RangeFilter::new();
+        filter.add_range(Fields::Mapq, 30..=255).unwrap();
+        assert_eq!(filter.candidate_records(&reader), [300..1000]);
+        assert_eq!(check(&mut reader, &filter), 700);
+        filter.add_range(Fields::TemplateLength, 700..=800).unwrap();
+        assert_eq!(check(&mut reader, &filter), 200);
+        // POS has no stats, its blocks are checked record by record.
+        filter.add_range(Fields::Pos, 0..=349).unwrap();
+        assert_eq!(filter.candidate_records(&reader), [300..1000]);
+        assert_eq!(check(&mut reader, &filter), 15);
+
+        // Stats are of TLEN as decoded, not of stored residuals.
+        let mut filter = RangeFilter::new();
+        filter.add_range(Fields::TemplateLength, 700..=800).unwrap();
+        assert_eq!(filter.candidate_records(&reader), [0..1000]);
+        let mut filter = RangeFilter::new();
+        filter.add_range(Fields::TemplateLength, 1000..=2000).unwrap();
+        assert!(filter.candidate_records(&reader).is_empty());
+        assert_eq!(check(&mut reader, &filter), 0);
+        assert!(filter.add_range(Fields::Flags, 0..=1).is_err());
+    }
+}
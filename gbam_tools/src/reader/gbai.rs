@@ -0,0 +1,332 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use bam_tools::record::fields::Fields;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    reader::{parse_file_info, verify_and_parse_meta, Reader},
+    record::GbamRecord,
+    region::{Region, LOCATOR_FIELDS},
+};
+use crate::query::cigar::base_coverage;
+use crate::writer::write_meta;
+
+const GBAI_MAGIC: &[u8; 4] = b"GBAI";
+const GBAI_VERSION: u8 = 1;
+
+/// Records mapped to one reference which no block boundary of any column
+/// splits. Records of a block spanning several references get entry for
+/// each of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub ref_id: i32,
+    /// Leftmost POS of records.
+    pub min_pos: i32,
+    /// Rightmost end (exclusive) of records on reference.
+    pub max_end: i64,
+    /// Record number of the first record.
+    pub first_record: u64,
+    pub num_records: u32,
+    /// Offset of the first and end of the last block holding records, of
+    /// all columns. Reading records needs nothing outside of this range.
+    pub offset: u64,
+    pub end_offset: u64,
+}
+
+/// Index of coordinate sorted GBAM file: which records of which blocks cover
+/// a genomic interval. Kept either in file meta (footer) or in sidecar
+/// `<file>.gbai`. Built after file is written, see `GbamIndex::build`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct GbamIndex {
+    /// Number of records in indexed file.
+    pub num_records: u64,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl GbamIndex {
+    /// Builds index of coordinate sorted GBAM file. Only RNAME, POS and CIGAR
+    /// columns are read.
+    pub fn build(gbam_path: &Path) -> Result<Self> {
+        let mut reader = Reader::with_fields(File::open(gbam_path)?, &LOCATOR_FIELDS)?;
+        if !reader.is_sorted() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Only coordinate sorted file can be indexed."));
+        }
+        // First record, offset and end of every block of every column.
+        let columns: Vec<Vec<(u64, u64, u64)>> = Fields::iterator()
+            .map(|field| {
+                let mut first_record = 0;
+                reader.file_meta.view_blocks(field).iter().map(|block| {
+                    let first = first_record;
+                    first_record += u64::from(block.numitems);
+                    (first, block.seekpos, block.seekpos + u64::from(block.block_size))
+                }).collect()
+            })
+            .collect();
+        let boundaries: BTreeSet<u64> = columns.iter().flatten().map(|&(first, ..)| first).collect();
+
+        let mut index = GbamIndex { num_records: reader.amount as u64, entries: Vec::new() };
+        let mut rec = GbamRecord::default();
+        for rec_num in 0..index.num_records {
+            reader.try_fill_record(rec_num as usize, &mut rec)?;
+            let (ref_id, pos) = rec.refid.zip(rec.pos).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidData, "File without RNAME or POS column can't be indexed.")
+            })?;
+            let span = rec.cigar.as_ref().map_or(0, |cigar| base_coverage(&cigar.0)).max(1);
+            let end = i64::from(pos) + i64::from(span);
+            match index.entries.last_mut() {
+                Some(entry) if entry.ref_id == ref_id && !boundaries.contains(&rec_num) => {
+                    entry.min_pos = entry.min_pos.min(pos);
+                    entry.max_end = entry.max_end.max(end);
+                    entry.num_records += 1;
+                }
+                _ => index.entries.push(IndexEntry {
+                    ref_id,
+                    min_pos: pos,
+                    max_end: end,
+                    first_record: rec_num,
+                    num_records: 1,
+                    offset: 0,
+                    end_offset: 0,
+                }),
+            }
+        }
+        for entry in &mut index.entries {
+            let (offset, end_offset) = columns
+                .iter()
+                .filter_map(|blocks| block_range(blocks, entry.first_record, entry.num_records))
+                .fold((u64::MAX, 0), |(offset, end), (first, last)| (offset.min(first), end.max(last)));
+            entry.offset = offset;
+            entry.end_offset = end_offset;
+        }
+        Ok(index)
+    }
+
+    /// Range of record numbers holding all records overlapping `region`.
+    /// Records of the range not overlapping it still have to be skipped.
+    pub fn overlapping(&self, region: &Region) -> Range<usize> {
+        let mut entries = self.entries.iter().filter(|entry| {
+            entry.ref_id == region.ref_id
+                && i64::from(entry.min_pos) < i64::from(region.end)
+                && entry.max_end > i64::from(region.start)
+        });
+        let first = match entries.next() {
+            Some(first) => first,
+            None => return 0..0,
+        };
+        let last = entries.next_back().unwrap_or(first);
+        first.first_record as usize..(last.first_record + u64::from(last.num_records)) as usize
+    }
+
+    /// Byte range of file (see `IndexEntry::offset`) holding all records
+    /// overlapping `region`, None if there are none.
+    pub fn byte_range(&self, region: &Region) -> Option<Range<u64>> {
+        let records = self.overlapping(region);
+        self.entries
+            .iter()
+            .filter(|entry| (entry.first_record as usize) < records.end && entry.first_record as usize >= records.start)
+            .map(|entry| entry.offset..entry.end_offset)
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+    }
+
+    /// Path of sidecar index of GBAM file: `<file>.gbai`.
+    pub fn sidecar_path(gbam_path: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.gbai", gbam_path.display()))
+    }
+
+    /// Sidecar index of GBAM file, None if there is none.
+    pub fn read_sidecar(gbam_path: &Path) -> Result<Option<Self>> {
+        match Self::read(&Self::sidecar_path(gbam_path)) {
+            Ok(index) => Ok(Some(index)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes index to sidecar file: magic, version and index as JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(GBAI_MAGIC)?;
+        out.write_all(&[GBAI_VERSION])?;
+        serde_json::to_writer(&mut out, self)?;
+        out.flush()
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut header = [0; 5];
+        input.read_exact(&mut header)?;
+        if &header[..4] != GBAI_MAGIC || header[4] != GBAI_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is not GBAM index.", path.display())));
+        }
+        Ok(serde_json::from_reader(input)?)
+    }
+
+    /// Stores index in meta of GBAM file, replacing one stored before. Meta
+    /// is rewritten in place, data blocks are not touched.
+    pub fn embed(&self, gbam_path: &Path) -> Result<()> {
+        let mut file = OpenOptions::new().read(true).write(true).open(gbam_path)?;
+        let (mut file_info, mut file_meta) = {
+            let mmap = unsafe { Mmap::map(&file)? };
+            (parse_file_info(&mmap)?, verify_and_parse_meta(&mmap)?)
+        };
+        file_meta.set_index(Some(self.clone()));
+        file.seek(SeekFrom::Start(file_info.seekpos))?;
+        let mut out = BufWriter::new(&mut file);
+        let end = write_meta(&mut out, &mut file_info, &file_meta)?;
+        drop(out);
+        file.set_len(end)
+    }
+}
+
+/// Offset of the first and end of the last block of column holding
+/// `num_records` records starting at `first_record`.
+fn block_range(blocks: &[(u64, u64, u64)], first_record: u64, num_records: u32) -> Option<(u64, u64)> {
+    let first = blocks.partition_point(|&(first, ..)| first <= first_record).checked_sub(1)?;
+    let end_record = first_record + u64::from(num_records);
+    let last = blocks.partition_point(|&(first, ..)| first < end_record) - 1;
+    let offset = blocks[first..=last].iter().map(|&(_, offset, _)| offset).min()?;
+    let end = blocks[first..=last].iter().map(|&(.., end)| end).max()?;
+    Some((offset, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::bam_to_gbam::{bam_sort_to_gbam, index_sort_path};
+    use crate::bam::gbam_to_bam::gbam_to_bam;
+    use crate::bam::merge::SortOrder;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::writer::Writer;
+    use crate::writer_builder::WriterBuilder;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use tempdir::TempDir;
+
+    fn fetch_names(reader: &mut Reader, region: &str) -> Vec<Vec<u8>> {
+        let mut names = Vec::new();
+        let mut records = reader.fetch(region).unwrap();
+        while let Some(rec) = records.next_rec() {
+            names.push(rec.read_name.clone().unwrap());
+        }
+        names
+    }
+
+    #[test]
+    fn test_gbai() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:chr2\tLN:10000\n");
+        sam.push_str("long\t0\tchr1\t10\t60\t50M2000N50M\t*\t0\t0\t*\t*\n");
+        for i in 0..45 {
+            sam.push_str(&format!("a{}\t0\tchr1\t{}\t60\t20M\t*\t0\t0\t*\t*\n", i, 100 + i * 100));
+        }
+        for i in 0..20 {
+            sam.push_str(&format!("b{}\t0\tchr2\t{}\t60\t20M\t*\t0\t0\t*\t*\n", i, 1 + i * 5));
+        }
+        sam.push_str("unmapped\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n");
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("gbai").unwrap();
+        let path = dir.path().join("sorted.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(10) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let index = GbamIndex::build(&path).unwrap();
+        assert_eq!(index.num_records, 67);
+        let mut corrupted = std::fs::read(&path).unwrap();
+        let reader = Reader::with_fields(File::open(&path).unwrap(), &[Fields::Pos]).unwrap();
+        let block = &reader.file_meta.view_blocks(&Fields::Pos)[2];
+        corrupted[block.seekpos as usize..][..block.block_size as usize].fill(0);
+        let corrupted_path = dir.path().join("corrupted.gbam");
+        std::fs::write(&corrupted_path, corrupted).unwrap();
+        assert!(GbamIndex::build(&corrupted_path).is_err());
+        // Blocks of records 40..50 hold the last records of chr1 and the
+        // first ones of chr2.
+        let split: Vec<_> = index
+            .entries
+            .iter()
+            .filter(|entry| (40..50).contains(&entry.first_record))
+            .map(|entry| (entry.ref_id, entry.first_record, entry.num_records))
+            .collect();
+        assert_eq!(split, vec![(0, 40, 6), (1, 46, 4)]);
+        let first = &index.entries[0];
+        assert_eq!((first.ref_id, first.min_pos, first.max_end, first.num_records), (0, 9, 2109, 10));
+        assert!(index.entries.iter().all(|entry| entry.offset < entry.end_offset));
+        assert!(index.entries[0].end_offset < index.entries.last().unwrap().end_offset);
+        assert_eq!(index.entries.last().unwrap().ref_id, -1);
+        let region = Region { ref_id: 0, start: 2000, end: 2001 };
+        assert_eq!(index.overlapping(&region), 0..30);
+        assert_eq!(index.overlapping(&Region { ref_id: 1, start: 5000, end: 6000 }), 0..0);
+        assert_eq!(index.byte_range(&Region { ref_id: 1, start: 5000, end: 6000 }), None);
+        let bytes = index.byte_range(&region).unwrap();
+        assert_eq!(bytes, index.entries[0].offset..index.entries[2].end_offset);
+
+        let sidecar = GbamIndex::sidecar_path(&path);
+        assert!(GbamIndex::read_sidecar(&path).unwrap().is_none());
+        index.write(&sidecar).unwrap();
+        // Index is rebuilt over the previous one.
+        index.write(&sidecar).unwrap();
+        assert_eq!(GbamIndex::read(&sidecar).unwrap(), index);
+        assert_eq!(GbamIndex::read_sidecar(&path).unwrap(), Some(index.clone()));
+        assert!(GbamIndex::read(&path).is_err());
+
+        let regions = ["chr1:2001-2001", "chr1:3000-3100", "chr2:50-60", "chr2"];
+        let mut reader = Reader::with_fields(File::open(&path).unwrap(), &[Fields::ReadName]).unwrap();
+        let expected: Vec<_> = regions.iter().map(|region| fetch_names(&mut reader, region)).collect();
+        reader.set_index(index.clone()).unwrap();
+        let with_sidecar: Vec<_> = regions.iter().map(|region| fetch_names(&mut reader, region)).collect();
+        assert_eq!(with_sidecar, expected);
+        assert_eq!(expected[0], vec![b"long\0".to_vec(), b"a19\0".to_vec()]);
+
+        index.embed(&path).unwrap();
+        index.embed(&path).unwrap();
+        let mut reader = Reader::with_fields(File::open(&path).unwrap(), &[Fields::ReadName]).unwrap();
+        assert_eq!(reader.file_meta.get_index(), Some(&index));
+        let with_footer: Vec<_> = regions.iter().map(|region| fetch_names(&mut reader, region)).collect();
+        assert_eq!(with_footer, expected);
+        assert!(reader.set_index(GbamIndex::default()).is_err());
+    }
+
+    #[test]
+    fn test_gbai_of_index_sorted_file() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:10000\n");
+        for i in 0..30 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t20M\t*\t0\t0\t*\t*\n", i, 1 + i * 10));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("gbai_index_sort").unwrap();
+        let path = dir.path().join("in.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), false);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+        let bam_path = dir.path().join("in.bam");
+        gbam_to_bam(path.to_str().unwrap(), bam_path.to_str().unwrap()).unwrap();
+
+        let out_path = dir.path().join("out.gbam");
+        let out = out_path.to_str().unwrap();
+        let builder = WriterBuilder::new().codecs(vec![Codecs::Lz4]).thread_num(2);
+        bam_sort_to_gbam(bam_path.to_str().unwrap(), out, SortOrder::Coordinate, Some("ram".to_owned()), Some(dir.path().to_owned()), true, builder, None).unwrap();
+        let order = std::fs::read(index_sort_path(out)).unwrap();
+
+        // Sidecar index is kept next to record order.
+        let index = GbamIndex::build(&out_path).unwrap();
+        index.write(&GbamIndex::sidecar_path(&out_path)).unwrap();
+        assert_ne!(GbamIndex::sidecar_path(&out_path).to_str().unwrap(), index_sort_path(out));
+        assert_eq!(std::fs::read(index_sort_path(out)).unwrap(), order);
+        let expected: Vec<u8> = (0..30u32).flat_map(u32::to_le_bytes).collect();
+        assert_eq!(order, expected);
+        assert_eq!(GbamIndex::read_sidecar(&out_path).unwrap(), Some(index));
+    }
+}
@@ -0,0 +1,250 @@
+use std::io::{Error, ErrorKind, Result};
+
+use bam_tools::record::fields::Fields;
+use xxhash_rust::xxh64::xxh64;
+
+use super::{
+    parse_tmplt::ParsingTemplate,
+    reader::Reader,
+    record::GbamRecord,
+    region::{lower_bound, Region},
+};
+
+const FLAG_PAIRED: u16 = 0x1;
+const FLAG_FIRST: u16 = 0x40;
+const FLAG_LAST: u16 = 0x80;
+/// Secondary and supplementary alignments are not mates of their own.
+const FLAG_NOT_PRIMARY: u16 = 0x100 | 0x800;
+
+/// Fields telling whether one record is mate of another.
+const MATE_FIELDS: [Fields; 6] = [
+    Fields::ReadName,
+    Fields::Flags,
+    Fields::RefID,
+    Fields::Pos,
+    Fields::NextRefID,
+    Fields::NextPos,
+];
+
+/// Record numbers by read name, for finding records by name in file of any
+/// order. Holds 16 bytes per record, names themselves are not kept.
+#[derive(Clone, Debug, Default)]
+pub struct NameIndex {
+    /// Hash of read name and record number, sorted.
+    entries: Vec<(u64, u64)>,
+}
+
+impl NameIndex {
+    /// Reads ReadName column of file of `reader`. Record numbers follow
+    /// order of reader (see `Reader::new_with_index`).
+    pub fn build(reader: &Reader) -> Result<Self> {
+        let mut names = reader.sibling(ParsingTemplate::new_with(&[Fields::ReadName]))?;
+        let mut rec = GbamRecord::default();
+        let mut entries = Vec::with_capacity(names.amount);
+        for rec_num in 0..names.amount {
+            names.try_fill_record(rec_num, &mut rec)?;
+            entries.push((name_hash(rec.name().unwrap_or_default()), rec_num as u64));
+        }
+        entries.sort_unstable();
+        Ok(Self { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Records which may be named `name`, hashes of names may collide.
+    fn candidates(&self, name: &[u8]) -> Vec<usize> {
+        let hash = name_hash(name);
+        let start = self.entries.partition_point(|&(other, _)| other < hash);
+        self.entries[start..]
+            .iter()
+            .take_while(|&&(other, _)| other == hash)
+            .map(|&(_, rec_num)| rec_num as usize)
+            .collect()
+    }
+}
+
+fn name_hash(name: &[u8]) -> u64 {
+    xxh64(name, 0)
+}
+
+impl Reader {
+    /// Lets `fetch_mate` find mates without position and in files which are
+    /// not coordinate sorted. Fails if index was built for another file.
+    pub fn set_name_index(&mut self, index: NameIndex) -> Result<()> {
+        if index.len() != self.amount {
+            return Err(Error::new(ErrorKind::InvalidInput, "Name index doesn't match the file."));
+        }
+        self.name_index = Some(index);
+        Ok(())
+    }
+
+    /// Mate of paired `rec`: primary record of the same name at RNEXT and
+    /// PNEXT of `rec` (if RNEXT is not `*`), which is the other segment of template if `rec` is
+    /// first or last one. `rec` needs ReadName, Flags, RefID, Pos, NextRefID
+    /// and NextPos. Coordinate sorted file is binary searched for records
+    /// starting at PNEXT. Otherwise, or if mate is not found there (e.g. it
+    /// is unmapped and RNEXT is `*`), records of the same name are looked up
+    /// in name index (see `set_name_index`). Mate is returned with fields of
+    /// reader, None if `rec` is not paired or mate is not in file. Fails if
+    /// neither position nor name index can be used, or names were elided.
+    pub fn fetch_mate(&mut self, rec: &GbamRecord) -> Result<Option<GbamRecord>> {
+        if self.file_meta.is_omitted(&Fields::ReadName) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Mates can't be found in file without read names."));
+        }
+        let missing = || Error::new(ErrorKind::InvalidInput, "Record lacks fields needed to find its mate.");
+        let flag = rec.flag.ok_or_else(missing)?;
+        if flag & FLAG_PAIRED == 0 {
+            return Ok(None);
+        }
+        let name = rec.name().ok_or_else(missing)?;
+        let (ref_id, pos) = (rec.refid.ok_or_else(missing)?, rec.pos.ok_or_else(missing)?);
+        let (next_ref_id, next_pos) = (rec.next_ref_id.ok_or_else(missing)?, rec.next_pos.ok_or_else(missing)?);
+        let is_mate = |other: &GbamRecord| {
+            let other_flag = other.flag.unwrap_or_default();
+            let order = (flag & (FLAG_FIRST | FLAG_LAST), other_flag & (FLAG_FIRST | FLAG_LAST));
+            other.name() == Some(name)
+                && other_flag & (FLAG_PAIRED | FLAG_NOT_PRIMARY) == FLAG_PAIRED
+                && (next_ref_id < 0 || (other.refid == Some(next_ref_id) && other.pos == Some(next_pos)))
+                && match order {
+                    (FLAG_FIRST, other) => other == FLAG_LAST,
+                    (FLAG_LAST, other) => other == FLAG_FIRST,
+                    // Segment order is unknown, only positions tell.
+                    _ => other.next_ref_id == Some(ref_id) && other.next_pos == Some(pos),
+                }
+        };
+
+        let mut checker = self.sibling(ParsingTemplate::new_with(&MATE_FIELDS))?;
+        let positioned = next_ref_id >= 0 && next_pos >= 0 && self.index_mapping.is_none() && self.is_sorted();
+        let mut found = None;
+        if positioned {
+            let mut buf = GbamRecord::default();
+            let start = lower_bound(&mut checker, &mut buf, Region::key(next_ref_id, i64::from(next_pos)));
+            let end = lower_bound(&mut checker, &mut buf, Region::key(next_ref_id, i64::from(next_pos) + 1));
+            found = first_match(&mut checker, start..end, is_mate)?;
+        }
+        if found.is_none() {
+            match &self.name_index {
+                Some(index) => found = first_match(&mut checker, index.candidates(name), is_mate)?,
+                None if !positioned => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Mate without position or in unsorted file can be fetched only with name index.",
+                    ))
+                }
+                None => {}
+            }
+        }
+        match found {
+            Some(rec_num) => {
+                let mut mate = GbamRecord::default();
+                self.try_fill_record(rec_num, &mut mate)?;
+                Ok(Some(mate))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// First of `candidates` records of `checker` which is mate.
+fn first_match<I, F>(checker: &mut Reader, candidates: I, is_mate: F) -> Result<Option<usize>>
+where
+    I: IntoIterator<Item = usize>,
+    F: Fn(&GbamRecord) -> bool,
+{
+    let mut buf = GbamRecord::default();
+    for rec_num in candidates {
+        checker.try_fill_record(rec_num, &mut buf)?;
+        if is_mate(&buf) {
+            return Ok(Some(rec_num));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::Codecs;
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn write_gbam(path: &Path, sam: &str, sorted: bool) {
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let mut writer = Writer::new(File::create(path).unwrap(), vec![Codecs::Gzip], 2, Vec::new(), ref_seqs, sam_header, String::new(), sorted);
+        writer.set_mate_relative();
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_fetch_mate() {
+        // p2 has a supplementary alignment at its mate position, p3 lacks
+        // mate position of its unmapped mate.
+        let lines = [
+            "p1\t99\tchr1\t100\t60\t4M\t=\t300\t204\tACGT\t*",
+            "p2\t97\tchr1\t150\t60\t4M\tchr2\t50\t0\tACGT\t*",
+            "p3\t73\tchr1\t200\t60\t4M\t*\t0\t0\tACGT\t*",
+            "p1\t147\tchr1\t300\t60\t4M\t=\t100\t-204\tACGT\t*",
+            "p1\t2195\tchr1\t300\t0\t4M\t=\t100\t-204\tACGT\t*",
+            "se\t0\tchr1\t300\t60\t4M\t*\t0\t0\tACGT\t*",
+            "p2\t2129\tchr2\t50\t0\t4M\tchr1\t150\t0\tACGT\t*",
+            "p2\t145\tchr2\t50\t60\t4M\tchr1\t150\t0\tACGT\t*",
+            "p3\t133\t*\t0\t0\t*\t*\t0\t0\tACGT\t*",
+        ];
+        let header = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n";
+        let dir = TempDir::new("fetch_mate").unwrap();
+        let sorted = dir.path().join("sorted.gbam");
+        write_gbam(&sorted, &format!("{}{}\n", header, lines.join("\n")), true);
+        let mut reader = ReaderBuilder::new().open(File::open(&sorted).unwrap()).unwrap();
+        let records: Vec<_> = reader.records().map(|rec| rec.unwrap()).collect();
+        let mate_of = |reader: &mut Reader, i: usize| {
+            let mate = reader.fetch_mate(&records[i]).unwrap()?;
+            records.iter().position(|rec| format!("{:?}", rec) == format!("{:?}", mate))
+        };
+        assert_eq!(mate_of(&mut reader, 0), Some(3));
+        assert_eq!(mate_of(&mut reader, 3), Some(0));
+        assert_eq!(mate_of(&mut reader, 4), Some(0));
+        assert_eq!(mate_of(&mut reader, 1), Some(7));
+        assert_eq!(mate_of(&mut reader, 7), Some(1));
+        assert_eq!(mate_of(&mut reader, 5), None);
+        // Without position mate is found only with name index.
+        assert!(reader.fetch_mate(&records[2]).is_err());
+        let index = NameIndex::build(&reader).unwrap();
+        assert_eq!(index.len(), lines.len());
+        reader.set_name_index(index).unwrap();
+        assert_eq!(mate_of(&mut reader, 2), Some(8));
+        assert_eq!(mate_of(&mut reader, 8), Some(2));
+
+        let missing = GbamRecord { flag: Some(1), ..Default::default() };
+        assert!(reader.fetch_mate(&missing).is_err());
+
+        // Unsorted file needs name index.
+        let unsorted = dir.path().join("unsorted.gbam");
+        let mut shuffled = lines.to_vec();
+        shuffled.reverse();
+        write_gbam(&unsorted, &format!("@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n{}\n", shuffled.join("\n")), false);
+        let mut reader = ReaderBuilder::new().fields(&MATE_FIELDS).open(File::open(&unsorted).unwrap()).unwrap();
+        let rec = reader.records().nth(5).unwrap().unwrap();
+        assert!(reader.fetch_mate(&rec).is_err());
+        let index = NameIndex::build(&reader).unwrap();
+        reader.set_name_index(index).unwrap();
+        let mate = reader.fetch_mate(&rec).unwrap().unwrap();
+        assert_eq!((mate.read_name.as_deref(), mate.flag, mate.pos), (Some(&b"p1\0"[..]), Some(99), Some(99)));
+        assert!(reader.set_name_index(NameIndex::default()).is_err());
+    }
+}
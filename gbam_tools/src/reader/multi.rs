@@ -0,0 +1,191 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{Error, ErrorKind, Result};
+
+use bam_tools::record::fields::Fields;
+
+use super::{reader::Reader, record::GbamRecord, region::Region};
+use crate::bam::merge::{MergedHeader, SortOrder};
+use crate::bam::sam_reader::RefSeqs;
+
+/// Iterates over records of several coordinate sorted GBAM files as over one
+/// merged file, without writing it. Reference sequences and read groups of
+/// files are reconciled into a single header as `MergeReader` does, records
+/// are rewritten to refer to it. Records of the same position come in order
+/// of files.
+pub struct MultiReader {
+    readers: Vec<Reader>,
+    header: MergedHeader,
+    /// Number of the next record of every reader.
+    next: Vec<usize>,
+    /// The earliest record not returned yet of every reader.
+    heads: Vec<GbamRecord>,
+    queue: BinaryHeap<Reverse<((u32, i64), usize)>>,
+    record: GbamRecord,
+    last_key: Option<(u32, i64)>,
+}
+
+impl MultiReader {
+    /// Merges records of `readers`, with fields each of them decodes (see
+    /// `ReaderBuilder::fields`) plus RNAME and POS, which order them (reader
+    /// lacking them is reopened without its prefetch settings). Fails if
+    /// some file isn't coordinate sorted or headers can't be reconciled.
+    pub fn new(readers: Vec<Reader>) -> Result<Self> {
+        let mut headers = Vec::new();
+        let mut merged = Vec::new();
+        for (input, reader) in readers.into_iter().enumerate() {
+            if !reader.is_sorted() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Input {} is not coordinate sorted.", input + 1),
+                ));
+            }
+            headers.push((reader.file_meta.get_sam_header().to_vec(), reader.file_meta.get_ref_seqs().clone()));
+            if reader.parsing_template.check_if_active(&[Fields::RefID, Fields::Pos]) {
+                merged.push(reader);
+                continue;
+            }
+            let mut template = reader.parsing_template.clone();
+            template.set(&Fields::RefID, true);
+            template.set(&Fields::Pos, true);
+            merged.push(reader.sibling(template)?);
+        }
+        let header = MergedHeader::new(&headers, SortOrder::Coordinate)?;
+        let mut multi_reader = Self {
+            next: vec![0; merged.len()],
+            heads: vec![GbamRecord::default(); merged.len()],
+            readers: merged,
+            header,
+            queue: BinaryHeap::new(),
+            record: GbamRecord::default(),
+            last_key: None,
+        };
+        for input in 0..multi_reader.readers.len() {
+            multi_reader.advance(input)?;
+        }
+        Ok(multi_reader)
+    }
+
+    /// Header bytes laid out as in BAM file.
+    pub fn header(&self) -> &[u8] {
+        &self.header.bytes
+    }
+
+    pub fn ref_seqs(&self) -> &RefSeqs {
+        &self.header.ref_seqs
+    }
+
+    /// Panics if block can't be read or file turns out not to be sorted,
+    /// see `try_next_rec`.
+    pub fn next_rec(&mut self) -> Option<&GbamRecord> {
+        self.try_next_rec().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Next record of merged files. Fails if block can't be read or some
+    /// file turns out not to be sorted.
+    pub fn try_next_rec(&mut self) -> Result<Option<&GbamRecord>> {
+        let Reverse((key, input)) = match self.queue.pop() {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        std::mem::swap(&mut self.record, &mut self.heads[input]);
+        self.last_key = Some(key);
+        self.advance(input)?;
+        Ok(Some(&self.record))
+    }
+
+    /// Reads next record of `input` and queues it.
+    fn advance(&mut self, input: usize) -> Result<()> {
+        let rec_num = self.next[input];
+        if rec_num == self.readers[input].amount {
+            return Ok(());
+        }
+        let rec = &mut self.heads[input];
+        self.readers[input].try_fill_record(rec_num, rec)?;
+        self.next[input] += 1;
+        self.header.rewrite_gbam_record(input, rec);
+        let key = Region::key(rec.refid.unwrap(), i64::from(rec.pos.unwrap()));
+        if self.last_key.is_some_and(|last| key < last) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Input {} is not coordinate sorted.", input + 1),
+            ));
+        }
+        self.queue.push(Reverse((key, input)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::merge::header_text;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::Codecs;
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn write_gbam(path: &Path, sam: &str, sorted: bool) {
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let mut writer = Writer::new(File::create(path).unwrap(), vec![Codecs::Gzip], 2, Vec::new(), ref_seqs, sam_header, String::new(), sorted);
+        writer.set_mate_relative();
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_multi_reader() {
+        let dir = TempDir::new("multi_reader").unwrap();
+        let first = dir.path().join("first.gbam");
+        write_gbam(
+            &first,
+            "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100\n@SQ\tSN:chr2\tLN:100\n@RG\tID:a\tSM:x\n\
+a1\t0\tchr1\t5\t0\t*\t*\t0\t0\tA\t*\tRG:Z:a\n\
+a2\t0\tchr2\t1\t0\t*\tchr1\t3\t0\tA\t*\tRG:Z:a\n\
+a3\t4\t*\t0\t0\t*\t*\t0\t0\tA\t*\tRG:Z:a\n",
+            true,
+        );
+        let second = dir.path().join("second.gbam");
+        write_gbam(
+            &second,
+            "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr2\tLN:100\n@SQ\tSN:chr3\tLN:50\n@RG\tID:a\tSM:y\n\
+b2\t0\tchr2\t1\t0\t*\t=\t7\t0\tA\t*\tRG:Z:a\n\
+b1\t0\tchr3\t9\t0\t*\t*\t0\t0\tA\t*\tRG:Z:a\tNM:i:0\n",
+            true,
+        );
+        let open = |path: &Path| ReaderBuilder::new().open(File::open(path).unwrap()).unwrap();
+        let open_flags = |path: &Path| ReaderBuilder::new().fields(&[Fields::Flags]).open(File::open(path).unwrap()).unwrap();
+        let mut multi_reader = MultiReader::new(vec![open(&first), open(&second)]).unwrap();
+        let ref_seqs: Vec<_> = multi_reader.ref_seqs().iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(ref_seqs, ["chr1", "chr2", "chr3"]);
+        assert!(header_text(multi_reader.header()).contains("@RG\tID:a\tSM:x\n@RG\tID:a-1\tSM:y\n"));
+        let mut merged = Vec::new();
+        while let Some(rec) = multi_reader.next_rec() {
+            merged.push((rec.read_name.clone().unwrap(), rec.refid.unwrap(), rec.next_ref_id.unwrap(), rec.tags.clone().unwrap()));
+        }
+        let names: Vec<_> = merged.iter().map(|rec| String::from_utf8_lossy(&rec.0).into_owned()).collect();
+        assert_eq!(names, ["a1\0", "a2\0", "b2\0", "b1\0", "a3\0"]);
+        assert_eq!((merged[1].1, merged[1].2), (1, 0));
+        assert_eq!((merged[2].1, merged[2].2), (1, 1));
+        assert_eq!(merged[3].1, 2);
+        assert_eq!(merged[3].3, b"RGZa-1\0NMC\0");
+        assert_eq!(merged[4].3, b"RGZa\0");
+
+        // RNAME and POS are decoded for ordering even if not asked for.
+        let mut multi_reader = MultiReader::new(vec![open_flags(&first), open_flags(&second)]).unwrap();
+        let rec = multi_reader.next_rec().unwrap();
+        assert_eq!((rec.refid, rec.pos, rec.flag, rec.read_name.as_ref()), (Some(0), Some(4), Some(0), None));
+
+        let unsorted = dir.path().join("unsorted.gbam");
+        write_gbam(&unsorted, "@SQ\tSN:chr1\tLN:100\nr1\t0\tchr1\t5\t0\t*\t*\t0\t0\tA\t*\n", false);
+        assert!(MultiReader::new(vec![open(&first), open(&unsorted)]).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Result;
+use std::sync::Arc;
+
+use bam_tools::record::fields::Fields;
+use rayon::ThreadPool;
+
+use super::storage::Storage;
+use crate::decompressor::Decompressor;
+use crate::meta::FileMeta;
+
+/// Decompresses blocks following the one being read on a thread pool, so
+/// sequential scan rarely waits for decompression. Each column has its own
+/// prefetcher, blocks are handed over in order of block numbers. Buffers of
+/// consumed blocks are reused by the next ones.
+pub(crate) struct Prefetcher {
+    blocks_ahead: usize,
+    decompressor: Decompressor,
+    /// Scheduled blocks in ascending order, with ordering keys of their
+    /// decompression tasks.
+    pending: VecDeque<(usize, u64)>,
+    /// Scheduled blocks decompressed before they were asked for.
+    ready: HashMap<u64, Result<Vec<u8>>>,
+    next_key: u64,
+}
+
+impl Prefetcher {
+    pub(crate) fn new(blocks_ahead: usize, pool: Arc<ThreadPool>) -> Self {
+        Self {
+            blocks_ahead,
+            decompressor: Decompressor::with_pool(pool),
+            pending: VecDeque::new(),
+            ready: HashMap::new(),
+            next_key: 0,
+        }
+    }
+
+    /// Decompressed block `block_num` if it was scheduled, waiting for it if
+    /// needed. Blocks scheduled before it are dropped, as are all of them if
+    /// reading jumped elsewhere.
+    pub(crate) fn take(&mut self, block_num: usize) -> Option<Result<Vec<u8>>> {
+        while let Some((num, key)) = self.pending.pop_front() {
+            if num == block_num {
+                return Some(self.wait(key));
+            }
+            self.drop_block(key);
+            if num > block_num {
+                while let Some((_, key)) = self.pending.pop_front() {
+                    self.drop_block(key);
+                }
+            }
+        }
+        None
+    }
+
+    /// Result of task `key`, which is not pending anymore. Tasks completed
+    /// meanwhile are kept if still pending.
+    fn wait(&mut self, key: u64) -> Result<Vec<u8>> {
+        if let Some(block) = self.ready.remove(&key) {
+            return block;
+        }
+        loop {
+            let task = self.decompressor.get_decompr_block();
+            if task.ordering_key == key {
+                return task.buf;
+            }
+            if self.pending.iter().any(|&(_, pending)| pending == task.ordering_key) {
+                self.ready.insert(task.ordering_key, task.buf);
+            } else if let Ok(buf) = task.buf {
+                self.recycle(buf);
+            }
+        }
+    }
+
+    /// Drops block of task `key`, unless it is still decompressed. Then it is
+    /// dropped once it completes, see `wait`.
+    fn drop_block(&mut self, key: u64) {
+        if let Some(Ok(buf)) = self.ready.remove(&key) {
+            self.recycle(buf);
+        }
+    }
+
+    /// Schedules blocks up to `blocks_ahead` after `block_num` which are not
+    /// scheduled yet.
+    pub(crate) fn schedule(&mut self, block_num: usize, storage: &Arc<dyn Storage>, meta: &Arc<FileMeta>, field: Fields) {
+        let blocks_num = meta.view_blocks(&field).len();
+        let next = self.pending.back().map_or(block_num + 1, |&(num, _)| num + 1);
+        for num in next..(block_num + 1 + self.blocks_ahead).min(blocks_num) {
+            let key = self.next_key;
+            self.next_key += 1;
+            self.decompressor.decompress_block(key, field, num, storage.clone(), meta.clone());
+            self.pending.push_back((num, key));
+        }
+    }
+
+    /// Returns buffer of consumed block for reuse.
+    pub(crate) fn recycle(&self, buf: Vec<u8>) {
+        self.decompressor.recycle(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::{reader::Reader, record::GbamRecord};
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use bam_tools::record::fields::Fields;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_prefetch() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:10000\n");
+        for i in 0..500 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\tNM:i:{}\n", i, i + 1, i % 3));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("prefetch").unwrap();
+        let path = dir.path().join("prefetch.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Zstd], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(7) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let fields = [Fields::ReadName, Fields::Pos, Fields::RawSequence, Fields::RawTags];
+        let mut reader = Reader::with_fields(File::open(&path).unwrap(), &fields).unwrap();
+        let expected: Vec<_> = reader.records().map(|rec| format!("{:?}", rec.unwrap())).collect();
+
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap());
+        let mut reader = Reader::with_fields(File::open(&path).unwrap(), &fields).unwrap();
+        reader.set_prefetch(4, pool);
+        let prefetched: Vec<_> = reader.records().map(|rec| format!("{:?}", rec.unwrap())).collect();
+        assert_eq!(prefetched, expected);
+
+        // Jumping back and forth drops blocks scheduled in vain.
+        let mut rec = GbamRecord::default();
+        for &rec_num in &[400, 3, 250, 251, 499, 0] {
+            reader.fill_record(rec_num, &mut rec);
+            assert_eq!(format!("{:?}", rec), expected[rec_num]);
+        }
+    }
+}
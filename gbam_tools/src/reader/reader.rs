@@ -1,22 +1,27 @@
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::sync::Arc;
-use std::{borrow::Borrow, fs::File};
 
 use bam_tools::record::fields::{
     field_type, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
 };
 use byteorder::LittleEndian;
-use memmap2::MmapOptions;
-use memmap2::Mmap;
+use memmap2::{Mmap, MmapOptions};
 
+use crate::mate_fields;
 use crate::meta::{FileInfo, FileMeta, FILE_INFO_SIZE, BlockMeta};
 use crate::writer::calc_crc_for_meta_bytes;
 
 use super::{
-    column::{Column, FixedColumn, Inner, VariableColumn},
+    cache::BlockCache,
+    column::{Column, FixedColumn, Inner, OmittedColumn, VariableColumn},
+    gbai::GbamIndex,
+    mate::NameIndex,
     parse_tmplt::ParsingTemplate,
     record::GbamRecord,
     records::Records,
+    region::{candidate_records, Region, RegionRecords, LOCATOR_FIELDS},
+    storage::Storage,
 };
 
 use std::convert::TryFrom;
@@ -28,32 +33,54 @@ pub struct Reader {
     original_template: ParsingTemplate,
     pub amount: usize,
     pub file_meta: Arc<FileMeta>,
-    // Kept so File won't drop while used by mmap.
-    _inner: Box<File>,
-    index_mapping: Option<Arc<Vec<u32>>>,
-    pub mmap: Arc<Mmap>
+    pub(crate) index_mapping: Option<Arc<Vec<u32>>>,
+    /// Sidecar index used by `fetch` instead of one in file meta.
+    gbai: Option<Arc<GbamIndex>>,
+    /// Used by `fetch_mate` when mate can't be found by position.
+    pub(crate) name_index: Option<NameIndex>,
+    /// Shared with readers `sibling` makes, see `set_block_cache`.
+    pub(crate) block_cache: Option<Arc<BlockCache>>,
+    pub storage: Arc<dyn Storage>,
+    /// From `FileInfo`, parsed once since storage may be remote.
+    sorted: bool,
 }
 
 impl Reader {
+    /// Reader of memory mapped file, see `ReaderBuilder` for other options.
     pub fn new(inner: File, parsing_template: ParsingTemplate) -> std::io::Result<Self> {
-        let inner = inner;
-        let mmap = unsafe { Mmap::map(inner.borrow())? };
-        let file_meta = verify_and_parse_meta(&mmap)?;
-        Self::new_with_meta(inner, parsing_template, &Arc::new(file_meta), None)
+        Self::new_with_index(inner, parsing_template, None)
+    }
+
+    /// Reader decoding only `fields`, e.g. FLAG and POS for flagstat. Blocks
+    /// of other columns are neither fetched nor decompressed, so they stay
+    /// `None` in filled records. Index columns of variable sized fields, and
+    /// RNAME and POS of mate relative files, are added as needed.
+    pub fn with_fields(inner: File, fields: &[Fields]) -> std::io::Result<Self> {
+        Self::new(inner, ParsingTemplate::new_with(fields))
     }
 
     pub fn new_with_index(inner: File, parsing_template: ParsingTemplate, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
-        let inner = inner;
-        let mmap = unsafe { Mmap::map(inner.borrow())? };
+        let mmap = unsafe { MmapOptions::new().map(&inner)? };
         let file_meta = verify_and_parse_meta(&mmap)?;
-        Self::new_with_meta(inner, parsing_template, &Arc::new(file_meta), index_mapping)
+        Self::from_storage(Arc::new(mmap), parsing_template, &Arc::new(file_meta), index_mapping)
     }
 
     pub fn new_with_meta(_inner: File, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
-        let _copy = _inner.try_clone()?;
-        let _inner: Box<File> = Box::new(_inner);
-        
-        let mmap = Arc::new(unsafe { MmapOptions::new().map(&_copy)? });
+        let mmap = unsafe { MmapOptions::new().map(&_inner)? };
+        Self::from_storage(Arc::new(mmap), parsing_template, file_meta, index_mapping)
+    }
+
+    /// Reader of file already opened as `storage`, whose meta was parsed
+    /// before. Readers of one file may share both.
+    pub fn from_storage(storage: Arc<dyn Storage>, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
+        let sorted = parse_file_info(&*storage)?.is_sorted;
+        Ok(Self::from_parts(storage, parsing_template, file_meta, index_mapping, sorted))
+    }
+
+    fn from_parts(storage: Arc<dyn Storage>, mut parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>, sorted: bool) -> Self {
+        if file_meta.is_mate_relative() {
+            mate_fields::add_dependencies(&mut parsing_template);
+        }
         // mmap.advise(memmap2::Advice::WillNeed)?;
         // Consumes up to 16 percent of runtime on big files (20GB).
         // verify(&mmap)?;
@@ -64,20 +91,42 @@ impl Reader {
         let meta = file_meta.clone();
 
         
-        Ok(Self {
-            columns: init_columns(&mmap, &parsing_template, &meta),
+        Self {
+            columns: init_columns(&storage, &parsing_template, &meta),
             original_template: parsing_template.clone(),
             parsing_template,
             file_meta: meta,
             amount,
-            _inner,
-            mmap,
+            storage,
             index_mapping: index_mapping.clone(),
-        })
+            gbai: None,
+            name_index: None,
+            block_cache: None,
+            sorted,
+        }
+    }
+
+    /// Reader of the same file decoding fields of `template`, in the same
+    /// order of records and sharing block cache.
+    pub(crate) fn sibling(&self, template: ParsingTemplate) -> std::io::Result<Reader> {
+        let mut reader = Self::from_parts(self.storage.clone(), template, &self.file_meta, self.index_mapping.clone(), self.sorted);
+        if let Some(cache) = &self.block_cache {
+            reader.set_block_cache(cache.clone());
+        }
+        Ok(reader)
+    }
+
+    /// Fills `rec` with fields of record `rec_num`. Panics if block holding
+    /// it can't be fetched, see `try_fill_record`.
+    #[inline(always)]
+    pub fn fill_record(&mut self, rec_num: usize, rec: &mut GbamRecord) {
+        self.try_fill_record(rec_num, rec).unwrap_or_else(|err| panic!("{}", err));
     }
 
+    /// Same as `fill_record`, but corrupted or undecodable blocks are
+    /// returned as error.
     #[inline(always)]
-    pub fn fill_record(&mut self, mut rec_num: usize, rec: &mut GbamRecord) {
+    pub fn try_fill_record(&mut self, mut rec_num: usize, rec: &mut GbamRecord) -> std::io::Result<()> {
         if let Some(index_map) = &self.index_mapping {
             rec_num = index_map[rec_num] as usize;
         }
@@ -86,8 +135,12 @@ impl Reader {
             self.columns[field as usize]
                 .as_mut()
                 .unwrap()
-                .fill_record_field(rec_num, rec);
+                .fill_record_field(rec_num, rec)?;
         }
+        if self.file_meta.is_mate_relative() {
+            mate_fields::decode(rec);
+        }
+        Ok(())
     }
 
     pub fn get_column(&mut self, field: &Fields) -> &mut Box<dyn Column + Send> {
@@ -102,6 +155,9 @@ impl Reader {
         for field in fields {
             self.parsing_template.set(field, true);
         }
+        if self.file_meta.is_mate_relative() {
+            mate_fields::add_dependencies(&mut self.parsing_template);
+        }
     }
 
     // Restores original template if some fields fetching was paused.
@@ -109,62 +165,161 @@ impl Reader {
         self.parsing_template = self.original_template.clone();
     }
 
+    /// Decompresses up to `blocks_ahead` blocks of every fetched column ahead
+    /// of the one being read, on `pool`. Speeds up sequential scans, random
+    /// access works as before.
+    pub fn set_prefetch(&mut self, blocks_ahead: usize, pool: Arc<rayon::ThreadPool>) {
+        for column in self.columns.iter_mut().flatten() {
+            column.set_prefetch(blocks_ahead, &pool);
+        }
+    }
+
+    /// Keeps decompressed blocks in `cache` and takes them from there
+    /// instead of decompressing again, see `BlockCache`. Readers made for
+    /// `fetch`, `filtered`, `fetch_mate` and `par_scan` share it.
+    pub fn set_block_cache(&mut self, cache: Arc<BlockCache>) {
+        for column in self.columns.iter_mut().flatten() {
+            column.set_block_cache(&cache);
+        }
+        self.block_cache = Some(cache);
+    }
+
     /// Get iterator over all GBAM records (according to parsing template).
+    /// Yields owned records, use `Records::next_rec` or `Records::read_into`
+    /// to reuse one buffer instead.
     pub fn records(&mut self) -> Records {
         Records::new(self)
     }
+
+    /// Get iterator over records starting at `rec_num` (in order of index
+    /// if reader has one). Columns jump straight to blocks holding it by
+    /// their record counts, records before it aren't decoded. Fails if
+    /// `rec_num` is past the last record.
+    pub fn seek_record(&mut self, rec_num: usize) -> std::io::Result<Records<'_>> {
+        let amount = self.amount;
+        self.record_range(rec_num..amount)
+    }
+
+    /// Get iterator over records of `range`, e.g. one shard of file split
+    /// among workers by record numbers. See `seek_record`.
+    pub fn record_range(&mut self, range: std::ops::Range<usize>) -> std::io::Result<Records<'_>> {
+        if range.start > range.end || range.end > self.amount {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Records {}..{} are out of file with {} records.", range.start, range.end, self.amount),
+            ));
+        }
+        Ok(Records::with_range(self, range))
+    }
+
+    /// Get iterator over records overlapping `region` (e.g. `chr1:100-200`,
+    /// see `Region::parse`), same as `samtools view` returns. File must be
+    /// coordinate sorted.
+    pub fn fetch(&mut self, region: &str) -> std::io::Result<RegionRecords<'_>> {
+        let (region, mut locator) = self.locate(region)?;
+        let range = candidate_records(&mut locator, &region, self.index_range(&region));
+        Ok(RegionRecords::new(self, locator, region, range))
+    }
+
+    /// Parses `region` of coordinate sorted file. Returns it with reader of
+    /// fields telling whether record overlaps it (see `LOCATOR_FIELDS`).
+    pub(crate) fn locate(&self, region: &str) -> std::io::Result<(Region, Reader)> {
+        let region = Region::parse(region, self.file_meta.get_ref_seqs())?;
+        if !self.is_sorted() || self.index_mapping.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Regions can be fetched only from coordinate sorted file.",
+            ));
+        }
+        let locator = self.sibling(ParsingTemplate::new_with(&LOCATOR_FIELDS))?;
+        Ok((region, locator))
+    }
+
+    /// Records which may overlap `region` according to index, if file has
+    /// one.
+    pub(crate) fn index_range(&self, region: &Region) -> Option<std::ops::Range<usize>> {
+        self.gbai
+            .as_deref()
+            .or_else(|| self.file_meta.get_index())
+            .map(|index| index.overlapping(region))
+    }
+
+    /// Whether file is coordinate sorted.
+    pub fn is_sorted(&self) -> bool {
+        self.sorted
+    }
+
+    /// Mapped file, `None` unless reader was opened with `Access::Mmap`.
+    /// Replaces former public `mmap` field, since storage may be local file
+    /// or remote now, see `storage` and "Library API changes" in README.
+    pub fn mmap(&self) -> Option<&Mmap> {
+        self.storage.as_mmap()
+    }
+
+    /// Uses index (e.g. sidecar `.gbai`, see `GbamIndex::read`) for `fetch`.
+    /// Fails if index was built for another file.
+    pub fn set_index(&mut self, index: GbamIndex) -> std::io::Result<()> {
+        if index.num_records != self.amount as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Index doesn't match the file.",
+            ));
+        }
+        self.gbai = Some(Arc::new(index));
+        Ok(())
+    }
 }
 
 fn init_columns(
-    mmap: &Arc<Mmap>,
+    storage: &Arc<dyn Storage>,
     parse_template: &ParsingTemplate,
     meta: &Arc<FileMeta>,
 ) -> Vec<Option<Box<dyn Column + Send>>> {
     let mut res = Vec::new();
     (0..FIELDS_NUM).for_each(|_| res.push(None));
     for &field in parse_template.get_active_fields_iter() {
-        res[field as usize] = Some(init_col(field, mmap, meta));
+        res[field as usize] = Some(init_col(field, storage, meta));
     }
     res
 }
 
-fn init_col(field: Fields, mmap: &Arc<Mmap>, meta: &Arc<FileMeta>) -> Box<dyn Column + Send> {
-    let inner = Inner::new(meta.clone(), field, mmap.clone());
+fn init_col(field: Fields, storage: &Arc<dyn Storage>, meta: &Arc<FileMeta>) -> Box<dyn Column + Send> {
+    let inner = Inner::new(meta.clone(), field, storage.clone());
     match field_type(&field) {
         FieldType::FixedSized => Box::new(FixedColumn::new(inner, meta.get_field_size(&field).unwrap() as usize)),
         FieldType::VariableSized => {
             let idx_field = var_size_field_to_index(&field);
-            let idx_inner = Inner::new(meta.clone(), idx_field, mmap.clone());
+            let idx_inner = Inner::new(meta.clone(), idx_field, storage.clone());
             let idx_col = FixedColumn::new(idx_inner, meta.get_field_size(&idx_field).unwrap() as usize);
+            if meta.is_omitted(&field) {
+                return Box::new(OmittedColumn::new(field, idx_col));
+            }
             Box::new(VariableColumn::new(inner, idx_col))
         }
     }
 }
 
-fn parse_file_info(mmap: &Mmap) -> FileInfo {
-    let file_info_bytes = &mmap[0..FILE_INFO_SIZE];
-    let end_of_json = file_info_bytes.iter().position(|&r| r == 0).unwrap();
-    let file_info_str = String::from_utf8(file_info_bytes[..end_of_json].to_owned()).unwrap();
-    serde_json::from_str(&file_info_str).expect("File meta json string was damaged.")
+pub(crate) fn parse_file_info(storage: &dyn Storage) -> std::io::Result<FileInfo> {
+    let mut buf = Vec::new();
+    let file_info_bytes = storage.read(0..FILE_INFO_SIZE as u64, &mut buf)?;
+    let damaged = |err: String| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("File info is damaged: {}", err));
+    let end_of_json = file_info_bytes
+        .iter()
+        .position(|&r| r == 0)
+        .ok_or_else(|| damaged("JSON is not terminated".to_owned()))?;
+    let file_info_str = std::str::from_utf8(&file_info_bytes[..end_of_json]).map_err(|err| damaged(err.to_string()))?;
+    serde_json::from_str(file_info_str).map_err(|err| damaged(err.to_string()))
 }
 
 #[allow(dead_code)]
-fn verify(mmap: &Mmap) -> std::io::Result<()>{
-    let file_info = parse_file_info(mmap);
-    // Read file meta
-    let buf = &mmap[file_info.seekpos as usize..];
-    if calc_crc_for_meta_bytes(buf) != file_info.crc32 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Metadata JSON was damaged.",
-        ));
-    }
-    Ok(())
+fn verify(storage: &dyn Storage) -> std::io::Result<()>{
+    verify_and_parse_meta(storage).map(|_| ())
 }
-fn verify_and_parse_meta(mmap: &Mmap) -> std::io::Result<FileMeta> {
-    let file_info = parse_file_info(mmap);
+pub(crate) fn verify_and_parse_meta(storage: &dyn Storage) -> std::io::Result<FileMeta> {
+    let file_info = parse_file_info(storage)?;
     // Read file meta
-    let buf = &mmap[file_info.seekpos as usize..];
+    let mut meta_buf = Vec::new();
+    let buf = storage.read(file_info.seekpos..storage.size(), &mut meta_buf)?;
     if calc_crc_for_meta_bytes(buf) != file_info.crc32 {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
@@ -172,7 +327,8 @@ fn verify_and_parse_meta(mmap: &Mmap) -> std::io::Result<FileMeta> {
         ));
     }
     let file_meta_json_str = String::from_utf8(buf.to_owned()).unwrap();
-    Ok(serde_json::from_str(&file_meta_json_str).expect("File meta json string was damaged."))
+    serde_json::from_str(&file_meta_json_str)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("File meta is damaged: {}", err)))
 }
 
 // The tree map will be used to quickly determine which block record belong to.
@@ -16,7 +16,7 @@ use std::mem;
 use crate::{query::cigar::Cigar, query::cigar::Op, U32_SIZE};
 
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 /// Represents a GBAM record in which some fields may be omitted.
 pub struct GbamRecord {
     /// Reference sequence ID
@@ -57,6 +57,17 @@ pub fn parse_cigar(bytes: &[u8], prealloc: &mut Cigar) {
 // TODO :: ADD TEMPLATE LENGTHS TO GBAM RECORD
 // TODO :: REMOVE CG TAG FROM ORIGINAL FILE
 impl GbamRecord {
+    /// Read name without NUL terminator, `*` if names were elided at write
+    /// time (see `Writer::omit_field`). Names come out the same whether
+    /// their blocks were tokenized or not.
+    pub fn name(&self) -> Option<&[u8]> {
+        let name = self.read_name.as_deref()?;
+        match name.strip_suffix(b"\0").unwrap_or(name) {
+            b"" => Some(b"*"),
+            name => Some(name),
+        }
+    }
+
     pub(crate) fn parse_from_bytes(&mut self, field: &Fields, mut bytes: &[u8]) {
         match field {
             Fields::RefID => self.refid = Some(bytes.read_i32::<LittleEndian>().unwrap()),
@@ -1,6 +1,10 @@
+use std::io::Result;
+use std::ops::Range;
+
 use super::{reader::Reader, record::GbamRecord};
 
-/// Iterates over GBAM file.
+/// Iterates over GBAM file. As `Iterator` yields owned records, `next_rec`
+/// and `read_into` avoid allocating a record per item.
 pub struct Records<'a> {
     reader: &'a mut Reader,
     cur_rec: usize,
@@ -10,10 +14,16 @@ pub struct Records<'a> {
 
 impl<'a> Records<'a> {
     pub fn new(reader: &'a mut Reader) -> Self {
+        let amount = reader.amount;
+        Self::with_range(reader, 0..amount)
+    }
+
+    /// Iterates over records of `range` only, which must lie within file.
+    pub(crate) fn with_range(reader: &'a mut Reader, range: Range<usize>) -> Self {
         Self {
-            rec_amount: reader.amount,
             reader,
-            cur_rec: 0,
+            cur_rec: range.start,
+            rec_amount: range.end,
             buf: GbamRecord::default(),
         }
     }
@@ -26,4 +36,94 @@ impl<'a> Records<'a> {
         self.cur_rec += 1;
         Some(&self.buf)
     }
-}
\ No newline at end of file
+
+    /// Fills `rec` with the next record. Returns false once all records were
+    /// read. Record which failed to decode is skipped by the next call.
+    pub fn read_into(&mut self, rec: &mut GbamRecord) -> Result<bool> {
+        if self.cur_rec == self.rec_amount {
+            return Ok(false);
+        }
+        self.cur_rec += 1;
+        self.reader.try_fill_record(self.cur_rec - 1, rec)?;
+        Ok(true)
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<GbamRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut rec = GbamRecord::default();
+        match self.read_into(&mut rec) {
+            Ok(true) => Some(Ok(rec)),
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.rec_amount - self.cur_rec;
+        (left, Some(left))
+    }
+}
+
+impl ExactSizeIterator for Records<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::reader::reader::Reader;
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use bam_tools::record::fields::Fields;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_seek_record() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:10000\n");
+        for i in 0..250 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t{}\t{}M\t*\t0\t0\t*\t*\n", i, i * 3 + 1, i % 60, i % 9 + 1));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("seek_record").unwrap();
+        let path = dir.path().join("seek.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), false);
+        // Columns have blocks of different record counts.
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(32) });
+        writer.set_block_limits(Fields::Pos, BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(7) });
+        writer.set_block_limits(Fields::ReadName, BlockLimits { max_bytes: 50, max_records: None });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+        let all: Vec<_> = reader.records().map(|rec| format!("{:?}", rec.unwrap())).collect();
+        let from = |reader: &mut Reader, rec_num| {
+            reader.seek_record(rec_num).unwrap().map(|rec| format!("{:?}", rec.unwrap())).collect::<Vec<_>>()
+        };
+        for rec_num in [249, 100, 0, 33, 7, 250] {
+            assert_eq!(from(&mut reader, rec_num), all[rec_num..]);
+        }
+        let mut records = reader.seek_record(201).unwrap();
+        assert_eq!(records.len(), 49);
+        assert_eq!(format!("{:?}", records.next_rec().unwrap()), all[201]);
+
+        // Shards read in any order make up the file.
+        let mut sharded = Vec::new();
+        for start in (0..250).step_by(60).rev() {
+            let shard: Vec<_> = reader.record_range(start..(start + 60).min(250)).unwrap().map(|rec| format!("{:?}", rec.unwrap())).collect();
+            sharded.splice(0..0, shard);
+        }
+        assert_eq!(sharded, all);
+        assert!(reader.seek_record(251).is_err());
+        assert!(reader.record_range(10..251).is_err());
+        assert_eq!(reader.record_range(10..10).unwrap().count(), 0);
+    }
+}
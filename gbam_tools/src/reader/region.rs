@@ -0,0 +1,236 @@
+use std::cmp::Ordering;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+
+use bam_tools::record::fields::Fields;
+
+use super::{reader::Reader, record::GbamRecord};
+use crate::query::cigar::base_coverage;
+
+/// Genomic interval, 0-based and half-open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub ref_id: i32,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Region {
+    /// Parses region as `samtools view` does: `chr1` is the whole reference,
+    /// `chr1:100` and `chr1:100-` run from position 100 to its end and
+    /// `chr1:100-200` is 1-based and inclusive. Positions may contain commas.
+    /// Reference names containing `:` are matched as a whole first.
+    pub fn parse(region: &str, ref_seqs: &[(String, u32)]) -> Result<Self> {
+        let find = |name: &str| ref_seqs.iter().position(|(ref_name, _)| ref_name == name);
+        if let Some(ref_id) = find(region) {
+            return Ok(Region { ref_id: ref_id as i32, start: 0, end: ref_seqs[ref_id].1 });
+        }
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid region: {}", region));
+        let (name, range) = region.rsplit_once(':').ok_or_else(invalid)?;
+        let ref_id = find(name).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, format!("Region of unknown reference: {}", name))
+        })?;
+        let parse_pos = |pos: &str| pos.replace(',', "").parse::<u32>().map_err(|_| invalid());
+        let ref_len = ref_seqs[ref_id].1;
+        let (start, end) = match range.split_once('-') {
+            None => (parse_pos(range)?, ref_len),
+            Some((start, "")) => (parse_pos(start)?, ref_len),
+            Some((start, end)) => (parse_pos(start)?, parse_pos(end)?),
+        };
+        if start == 0 || end < start {
+            return Err(invalid());
+        }
+        Ok(Region { ref_id: ref_id as i32, start: start - 1, end })
+    }
+
+    /// Sort key of record start in coordinate sorted file. Unmapped records
+    /// (reference ID -1) go last.
+    pub(crate) fn key(ref_id: i32, pos: i64) -> (u32, i64) {
+        (ref_id as u32, pos)
+    }
+
+    /// Record with RNAME, POS and CIGAR overlaps region. Records not
+    /// consuming reference (e.g. unmapped placed next to their mate) take a
+    /// single base.
+    pub(crate) fn overlaps(&self, rec: &GbamRecord) -> bool {
+        let pos = match (rec.refid, rec.pos) {
+            (Some(ref_id), Some(pos)) if ref_id == self.ref_id && pos >= 0 => i64::from(pos),
+            _ => return false,
+        };
+        let span = rec.cigar.as_ref().map_or(0, |cigar| base_coverage(&cigar.0)).max(1);
+        pos < i64::from(self.end) && pos + i64::from(span) > i64::from(self.start)
+    }
+}
+
+/// Records of coordinate sorted file which may overlap `region`: `range` of
+/// index if there is one, otherwise found with binary search over RNAME and
+/// POS of `locator`, starting at most the longest record span of the
+/// reference before region (see `FileMeta::get_max_span`).
+pub(crate) fn candidate_records(locator: &mut Reader, region: &Region, range: Option<Range<usize>>) -> Range<usize> {
+    let Range { start, end } = range.unwrap_or_else(|| {
+        let mut buf = GbamRecord::default();
+        let max_span = locator
+            .file_meta
+            .get_max_span(region.ref_id as usize)
+            .unwrap_or(region.start);
+        let start_key = Region::key(region.ref_id, i64::from(region.start) - i64::from(max_span));
+        let start = lower_bound(locator, &mut buf, start_key);
+        start..lower_bound(locator, &mut buf, Region::key(region.ref_id, i64::from(region.end)))
+    });
+    start..end.max(start)
+}
+
+/// Iterates over records of coordinate sorted GBAM file overlapping region,
+/// in file order. Only blocks around region are fetched, see
+/// `candidate_records`.
+pub struct RegionRecords<'a> {
+    reader: &'a mut Reader,
+    /// Fetches RNAME, POS and CIGAR to decide whether record overlaps.
+    locator: Reader,
+    region: Region,
+    cur_rec: usize,
+    end_rec: usize,
+    buf: GbamRecord,
+    loc_buf: GbamRecord,
+}
+
+impl<'a> RegionRecords<'a> {
+    pub(crate) fn new(reader: &'a mut Reader, locator: Reader, region: Region, range: Range<usize>) -> Self {
+        Self {
+            reader,
+            locator,
+            region,
+            cur_rec: range.start,
+            end_rec: range.end,
+            buf: GbamRecord::default(),
+            loc_buf: GbamRecord::default(),
+        }
+    }
+
+    /// Panics if block can't be read, see `try_next_rec`.
+    pub fn next_rec(&mut self) -> Option<&GbamRecord> {
+        self.try_next_rec().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Next overlapping record, error if its block can't be read (e.g.
+    /// corrupted). Reading goes on after failed record.
+    pub fn try_next_rec(&mut self) -> Result<Option<&GbamRecord>> {
+        while self.cur_rec < self.end_rec {
+            let rec_num = self.cur_rec;
+            self.cur_rec += 1;
+            self.locator.try_fill_record(rec_num, &mut self.loc_buf)?;
+            if self.region.overlaps(&self.loc_buf) {
+                self.reader.try_fill_record(rec_num, &mut self.buf)?;
+                return Ok(Some(&self.buf));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// First record with RNAME and POS not less than `key`.
+pub(crate) fn lower_bound(locator: &mut Reader, buf: &mut GbamRecord, key: (u32, i64)) -> usize {
+    let (mut lo, mut hi) = (0, locator.amount);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        locator.fill_record(mid, buf);
+        let rec_key = Region::key(buf.refid.unwrap(), i64::from(buf.pos.unwrap()));
+        match rec_key.cmp(&key) {
+            Ordering::Less => lo = mid + 1,
+            _ => hi = mid,
+        }
+    }
+    lo
+}
+
+pub(crate) const LOCATOR_FIELDS: [Fields; 3] = [Fields::RefID, Fields::Pos, Fields::RawCigar];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::SIZE_LIMIT;
+    use crate::writer::Writer;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_region_parse() {
+        let ref_seqs = vec![("chr1".to_owned(), 1000), ("HLA-A*01:01".to_owned(), 300)];
+        let parse = |region| Region::parse(region, &ref_seqs);
+        assert_eq!(parse("chr1").unwrap(), Region { ref_id: 0, start: 0, end: 1000 });
+        assert_eq!(parse("chr1:1,001-2,000").unwrap(), Region { ref_id: 0, start: 1000, end: 2000 });
+        assert_eq!(parse("chr1:100").unwrap(), Region { ref_id: 0, start: 99, end: 1000 });
+        assert_eq!(parse("chr1:100-").unwrap(), Region { ref_id: 0, start: 99, end: 1000 });
+        assert_eq!(parse("HLA-A*01:01").unwrap(), Region { ref_id: 1, start: 0, end: 300 });
+        assert_eq!(parse("HLA-A*01:01:5-10").unwrap(), Region { ref_id: 1, start: 4, end: 10 });
+        assert!(parse("chr2:1-10").is_err());
+        assert!(parse("chr1:0-10").is_err());
+        assert!(parse("chr1:20-10").is_err());
+        assert!(parse("chr1:a-10").is_err());
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:chr2\tLN:10000\n");
+        // Long read starting far before the regions queried.
+        sam.push_str("long\t0\tchr1\t10\t60\t50M2000N50M\t*\t0\t0\t*\t*\n");
+        for i in 0..300 {
+            sam.push_str(&format!("a{}\t0\tchr1\t{}\t60\t20M\t*\t0\t0\t*\t*\n", i, 100 + i * 10));
+            if i == 190 {
+                sam.push_str("placed\t4\tchr1\t2000\t0\t*\t*\t0\t0\t*\t*\n");
+            }
+        }
+        for i in 0..100 {
+            sam.push_str(&format!("b{}\t0\tchr2\t{}\t60\t20M\t*\t0\t0\t*\t*\n", i, 1 + i * 5));
+        }
+        sam.push_str("unmapped\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n");
+
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("region").unwrap();
+        let path = dir.path().join("sorted.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(16) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = Reader::with_fields(File::open(&path).unwrap(), &[Fields::ReadName, Fields::RefID, Fields::Pos, Fields::RawCigar]).unwrap();
+        assert_eq!(reader.file_meta.get_max_span(0), Some(2100));
+        let mut all = Vec::new();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec() {
+            let span = rec.cigar.as_ref().map_or(0, |cigar| base_coverage(&cigar.0)).max(1);
+            all.push((rec.read_name.clone().unwrap(), rec.refid.unwrap(), rec.pos.unwrap(), span));
+        }
+
+        for region in ["chr1:2001-2100", "chr1:1,500-1,600", "chr1:2050", "chr2", "chr2:1-1", "chr1:9000-9999"] {
+            let parsed = Region::parse(region, reader.file_meta.get_ref_seqs()).unwrap();
+            let expected: Vec<_> = all
+                .iter()
+                .filter(|(_, ref_id, pos, span)| {
+                    *ref_id == parsed.ref_id && *pos >= 0 && (*pos as u32) < parsed.end && *pos as u32 + span > parsed.start
+                })
+                .map(|(name, ..)| name.clone())
+                .collect();
+            let mut fetched = Vec::new();
+            let mut records = reader.fetch(region).unwrap();
+            while let Some(rec) = records.next_rec() {
+                fetched.push(rec.read_name.clone().unwrap());
+            }
+            assert_eq!(fetched, expected, "{}", region);
+        }
+        let mut records = reader.fetch("chr1:2000-2000").unwrap();
+        let mut names = Vec::new();
+        while let Some(rec) = records.next_rec() {
+            names.push(String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned());
+        }
+        assert!(names.contains(&"long".to_owned()) && names.contains(&"placed".to_owned()));
+        assert!(reader.fetch("chr3:1-10").is_err());
+    }
+}
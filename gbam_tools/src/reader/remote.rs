@@ -0,0 +1,207 @@
+use std::env;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::ops::Range;
+
+use ureq::{Agent, Response};
+
+use super::storage::Storage;
+
+/// GBAM file behind `http://`, `https://` or `s3://` URL. Every read is a
+/// range request, so opening file fetches only its file info and meta, and
+/// reader fetches blocks of decoded columns as needed. Data block layout
+/// makes region queries over remote file cheap: only blocks of requested
+/// columns around region are transferred.
+///
+/// `s3://bucket/key` is read anonymously (public objects), from endpoint of
+/// `AWS_ENDPOINT_URL` (e.g. MinIO) if set, otherwise from AWS in region
+/// `AWS_REGION`. For private objects use presigned `https://` URL.
+pub struct HttpStorage {
+    agent: Agent,
+    url: String,
+    size: u64,
+}
+
+/// Location should be opened with `HttpStorage` rather than as local file.
+pub fn is_remote(location: &str) -> bool {
+    ["http://", "https://", "s3://"]
+        .iter()
+        .any(|scheme| location.starts_with(scheme))
+}
+
+/// HTTPS URL of `s3://bucket/key`.
+fn s3_to_https(bucket_and_key: &str) -> Result<String> {
+    let (bucket, key) = bucket_and_key
+        .split_once('/')
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid S3 URL: s3://{}", bucket_and_key)))?;
+    Ok(match (env::var("AWS_ENDPOINT_URL"), env::var("AWS_REGION")) {
+        (Ok(endpoint), _) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, key),
+        (_, Ok(region)) => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, key),
+        _ => format!("https://{}.s3.amazonaws.com/{}", bucket, key),
+    })
+}
+
+fn http_error(url: &str, err: ureq::Error) -> Error {
+    match err {
+        ureq::Error::Status(404, _) => Error::new(ErrorKind::NotFound, format!("{} not found.", url)),
+        ureq::Error::Status(403, _) => Error::new(ErrorKind::PermissionDenied, format!("Access to {} denied.", url)),
+        err => Error::other(format!("Request to {} failed: {}", url, err)),
+    }
+}
+
+impl HttpStorage {
+    pub fn new(location: &str) -> Result<Self> {
+        let url = match location.strip_prefix("s3://") {
+            Some(bucket_and_key) => s3_to_https(bucket_and_key)?,
+            None => location.to_owned(),
+        };
+        let agent = Agent::new();
+        // HEAD isn't used, presigned URLs are valid for GET only.
+        let response = Self::get_range(&agent, &url, 0..1)?;
+        let size = response
+            .header("Content-Range")
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, size)| size.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} has no size in Content-Range.", url)))?;
+        Ok(Self { agent, url, size })
+    }
+
+    /// Response of server which supports range requests.
+    fn get_range(agent: &Agent, url: &str, range: Range<u64>) -> Result<Response> {
+        let response = agent
+            .get(url)
+            .set("Range", &format!("bytes={}-{}", range.start, range.end - 1))
+            .call()
+            .map_err(|err| http_error(url, err))?;
+        if response.status() != 206 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Server of {} doesn't support range requests.", url),
+            ));
+        }
+        Ok(response)
+    }
+}
+
+impl Storage for HttpStorage {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read<'a>(&'a self, range: Range<u64>, buf: &'a mut Vec<u8>) -> Result<&'a [u8]> {
+        if range.end > self.size || range.start > range.end {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Bytes {}..{} are out of file of {} bytes.", range.start, range.end, self.size),
+            ));
+        }
+        buf.clear();
+        if range.is_empty() {
+            return Ok(buf);
+        }
+        let len = range.end - range.start;
+        let response = Self::get_range(&self.agent, &self.url, range)?;
+        response.into_reader().take(len).read_to_end(buf)?;
+        if buf.len() as u64 != len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!("Response of {} was cut short.", self.url)));
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use bam_tools::record::fields::Fields;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+
+    /// Serves `data` with range requests, recording requested ranges.
+    fn serve(data: Vec<u8>, requests: Arc<Mutex<Vec<(u64, u64)>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+                while let Some(Ok(line)) = lines.next() {
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(bytes) = line.strip_prefix("Range: bytes=") {
+                        let (start, end) = bytes.split_once('-').unwrap();
+                        range = Some((start.parse::<u64>().unwrap(), end.parse::<u64>().unwrap()));
+                    }
+                }
+                let (start, end) = range.unwrap();
+                requests.lock().unwrap().push((start, end));
+                let body = &data[start as usize..=end as usize];
+                write!(
+                    stream,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    start, end, data.len(), body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        format!("http://{}/file.gbam", addr)
+    }
+
+    #[test]
+    fn test_http_storage() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..2000 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\n", i, i * 10 + 1));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("remote").unwrap();
+        let path = dir.path().join("remote.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(100) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let url = serve(std::fs::read(&path).unwrap(), requests.clone());
+        assert!(is_remote(&url) && is_remote("s3://bucket/file.gbam") && !is_remote(path.to_str().unwrap()));
+
+        let local: Vec<_> = ReaderBuilder::new()
+            .open(File::open(&path).unwrap())
+            .unwrap()
+            .records()
+            .map(|rec| format!("{:?}", rec.unwrap()))
+            .collect();
+        let mut reader = ReaderBuilder::new().open_url(&url).unwrap();
+        let remote: Vec<_> = reader.records().map(|rec| format!("{:?}", rec.unwrap())).collect();
+        assert_eq!(remote, local);
+
+        // Region query transfers blocks around region only.
+        let mut reader = ReaderBuilder::new().fields(&[Fields::ReadName]).open_url(&url).unwrap();
+        requests.lock().unwrap().clear();
+        let mut records = reader.fetch("chr1:10001-10010").unwrap();
+        let mut names = Vec::new();
+        while let Some(rec) = records.next_rec() {
+            names.push(String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned());
+        }
+        assert_eq!(names, ["r1000"]);
+        let transferred: u64 = requests.lock().unwrap().iter().map(|(start, end)| end - start + 1).sum();
+        assert!(transferred * 4 < std::fs::metadata(&path).unwrap().len(), "{}", transferred);
+
+        assert_eq!(s3_to_https("bucket").unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::{Error, Result};
+use std::ops::Range;
+
+use bam_tools::record::fields::Fields;
+use rayon::prelude::*;
+
+use super::parse_tmplt::ParsingTemplate;
+use super::reader::Reader;
+use super::record::GbamRecord;
+use super::region::{candidate_records, Region, LOCATOR_FIELDS};
+
+/// Most records passed to callback of `Reader::par_scan` at once.
+const BATCH_RECORDS: usize = 4096;
+
+impl Reader {
+    /// Decodes `fields` of records overlapping any of `regions`, or of all
+    /// records if there are no regions, on rayon's thread pool (call it
+    /// within `ThreadPool::install`, moving reader in, to choose one) and passes them to `f` in
+    /// batches. Records are split into chunks at block boundaries of the
+    /// fetched column with the largest blocks, so threads seldom decompress
+    /// the same block. Batch holds up to 4096 records of one chunk in file
+    /// order, chunks come in no particular order. With regions, RNAME, POS
+    /// and CIGAR are decoded too, and record overlapping several regions is
+    /// passed once for each. Invalid region fails before `f` is called, the
+    /// first error of `f` or of decoding stops the scan.
+    pub fn par_scan<F>(&self, regions: &[String], fields: &[Fields], f: F) -> Result<()>
+    where
+        F: Fn(&[GbamRecord]) -> Result<()> + Sync,
+    {
+        let mut template = ParsingTemplate::new_with(fields);
+        let mut ranges = Vec::new();
+        if regions.is_empty() {
+            ranges.push((0..self.amount, None));
+        }
+        for region in regions {
+            let (region, mut locator) = self.locate(region)?;
+            ranges.push((candidate_records(&mut locator, &region, self.index_range(&region)), Some(region)));
+            LOCATOR_FIELDS.iter().for_each(|field| template.set(field, true));
+        }
+        let starts = self.chunk_starts(&template);
+        let chunks: Vec<(Range<usize>, Option<Region>)> = ranges
+            .into_iter()
+            .flat_map(|(range, region)| split(range, &starts).into_iter().map(move |chunk| (chunk, region)))
+            .collect();
+
+        // Reader itself is not Sync, threads build their own from its parts.
+        let (storage, file_meta, index_mapping, cache) = (&self.storage, &self.file_meta, &self.index_mapping, &self.block_cache);
+        let init = || {
+            let mut reader = Reader::from_storage(storage.clone(), template.clone(), file_meta, index_mapping.clone());
+            if let (Ok(reader), Some(cache)) = (reader.as_mut(), cache) {
+                reader.set_block_cache(cache.clone());
+            }
+            (reader, Vec::new())
+        };
+        chunks.into_par_iter().try_for_each_init(init, |(reader, records), (chunk, region)| {
+            let reader = reader.as_mut().map_err(|err| Error::new(err.kind(), err.to_string()))?;
+            let mut len = 0;
+            for rec_num in chunk {
+                if len == records.len() {
+                    records.push(GbamRecord::default());
+                }
+                reader.try_fill_record(rec_num, &mut records[len])?;
+                if region.is_none_or(|region| region.overlaps(&records[len])) {
+                    len += 1;
+                }
+                if len == BATCH_RECORDS {
+                    f(&records[..len])?;
+                    len = 0;
+                }
+            }
+            if len > 0 {
+                f(&records[..len])?;
+            }
+            Ok(())
+        })
+    }
+
+    /// First records of blocks of the column of `template` with the largest
+    /// blocks.
+    fn chunk_starts(&self, template: &ParsingTemplate) -> Vec<usize> {
+        template
+            .get_active_fields_iter()
+            .map(|field| self.file_meta.view_blocks(field))
+            .filter(|blocks| !blocks.is_empty())
+            .min_by_key(|blocks| blocks.len())
+            .map(|blocks| {
+                blocks
+                    .iter()
+                    .scan(0, |start, block| {
+                        let first = *start;
+                        *start += block.numitems as usize;
+                        Some(first)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Splits `range` of records at `starts` of chunks.
+fn split(range: Range<usize>, starts: &[usize]) -> Vec<Range<usize>> {
+    if range.is_empty() {
+        return Vec::new();
+    }
+    let inner = &starts[starts.partition_point(|&start| start <= range.start)..starts.partition_point(|&start| start < range.end)];
+    let bounds: Vec<_> = std::iter::once(range.start).chain(inner.iter().copied()).chain(std::iter::once(range.end)).collect();
+    bounds.windows(2).filter(|bounds| bounds[0] < bounds[1]).map(|bounds| bounds[0]..bounds[1]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::meta::{BlockLimits, Codecs};
+    use crate::reader::builder::ReaderBuilder;
+    use crate::writer::Writer;
+    use crate::SIZE_LIMIT;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::sync::Mutex;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_par_scan() {
+        assert_eq!(split(5..30, &[0, 10, 20, 30]), [5..10, 10..20, 20..30]);
+        assert_eq!(split(10..10, &[0, 10]), Vec::<Range<usize>>::new());
+
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n@SQ\tSN:chr2\tLN:100000\n");
+        for i in 0..3000 {
+            let ref_name = if i < 2000 { "chr1" } else { "chr2" };
+            sam.push_str(&format!("r{}\t{}\t{}\t{}\t{}\t20M\t*\t0\t0\t*\t*\n", i, (i % 3) * 16, ref_name, i % 2000 * 10 + 1, i % 61));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("par_scan").unwrap();
+        let path = dir.path().join("sorted.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        writer.set_all_block_limits(BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(100) });
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = ReaderBuilder::new().open(File::open(&path).unwrap()).unwrap();
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let seen = Mutex::new(Vec::new());
+        let mut reader = pool.install(|| {
+            reader
+                .par_scan(&[], &[Fields::Flags, Fields::Mapq], |batch| {
+                    assert!(batch.len() <= 100 && batch.iter().all(|rec| rec.pos.is_none()));
+                    seen.lock().unwrap().extend(batch.iter().map(|rec| (rec.flag.unwrap(), rec.mapq.unwrap())));
+                    Ok(())
+                })
+                .unwrap();
+            reader
+        });
+        let mut expected: Vec<_> = (0..3000).map(|i| ((i % 3) as u16 * 16, (i % 61) as u8)).collect();
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(seen, expected);
+
+        let regions = ["chr1:105-300".to_owned(), "chr2:9000-9500".to_owned()];
+        let mut expected = Vec::new();
+        for region in &regions {
+            let mut records = reader.fetch(region).unwrap();
+            while let Some(rec) = records.next_rec() {
+                expected.push(rec.read_name.clone().unwrap());
+            }
+        }
+        let seen = Mutex::new(Vec::new());
+        reader
+            .par_scan(&regions, &[Fields::ReadName], |batch| {
+                seen.lock().unwrap().extend(batch.iter().map(|rec| rec.read_name.clone().unwrap()));
+                Ok(())
+            })
+            .unwrap();
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(seen.len(), 73);
+        assert_eq!(seen, expected);
+
+        let err = reader.par_scan(&[], &[Fields::Flags], |_| Err(Error::other("stop")));
+        assert_eq!(err.unwrap_err().to_string(), "stop");
+        assert!(reader.par_scan(&["chr3".to_owned()], &[], |_| Ok(())).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use memmap2::Mmap;
+
+/// Bytes of GBAM file the reader fetches blocks and meta from.
+pub trait Storage: Send + Sync {
+    /// Size of the file.
+    fn size(&self) -> u64;
+
+    /// Bytes of `range`. Storage holding them in memory (e.g. mmap) returns
+    /// them as they are, others read them into `buf`.
+    fn read<'a>(&'a self, range: Range<u64>, buf: &'a mut Vec<u8>) -> Result<&'a [u8]>;
+
+    /// Mapped file if storage is memory mapped.
+    fn as_mmap(&self) -> Option<&Mmap> {
+        None
+    }
+}
+
+fn out_of_bounds(range: &Range<u64>, size: u64) -> Error {
+    Error::new(
+        ErrorKind::UnexpectedEof,
+        format!("Bytes {}..{} are out of file of {} bytes.", range.start, range.end, size),
+    )
+}
+
+/// Blocks are decompressed right from mapped memory, page cache decides
+/// which parts of file stay in memory.
+impl Storage for Mmap {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read<'a>(&'a self, range: Range<u64>, _buf: &'a mut Vec<u8>) -> Result<&'a [u8]> {
+        if range.end > self.size() || range.start > range.end {
+            return Err(out_of_bounds(&range, self.size()));
+        }
+        Ok(&self[range.start as usize..range.end as usize])
+    }
+
+    fn as_mmap(&self) -> Option<&Mmap> {
+        Some(self)
+    }
+}
+
+/// File read with plain reads, for filesystems where memory mapping is slow
+/// or unsafe (e.g. network filesystems, files which may be truncated).
+pub struct FileStorage {
+    file: Mutex<File>,
+    size: u64,
+}
+
+impl FileStorage {
+    pub fn new(file: File) -> Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(Self { file: Mutex::new(file), size })
+    }
+}
+
+impl Storage for FileStorage {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read<'a>(&'a self, range: Range<u64>, buf: &'a mut Vec<u8>) -> Result<&'a [u8]> {
+        if range.end > self.size || range.start > range.end {
+            return Err(out_of_bounds(&range, self.size));
+        }
+        buf.resize((range.end - range.start) as usize, 0);
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(range.start))?;
+        file.read_exact(buf)?;
+        Ok(buf)
+    }
+}
+
+/// How reader accesses local GBAM file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Access {
+    /// Memory mapped, see `Storage for Mmap`.
+    #[default]
+    Mmap,
+    /// Plain reads, see `FileStorage`.
+    Read,
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mmap" => Ok(Access::Mmap),
+            "read" => Ok(Access::Read),
+            _ => Err(format!("Unknown access mode {}, expected mmap or read.", s)),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::utils::bed::{parse_bed_from_file, parse_region_query};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::path::Path;
+
+/// Selects records kept by the writer, as `samtools view -f -F -q` with
+/// regions would. See `Writer::set_filter`.
+#[derive(Clone, Debug, Default)]
+pub struct RecordFilter {
+    /// Records have to have all of these flags set.
+    pub required_flags: u16,
+    /// Records must not have any of these flags set.
+    pub excluded_flags: u16,
+    /// Records with lower MAPQ are dropped.
+    pub min_mapq: u8,
+    /// Reference name and 0-based half-open interval of every region.
+    regions: Vec<(String, u32, u32)>,
+    /// Sorted intervals of `regions` by reference ID, filled by `resolve`.
+    intervals: HashMap<i32, Vec<(u32, u32)>>,
+}
+
+impl RecordFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps only records overlapping one of the regions added. Region is
+    /// given as `chr1:100-200`, 1-based and inclusive.
+    pub fn add_region(&mut self, region: &str) -> io::Result<()> {
+        let (name, start, end) = parse_region_query(region)?;
+        if start == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Region starts at 1: {}", region)));
+        }
+        self.regions.push((name.to_owned(), start - 1, end));
+        Ok(())
+    }
+
+    /// Adds all regions of BED file, see `add_region`.
+    pub fn add_bed_regions(&mut self, path: &Path) -> io::Result<()> {
+        for (name, intervals) in parse_bed_from_file(path)? {
+            for (start, end) in intervals {
+                self.regions.push((name.clone(), start, end));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_regions(&self) -> bool {
+        !self.regions.is_empty()
+    }
+
+    /// Maps region names to reference IDs. Unknown reference is an error.
+    pub(crate) fn resolve(&mut self, ref_seqs: &[(String, u32)]) -> io::Result<()> {
+        let ids: HashMap<&str, i32> = ref_seqs
+            .iter()
+            .enumerate()
+            .map(|(id, (name, _))| (name.as_str(), id as i32))
+            .collect();
+        self.intervals.clear();
+        for (name, start, end) in &self.regions {
+            let id = ids.get(name.as_str()).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, format!("Region of unknown reference: {}", name))
+            })?;
+            self.intervals.entry(*id).or_default().push((*start, *end));
+        }
+        for intervals in self.intervals.values_mut() {
+            intervals.sort_unstable();
+        }
+        Ok(())
+    }
+
+    pub fn accepts(&self, rec: &BAMRawRecord) -> bool {
+        let flags = LittleEndian::read_u16(rec.get_bytes(&Fields::Flags));
+        if flags & self.required_flags != self.required_flags
+            || flags & self.excluded_flags != 0
+            || rec.get_bytes(&Fields::Mapq)[0] < self.min_mapq
+        {
+            return false;
+        }
+        if !self.has_regions() {
+            return true;
+        }
+        let refid = LittleEndian::read_i32(rec.get_bytes(&Fields::RefID));
+        let pos = LittleEndian::read_i32(rec.get_bytes(&Fields::Pos));
+        let intervals = match self.intervals.get(&refid) {
+            Some(intervals) if pos >= 0 => intervals,
+            _ => return false,
+        };
+        let start = pos as u32;
+        // Unmapped records placed at a position occupy one base.
+        let end = start + reference_len(rec).max(1);
+        intervals
+            .iter()
+            .take_while(|(region_start, _)| *region_start < end)
+            .any(|(_, region_end)| *region_end > start)
+    }
+}
+
+/// Count of reference bases CIGAR of record covers.
+pub(crate) fn reference_len(rec: &BAMRawRecord) -> u32 {
+    rec.get_bytes(&Fields::RawCigar)
+        .chunks_exact(4)
+        .map(LittleEndian::read_u32)
+        // M, D, N, = and X consume reference.
+        .filter(|op| matches!(op & 0xF, 0 | 2 | 3 | 7 | 8))
+        .map(|op| op >> 4)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_record_filter() {
+        let sam = "@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n\
+                   a\t0\tchr1\t100\t60\t10M\t*\t0\t0\t*\t*\n\
+                   b\t16\tchr1\t95\t60\t5M\t*\t0\t0\t*\t*\n\
+                   c\t0\tchr1\t90\t10\t20M\t*\t0\t0\t*\t*\n\
+                   d\t1024\tchr2\t100\t60\t10M\t*\t0\t0\t*\t*\n\
+                   e\t4\tchr2\t500\t0\t*\t*\t0\t0\t*\t*\n\
+                   f\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+        let mut reader = SamReader::new(sam.as_bytes());
+        let (_, ref_seqs) = reader.read_header().unwrap();
+        let mut recs = Vec::new();
+        while let Some(rec) = reader.next_rec() {
+            recs.push(BAMRawRecord(Cow::Owned(rec.unwrap().to_vec())));
+        }
+        let kept = |filter: &RecordFilter| -> String {
+            recs.iter()
+                .filter(|rec| filter.accepts(rec))
+                .map(|rec| rec.get_bytes(&Fields::ReadName)[0] as char)
+                .collect()
+        };
+
+        let mut filter = RecordFilter::new();
+        assert_eq!(kept(&filter), "abcdef");
+        filter.excluded_flags = 0x4 | 0x400;
+        filter.min_mapq = 20;
+        assert_eq!(kept(&filter), "ab");
+        filter.required_flags = 0x10;
+        assert_eq!(kept(&filter), "b");
+
+        let mut filter = RecordFilter::new();
+        filter.add_region("chr1:100-100").unwrap();
+        filter.add_region("chr2:500-600").unwrap();
+        filter.resolve(&ref_seqs).unwrap();
+        // b ends right before 100, c covers it.
+        assert_eq!(kept(&filter), "ace");
+        assert!(filter.add_region("chr1:0-10").is_err());
+        filter.add_region("chr3:1-10").unwrap();
+        assert!(filter.resolve(&ref_seqs).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::bam::merge::header_text;
+use crate::bam::sam_reader::RefSeqs;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// M5 of every reference sequence of `ref_seqs`, taken from @SQ lines of
+/// header bytes laid out as in BAM file. Empty if no @SQ line has M5.
+pub(crate) fn sq_md5s(sam_header: &[u8], ref_seqs: &RefSeqs) -> Vec<Option<String>> {
+    if sam_header.len() < 4 {
+        return Vec::new();
+    }
+    let mut md5s = HashMap::new();
+    for line in header_text(sam_header).lines().filter(|line| line.starts_with("@SQ\t")) {
+        let value = |tag: &str| line.split('\t').find_map(|field| field.strip_prefix(tag));
+        if let (Some(name), Some(md5)) = (value("SN:"), value("M5:")) {
+            md5s.insert(name.to_owned(), md5.to_ascii_lowercase());
+        }
+    }
+    if md5s.is_empty() {
+        return Vec::new();
+    }
+    ref_seqs.iter().map(|(name, _)| md5s.remove(name)).collect()
+}
+
+/// Reads names and lengths of reference sequences from FASTA index. Either
+/// the index itself or FASTA file with `.fai` next to it can be given.
+pub fn read_fai(path: &Path) -> io::Result<RefSeqs> {
+    let fai_path = fai_path(path);
+    let mut ref_seqs = Vec::new();
+    for line in BufReader::new(File::open(&fai_path)?).lines() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next().map(str::parse::<u32>)) {
+            (Some(name), Some(Ok(len))) if !name.is_empty() => ref_seqs.push((name.to_owned(), len)),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed line of {}: {}", fai_path.display(), line),
+                ))
+            }
+        }
+    }
+    Ok(ref_seqs)
+}
+
+/// Path of FASTA index of `path`, which is either the index itself or FASTA.
+fn fai_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(ext) if ext == "fai" => path.to_path_buf(),
+        _ => PathBuf::from(format!("{}.fai", path.display())),
+    }
+}
+
+/// Location of sequence in FASTA file, from a line of `.fai`.
+struct FaiEntry {
+    len: u64,
+    offset: u64,
+    line_bases: u64,
+    line_width: u64,
+}
+
+/// Uncompressed FASTA file with `.fai` next to it, from which whole
+/// sequences are read by name.
+pub struct IndexedFasta {
+    file: File,
+    entries: HashMap<String, FaiEntry>,
+}
+
+impl IndexedFasta {
+    /// Opens FASTA file, or FASTA of index if `.fai` is given.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let fai_path = fai_path(path);
+        let fasta_path = fai_path.with_extension("");
+        let mut entries = HashMap::new();
+        for line in BufReader::new(File::open(&fai_path)?).lines() {
+            let line = line?;
+            let fields: Vec<_> = line.split('\t').collect();
+            let num = |i: usize| fields.get(i).and_then(|field| field.parse::<u64>().ok());
+            match (num(1), num(2), num(3), num(4)) {
+                (Some(len), Some(offset), Some(line_bases), Some(line_width))
+                    if !fields[0].is_empty() && line_bases > 0 && line_width >= line_bases =>
+                {
+                    entries.insert(fields[0].to_owned(), FaiEntry { len, offset, line_bases, line_width });
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Malformed line of {}: {}", fai_path.display(), line),
+                    ))
+                }
+            }
+        }
+        Ok(Self { file: File::open(fasta_path)?, entries })
+    }
+
+    /// Bases of sequence `name` in upper case.
+    pub fn read_sequence(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Reference {} is missing in FASTA.", name)))?;
+        let size = entry.len / entry.line_bases * entry.line_width + entry.len % entry.line_bases;
+        let mut bytes = Vec::with_capacity(size as usize);
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        (&mut self.file).take(size).read_to_end(&mut bytes)?;
+        bytes.retain(|c| !c.is_ascii_whitespace());
+        if bytes.len() as u64 != entry.len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Reference {} in FASTA doesn't match its index.", name),
+            ));
+        }
+        bytes.make_ascii_uppercase();
+        Ok(bytes)
+    }
+}
+
+/// Reference sequence of the header which doesn't match FASTA index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceMismatch {
+    Missing(String),
+    Length { name: String, header: u32, fasta: u32 },
+}
+
+impl fmt::Display for ReferenceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReferenceMismatch::Missing(name) => write!(f, "Reference {} is missing in FASTA.", name),
+            ReferenceMismatch::Length { name, header, fasta } => write!(
+                f,
+                "Reference {} has length {} in header, but {} in FASTA.",
+                name, header, fasta
+            ),
+        }
+    }
+}
+
+/// Checks that every reference sequence of the header is present in FASTA
+/// index `fai` with the same length. FASTA may have extra sequences.
+pub fn check_references(ref_seqs: &RefSeqs, fai: &RefSeqs) -> Result<(), ReferenceMismatch> {
+    let fai: HashMap<_, _> = fai.iter().map(|(name, len)| (name.as_str(), *len)).collect();
+    for (name, len) in ref_seqs {
+        match fai.get(name.as_str()) {
+            None => return Err(ReferenceMismatch::Missing(name.clone())),
+            Some(fasta) if fasta != len => {
+                return Err(ReferenceMismatch::Length {
+                    name: name.clone(),
+                    header: *len,
+                    fasta: *fasta,
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_references() {
+        let sam = "@SQ\tSN:chr1\tLN:1000\tM5:ABCDEF\n@SQ\tSN:chr2\tLN:500\n";
+        let (sam_header, ref_seqs) = SamReader::new(sam.as_bytes()).read_header().unwrap();
+        assert_eq!(sq_md5s(&sam_header, &ref_seqs), vec![Some("abcdef".to_owned()), None]);
+
+        let dir = TempDir::new("reference").unwrap();
+        let fasta = dir.path().join("ref.fa");
+        fs::write(dir.path().join("ref.fa.fai"), "chr1\t1000\t6\t60\t61\nchr2\t500\t1030\t60\t61\nchrM\t16569\t1550\t60\t61\n").unwrap();
+        let fai = read_fai(&fasta).unwrap();
+        assert_eq!(fai, read_fai(&dir.path().join("ref.fa.fai")).unwrap());
+        assert_eq!(check_references(&ref_seqs, &fai), Ok(()));
+
+        let other = vec![("chr1".to_owned(), 1000), ("chr2".to_owned(), 501)];
+        assert_eq!(
+            check_references(&ref_seqs, &other),
+            Err(ReferenceMismatch::Length { name: "chr2".to_owned(), header: 500, fasta: 501 })
+        );
+        assert_eq!(check_references(&ref_seqs, &other[..1].to_vec()), Err(ReferenceMismatch::Missing("chr2".to_owned())));
+
+        fs::write(dir.path().join("bad.fai"), "chr1\tlong\n").unwrap();
+        assert!(read_fai(&dir.path().join("bad.fai")).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Error, ErrorKind, Read, Result};
+
+/// BAM 4-bit codes of A, C, G, T. Index is the 2-bit code.
+const BASES: [u8; 4] = [1, 2, 4, 8];
+const ZSTD_LEVEL: i32 = 15;
+
+fn two_bit_code(nibble: u8) -> Option<u8> {
+    match nibble {
+        1 => Some(0),
+        2 => Some(1),
+        4 => Some(2),
+        8 => Some(3),
+        _ => None,
+    }
+}
+
+/// Repacks 4-bit BAM sequence into 2 bits per base and entropy codes it.
+/// Anything besides ACGT (N, ambiguity codes, padding of odd length reads)
+/// goes to exceptions list of (distance from previous exception, nibble).
+///
+/// Layout before zstd: source length (u64), exceptions number (u32),
+/// exceptions, packed bases.
+pub(crate) fn pack(source: &[u8]) -> Result<Vec<u8>> {
+    let nibbles = source.iter().flat_map(|b| [b >> 4, b & 0xf]);
+    let mut exceptions = Vec::new();
+    let mut packed = vec![0u8; (source.len() * 2).div_ceil(4)];
+    let mut last_exception = 0;
+    for (i, nibble) in nibbles.enumerate() {
+        let code = two_bit_code(nibble).unwrap_or_else(|| {
+            exceptions.push(((i - last_exception) as u32, nibble));
+            last_exception = i;
+            0
+        });
+        packed[i / 4] |= code << ((i % 4) * 2);
+    }
+
+    let mut buf = Vec::with_capacity(12 + exceptions.len() * 5 + packed.len());
+    buf.write_u64::<LittleEndian>(source.len() as u64)?;
+    buf.write_u32::<LittleEndian>(exceptions.len() as u32)?;
+    for (distance, nibble) in exceptions {
+        buf.write_u32::<LittleEndian>(distance)?;
+        buf.push(nibble);
+    }
+    buf.extend_from_slice(&packed);
+    zstd::stream::encode_all(&buf[..], ZSTD_LEVEL)
+}
+
+/// Inverse of `pack`, writes 4-bit BAM sequence into `dest`.
+pub(crate) fn unpack(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let buf = zstd::stream::decode_all(source)?;
+    let mut reader = &buf[..];
+    let len = reader.read_u64::<LittleEndian>()? as usize;
+    let exceptions_num = reader.read_u32::<LittleEndian>()? as usize;
+    let mut exceptions = Vec::with_capacity(exceptions_num);
+    let mut pos = 0;
+    for _ in 0..exceptions_num {
+        pos += reader.read_u32::<LittleEndian>()? as usize;
+        exceptions.push((pos, reader.read_u8()?));
+    }
+    let mut packed = Vec::new();
+    reader.read_to_end(&mut packed)?;
+    if packed.len() != (len * 2).div_ceil(4) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Packed sequence size doesn't match its length.",
+        ));
+    }
+
+    let nibble_at = |i: usize| BASES[((packed[i / 4] >> ((i % 4) * 2)) & 0b11) as usize];
+    dest.clear();
+    dest.extend((0..len).map(|i| (nibble_at(2 * i) << 4) | nibble_at(2 * i + 1)));
+    for (pos, nibble) in exceptions {
+        let byte = dest
+            .get_mut(pos / 2)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Exception out of sequence."))?;
+        if pos % 2 == 0 {
+            *byte = (*byte & 0x0f) | (nibble << 4);
+        } else {
+            *byte = (*byte & 0xf0) | nibble;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seq_packing_roundtrip() {
+        // ACGT repeated, N in the middle, padding nibble at the end.
+        let mut source: Vec<u8> = (0..1000).map(|i| [0x12, 0x48][i % 2]).collect();
+        source[500] = 0x1f;
+        source.push(0x80);
+        let packed = pack(&source).unwrap();
+        assert!(packed.len() < source.len());
+        let mut unpacked = Vec::new();
+        unpack(&packed, &mut unpacked).unwrap();
+        assert_eq!(unpacked, source);
+    }
+}
@@ -0,0 +1,218 @@
+use super::bam::header_edit::HeaderEdits;
+use super::bam::merge::header_text;
+use super::record_filter::RecordFilter;
+use super::writer::Writer;
+use super::writer_builder::{ConfigError, WriterBuilder};
+use crate::CompressionReport;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::sync::Arc;
+
+/// Name of output of records without the key.
+const UNASSIGNED: &[u8] = b"unassigned";
+
+/// What records are split by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitBy {
+    /// RG tag. Header of every output keeps only @RG line of its group.
+    ReadGroup,
+    /// Any string tag, e.g. CB or BC barcode.
+    Tag([u8; 2]),
+}
+
+impl SplitBy {
+    fn tag(&self) -> &[u8; 2] {
+        match self {
+            SplitBy::ReadGroup => b"RG",
+            SplitBy::Tag(tag) => tag,
+        }
+    }
+}
+
+impl std::str::FromStr for SplitBy {
+    type Err = String;
+
+    /// Either `rg` or two letter tag, e.g. `CB`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            b"rg" | b"RG" => Ok(SplitBy::ReadGroup),
+            [first, second] if first.is_ascii_alphabetic() && second.is_ascii_alphanumeric() => {
+                Ok(SplitBy::Tag([*first, *second]))
+            }
+            _ => Err(format!("Expected rg or two letter tag, got: {}", s)),
+        }
+    }
+}
+
+/// Demultiplexes records into separate GBAM files in a single pass. Output
+/// of every value of the key is created on its first record and named
+/// `<prefix>.<value>.gbam`, records without the key go to
+/// `<prefix>.unassigned.gbam`. Outputs share compression thread pool and
+/// settings. Every output holds its own blocks in memory, so many outputs
+/// (e.g. cell barcodes) call for smaller block limits.
+pub struct SplitWriter {
+    split_by: SplitBy,
+    out_prefix: String,
+    /// Configuration of every output, with shared pool and header edits
+    /// already applied.
+    builder: WriterBuilder,
+    filter: Option<RecordFilter>,
+    outputs: HashMap<Vec<u8>, Writer<BufWriter<File>>>,
+}
+
+impl SplitWriter {
+    /// Every output is configured with `builder`, header of @RG split
+    /// outputs is reduced to own read group. Filter of `builder` is applied
+    /// before splitting, so filtered out records create no outputs.
+    pub fn new(out_prefix: &str, split_by: SplitBy, builder: WriterBuilder) -> Result<Self, ConfigError> {
+        builder.validate()?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(builder.thread_num)
+            .build()
+            .map_err(|_| ConfigError::ThreadNum)?;
+        let mut builder = builder.thread_pool(Arc::new(pool));
+        // Edited once, so @RG lines added by edits are split too.
+        let sam_header = builder.header_edits.apply(&builder.sam_header);
+        builder.sam_header = sam_header;
+        builder.header_edits = HeaderEdits::new();
+        let mut filter = builder.filter.take();
+        if let Some(filter) = filter.as_mut() {
+            filter.resolve(&builder.ref_seqs).map_err(|err| ConfigError::Region(err.to_string()))?;
+        }
+        Ok(Self {
+            split_by,
+            out_prefix: out_prefix.to_owned(),
+            builder,
+            filter,
+            outputs: HashMap::new(),
+        })
+    }
+
+    /// Path of output of records with key `value`.
+    pub fn output_path(&self, value: &[u8]) -> String {
+        let name: String = value
+            .iter()
+            .map(|&c| match c {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => c as char,
+                _ => '_',
+            })
+            .collect();
+        format!("{}.{}.gbam", self.out_prefix, name)
+    }
+
+    /// Pushes record into output of its key, creating output if needed.
+    /// Compression failures are returned as `io::Error` wrapping
+    /// `CompressError`.
+    pub fn push_record(&mut self, record: &BAMRawRecord) -> io::Result<()> {
+        if self.filter.as_ref().is_some_and(|filter| !filter.accepts(record)) {
+            return Ok(());
+        }
+        let value = record.get_tag(self.split_by.tag()).unwrap_or(UNASSIGNED);
+        if !self.outputs.contains_key(value) {
+            let writer = self.new_output(value)?;
+            self.outputs.insert(value.to_vec(), writer);
+        }
+        self.outputs.get_mut(value).unwrap().push_record(record)?;
+        Ok(())
+    }
+
+    fn new_output(&self, value: &[u8]) -> io::Result<Writer<BufWriter<File>>> {
+        let mut builder = self.builder.clone();
+        if self.split_by == SplitBy::ReadGroup {
+            builder.sam_header = keep_read_group(&builder.sam_header, value);
+        }
+        let fout = File::create(self.output_path(value))?;
+        Ok(builder.build(BufWriter::new(fout))?)
+    }
+
+    /// Finishes all outputs. Returns path and compression report of every
+    /// output, ordered by path.
+    pub fn finish(&mut self) -> io::Result<Vec<(String, CompressionReport)>> {
+        let mut reports = Vec::new();
+        for (value, mut writer) in self.outputs.drain() {
+            writer.finish()?;
+            reports.push((value, writer.compression_report().cloned().unwrap()));
+        }
+        let mut reports: Vec<_> = reports
+            .into_iter()
+            .map(|(value, report)| (self.output_path(&value), report))
+            .collect();
+        reports.sort_by(|left, right| left.0.cmp(&right.0));
+        Ok(reports)
+    }
+}
+
+/// Drops @RG lines of header bytes laid out as in BAM file, except one
+/// of read group `id`.
+fn keep_read_group(sam_header: &[u8], id: &[u8]) -> Vec<u8> {
+    let id = String::from_utf8_lossy(id);
+    let own_line = |line: &str| line.split('\t').any(|field| field.strip_prefix("ID:") == Some(&id[..]));
+    let mut text = String::new();
+    for line in header_text(sam_header).lines() {
+        if !line.starts_with("@RG\t") || own_line(line) {
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    let l_text = LittleEndian::read_u32(sam_header) as usize;
+    let mut bytes = (text.len() as u32).to_le_bytes().to_vec();
+    bytes.extend_from_slice(text.as_bytes());
+    // Reference sequences are kept as is.
+    bytes.extend_from_slice(&sam_header[4 + l_text..]);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::Codecs;
+    use bam_tools::record::fields::Fields;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::Reader;
+    use crate::reader::record::GbamRecord;
+    use std::borrow::Cow;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_split_by_read_group() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:1000\n@RG\tID:a\tSM:x\n@RG\tID:b/1\tSM:y\n");
+        for i in 0..30 {
+            let rg = ["\tRG:Z:a", "\tRG:Z:b/1", ""][i % 3];
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t0\t*\t*\t0\t0\tACGT\tIIII{}\n", i, i + 1, rg));
+        }
+        let mut reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = reader.read_header().unwrap();
+        let dir = TempDir::new("split").unwrap();
+        let prefix = dir.path().join("out");
+        let builder = WriterBuilder::new().codecs(vec![Codecs::Lz4]).thread_num(2).header(ref_seqs, sam_header);
+        let mut writer = SplitWriter::new(prefix.to_str().unwrap(), "rg".parse().unwrap(), builder).unwrap();
+        while let Some(rec) = reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        let reports = writer.finish().unwrap();
+        let paths: Vec<_> = reports.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            paths,
+            ["out.a.gbam", "out.b_1.gbam", "out.unassigned.gbam"]
+                .iter()
+                .map(|name| dir.path().join(name).to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        );
+
+        for (path, first) in paths.iter().zip([0, 1, 2]) {
+            let tmplt = ParsingTemplate::new_with(&[Fields::ReadName]);
+            let mut reader = Reader::new(File::open(path).unwrap(), tmplt).unwrap();
+            assert_eq!(reader.amount, 10);
+            let mut rec = GbamRecord::default();
+            reader.fill_record(1, &mut rec);
+            assert_eq!(rec.read_name.as_deref().unwrap(), format!("r{}\0", first + 3).as_bytes());
+            let header = String::from_utf8_lossy(reader.file_meta.get_sam_header()).into_owned();
+            assert_eq!(header.contains("ID:a"), first == 0);
+            assert_eq!(header.contains("ID:b/1"), first == 1);
+        }
+    }
+}
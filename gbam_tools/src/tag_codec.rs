@@ -0,0 +1,156 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Error, ErrorKind, Read, Result};
+
+const ZSTD_LEVEL: i32 = 15;
+
+fn invalid_tags() -> Error {
+    Error::new(ErrorKind::InvalidData, "Malformed auxiliary data.")
+}
+
+fn item_size(tag_type: u8) -> Result<usize> {
+    match tag_type {
+        b'A' | b'C' | b'c' => Ok(1),
+        b'S' | b's' => Ok(2),
+        b'I' | b'i' | b'f' => Ok(4),
+        _ => Err(invalid_tags()),
+    }
+}
+
+/// Size of tag value (excluding key and type) at the start of `data`.
+fn value_len(tag_type: u8, data: &[u8]) -> Result<usize> {
+    match tag_type {
+        b'Z' | b'H' => data
+            .iter()
+            .position(|&b| b == 0)
+            .map(|pos| pos + 1)
+            .ok_or_else(invalid_tags),
+        b'B' => {
+            let mut header = data.get(..5).ok_or_else(invalid_tags)?;
+            let item_size = item_size(header.read_u8()?)?;
+            let count = header.read_u32::<LittleEndian>()? as usize;
+            Ok(5 + count * item_size)
+        }
+        _ => item_size(tag_type),
+    }
+}
+
+fn compress_stream(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let frame = zstd::stream::encode_all(source, ZSTD_LEVEL)?;
+    dest.write_u64::<LittleEndian>(frame.len() as u64)?;
+    dest.extend_from_slice(&frame);
+    Ok(())
+}
+
+fn decompress_stream(source: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = source.read_u64::<LittleEndian>()? as usize;
+    let frame = source.get(..len).ok_or_else(invalid_tags)?;
+    *source = &source[len..];
+    zstd::stream::decode_all(frame)
+}
+
+/// Splits block of auxiliary data into per-tag columns. Each distinct tag
+/// (key and type, e.g. `NMC` or `MDZ`) gets its own value stream, order of
+/// tags is kept in a separate stream of tag indices. Values of the same tag
+/// are similar, so compressing them together works better than compressing
+/// them interleaved with other tags.
+///
+/// Layout: keys stream (tags number (u16), tags, tag order as u16 indices),
+/// then value stream of every tag. Each stream is a separate ZSTD frame
+/// prefixed with its size (u64).
+pub(crate) fn compress(source: &[u8]) -> Result<Vec<u8>> {
+    let mut tags: Vec<[u8; 3]> = Vec::new();
+    let mut values: Vec<Vec<u8>> = Vec::new();
+    let mut order = Vec::new();
+    let mut rest = source;
+    while !rest.is_empty() {
+        let key = rest.get(..3).ok_or_else(invalid_tags)?;
+        let len = value_len(key[2], &rest[3..])?;
+        let value = rest.get(3..3 + len).ok_or_else(invalid_tags)?;
+        let idx = match tags.iter().position(|tag| tag == key) {
+            Some(idx) => idx,
+            None => {
+                if tags.len() == u16::MAX as usize {
+                    return Err(Error::new(ErrorKind::InvalidData, "Too many distinct tags."));
+                }
+                tags.push([key[0], key[1], key[2]]);
+                values.push(Vec::new());
+                tags.len() - 1
+            }
+        };
+        order.write_u16::<LittleEndian>(idx as u16)?;
+        values[idx].extend_from_slice(value);
+        rest = &rest[3 + len..];
+    }
+
+    let mut keys = Vec::with_capacity(2 + tags.len() * 3 + order.len());
+    keys.write_u16::<LittleEndian>(tags.len() as u16)?;
+    for tag in &tags {
+        keys.extend_from_slice(tag);
+    }
+    keys.extend_from_slice(&order);
+
+    let mut dest = Vec::new();
+    compress_stream(&keys, &mut dest)?;
+    for value in &values {
+        compress_stream(value, &mut dest)?;
+    }
+    Ok(dest)
+}
+
+pub(crate) fn decompress(source: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let mut source = source;
+    let keys = decompress_stream(&mut source)?;
+    let mut keys = &keys[..];
+    let tags_num = keys.read_u16::<LittleEndian>()? as usize;
+    let mut tags = vec![[0u8; 3]; tags_num];
+    for tag in tags.iter_mut() {
+        keys.read_exact(tag)?;
+    }
+    let values = (0..tags_num)
+        .map(|_| decompress_stream(&mut source))
+        .collect::<Result<Vec<_>>>()?;
+    let mut positions = vec![0; tags_num];
+
+    dest.clear();
+    while !keys.is_empty() {
+        let idx = keys.read_u16::<LittleEndian>()? as usize;
+        let tag = tags.get(idx).ok_or_else(invalid_tags)?;
+        let value = &values[idx][positions[idx]..];
+        let len = value_len(tag[2], value)?;
+        dest.extend_from_slice(tag);
+        dest.extend_from_slice(value.get(..len).ok_or_else(invalid_tags)?);
+        positions[idx] += len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_codec_roundtrip() {
+        let mut source = Vec::new();
+        for i in 0..1000u32 {
+            source.extend_from_slice(b"NMC");
+            source.push((i % 5) as u8);
+            source.extend_from_slice(b"MDZ");
+            source.extend_from_slice(format!("{}A{}", i % 90, 99 - i % 90).as_bytes());
+            source.push(0);
+            if i % 3 == 0 {
+                source.extend_from_slice(b"ZBBs");
+                source.write_u32::<LittleEndian>(2).unwrap();
+                source.write_i16::<LittleEndian>(-(i as i16)).unwrap();
+                source.write_i16::<LittleEndian>(i as i16).unwrap();
+            }
+            source.extend_from_slice(b"ASi");
+            source.write_i32::<LittleEndian>(i as i32).unwrap();
+        }
+        let compressed = compress(&source).unwrap();
+        let mut decompressed = Vec::new();
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, source);
+
+        assert!(compress(b"NMQ\0").is_err());
+    }
+}
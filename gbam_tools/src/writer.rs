@@ -1,16 +1,25 @@
-use super::meta::{BlockMeta, Codecs, FileInfo, FileMeta, FILE_INFO_SIZE, Stat};
-use crate::compressor::{CompressTask, Compressor, OrderingKey};
-use crate::{SIZE_LIMIT, U32_SIZE};
+use super::checkpoint::{BufferState, Checkpoint};
+use super::meta::{BlockLimits, BlockMeta, Codecs, FileInfo, FileMeta, NameTokenization, QualBinning, FILE_INFO_SIZE, Stat, STATS_FIELDS};
+use crate::const_codec;
+use crate::compressor::{CompressError, CompressTask, CompressionReport, Compressor, OrderingKey};
+use crate::bam::header_edit::HeaderEdits;
+use crate::dup_marker::{DuplicateMarker, DuplicateStats};
+use crate::mate_fields;
+use crate::progress::{Progress, ProgressObserver};
+use crate::record_filter::{reference_len, RecordFilter};
+use crate::U32_SIZE;
 use bam_tools::record::bamrawrecord::BAMRawRecord;
 use bam_tools::record::fields::{
-    field_type, is_data_field, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
+    field_item_size, field_type, is_data_field, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
 };
-use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use crc32fast::Hasher;
 use std::borrow::Cow;
 use std::convert::TryInto;
 use std::convert::TryFrom;
-use std::io::{Seek, SeekFrom, Write};
+use rayon::ThreadPool;
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::sync::Arc;
 
 pub(crate) struct BlockInfo {
     pub numitems: u32,
@@ -18,6 +27,10 @@ pub(crate) struct BlockInfo {
     pub field: Fields,
     // Interpretation is up to the reader.
     pub stats: Option<Stat>,
+    // Filled by compressor, since codec may be chosen per block.
+    pub codec: Option<Codecs>,
+    // xxhash64 of uncompressed data, filled by compressor.
+    pub checksum: Option<u64>,
 }
 
 impl Default for BlockInfo {
@@ -27,6 +40,8 @@ impl Default for BlockInfo {
             uncompr_size: 0,
             field: Fields::RefID,
             stats: None,
+            codec: None,
+            checksum: None,
         }
     }
 }
@@ -49,16 +64,35 @@ where
     columns: Vec<Box<dyn Column>>,
     compressor: Compressor,
     inner: WS,
+    // Lookup table of quality binning, if enabled.
+    qual_binning: Option<[u8; 256]>,
+    mate_relative: bool,
+    // Available once finished.
+    report: Option<CompressionReport>,
+    // Dry runs compress only every n-th block of each field.
+    sample_every: Option<u64>,
+    progress: Progress,
+    observer: Option<Box<dyn ProgressObserver>>,
+    filter: Option<RecordFilter>,
+    dup_marker: Option<DuplicateMarker>,
 }
 
 impl<WS> Writer<WS>
 where
     WS: Write + Seek,
 {
+    /// `codecs` are indexed by field (`Fields as usize`). The first codec is
+    /// the default for fields without an entry. POS is delta encoded only if
+    /// its codec is `Codecs::PosDelta`, whether file is sorted or not.
+    ///
+    /// Input without reference sequences (uBAM, FASTQ) is unaligned, so
+    /// alignment columns hold the same value in every record. Such blocks
+    /// of fixed sized columns are stored as `Codecs::Constant` instead of
+    /// being compressed, and mate fields are not made relative.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         mut inner: WS,
-        codecs: Vec<Codecs>,
+        mut codecs: Vec<Codecs>,
         thread_num: usize,
         collect_stats_for: Vec<Fields>,
         ref_seqs: Vec<(String, u32)>,
@@ -90,12 +124,38 @@ where
         }
         debug_assert!(count == FIELDS_NUM);
 
+        // Codecs are indexed by field, first one is the default for all fields.
+        codecs.resize(FIELDS_NUM, codecs[0]);
+        let default_codec = codecs[0];
+        if ref_seqs.is_empty() {
+            for col in columns.iter_mut() {
+                let fixed = match col.get_inners() {
+                    (_, Some(index)) => index,
+                    (inner, None) => inner,
+                };
+                fixed.constant_item_size = field_item_size(&fixed.field).map(|size| size as u32);
+            }
+        }
+        let mut file_meta = FileMeta::new(default_codec, ref_seqs, sam_header);
+        for (field, codec) in Fields::iterator().zip(codecs.iter()) {
+            if *codec != default_codec {
+                file_meta.set_field_codec(field, *codec);
+            }
+        }
+
         Self {
-            // TODO: Codecs (currently only one is supported).
-            file_meta: FileMeta::new(codecs[0], ref_seqs, sam_header),
+            file_meta,
             inner,
             compressor: Compressor::new(thread_num),
             columns,
+            qual_binning: None,
+            mate_relative: false,
+            report: None,
+            sample_every: None,
+            progress: Progress::default(),
+            observer: None,
+            filter: None,
+            dup_marker: None,
             file_info: FileInfo::new([1, 0], 0, 0, full_command, is_sorted),
         }
     }
@@ -121,8 +181,300 @@ where
         )
     }
 
-    /// Push BAM record into this writer
-    pub fn push_record(&mut self, record: &BAMRawRecord) {
+    /// Bin qualities of all records pushed after this call. Binning scheme is
+    /// recorded in file meta.
+    pub fn set_qual_binning(&mut self, binning: QualBinning) {
+        self.qual_binning = Some(binning.lookup_table());
+        self.file_meta.set_qual_binning(binning);
+    }
+
+    /// Store mate fields of all records pushed after this call relative to
+    /// record's own RNAME and POS. Decoded transparently by the reader.
+    /// Ignored for unaligned input, see `new`.
+    pub fn set_mate_relative(&mut self) {
+        if self.file_meta.get_ref_seqs().is_empty() {
+            return;
+        }
+        self.mate_relative = true;
+        self.file_meta.set_mate_relative(true);
+    }
+
+    /// Per-field compression totals, available after `finish`.
+    pub fn compression_report(&self) -> Option<&CompressionReport> {
+        self.report.as_ref()
+    }
+
+    /// Applies `edits` to SAM header stored in file meta. May be called any
+    /// time before `finish`.
+    pub fn edit_header(&mut self, edits: &HeaderEdits) {
+        let sam_header = edits.apply(self.file_meta.get_sam_header());
+        self.file_meta.set_sam_header(sam_header);
+    }
+
+    /// Drops records pushed after this call unless `filter` accepts them.
+    /// Fails if a region refers to reference missing in the header.
+    pub fn set_filter(&mut self, mut filter: RecordFilter) -> std::io::Result<()> {
+        filter.resolve(self.file_meta.get_ref_seqs())?;
+        self.filter = Some(filter);
+        Ok(())
+    }
+
+    /// Marks duplicates of records pushed after this call, see
+    /// `DuplicateMarker`. Records are written with a delay of about two
+    /// read lengths. Fails unless the file is coordinate sorted.
+    pub fn set_duplicate_marking(&mut self, optical_distance: u32) -> std::io::Result<()> {
+        if !self.file_info.is_sorted {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "Duplicates can be marked only in coordinate sorted file."));
+        }
+        self.dup_marker = Some(DuplicateMarker::new(optical_distance));
+        Ok(())
+    }
+
+    /// Totals of duplicate marking so far, if enabled. Final once finished.
+    pub fn duplicate_stats(&self) -> Option<&DuplicateStats> {
+        self.dup_marker.as_ref().map(|marker| marker.stats())
+    }
+
+    /// Report progress to `observer` from now on.
+    pub fn set_progress_observer(&mut self, observer: Box<dyn ProgressObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Totals so far.
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    fn notify_progress(&mut self) {
+        if let Some(observer) = self.observer.as_mut() {
+            observer.on_progress(&self.progress);
+        }
+    }
+
+    /// Compress following blocks on `pool`, e.g. a pool shared with the rest
+    /// of application. Blocks queued so far are written out first. Number of
+    /// threads doesn't affect the output.
+    pub fn set_thread_pool(&mut self, pool: Arc<ThreadPool>) -> Result<(), CompressError> {
+        self.drain_compressor()?;
+        self.compressor.set_pool(pool);
+        Ok(())
+    }
+
+    /// Compress following blocks on a new pool of `thread_num` threads.
+    /// Blocks queued so far are written out first.
+    pub fn set_thread_num(&mut self, thread_num: usize) -> Result<(), CompressError> {
+        self.drain_compressor()?;
+        self.compressor.set_thread_num(thread_num);
+        Ok(())
+    }
+
+    fn drain_compressor(&mut self) -> Result<(), CompressError> {
+        for task in self.compressor.drain() {
+            write_task(&mut self.inner, &mut self.file_meta, &mut self.progress, task)?;
+        }
+        self.notify_progress();
+        Ok(())
+    }
+
+    /// Limits blocks of `field`. Small blocks favor random access, big ones
+    /// compression ratio. Limits are recorded in file meta. Variable sized
+    /// fields have their index (e.g. `RawSeqLen` for `RawSequence`) limited
+    /// separately. Has to be called before pushing records, since reader
+    /// expects all blocks of fixed sized field but the last to be equal.
+    pub fn set_block_limits(&mut self, field: Fields, limits: BlockLimits) {
+        assert!(
+            limits.max_bytes > 0 && limits.max_records != Some(0),
+            "Block limits should be positive."
+        );
+        assert!(self.progress.records == 0, "Block limits have to be set before pushing records.");
+        for col in self.columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            for inner in std::iter::once(inner).chain(idx) {
+                if inner.field == field {
+                    inner.limits = limits;
+                }
+            }
+        }
+        self.file_meta.set_block_limits(&field, limits);
+    }
+
+    /// Same limits for blocks of all fields, see `set_block_limits`.
+    pub fn set_all_block_limits(&mut self, limits: BlockLimits) {
+        for field in Fields::iterator() {
+            self.set_block_limits(*field, limits);
+        }
+    }
+
+    /// Doesn't store data of `field`, only ReadName, RawQual and RawTags may
+    /// be omitted. Reader returns placeholders instead: `*` names (so mates
+    /// can't be paired by name), qualities of `*` (0xFF) of the read length
+    /// and no tags. Has to be called before pushing records.
+    pub fn omit_field(&mut self, field: Fields) {
+        assert!(
+            matches!(field, Fields::ReadName | Fields::RawQual | Fields::RawTags),
+            "Only ReadName, RawQual and RawTags may be omitted."
+        );
+        assert!(self.progress.records == 0, "Fields have to be omitted before pushing records.");
+        for col in self.columns.iter_mut() {
+            let (inner, _) = col.get_inners();
+            if inner.field == field {
+                inner.omitted = true;
+            }
+        }
+        self.file_meta.set_omitted(&field);
+    }
+
+    /// Whether blocks of read names are tokenized (`Codecs::NameTok`)
+    /// instead of compressed with the codec of the column. Tokenization is
+    /// recorded per block, so blocks may differ. `Off` by default, which
+    /// keeps `Codecs::NameTok` only if it is the codec of the column.
+    pub fn set_name_tokenization(&mut self, tokenization: NameTokenization) {
+        for col in self.columns.iter_mut() {
+            let (inner, _) = col.get_inners();
+            if inner.field == Fields::ReadName {
+                inner.tokenization = tokenization;
+            }
+        }
+    }
+
+    /// Snapshot of the writer allowing to resume writing after crash (see
+    /// `resume`). Blocks queued for compression are written out and output
+    /// is flushed first, syncing it to disk is up to the caller. Blocks not
+    /// filled yet are kept in the checkpoint as they are.
+    pub fn checkpoint(&mut self) -> std::io::Result<Checkpoint> {
+        if self.dup_marker.is_some() {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "Checkpoints are not supported with duplicate marking."));
+        }
+        self.drain_compressor()?;
+        self.inner.flush()?;
+        let mut buffers = Vec::new();
+        let mut data = Vec::new();
+        for col in self.columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            for inner in std::iter::once(inner).chain(idx) {
+                buffers.push(BufferState {
+                    field: inner.field,
+                    len: inner.offset,
+                    rec_count: inner.rec_count,
+                    block_num: inner.block_num,
+                    stats: inner.stats_collector.clone(),
+                });
+                data.extend_from_slice(&inner.buffer[..inner.offset]);
+            }
+        }
+        Ok(Checkpoint {
+            progress: self.progress,
+            data_end: self.inner.stream_position()?,
+            file_meta: self.file_meta.clone(),
+            buffers,
+            data,
+        })
+    }
+
+    /// Continues writing from `checkpoint` taken by writer of the same
+    /// configuration. Output has to hold data written up to the checkpoint
+    /// and nothing after it (see `Checkpoint::data_end`). Records following
+    /// `Checkpoint::records` are pushed next. Compression report covers only
+    /// blocks written after resume. Has to be called before pushing records.
+    pub fn resume(&mut self, checkpoint: Checkpoint) -> std::io::Result<()> {
+        assert!(self.progress.records == 0, "Writer has to be resumed before pushing records.");
+        let mut inners = Vec::new();
+        for col in self.columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            inners.push(inner);
+            inners.extend(idx);
+        }
+        let matches = inners.len() == checkpoint.buffers.len()
+            && inners.iter().zip(&checkpoint.buffers).all(|(inner, state)| inner.field == state.field);
+        if !matches {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "Checkpoint of different writer."));
+        }
+        let mut data = &checkpoint.data[..];
+        for (inner, state) in inners.into_iter().zip(checkpoint.buffers) {
+            let (buffer, rest) = data.split_at(state.len);
+            data = rest;
+            inner.buffer.clear();
+            inner.buffer.extend_from_slice(buffer);
+            inner.offset = state.len;
+            inner.rec_count = state.rec_count;
+            inner.block_num = state.block_num;
+            inner.stats_collector = state.stats;
+        }
+        self.inner.seek(SeekFrom::Start(checkpoint.data_end))?;
+        self.file_meta = checkpoint.file_meta;
+        self.progress = checkpoint.progress;
+        Ok(())
+    }
+
+    /// Output the writer writes into.
+    pub fn get_ref(&self) -> &WS {
+        &self.inner
+    }
+
+    /// Limit uncompressed bytes queued for compression. Pushing records
+    /// blocks while the limit is reached.
+    pub fn set_compression_memory_budget(&mut self, bytes: usize) {
+        self.compressor.set_in_flight_budget(bytes);
+    }
+
+    /// Push BAM record into this writer. Fails if compression of a block
+    /// filled earlier failed, the record itself is still added to all
+    /// columns.
+    pub fn push_record(&mut self, record: &BAMRawRecord) -> Result<(), CompressError> {
+        self.progress.records += 1;
+        self.progress.bytes_in += record.0.len() as u64;
+        if self.filter.as_ref().is_some_and(|filter| !filter.accepts(record)) {
+            return Ok(());
+        }
+        let blocks_flushed = self.progress.blocks_flushed;
+        let result = match self.dup_marker.as_mut() {
+            Some(marker) => {
+                let marked = marker.push(record);
+                self.write_records(marked)
+            }
+            None => self.write_record(record),
+        };
+        if self.progress.blocks_flushed != blocks_flushed {
+            self.notify_progress();
+        }
+        result
+    }
+
+    /// Writes records released by duplicate marker. Keeps the first error.
+    fn write_records(&mut self, records: Vec<Vec<u8>>) -> Result<(), CompressError> {
+        let mut result = Ok(());
+        for rec in records {
+            result = result.and(self.write_record(&BAMRawRecord(Cow::Owned(rec))));
+        }
+        result
+    }
+
+    /// Writes accepted record into columns.
+    fn write_record(&mut self, record: &BAMRawRecord) -> Result<(), CompressError> {
+        let original = record;
+        let transformed;
+        let record = if self.qual_binning.is_some() || self.mate_relative {
+            let mut bytes = record.0.to_vec();
+            if let Some(table) = self.qual_binning.as_ref() {
+                for qual in &mut bytes[record.get_range(&Fields::RawQual)] {
+                    *qual = table[*qual as usize];
+                }
+            }
+            if self.mate_relative {
+                mate_fields::encode(&mut bytes);
+            }
+            transformed = BAMRawRecord(Cow::Owned(bytes));
+            &transformed
+        } else {
+            record
+        };
+        if self.file_info.is_sorted {
+            // Lets region queries know how far before a region overlapping
+            // records may start.
+            let ref_id = LittleEndian::read_i32(record.get_bytes(&Fields::RefID));
+            self.file_meta.update_max_span(ref_id, reference_len(record).max(1));
+        }
+        let mut result = Ok(());
         // Index fields are not written on their own. They hold index data for variable sized fields.
         for col in self.columns.iter_mut() {
             // Attempt to write data in this column. If the column is full it
@@ -130,72 +482,110 @@ where
             // variable sized columns also have index columns (fixed size)
             // inside and they might also come full and request flushing
             // simultaneously with containing variable sized field column.
-            while let WriteStatus::Full(inner) = col.write_record_field(record) {
-                flush_field_buffer(
+            while let WriteStatus::Full(inner) = col.write_record_field(record, original) {
+                // Keeps the first error.
+                result = result.and(flush_field_buffer(
                     &mut self.inner,
                     &mut self.file_meta,
                     &mut self.compressor,
+                    &mut self.progress,
                     inner,
-                );
+                    self.sample_every,
+                ));
             }
         }
+        result
     }
 
     /// Terminates the writer. Always call after writting all the data. Returns
-    /// total amount of bytes written.
+    /// total amount of bytes written. Compression failures are returned as
+    /// `io::Error` wrapping `CompressError`.
     pub fn finish(&mut self) -> std::io::Result<u64> {
+        if let Some(marked) = self.dup_marker.as_mut().map(|marker| marker.finish()) {
+            self.write_records(marked)?;
+        }
         // Flush leftovers
         let mut columns: Vec<Box<dyn Column>> = self.columns.drain(..).collect();
         for (inner, idx) in columns.iter_mut().map(|col| col.get_inners()) {
             let writer = &mut self.inner;
             let meta = &mut self.file_meta;
             let compress = &mut self.compressor;
+            let progress = &mut self.progress;
+            let sample_every = self.sample_every;
 
-            flush_field_buffer(writer, meta, compress, inner);
+            if !inner.omitted {
+                flush_field_buffer(writer, meta, compress, progress, inner, sample_every)?;
+            }
             if let Some(idx_inner) = idx {
-                flush_field_buffer(writer, meta, compress, idx_inner);
+                flush_field_buffer(writer, meta, compress, progress, idx_inner, sample_every)?;
             }
         }
 
-        for mut task in self.compressor.finish() {
-            if let OrderingKey::Key(key) = task.ordering_key {
-                write_data_and_update_meta(&mut self.inner, &mut self.file_meta, key, &mut task);
-            }
+        let (leftovers, report) = self.compressor.finish();
+        for task in leftovers {
+            write_task(&mut self.inner, &mut self.file_meta, &mut self.progress, task)?;
         }
+        self.report = Some(report);
 
-        let meta_start_pos = self.inner.stream_position()?;
-        // Write meta
-        let main_meta = serde_json::to_string(&self.file_meta).unwrap();
-        let main_meta_bytes = main_meta.as_bytes();
-        let crc32 = calc_crc_for_meta_bytes(main_meta_bytes);
-        self.inner.write_all(main_meta_bytes)?;
-
-        let total_bytes_written = self.inner.stream_position()?;
-        // Revert back to the beginning of the file
-        self.inner.seek(SeekFrom::Start(0)).unwrap();
-        self.inner.write_all(&[0;FILE_INFO_SIZE]).unwrap();
-        self.inner.seek(SeekFrom::Start(0)).unwrap();
-        let file_info = & mut self.file_info;
-        file_info.seekpos = meta_start_pos;
-        file_info.crc32 = crc32;
-        let file_info_bytes = serde_json::to_string(&file_info).unwrap();
-        self.inner.write_all(file_info_bytes.as_bytes())?;
+        let total_bytes_written = write_meta(&mut self.inner, &mut self.file_info, &self.file_meta)?;
+        self.progress.bytes_out = total_bytes_written;
+        if let Some(observer) = self.observer.as_mut() {
+            observer.on_finish(&self.progress);
+        }
         Ok(total_bytes_written)
     }
 }
 
+/// Writes file meta at current position and file info pointing to it at the
+/// beginning of the file. Returns position of the end of meta, which is the
+/// end of the file.
+pub(crate) fn write_meta<W: Write + Seek>(inner: &mut W, file_info: &mut FileInfo, file_meta: &FileMeta) -> std::io::Result<u64> {
+    let meta_start_pos = inner.stream_position()?;
+    // Write meta
+    let main_meta = serde_json::to_string(file_meta)?;
+    let main_meta_bytes = main_meta.as_bytes();
+    let crc32 = calc_crc_for_meta_bytes(main_meta_bytes);
+    inner.write_all(main_meta_bytes)?;
+
+    let total_bytes_written = inner.stream_position()?;
+    // Revert back to the beginning of the file
+    inner.seek(SeekFrom::Start(0))?;
+    inner.write_all(&[0;FILE_INFO_SIZE])?;
+    inner.seek(SeekFrom::Start(0))?;
+    file_info.seekpos = meta_start_pos;
+    file_info.crc32 = crc32;
+    let file_info_bytes = serde_json::to_string(&file_info).unwrap();
+    inner.write_all(file_info_bytes.as_bytes())?;
+    inner.flush()?;
+    Ok(total_bytes_written)
+}
+
 fn flush_field_buffer<WS: Write + Seek>(
     writer: &mut WS,
     file_meta: &mut FileMeta,
     compressor: &mut Compressor,
+    progress: &mut Progress,
     inner: &mut Inner,
-) {
+    sample_every: Option<u64>,
+) -> Result<(), CompressError> {
+    if sample_every.is_some_and(|every| !inner.block_num.is_multiple_of(every)) {
+        compressor.skip_block(&inner.generate_block_info());
+        inner.reset_for_new_block();
+        return Ok(());
+    }
+
     // Use an empty buffer to start the flushing process
-    // Don't worry, Vec::new() is temporary, it won't need to fully allocate the Vec as it replaces the reference with the &mut from the reused Buffer
-    let data = std::mem::replace(&mut inner.buffer, Vec::new());
+    // Don't worry, the empty Vec is temporary, it won't need to fully allocate the Vec as it replaces the reference with the &mut from the reused Buffer
+    let data = std::mem::take(&mut inner.buffer);
 
     let field = &inner.field;
-    let codec = *file_meta.get_field_codec(field);
+    let codec = match (field, inner.constant_item_size) {
+        (_, Some(item_size)) if const_codec::is_constant(&data[..inner.offset], item_size as usize) => {
+            Codecs::Constant(item_size)
+        }
+        (Fields::ReadName, _) => inner.tokenization.block_codec(*file_meta.get_field_codec(field), inner.rec_count),
+        _ => *file_meta.get_field_codec(field),
+    };
 
     compressor.compress_block(
         OrderingKey::Key(inner.block_num),
@@ -204,34 +594,49 @@ fn flush_field_buffer<WS: Write + Seek>(
         codec,
     );
 
-    let mut completed_task = compressor.get_compr_block();
+    let completed_task = compressor.get_compr_block();
+    let result = write_task(writer, file_meta, progress, completed_task);
 
-    if let OrderingKey::Key(key) = completed_task.ordering_key {
-        write_data_and_update_meta(writer, file_meta, key, &mut completed_task);
-    }
+    inner.reset_for_new_block();
 
     // We need to reuse the same buffer for the next task, as it is always the same size so we can avoid re-allocating the same buffer for each processed block
-    inner.buffer = completed_task.buf;
+    inner.buffer = result?;
+    Ok(())
+}
 
-    inner.reset_for_new_block();
+/// Writes out completed task, unless it is a dummy one, and returns its
+/// buffer for reuse.
+fn write_task<WS: Write + Seek>(
+    writer: &mut WS,
+    file_meta: &mut FileMeta,
+    progress: &mut Progress,
+    mut task: CompressTask,
+) -> Result<Vec<u8>, CompressError> {
+    let buf = task.buf?;
+    if let OrderingKey::Key(key) = task.ordering_key {
+        write_data_and_update_meta(writer, file_meta, key, &mut task.block_info, &buf);
+        progress.bytes_out += buf.len() as u64;
+        progress.blocks_flushed += 1;
+    }
+    Ok(buf)
 }
 
 fn write_data_and_update_meta<WS: Write + Seek>(
     writer: &mut WS,
     file_meta: &mut FileMeta,
     key: u64,
-    task: &mut CompressTask,
+    block_info: &mut BlockInfo,
+    buf: &[u8],
 ) {
-    let compressed_size = task.buf.len();
     let meta = generate_meta(
         writer,
-        &mut task.block_info,
-        compressed_size.try_into().unwrap(),
+        block_info,
+        buf.len().try_into().unwrap(),
     );
 
-    writer.write_all(&task.buf).unwrap();
+    writer.write_all(buf).unwrap();
 
-    let field_meta = file_meta.get_blocks(&task.block_info.field);
+    let field_meta = file_meta.get_blocks(&block_info.field);
     if field_meta.len() <= key as usize {
         field_meta.resize(key as usize + 1, BlockMeta::default());
     }
@@ -252,6 +657,8 @@ fn generate_meta<S: Seek>(
         block_size,
         uncompressed_size: block_info.uncompr_size as u64,
         stats: block_info.stats.take(),
+        codec: block_info.codec,
+        checksum: block_info.checksum,
     }
 }
 
@@ -268,6 +675,14 @@ struct Inner {
     field: Fields,
     rec_count: u32,
     block_num: u64,
+    limits: BlockLimits,
+    // Data is dropped, see `Writer::omit_field`.
+    omitted: bool,
+    // Only used by data of ReadName, see `Writer::set_name_tokenization`.
+    tokenization: NameTokenization,
+    // Item size of fixed sized column of unaligned input. Blocks of one
+    // repeated item are stored as `Codecs::Constant`.
+    constant_item_size: Option<u32>,
 }
 
 impl Inner {
@@ -279,13 +694,17 @@ impl Inner {
             field,
             rec_count: 0,
             block_num: 0,
+            limits: BlockLimits::default(),
+            omitted: false,
+            tokenization: NameTokenization::default(),
+            constant_item_size: None,
         }
     }
-    pub fn write_data(&mut self, data: &[u8]) -> WriteStatus {
+    pub fn write_data(&mut self, data: &[u8]) -> WriteStatus<'_> {
         // At this point everything should be flushed.
         debug_assert!(!self.flush_required(data));
 
-        let limit = std::cmp::max(data.len(), SIZE_LIMIT);
+        let limit = std::cmp::max(self.offset + data.len(), self.limits.max_bytes);
         if self.buffer.len() < limit {
             self.buffer.resize(limit, 0);
         }
@@ -299,8 +718,10 @@ impl Inner {
     }
 
     pub fn flush_required(&self, data: &[u8]) -> bool {
-        // At least one record will be written in even if it exceeds SIZE_LIMIT.
-        self.offset > 0 && self.offset + data.len() > SIZE_LIMIT
+        // At least one record will be written in even if it exceeds the limit.
+        self.offset > 0
+            && (self.offset + data.len() > self.limits.max_bytes
+                || self.limits.max_records.is_some_and(|max| self.rec_count >= max))
     }
 
     pub fn reset_for_new_block(&mut self) {
@@ -321,13 +742,17 @@ impl Inner {
             uncompr_size: self.offset,
             field: self.field,
             stats: stat,
+            codec: None,
+            checksum: None,
         }
     }
 }
 
 trait Column {
-    // Extracts and writes data from corresponding BAMRawRecord record.
-    fn write_record_field(&mut self, rec: &BAMRawRecord) -> WriteStatus;
+    // Extracts and writes data from corresponding BAMRawRecord record. Block
+    // stats are of values of `original`, the record before quality binning
+    // and mate encoding, so that readers can compare them to decoded values.
+    fn write_record_field(&mut self, rec: &BAMRawRecord<'_>, original: &BAMRawRecord<'_>) -> WriteStatus<'_>;
 
     fn get_inners(&mut self) -> (&mut Inner, Option<&mut Inner>);
 }
@@ -337,15 +762,15 @@ struct FixedColumn(Inner);
 
 impl FixedColumn {
     pub fn new(field: Fields, comparator: Option<Stat>) -> Self {
-        if comparator.is_some() && field != Fields::RefID && field != Fields::Pos {
-            panic!("Stats collection is only supported for RefID and POS fields.");
+        if comparator.is_some() && !STATS_FIELDS.contains(&field) {
+            panic!("Stats collection is only supported for RefID, POS, MAPQ and TLEN fields.");
         }
         Self(Inner::new(field, comparator))
     }
 }
 
 impl Column for FixedColumn {
-    fn write_record_field(&mut self, rec: &BAMRawRecord) -> WriteStatus {
+    fn write_record_field(&mut self, rec: &BAMRawRecord<'_>, original: &BAMRawRecord<'_>) -> WriteStatus<'_> {
         let inner = &mut self.0;
         let data = rec.get_bytes(&inner.field);
 
@@ -354,7 +779,11 @@ impl Column for FixedColumn {
         }
 
         if let Some(ref mut stats) = inner.stats_collector {
-            stats.update((&data[..]).read_i32::<LittleEndian>().unwrap());
+            let value = original.get_bytes(&inner.field);
+            stats.update(match inner.field {
+                Fields::Mapq => i32::from(value[0]),
+                _ => LittleEndian::read_i32(value),
+            });
         }
 
         inner.write_data(data)
@@ -383,7 +812,7 @@ impl VariableColumn {
 }
 
 impl Column for VariableColumn {
-    fn write_record_field(&mut self, rec: &BAMRawRecord) -> WriteStatus {
+    fn write_record_field(&mut self, rec: &BAMRawRecord<'_>, _original: &BAMRawRecord<'_>) -> WriteStatus<'_> {
         let inner = &mut self.inner;
         let index_inner = &mut self.index.0;
 
@@ -394,6 +823,14 @@ impl Column for VariableColumn {
             return WriteStatus::Full(index_inner);
         }
 
+        if inner.omitted {
+            // Only length is kept.
+            (&mut idx_buf[..])
+                .write_u32::<LittleEndian>(u32::try_from(data.len()).unwrap())
+                .unwrap();
+            return index_inner.write_data(&idx_buf);
+        }
+
         if inner.flush_required(data) {
             return WriteStatus::Full(inner);
         }
@@ -422,7 +859,7 @@ where
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         assert!(!buf.is_empty());
         let wrapper = BAMRawRecord(Cow::Borrowed(buf));
-        self.push_record(&wrapper);
+        self.push_record(&wrapper)?;
         Ok(buf.len())
     }
 
@@ -443,6 +880,63 @@ where
 //     }
 // }
 
+impl Writer<DiscardSink> {
+    /// Writer for estimating compression of `codecs` without writing any
+    /// output. Only every `sample_every`-th block of each field is
+    /// compressed, `compression_report` projects sizes of the rest by
+    /// compression ratio of sampled ones.
+    pub fn new_dry_run(
+        codecs: Vec<Codecs>,
+        thread_num: usize,
+        is_sorted: bool,
+        sample_every: u64,
+    ) -> Self {
+        assert!(sample_every > 0, "Sampling interval should be positive.");
+        let mut writer = Self::new(
+            DiscardSink::default(),
+            codecs,
+            thread_num,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            String::new(),
+            is_sorted,
+        );
+        writer.sample_every = Some(sample_every);
+        writer
+    }
+}
+
+/// Output of dry runs. Drops all data, only keeps track of the position.
+#[derive(Default)]
+pub struct DiscardSink {
+    pos: u64,
+    len: u64,
+}
+
+impl Write for DiscardSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.len = std::cmp::max(self.len, self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for DiscardSink {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => (self.len as i64 + offset) as u64,
+            SeekFrom::Current(offset) => (self.pos as i64 + offset) as u64,
+        };
+        Ok(self.pos)
+    }
+}
+
 pub(crate) fn calc_crc_for_meta_bytes(bytes: &[u8]) -> u32 {
     let mut hasher = Hasher::new();
     hasher.update(bytes);
@@ -487,3 +981,491 @@ pub(crate) fn calc_crc_for_meta_bytes(bytes: &[u8]) -> u32 {
 //         // }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::sam_reader::SamReader;
+    use crate::checkpoint::Checkpoint;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::Reader;
+    use crate::reader::record::GbamRecord;
+    use crate::SIZE_LIMIT;
+    use std::fs::File;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_record_bigger_than_block() {
+        // Auxiliary data of long read exceeding SIZE_LIMIT.
+        let mut long_rec = BAMRawRecord::default();
+        let bytes = long_rec.0.to_mut();
+        bytes.extend_from_slice(b"XXZ");
+        bytes.extend_from_slice(&vec![b'A'; SIZE_LIMIT + 1000]);
+        bytes.push(0);
+        let records = vec![BAMRawRecord::default(), long_rec, BAMRawRecord::default()];
+
+        let dir = TempDir::new("writer").unwrap();
+        let path = dir.path().join("long.gbam");
+        let mut writer = Writer::new(
+            File::create(&path).unwrap(),
+            vec![Codecs::Lz4],
+            2,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            String::new(),
+            false,
+        );
+        for rec in &records {
+            writer.push_record(rec).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let tmplt = ParsingTemplate::new_with(&[Fields::RawTags]);
+        let mut reader = Reader::new(File::open(&path).unwrap(), tmplt).unwrap();
+        let mut rec = GbamRecord::default();
+        for (rec_num, orig) in records.iter().enumerate() {
+            reader.fill_record(rec_num, &mut rec);
+            assert_eq!(rec.tags.as_deref().unwrap(), orig.get_bytes(&Fields::RawTags));
+        }
+    }
+    /// Records with big tags of varying compressibility, so blocks finish
+    /// out of order.
+    fn varied_records() -> Vec<BAMRawRecord<'static>> {
+        (0..64u8)
+            .map(|i| {
+                let mut rec = BAMRawRecord::default();
+                let bytes = rec.0.to_mut();
+                bytes.extend_from_slice(b"XXZ");
+                bytes.extend((0..SIZE_LIMIT / 4).map(|j| (j as u8).wrapping_mul(i % 7)));
+                bytes.push(0);
+                rec
+            })
+            .collect()
+    }
+
+    fn memory_writer(thread_num: usize) -> Writer<Cursor<Vec<u8>>> {
+        Writer::new(
+            Cursor::new(Vec::new()),
+            vec![Codecs::Zstd],
+            thread_num,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            String::new(),
+            false,
+        )
+    }
+
+    fn write_all(mut writer: Writer<Cursor<Vec<u8>>>, records: &[BAMRawRecord]) -> Vec<u8> {
+        for rec in records {
+            writer.push_record(rec).unwrap();
+        }
+        writer.finish().unwrap();
+        writer.inner.into_inner()
+    }
+
+    #[test]
+    fn test_output_independent_of_thread_num() {
+        let records = varied_records();
+        let expected = write_all(memory_writer(1), &records);
+        for thread_num in [2, 4, 8] {
+            assert!(write_all(memory_writer(thread_num), &records) == expected);
+        }
+    }
+
+    #[test]
+    fn test_change_thread_pool_while_writing() {
+        let records = varied_records();
+        let expected = write_all(memory_writer(1), &records);
+
+        let mut writer = memory_writer(2);
+        for rec in &records[..20] {
+            writer.push_record(rec).unwrap();
+        }
+        writer.set_thread_num(4).unwrap();
+        for rec in &records[20..40] {
+            writer.push_record(rec).unwrap();
+        }
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        writer.set_thread_pool(Arc::new(pool)).unwrap();
+        assert!(write_all(writer, &records[40..]) == expected);
+    }
+    #[test]
+    fn test_dry_run_projection() {
+        let records = varied_records();
+        let mut writer = memory_writer(2);
+        for rec in &records {
+            writer.push_record(rec).unwrap();
+        }
+        writer.finish().unwrap();
+        let full = writer.compression_report().unwrap();
+
+        let mut dry_run = Writer::new_dry_run(vec![Codecs::Zstd], 2, false, 4);
+        for rec in &records {
+            dry_run.push_record(rec).unwrap();
+        }
+        dry_run.finish().unwrap();
+        let estimate = dry_run.compression_report().unwrap();
+
+        assert!(estimate.is_estimate());
+        let tags = estimate.field(&Fields::RawTags);
+        assert_eq!(tags.blocks + tags.skipped_blocks, full.field(&Fields::RawTags).blocks);
+        assert!(tags.skipped_blocks > 0);
+        assert_eq!(estimate.total_uncompressed(), full.total_uncompressed());
+        assert!(estimate.total_compressed() < full.total_compressed());
+    }
+
+    /// Keeps every progress it was notified of.
+    struct Recorder(Arc<std::sync::Mutex<Vec<Progress>>>);
+
+    impl ProgressObserver for Recorder {
+        fn on_progress(&mut self, progress: &Progress) {
+            self.0.lock().unwrap().push(*progress);
+        }
+    }
+
+    #[test]
+    fn test_progress_observer() {
+        let records = varied_records();
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut writer = memory_writer(2);
+        writer.set_progress_observer(Box::new(Recorder(seen.clone())));
+        for rec in &records {
+            writer.push_record(rec).unwrap();
+        }
+        assert!(!seen.lock().unwrap().is_empty());
+        let total_bytes = writer.finish().unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert!(seen.windows(2).all(|pair| pair[0].blocks_flushed <= pair[1].blocks_flushed));
+        let last = seen.last().unwrap();
+        assert_eq!(last, writer.progress());
+        assert_eq!(last.records, records.len() as u64);
+        assert_eq!(last.bytes_in, records.iter().map(|rec| rec.0.len() as u64).sum::<u64>());
+        assert_eq!(last.bytes_out, total_bytes);
+        let blocks: usize = Fields::iterator().map(|field| writer.file_meta.view_blocks(field).len()).sum();
+        assert_eq!(last.blocks_flushed, blocks as u64);
+    }
+
+    #[test]
+    fn test_block_limits() {
+        let records: Vec<_> = varied_records().into_iter().take(20).collect();
+        let dir = TempDir::new("writer").unwrap();
+        let path = dir.path().join("limits.gbam");
+        let mut writer = Writer::new(
+            File::create(&path).unwrap(),
+            vec![Codecs::Lz4],
+            2,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            String::new(),
+            false,
+        );
+        let by_records = BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(3) };
+        let by_bytes = BlockLimits { max_bytes: 100, max_records: None };
+        writer.set_all_block_limits(by_records);
+        writer.set_block_limits(Fields::RawTags, by_bytes);
+        for rec in &records {
+            writer.push_record(rec).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let tmplt = ParsingTemplate::new_with(&[Fields::Pos, Fields::RawTags]);
+        let mut reader = Reader::new(File::open(&path).unwrap(), tmplt).unwrap();
+        let meta = reader.file_meta.clone();
+        assert_eq!(meta.get_block_limits(&Fields::Pos), by_records);
+        assert_eq!(meta.get_block_limits(&Fields::RawTags), by_bytes);
+        assert_eq!(meta.view_blocks(&Fields::Pos).len(), 7);
+        assert!(meta.view_blocks(&Fields::Pos).iter().all(|block| block.numitems <= 3));
+        assert!(meta.view_blocks(&Fields::RawTags).iter().all(|block| block.numitems == 1));
+        let mut rec = GbamRecord::default();
+        for (rec_num, orig) in records.iter().enumerate() {
+            reader.fill_record(rec_num, &mut rec);
+            assert_eq!(rec.tags.as_deref().unwrap(), orig.get_bytes(&Fields::RawTags));
+        }
+    }
+
+    #[test]
+    fn test_omit_fields() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:1000\n");
+        for i in 0..100 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\tXI:i:{}\n", i, i + 1, i));
+        }
+        sam.push_str("u\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n");
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("writer").unwrap();
+        let path = dir.path().join("omitted.gbam");
+        let mut writer = Writer::new(
+            File::create(&path).unwrap(),
+            vec![Codecs::Lz4],
+            2,
+            Vec::new(),
+            ref_seqs,
+            sam_header,
+            String::new(),
+            false,
+        );
+        writer.omit_field(Fields::RawQual);
+        writer.omit_field(Fields::RawTags);
+        writer.omit_field(Fields::ReadName);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let tmplt = ParsingTemplate::new_with(&[Fields::Pos, Fields::ReadName, Fields::RawSequence, Fields::RawQual, Fields::RawTags]);
+        let mut reader = Reader::new(File::open(&path).unwrap(), tmplt).unwrap();
+        let meta = reader.file_meta.clone();
+        assert!(meta.is_omitted(&Fields::RawQual) && meta.is_omitted(&Fields::RawTags));
+        assert!(!meta.is_omitted(&Fields::RawSequence));
+        assert!(meta.view_blocks(&Fields::RawQual).is_empty());
+        assert!(meta.view_blocks(&Fields::RawTags).is_empty());
+        assert!(meta.is_omitted(&Fields::ReadName) && meta.view_blocks(&Fields::ReadName).is_empty());
+        let mut rec = GbamRecord::default();
+        for rec_num in 0..100 {
+            reader.fill_record(rec_num, &mut rec);
+            assert_eq!(rec.pos, Some(rec_num as i32));
+            assert_eq!(rec.seq.as_deref(), Some("ACGT"));
+            assert_eq!(rec.qual.as_deref(), Some(&[0xFF; 4][..]));
+            assert_eq!(rec.tags.as_deref(), Some(&[][..]));
+            assert_eq!((rec.read_name.as_deref(), rec.name()), (Some(&b"*\0"[..]), Some(&b"*"[..])));
+        }
+        reader.fill_record(100, &mut rec);
+        assert_eq!(rec.qual.as_deref(), Some(&[][..]));
+    }
+
+    #[test]
+    fn test_reader_with_fields() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:1000\n");
+        for i in 0..10 {
+            sam.push_str(&format!("r{}\t16\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\n", i, i + 1));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("writer").unwrap();
+        let path = dir.path().join("projected.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = Reader::with_fields(File::open(&path).unwrap(), &[Fields::Flags, Fields::Pos]).unwrap();
+        assert!(reader.columns[Fields::RawSequence as usize].is_none());
+        assert!(reader.columns[Fields::ReadName as usize].is_none());
+        let mut rec = GbamRecord::default();
+        reader.fill_record(9, &mut rec);
+        assert_eq!((rec.flag, rec.pos), (Some(16), Some(9)));
+        assert!(rec.seq.is_none() && rec.read_name.is_none());
+    }
+
+    #[test]
+    fn test_records_iterator() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:1000\n");
+        for i in 0..10 {
+            sam.push_str(&format!("r{}\t{}\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\n", i, i % 2 * 16, i + 1));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("writer").unwrap();
+        let path = dir.path().join("records.gbam");
+        let mut writer = Writer::new(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, Vec::new(), ref_seqs, sam_header, String::new(), true);
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = Reader::with_fields(File::open(&path).unwrap(), &[Fields::Flags, Fields::Pos]).unwrap();
+        assert_eq!(reader.records().len(), 10);
+        let reverse: Vec<i32> = reader
+            .records()
+            .map(|rec| rec.unwrap())
+            .filter(|rec| rec.flag == Some(16))
+            .map(|rec| rec.pos.unwrap())
+            .collect();
+        assert_eq!(reverse, vec![1, 3, 5, 7, 9]);
+        let mut records = reader.records();
+        let mut rec = GbamRecord::default();
+        let mut count = 0;
+        while records.read_into(&mut rec).unwrap() {
+            assert_eq!(rec.pos, Some(count));
+            count += 1;
+        }
+        assert_eq!(count, 10);
+
+        // Flags are stored uncompressed, corruption is caught by checksum.
+        let offset = reader.file_meta.view_blocks(&Fields::Flags)[0].seekpos + 2;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[0xAB]).unwrap();
+        drop(file);
+        let mut reader = Reader::with_fields(File::open(&path).unwrap(), &[Fields::Flags]).unwrap();
+        let results: Vec<_> = reader.records().collect();
+        assert_eq!(results.len(), 10);
+        assert!(results.iter().all(|rec| rec.is_err()));
+        assert!(reader.records().collect::<std::io::Result<Vec<_>>>().is_err());
+    }
+
+    #[test]
+    fn test_unaligned_constant_blocks() {
+        let mut sam = String::new();
+        for i in 0..100 {
+            let flag = if i % 2 == 0 { 77 } else { 141 };
+            sam.push_str(&format!("r{}\t{}\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n", i / 2, flag));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let dir = TempDir::new("writer").unwrap();
+        let path = dir.path().join("unaligned.gbam");
+        let mut writer = Writer::new(
+            File::create(&path).unwrap(),
+            vec![Codecs::Lz4],
+            2,
+            Vec::new(),
+            ref_seqs,
+            sam_header,
+            String::new(),
+            false,
+        );
+        writer.set_mate_relative();
+        while let Some(rec) = sam_reader.next_rec() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let tmplt = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::Flags, Fields::RawCigar, Fields::NextPos]);
+        let mut reader = Reader::new(File::open(&path).unwrap(), tmplt).unwrap();
+        let meta = reader.file_meta.clone();
+        assert!(!meta.is_mate_relative());
+        for (field, codec) in [
+            (Fields::RefID, Codecs::Constant(4)),
+            (Fields::Pos, Codecs::Constant(4)),
+            (Fields::Bin, Codecs::Constant(2)),
+            (Fields::NCigar, Codecs::Constant(4)),
+        ] {
+            assert_eq!(meta.get_block_codec(&field, 0), codec, "{}", field);
+        }
+        assert!(!matches!(meta.get_block_codec(&Fields::Flags, 0), Codecs::Constant(_)));
+        let mut rec = GbamRecord::default();
+        for rec_num in [0, 1, 99] {
+            reader.fill_record(rec_num, &mut rec);
+            assert_eq!(rec.refid, Some(-1));
+            assert_eq!(rec.pos, Some(-1));
+            assert_eq!(rec.next_pos, Some(-1));
+            assert_eq!(rec.flag, Some(if rec_num % 2 == 0 { 77 } else { 141 }));
+        }
+    }
+
+    #[test]
+    fn test_name_tokenization() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..1500 {
+            sam.push_str(&format!("A00123:8:HXX:1:1101:{}:{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tIIII\n", 1000 + i, 2000 + i * 3, i + 1));
+        }
+        for (tokenization, codecs) in [
+            (NameTokenization::Auto, [Codecs::NameTok, Codecs::Lz4]),
+            (NameTokenization::On, [Codecs::NameTok, Codecs::NameTok]),
+            (NameTokenization::Off, [Codecs::Lz4, Codecs::Lz4]),
+        ] {
+            let mut sam_reader = SamReader::new(sam.as_bytes());
+            let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+            let dir = TempDir::new("writer").unwrap();
+            let path = dir.path().join("names.gbam");
+            let mut writer = Writer::new(
+                File::create(&path).unwrap(),
+                vec![Codecs::Lz4],
+                2,
+                Vec::new(),
+                ref_seqs,
+                sam_header,
+                String::new(),
+                false,
+            );
+            let limits = BlockLimits { max_bytes: SIZE_LIMIT, max_records: Some(1000) };
+            writer.set_block_limits(Fields::ReadName, limits);
+            writer.set_name_tokenization(tokenization);
+            while let Some(rec) = sam_reader.next_rec() {
+                writer.push_record(&BAMRawRecord(Cow::Borrowed(rec.unwrap()))).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let tmplt = ParsingTemplate::new_with(&[Fields::ReadName]);
+            let mut reader = Reader::new(File::open(&path).unwrap(), tmplt).unwrap();
+            let meta = reader.file_meta.clone();
+            let block_codecs: Vec<_> = (0..2).map(|block| meta.get_block_codec(&Fields::ReadName, block)).collect();
+            assert_eq!(block_codecs, codecs);
+            let mut rec = GbamRecord::default();
+            for rec_num in [0, 999, 1000, 1499] {
+                reader.fill_record(rec_num, &mut rec);
+                let name = format!("A00123:8:HXX:1:1101:{}:{}\0", 1000 + rec_num, 2000 + rec_num * 3);
+                assert_eq!(rec.read_name.as_deref(), Some(name.as_bytes()));
+                assert_eq!(rec.name(), Some(name.trim_end_matches('\0').as_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..3000 {
+            sam.push_str(&format!("r{}\t{}\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tII#I\tXI:i:{}\n", i, i % 3 * 16, i * 7 + 1, i));
+        }
+        let mut sam_reader = SamReader::new(sam.as_bytes());
+        let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+        let mut records = Vec::new();
+        while let Some(rec) = sam_reader.next_rec() {
+            records.push(rec.unwrap().to_vec());
+        }
+        let dir = TempDir::new("writer").unwrap();
+        let new_writer = |fout: File| {
+            let mut writer = Writer::new(
+                std::io::BufWriter::new(fout),
+                vec![Codecs::Zstd],
+                2,
+                vec![Fields::RefID],
+                ref_seqs.clone(),
+                sam_header.clone(),
+                String::new(),
+                true,
+            );
+            writer.set_all_block_limits(BlockLimits { max_bytes: 1000, max_records: None });
+            writer
+        };
+        let push = |writer: &mut Writer<_>, records: &[Vec<u8>]| {
+            for rec in records {
+                writer.push_record(&BAMRawRecord(Cow::Borrowed(&rec[..]))).unwrap();
+            }
+        };
+
+        let whole_path = dir.path().join("whole.gbam");
+        let mut writer = new_writer(File::create(&whole_path).unwrap());
+        push(&mut writer, &records);
+        writer.finish().unwrap();
+
+        // Writer crashes some time after the checkpoint.
+        let resumed_path = dir.path().join("resumed.gbam");
+        let mut writer = new_writer(File::create(&resumed_path).unwrap());
+        push(&mut writer, &records[..1234]);
+        let checkpoint_path = dir.path().join("resumed.checkpoint");
+        writer.checkpoint().unwrap().save(&checkpoint_path).unwrap();
+        push(&mut writer, &records[1234..2000]);
+        drop(writer);
+
+        let checkpoint = Checkpoint::load(&checkpoint_path).unwrap();
+        assert_eq!(checkpoint.records(), 1234);
+        let fout = std::fs::OpenOptions::new().write(true).open(&resumed_path).unwrap();
+        assert!(fout.metadata().unwrap().len() > checkpoint.data_end());
+        fout.set_len(checkpoint.data_end()).unwrap();
+        let mut writer = new_writer(fout);
+        writer.resume(checkpoint).unwrap();
+        push(&mut writer, &records[1234..]);
+        writer.finish().unwrap();
+        assert_eq!(writer.progress().records, 3000);
+
+        assert_eq!(std::fs::read(&whole_path).unwrap(), std::fs::read(&resumed_path).unwrap());
+    }
+}
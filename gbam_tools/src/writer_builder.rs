@@ -0,0 +1,378 @@
+use super::bam::header_edit::HeaderEdits;
+use super::bam::sam_reader::RefSeqs;
+use super::codec::get_codec;
+use super::meta::{BlockLimits, Codecs, NameTokenization, QualBinning, STATS_FIELDS};
+use super::record_filter::RecordFilter;
+use super::reference::{check_references, ReferenceMismatch};
+use super::writer::Writer;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use rayon::ThreadPool;
+use std::fmt;
+use std::io::{Seek, Write};
+use std::sync::Arc;
+
+/// Invalid writer configuration, reported by `WriterBuilder::build` before
+/// anything is written.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// No codecs or more codecs than fields.
+    CodecCount(usize),
+    /// Codec specific to another field, e.g. `Seq2Bit` for `RawQual`.
+    CodecField(Fields, Codecs),
+    /// Custom codec which is not registered.
+    UnknownCodec(u32),
+    ThreadNum,
+    BlockLimits(Fields, BlockLimits),
+    /// Stats are collected only for RefID, POS, MAPQ and TLEN.
+    Stats(Fields),
+    /// Only ReadName, RawQual and RawTags may be omitted.
+    OmittedField(Fields),
+    /// Region of reference missing in the header.
+    Region(String),
+    /// Duplicates are marked only in coordinate sorted files.
+    DuplicatesUnsorted,
+    /// Reference sequences of the header don't match given FASTA index.
+    Reference(ReferenceMismatch),
+    MemoryBudget,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::CodecCount(count) => write!(f, "Expected 1 to {} codecs, got {}.", FIELDS_NUM, count),
+            ConfigError::CodecField(field, codec) => write!(f, "Codec {:?} can't be used for {}.", codec, field),
+            ConfigError::UnknownCodec(id) => write!(f, "Custom codec {} is not registered.", id),
+            ConfigError::ThreadNum => write!(f, "Number of threads should be positive."),
+            ConfigError::BlockLimits(field, limits) => write!(f, "Block limits of {} should be positive: {:?}", field, limits),
+            ConfigError::Stats(field) => write!(f, "Stats are collected only for RefID, Pos, Mapq and TemplateLength, not {}.", field),
+            ConfigError::OmittedField(field) => write!(f, "Only ReadName, RawQual and RawTags may be omitted, not {}.", field),
+            ConfigError::Region(msg) => write!(f, "{}", msg),
+            ConfigError::DuplicatesUnsorted => write!(f, "Duplicates can be marked only in coordinate sorted file."),
+            ConfigError::Reference(mismatch) => write!(f, "{}", mismatch),
+            ConfigError::MemoryBudget => write!(f, "Compression memory budget should be positive."),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(err: ConfigError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Configuration of `Writer`, validated as a whole by `build`. Can be
+/// cloned to create several writers of the same configuration, e.g. by
+/// `SplitWriter`.
+#[derive(Clone)]
+pub struct WriterBuilder {
+    pub(crate) codecs: Vec<Codecs>,
+    pub(crate) thread_num: usize,
+    pub(crate) pool: Option<Arc<ThreadPool>>,
+    pub(crate) collect_stats_for: Vec<Fields>,
+    pub(crate) ref_seqs: RefSeqs,
+    pub(crate) sam_header: Vec<u8>,
+    pub(crate) full_command: String,
+    pub(crate) is_sorted: bool,
+    pub(crate) qual_binning: Option<QualBinning>,
+    pub(crate) mate_relative: bool,
+    pub(crate) block_limits: Vec<(Fields, BlockLimits)>,
+    pub(crate) omit_fields: Vec<Fields>,
+    pub(crate) header_edits: HeaderEdits,
+    pub(crate) filter: Option<RecordFilter>,
+    pub(crate) optical_distance: Option<u32>,
+    pub(crate) memory_budget: Option<usize>,
+    pub(crate) tokenize_names: NameTokenization,
+    pub(crate) reference: Option<RefSeqs>,
+}
+
+impl Default for WriterBuilder {
+    fn default() -> Self {
+        Self {
+            codecs: vec![Codecs::Gzip],
+            thread_num: 8,
+            pool: None,
+            collect_stats_for: vec![Fields::Pos, Fields::Mapq, Fields::TemplateLength],
+            ref_seqs: Vec::new(),
+            sam_header: Vec::new(),
+            full_command: String::new(),
+            is_sorted: false,
+            qual_binning: None,
+            mate_relative: false,
+            block_limits: Vec::new(),
+            omit_fields: Vec::new(),
+            header_edits: HeaderEdits::new(),
+            filter: None,
+            optical_distance: None,
+            memory_budget: None,
+            tokenize_names: NameTokenization::default(),
+            reference: None,
+        }
+    }
+}
+
+impl WriterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Codecs indexed by field, see `Writer::new`.
+    pub fn codecs(mut self, codecs: Vec<Codecs>) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    pub fn thread_num(mut self, thread_num: usize) -> Self {
+        self.thread_num = thread_num;
+        self
+    }
+
+    /// Compress on `pool` instead of a pool of `thread_num` threads.
+    pub fn thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Collect min and max of `field` for every block, besides POS, MAPQ and
+    /// TLEN collected by default. Lets readers skip blocks, see
+    /// `RangeFilter`.
+    pub fn collect_stats(mut self, field: Fields) -> Self {
+        self.collect_stats_for.push(field);
+        self
+    }
+
+    /// Reference sequences and header bytes laid out as in BAM file.
+    pub fn header(mut self, ref_seqs: RefSeqs, sam_header: Vec<u8>) -> Self {
+        self.ref_seqs = ref_seqs;
+        self.sam_header = sam_header;
+        self
+    }
+
+    pub fn full_command(mut self, full_command: String) -> Self {
+        self.full_command = full_command;
+        self
+    }
+
+    /// Records are coordinate sorted.
+    pub fn sorted(mut self, is_sorted: bool) -> Self {
+        self.is_sorted = is_sorted;
+        self
+    }
+
+    /// See `Writer::set_qual_binning`.
+    pub fn qual_binning(mut self, binning: Option<QualBinning>) -> Self {
+        self.qual_binning = binning;
+        self
+    }
+
+    /// See `Writer::set_mate_relative`.
+    pub fn mate_relative(mut self, mate_relative: bool) -> Self {
+        self.mate_relative = mate_relative;
+        self
+    }
+
+    /// See `Writer::set_block_limits`.
+    pub fn block_limits(mut self, field: Fields, limits: BlockLimits) -> Self {
+        self.block_limits.push((field, limits));
+        self
+    }
+
+    /// See `Writer::set_all_block_limits`.
+    pub fn all_block_limits(mut self, limits: BlockLimits) -> Self {
+        self.block_limits = Fields::iterator().map(|field| (*field, limits)).collect();
+        self
+    }
+
+    /// See `Writer::omit_field`.
+    pub fn omit_field(mut self, field: Fields) -> Self {
+        self.omit_fields.push(field);
+        self
+    }
+
+    /// See `Writer::edit_header`.
+    pub fn header_edits(mut self, edits: HeaderEdits) -> Self {
+        self.header_edits = edits;
+        self
+    }
+
+    /// See `Writer::set_filter`.
+    pub fn filter(mut self, filter: Option<RecordFilter>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Mark duplicates with `optical_distance`, if given. See
+    /// `Writer::set_duplicate_marking`.
+    pub fn mark_duplicates(mut self, optical_distance: Option<u32>) -> Self {
+        self.optical_distance = optical_distance;
+        self
+    }
+
+    /// See `Writer::set_compression_memory_budget`.
+    pub fn compression_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// See `Writer::set_name_tokenization`.
+    pub fn tokenize_names(mut self, tokenization: NameTokenization) -> Self {
+        self.tokenize_names = tokenization;
+        self
+    }
+
+    /// Reference sequences of FASTA index (see `reference::read_fai`) the
+    /// header has to match, so regions of the output can be queried against
+    /// the FASTA.
+    pub fn reference(mut self, fai: RefSeqs) -> Self {
+        self.reference = Some(fai);
+        self
+    }
+
+    /// Checks configuration without creating a writer.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.codecs.is_empty() || self.codecs.len() > FIELDS_NUM {
+            return Err(ConfigError::CodecCount(self.codecs.len()));
+        }
+        for (field, codec) in Fields::iterator().zip(self.codecs.iter().chain(std::iter::repeat(&self.codecs[0]))) {
+            match codec_field(codec) {
+                Some(own_field) if own_field != *field => return Err(ConfigError::CodecField(*field, *codec)),
+                _ => {}
+            }
+            if let Codecs::Custom(id) = codec {
+                get_codec(*id).map_err(|_| ConfigError::UnknownCodec(*id))?;
+            }
+        }
+        if self.pool.is_none() && self.thread_num == 0 {
+            return Err(ConfigError::ThreadNum);
+        }
+        for (field, limits) in &self.block_limits {
+            if limits.max_bytes == 0 || limits.max_records == Some(0) {
+                return Err(ConfigError::BlockLimits(*field, *limits));
+            }
+        }
+        if let Some(field) = self.collect_stats_for.iter().find(|field| !STATS_FIELDS.contains(field)) {
+            return Err(ConfigError::Stats(*field));
+        }
+        if let Some(field) = self.omit_fields.iter().find(|field| !matches!(field, Fields::ReadName | Fields::RawQual | Fields::RawTags)) {
+            return Err(ConfigError::OmittedField(*field));
+        }
+        if let Some(filter) = &self.filter {
+            filter.clone().resolve(&self.ref_seqs).map_err(|err| ConfigError::Region(err.to_string()))?;
+        }
+        if self.optical_distance.is_some() && !self.is_sorted {
+            return Err(ConfigError::DuplicatesUnsorted);
+        }
+        if self.memory_budget == Some(0) {
+            return Err(ConfigError::MemoryBudget);
+        }
+        if let Some(fai) = &self.reference {
+            check_references(&self.ref_seqs, fai).map_err(ConfigError::Reference)?;
+        }
+        Ok(())
+    }
+
+    /// Validates configuration and creates writer into `inner`. Nothing is
+    /// written into `inner` if configuration is invalid.
+    pub fn build<WS: Write + Seek>(self, inner: WS) -> Result<Writer<WS>, ConfigError> {
+        self.validate()?;
+        let thread_num = if self.pool.is_some() { 1 } else { self.thread_num };
+        let mut writer = Writer::new(
+            inner,
+            self.codecs,
+            thread_num,
+            self.collect_stats_for,
+            self.ref_seqs,
+            self.sam_header,
+            self.full_command,
+            self.is_sorted,
+        );
+        if let Some(pool) = self.pool {
+            // Nothing is queued yet, so it can't fail.
+            writer.set_thread_pool(pool).unwrap();
+        }
+        if let Some(binning) = self.qual_binning {
+            writer.set_qual_binning(binning);
+        }
+        if self.mate_relative {
+            writer.set_mate_relative();
+        }
+        for (field, limits) in self.block_limits {
+            writer.set_block_limits(field, limits);
+        }
+        for field in self.omit_fields {
+            writer.omit_field(field);
+        }
+        writer.edit_header(&self.header_edits);
+        if let Some(filter) = self.filter {
+            writer.set_filter(filter).unwrap();
+        }
+        if let Some(optical_distance) = self.optical_distance {
+            writer.set_duplicate_marking(optical_distance).unwrap();
+        }
+        if let Some(bytes) = self.memory_budget {
+            writer.set_compression_memory_budget(bytes);
+        }
+        writer.set_name_tokenization(self.tokenize_names);
+        Ok(writer)
+    }
+}
+
+/// Field a codec is specific to.
+fn codec_field(codec: &Codecs) -> Option<Fields> {
+    match codec {
+        Codecs::Seq2Bit => Some(Fields::RawSequence),
+        Codecs::QualCtx => Some(Fields::RawQual),
+        Codecs::CigarSplit => Some(Fields::RawCigar),
+        Codecs::TagSplit => Some(Fields::RawTags),
+        Codecs::FlagPlanes => Some(Fields::Flags),
+        Codecs::PosDelta => Some(Fields::Pos),
+        Codecs::NameTok => Some(Fields::ReadName),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_builder_validation() {
+        let build = |builder: WriterBuilder| builder.build(Cursor::new(Vec::new())).map(|_| ());
+        let mut codecs = vec![Codecs::Zstd; FIELDS_NUM];
+        codecs[Fields::RawQual as usize] = Codecs::QualCtx;
+        assert!(build(WriterBuilder::new().codecs(codecs.clone())).is_ok());
+        codecs[Fields::RawTags as usize] = Codecs::QualCtx;
+        assert!(matches!(
+            build(WriterBuilder::new().codecs(codecs)),
+            Err(ConfigError::CodecField(Fields::RawTags, Codecs::QualCtx))
+        ));
+        // Default codec applies to fields without entry.
+        assert!(matches!(build(WriterBuilder::new().codecs(vec![Codecs::Seq2Bit])), Err(ConfigError::CodecField(..))));
+        assert!(matches!(build(WriterBuilder::new().codecs(Vec::new())), Err(ConfigError::CodecCount(0))));
+        assert!(matches!(build(WriterBuilder::new().codecs(vec![Codecs::Custom(123_456)])), Err(ConfigError::UnknownCodec(123_456))));
+        assert!(matches!(build(WriterBuilder::new().thread_num(0)), Err(ConfigError::ThreadNum)));
+        let limits = BlockLimits { max_bytes: 0, max_records: None };
+        assert!(matches!(build(WriterBuilder::new().block_limits(Fields::Pos, limits)), Err(ConfigError::BlockLimits(Fields::Pos, _))));
+        assert!(matches!(build(WriterBuilder::new().collect_stats(Fields::Flags)), Err(ConfigError::Stats(Fields::Flags))));
+        assert!(matches!(build(WriterBuilder::new().omit_field(Fields::RawCigar)), Err(ConfigError::OmittedField(Fields::RawCigar))));
+        let mut filter = RecordFilter::new();
+        filter.add_region("chr1:1-10").unwrap();
+        assert!(matches!(build(WriterBuilder::new().filter(Some(filter.clone()))), Err(ConfigError::Region(_))));
+        let ref_seqs = vec![("chr1".to_owned(), 1000)];
+        assert!(build(WriterBuilder::new().header(ref_seqs, Vec::new()).filter(Some(filter))).is_ok());
+        assert!(matches!(build(WriterBuilder::new().mark_duplicates(Some(100))), Err(ConfigError::DuplicatesUnsorted)));
+        assert!(build(WriterBuilder::new().sorted(true).mark_duplicates(Some(100))).is_ok());
+        let mut codecs = vec![Codecs::Zstd; FIELDS_NUM];
+        codecs[Fields::ReadName as usize] = Codecs::NameTok;
+        // Codec of the column tokenizes every block even with tokenization off.
+        assert!(build(WriterBuilder::new().codecs(codecs)).is_ok());
+        let ref_seqs = vec![("chr1".to_owned(), 1000)];
+        assert!(build(WriterBuilder::new().header(ref_seqs.clone(), Vec::new()).reference(ref_seqs)).is_ok());
+        assert!(matches!(
+            build(WriterBuilder::new().header(vec![("chr1".to_owned(), 999)], Vec::new()).reference(vec![("chr1".to_owned(), 1000)])),
+            Err(ConfigError::Reference(ReferenceMismatch::Length { .. }))
+        ));
+    }
+}
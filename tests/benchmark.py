@@ -32,9 +32,9 @@ def gbam(bin_path, bam_path, res_path):
     gbam_path = res_path/"index_sort_out.gbam"
     depth_gbam_out = res_path/"gbam_depth_out.txt"
 
-    gbam_results["index-sort"]  = subprocess.check_output([f"{timer} {bin_path} {bam_path} -c -s --sort-temp-mode ram -o {gbam_path} --index-sort"], shell=True, stderr=subprocess.STDOUT)
-    gbam_results["flagstat"]  = subprocess.check_output([f"{timer} {bin_path} --flagstat {gbam_path}"], shell=True, stderr=subprocess.STDOUT)
-    gbam_results["depth"] = subprocess.check_output([f"{timer} {bin_path} --depth {gbam_path} --thread-num 12 --index-file {gbam_path.with_suffix('.gbam.gbai')} > {depth_gbam_out}"], shell=True, stderr=subprocess.STDOUT)
+    gbam_results["index-sort"]  = subprocess.check_output([f"{timer} {bin_path} write {bam_path} -s --sort-temp-mode ram -o {gbam_path} --index-sort"], shell=True, stderr=subprocess.STDOUT)
+    gbam_results["flagstat"]  = subprocess.check_output([f"{timer} {bin_path} stats {gbam_path}"], shell=True, stderr=subprocess.STDOUT)
+    gbam_results["depth"] = subprocess.check_output([f"{timer} {bin_path} depth {gbam_path} --threads 12 --index-file {gbam_path.with_suffix('.gbam.order')} > {depth_gbam_out}"], shell=True, stderr=subprocess.STDOUT)
 
     print(f"Completed GBAM benchmarking, took: {time.time()-start} seconds")
 
@@ -99,7 +99,7 @@ def gfainject(bin_path, gbam_path, gfa_path, bam_path, res_path):
 
 
 
-# python3 tests/benchmark.py --gbam_bin target/release/gbam_binary --bam_file test_data/little.bam --result_dir benchmarking --samtools_bin /usr/local/bin/samtools --sambamba_bin /usr/local/bin/sambamba-0.8.2-linux-amd64-static
+# python3 tests/benchmark.py --gbam_bin target/release/gbam --bam_file test_data/little.bam --result_dir benchmarking --samtools_bin /usr/local/bin/samtools --sambamba_bin /usr/local/bin/sambamba-0.8.2-linux-amd64-static
 if __name__ == '__main__':
     parser = argparse.ArgumentParser(description="Benchmark SAMTOOLS/SAMBAMBA/GBAM")
     parser.add_argument("--samtools_bin",   help="Path to samtools binary", required=False)
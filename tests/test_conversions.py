@@ -12,7 +12,7 @@ with_depth = pytest.mark.skipif("not config.getoption('with_depth')")
 cur_file_path = Path(__file__).parent.absolute()
 
 test_data_folder = cur_file_path.parent/"test_data"
-binary_path = cur_file_path.parent/"target"/"release"/"gbam_binary"
+binary_path = cur_file_path.parent/"target"/"release"/"gbam"
 
 bam_file_path = test_data_folder/"little.bam"
 
@@ -27,10 +27,10 @@ def gen_gbam_file(request):
         bam_file_path = Path(request.config.getoption("--use-custom-file"))
     gbam_file = NamedTemporaryFile()
     # Simply convert to GBAM.
-    subprocess.run([binary_path, bam_file_path, "-c", "-o", gbam_file.name]) 
+    subprocess.run([binary_path, "write", bam_file_path, "-o", gbam_file.name]) 
     gbam_file_sorted = NamedTemporaryFile()
     # Sort and convert to GBAM.
-    subprocess.run([binary_path, bam_file_path, "-c", "-s", "-o", gbam_file_sorted.name, "--index-sort"]) 
+    subprocess.run([binary_path, "write", bam_file_path, "-s", "-o", gbam_file_sorted.name, "--index-sort"]) 
     bam_file_sorted_path = NamedTemporaryFile(suffix=".bam")
     subprocess.run(["samtools", "sort", "-@ 8", bam_file_path, "-o", bam_file_sorted_path.name]) 
 
@@ -64,12 +64,12 @@ def test_bam_to_gbam_to_bam(request):
     cli_bam_path = request.config.getoption("--bam-file-path")
     
     if cli_bam_path is None:
-        subprocess.run([binary_path, "--convert-to-bam", gbam_file.name, "-o", bam_file_from_gbam.name]) 
+        subprocess.run([binary_path, "to-bam", gbam_file.name, "-o", bam_file_from_gbam.name]) 
     else:
         cli_bam_path = Path(cli_bam_path)
         temp_gbam = NamedTemporaryFile()
-        subprocess.run([binary_path, cli_bam_path.as_posix(), "-c", "-o", temp_gbam.name]) 
-        subprocess.run([binary_path, "--convert-to-bam", temp_gbam.name, "-o", bam_file_from_gbam.name]) 
+        subprocess.run([binary_path, "write", cli_bam_path.as_posix(), "-o", temp_gbam.name]) 
+        subprocess.run([binary_path, "to-bam", temp_gbam.name, "-o", bam_file_from_gbam.name]) 
         test_bam_file_path = cli_bam_path
         del temp_gbam
 
@@ -77,7 +77,7 @@ def test_bam_to_gbam_to_bam(request):
 
 def test_flagstat():
     view_of_original = subprocess.check_output(["samtools", "flagstat", str(bam_file_path)], stderr=subprocess.STDOUT)
-    view_of_result = subprocess.check_output([binary_path, "--flagstat", gbam_file.name], stderr=subprocess.STDOUT)
+    view_of_result = subprocess.check_output([binary_path, "stats", gbam_file.name], stderr=subprocess.STDOUT)
 
     assert(len(view_of_original) > 0)
     assert(view_of_original == view_of_result)
@@ -88,14 +88,14 @@ def test_sort(request):
     cli_bam_path = request.config.getoption("--bam-file-path")
     
     if cli_bam_path is None:
-        gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_file_sorted, gbam_file_sorted.name+".gbai", bam_file_sorted_path.name)
+        gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_file_sorted, gbam_file_sorted.name+".order", bam_file_sorted_path.name)
         byte_file_comparison(gbam_res.name, samtools_res.name)
         return
     
     cli_bam_path = Path(cli_bam_path)
     temporary_gbam = NamedTemporaryFile()
-    subprocess.run([binary_path, cli_bam_path.as_posix(), "-c", "-s", "-o", temporary_gbam.name, "--sort-temp-mode", "lz4_ram"]) 
-    subprocess.run([binary_path, "--convert-to-bam", temporary_gbam.name, "-o", gbam_sorted_results.name]) 
+    subprocess.run([binary_path, "write", cli_bam_path.as_posix(), "-s", "-o", temporary_gbam.name, "--sort-temp-mode", "lz4_ram"]) 
+    subprocess.run([binary_path, "to-bam", temporary_gbam.name, "-o", gbam_sorted_results.name]) 
     samtools_sorted_results = NamedTemporaryFile(suffix=".bam")
     subprocess.run(["samtools", "sort", cli_bam_path.as_posix(), "-o", samtools_sorted_results.name]) 
 
@@ -120,7 +120,7 @@ def test_depth():
 
     subprocess.check_call(['mosdepth', '-x', mosdepth_prefix, bam_file_path.name], cwd=temp_dir.name)
     bed_gz = NamedTemporaryFile()
-    subprocess.check_call([binary_path, gbam_file_sorted.name, '-d', '-o', bed_gz.name, '--index-file', gbam_file_sorted.name + '.gbai'])
+    subprocess.check_call([binary_path, 'depth', gbam_file_sorted.name, '-o', bed_gz.name, '--index-file', gbam_file_sorted.name + '.order'])
     
     with gzip.open(bed_gz, "rb") as gbam_res, gzip.open(mosdepth_file, "rb") as mosdepth_res:
         # Read the decompressed data from both files
@@ -138,7 +138,7 @@ def generate_views_for_gbam_and_bam_files(gbam_input, gbam_index, bam_input):
     if gbam_index is not None:
         index_option = f"--index-file {gbam_index}"
 
-    subprocess.check_output([f"{binary_path} -v {gbam_input.name} {index_option} | samtools view > {gbam_results.name}"], shell=True) 
+    subprocess.check_output([f"{binary_path} view {gbam_input.name} {index_option} | samtools view > {gbam_results.name}"], shell=True) 
     subprocess.check_output([f"samtools view {bam_input} > {samtools_results.name}"], shell=True) 
 
     return (gbam_results, samtools_results)